@@ -0,0 +1,96 @@
+use sha3::{Digest, Sha3_256};
+
+use super::ContractRecipt;
+
+// leaves and inner nodes are hashed with different prefixes, so that an inner node can not be
+// passed off as a recipt.
+fn leaf_hash(recipt: &ContractRecipt) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update([0]);
+    hasher.update(recipt.hash());
+    hasher.update(serde_json::to_string(recipt.status()).unwrap());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Every level of the tree over `recipts`, from the leaves up to the root. A node without a
+/// sibling is moved up as it is. Pairing it with itself instead would give a list of recipts
+/// and the same list with its last recipt repeated the same root.
+fn levels(recipts: &[ContractRecipt]) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![recipts.iter().map(leaf_hash).collect::<Vec<_>>()];
+    while levels[levels.len() - 1].len() > 1 {
+        let next = levels[levels.len() - 1]
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                _ => pair[0],
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// The root of the merkle tree over `recipts`, or all zeros if there are none.
+pub fn merkle_root(recipts: &[ContractRecipt]) -> [u8; 32] {
+    levels(recipts)
+        .last()
+        .and_then(|root| root.first())
+        .copied()
+        .unwrap_or_default()
+}
+
+/// The siblings on the path from the recipt at `index` to the root, bottom up, skipping the
+/// levels where the path has no sibling. `index` has to be less than the number of recipts.
+pub fn merkle_proof(recipts: &[ContractRecipt], mut index: usize) -> Vec<[u8; 32]> {
+    let levels = levels(recipts);
+    let mut proof = vec![];
+    for level in &levels[..levels.len() - 1] {
+        if let Some(sibling) = level.get(index ^ 1) {
+            proof.push(*sibling);
+        }
+        index /= 2;
+    }
+    proof
+}
+
+/// Whether `proof` shows that `recipt` is the one at `index` in the tree with `root` over `count`
+/// recipts. Only clients check proofs, the node just hands them out over rpc.
+#[cfg(test)]
+pub fn verify_proof(
+    root: [u8; 32],
+    recipt: &ContractRecipt,
+    mut index: usize,
+    mut count: usize,
+    proof: &[[u8; 32]],
+) -> bool {
+    if index >= count {
+        return false;
+    }
+    let mut hash = leaf_hash(recipt);
+    let mut siblings = proof.iter();
+    while count > 1 {
+        // the last node of a level with an odd count has no sibling, and moves up as it is.
+        if index ^ 1 < count {
+            let sibling = match siblings.next() {
+                Some(sibling) => sibling,
+                None => return false,
+            };
+            hash = if index & 1 == 0 {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        index /= 2;
+        count = count.div_ceil(2);
+    }
+    siblings.next().is_none() && hash == root
+}
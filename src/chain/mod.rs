@@ -1,22 +1,68 @@
+mod merkle;
+
 use std::{
     fmt::{self, Debug},
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
 
 use chrono::{DateTime, NaiveDateTime, Utc};
-use ed25519_consensus::VerificationKey;
+use ed25519_consensus::Signature;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use sha3::{Digest, Sha3_256};
+use thiserror::Error;
 
 use crate::{
-    contracts::{native_init, ContractRequest},
-    storage::Storage,
+    config::GenesisConfig,
+    contracts::{native_init, native_init_stakes, ContractRequest},
+    storage::{Storage, StorageError, WriteOp},
 };
 
-fn hash_recipts(recipts: &[ContractRecipt], time: i64, output: &mut [u8]) {
+use self::merkle::{merkle_proof, merkle_root};
+
+#[derive(Debug, Error)]
+pub enum ChainError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("the block's digest or recipts root does not match its recipts")]
+    InvalidDigest,
+    #[error("the block does not extend the latest block")]
+    NotOnHead,
+    #[error("expected a block at height {0} but got one at {1}")]
+    InvalidHeight(u64, u64),
+    #[error("there is no block at height {0}")]
+    MissingBlock(u64),
+    #[error("the block at height {0} does not extend the one before it")]
+    BrokenLink(u64),
+    #[error("could not encode a block: {0}")]
+    Encode(serde_json::Error),
+    #[error("could not decode a block: {0}")]
+    Decode(serde_json::Error),
+    #[error("the latest block is missing")]
+    MissingHead,
+    #[error("the block's time {1} is not after its parent's time {0}")]
+    NonMonotonicTime(i64, i64),
+    #[error("the block's time {0} is too far in the future")]
+    FutureTime(i64),
+    #[error("the chain was started from another genesis")]
+    WrongGenesis,
+}
+
+/// How far ahead of our clock, in milliseconds, a block's time may be.
+const MAX_FUTURE_DRIFT: i64 = 15_000;
+/// The keyspace the blocks are kept in.
+const BLOCKS_NAMESPACE: &str = "blocks";
+
+/// Hashes the header of `block`, which is the block it extends, its height, its beneficiary, its
+/// time and the root of its recipts, along with the recipts themselves.
+fn block_digest(block: &Block) -> [u8; 32] {
     let mut hasher = Sha3_256::new();
-    recipts.iter().for_each(|req| {
+    hasher.update(block.previous_digest);
+    hasher.update(block.height.to_be_bytes());
+    hasher.update(block.beneficiary);
+    hasher.update(block.time.to_be_bytes());
+    hasher.update(block.recipts_root);
+    block.recipts.iter().for_each(|req| {
         let mut s = String::with_capacity(50);
         s.push_str(&req.contract_name);
         s.push_str(&req.contract_method);
@@ -25,22 +71,84 @@ fn hash_recipts(recipts: &[ContractRecipt], time: i64, output: &mut [u8]) {
         // of ownership.
 
         hasher.update(s);
+        hasher.update(req.author);
+        hasher.update(req.nonce.to_be_bytes());
+        if let Some(signature) = req.signature {
+            hasher.update(signature.to_bytes());
+        }
+        hasher.update(serde_json::to_string(&req.status).unwrap());
     });
-    hasher.update(time.to_be_bytes());
+    hasher.finalize().into()
+}
 
-    output.copy_from_slice(&hasher.finalize());
+/// Whether a transaction was applied, or reverted and left the state as it was.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxStatus {
+    #[default]
+    Success,
+    Reverted(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContractRecipt {
     contract_name: String, // NOTE: this will work when the contract is updated because the chain is evaluated from the start.
     contract_method: String,
     req: Value,
+    #[serde(default)]
+    author: [u8; 32],
+    #[serde(default)]
+    nonce: u64,
+    /// The author's signature of the request, for whoever runs it again to check who sent it.
+    #[serde(default)]
+    signature: Option<Signature>,
+    #[serde(default)]
+    status: TxStatus,
+}
+
+impl ContractRecipt {
+    /// Identifies the transaction by who sent it and what it calls, so that a pending request and
+    /// the recipt it turns into hash the same. It leaves out the status, which a pending request
+    /// does not have yet.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha3_256::new();
+        hasher.update(&self.contract_name);
+        hasher.update(&self.contract_method);
+        hasher.update(serde_json::to_string(&self.req).unwrap());
+        hasher.update(self.author);
+        hasher.update(self.nonce.to_be_bytes());
+        hasher.finalize().into()
+    }
+
+    pub fn with_status(mut self, status: TxStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn status(&self) -> &TxStatus {
+        &self.status
+    }
+
+    /// The request the recipt was made from, signed as it was, for running it again.
+    pub fn request(&self) -> ContractRequest {
+        ContractRequest::new(
+            self.author,
+            self.contract_name.clone(),
+            self.contract_method.clone(),
+            self.req.clone(),
+            self.nonce,
+            0,
+        )
+        .with_signature(self.signature)
+    }
 }
 
 impl From<ContractRequest> for ContractRecipt {
     fn from(req: ContractRequest) -> Self {
         Self {
+            author: req.author(),
+            nonce: req.nonce(),
+            signature: req.signature(),
+            status: TxStatus::Success,
             contract_name: req.name,
             contract_method: req.method_name,
             req: req.req,
@@ -52,13 +160,20 @@ pub fn requests_to_recipts(req: Vec<ContractRequest>) -> Vec<ContractRecipt> {
     req.into_iter().map(|req| req.into()).collect()
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
     digest: [u8; 32],
     beneficiary: [u8; 32],
     previous_digest: [u8; 32],
     recipts: Vec<ContractRecipt>,
+    /// The root of the merkle tree over the recipts, which proves that a recipt is in the block
+    /// without the rest of them.
+    #[serde(default)]
+    recipts_root: [u8; 32],
     time: i64,
+    /// How many blocks came before this one, genesis being at 0.
+    #[serde(default)]
+    height: u64,
 }
 
 impl Block {
@@ -67,14 +182,58 @@ impl Block {
             digest: [0; 32],
             beneficiary,
             previous_digest: [0; 32],
+            recipts_root: merkle_root(&transactions),
             recipts: transactions,
             time: Utc::now().timestamp_millis(),
+            height: 0,
         }
     }
 
     pub fn recipt_count(&self) -> usize {
         self.recipts.len()
     }
+
+    pub fn recipts(&self) -> &[ContractRecipt] {
+        &self.recipts
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        self.digest
+    }
+
+    /// The validator that produced the block.
+    pub fn beneficiary(&self) -> [u8; 32] {
+        self.beneficiary
+    }
+
+    pub fn previous_digest(&self) -> [u8; 32] {
+        self.previous_digest
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// When the block was built, in milliseconds since the epoch.
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+
+    pub fn recipts_root(&self) -> [u8; 32] {
+        self.recipts_root
+    }
+
+    /// Proves that the recipt at `index` is in this block, by the hashes on its way up to the
+    /// `recipts_root`.
+    pub fn merkle_proof(&self, index: usize) -> Vec<[u8; 32]> {
+        merkle_proof(&self.recipts, index)
+    }
+
+    /// Whether `digest` is the hash of the header and the recipts, as `BlockBuilder` computes it,
+    /// and `recipts_root` is the root of the recipts.
+    pub fn verify_digest(&self) -> bool {
+        block_digest(self) == self.digest && merkle_root(&self.recipts) == self.recipts_root
+    }
 }
 
 impl fmt::Debug for Block {
@@ -86,6 +245,7 @@ impl fmt::Debug for Block {
             .field("digest", &base64::encode(self.digest))
             .field("previous_digest", &base64::encode(self.previous_digest))
             .field("beneficiary", &base64::encode(self.beneficiary))
+            .field("height", &self.height)
             .field("time", &time.to_rfc2822())
             // .field("recipts", &recipts) // TODO: somehow show something like [item1, ...] len: x
             .finish()
@@ -94,49 +254,113 @@ impl fmt::Debug for Block {
 
 struct BlockStorage {
     storage: Arc<dyn Storage>,
+    blocks: Arc<dyn Storage>,
 }
 
 impl BlockStorage {
-    fn new(storage: Arc<dyn Storage>) -> Self {
-        Self { storage }
+    fn new(storage: Arc<dyn Storage>) -> Result<Self, StorageError> {
+        Ok(Self {
+            blocks: storage.namespace(BLOCKS_NAMESPACE)?,
+            storage,
+        })
+    }
+
+    /// Inserts `block` in the same batch as `writes`, the ops of each namespace named with them.
+    fn insert_block(
+        &self,
+        block: &Block,
+        set_latest: bool,
+        writes: &[(&str, &[WriteOp])],
+    ) -> Result<(), ChainError> {
+        let ops = Self::insert_block_ops(block, set_latest)?;
+        let mut batches = vec![(BLOCKS_NAMESPACE, ops.as_slice())];
+        batches.extend_from_slice(writes);
+        Ok(self.storage.batch_namespaces(&batches)?)
     }
 
-    fn insert_block(&self, block: Block, set_latest: bool) {
+    fn insert_block_ops(block: &Block, set_latest: bool) -> Result<Vec<WriteOp>, ChainError> {
+        let serialized = serde_json::to_string(block).map_err(ChainError::Encode)?;
+        let mut ops = vec![
+            WriteOp::Set {
+                key: [b"block", block.digest.as_ref()].concat(),
+                value: serialized.into_bytes(),
+            },
+            WriteOp::Set {
+                key: Self::height_key(block.height),
+                value: block.digest.to_vec(),
+            },
+        ];
         if set_latest {
-            self.storage.set(b"latest_block", &block.digest);
+            ops.push(WriteOp::Set {
+                key: b"latest_block".to_vec(),
+                value: block.digest.to_vec(),
+            });
+        }
+        Ok(ops)
+    }
+
+    /// The heights are big endian so that the index sorts in chain order.
+    fn height_key(height: u64) -> Vec<u8> {
+        [b"height:".as_ref(), &height.to_be_bytes()].concat()
+    }
+
+    fn latest_block(&self) -> Result<Option<Block>, ChainError> {
+        match self.blocks.get(b"latest_block")? {
+            Some(latest_hash) => self.block_by_hash(&latest_hash),
+            None => Ok(None),
         }
-        let serialized = serde_json::to_string(&block).unwrap();
-        self.storage.set(
-            &[b"block", block.digest.as_ref()].concat(),
-            serialized.as_bytes(),
-        );
     }
 
-    fn latest_block(&self) -> Option<Block> {
-        let latest_hash = self.storage.get(b"latest_block")?;
-        self.block_by_hash(&latest_hash)
+    fn block_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, ChainError> {
+        match self.blocks.get(&[b"block", hash].concat())? {
+            Some(bytes) => Ok(Some(
+                serde_json::from_slice(&bytes).map_err(ChainError::Decode)?,
+            )),
+            None => Ok(None),
+        }
     }
 
-    fn block_by_hash(&self, hash: &[u8]) -> Option<Block> {
-        let bytes = self.storage.get(&[b"block", hash].concat())?;
-        serde_json::from_slice(&bytes).unwrap_or(None)
+    fn block_by_height(&self, height: u64) -> Result<Option<Block>, ChainError> {
+        match self.blocks.get(&Self::height_key(height))? {
+            Some(hash) => self.block_by_hash(&hash),
+            None => Ok(None),
+        }
     }
 
-    fn maybe_bootstrap(&self) {
-        if self.latest_block().is_none() {
-            self.insert_block(
-                Block {
-                    digest: [0; 32],
-                    beneficiary: [0; 32],
-                    previous_digest: [0; 32],
-                    recipts: vec![],
-                    time: 0,
-                },
-                true,
-            );
-            native_init(self.storage.clone());
+    fn maybe_bootstrap(&self, genesis: &GenesisConfig) -> Result<(), ChainError> {
+        if !self.blocks.contains_key(b"latest_block")? {
+            self.insert_block(&genesis_block(genesis), true, &[])?;
+            native_init(self.storage.clone(), &genesis.allocations)?;
+            native_init_stakes(self.storage.clone(), &genesis.validator_stakes())?;
             tracing::debug!("bootstrapped the blockchain.");
         }
+        Ok(())
+    }
+}
+
+/// The first block of the chain. It holds no recipts, and its digest commits to the initial
+/// allocations and stakes and the chain's parameters so that nodes started with different ones do
+/// not agree on it.
+fn genesis_block(genesis: &GenesisConfig) -> Block {
+    let mut hasher = Sha3_256::new();
+    for (account, balance) in &genesis.allocations {
+        hasher.update((account.len() as u64).to_be_bytes());
+        hasher.update(account);
+        hasher.update(balance.to_be_bytes());
+    }
+    for (validator, stake) in genesis.validator_stakes() {
+        hasher.update(validator);
+        hasher.update(stake.to_be_bytes());
+    }
+    hasher.update(serde_json::to_string(&genesis.params).unwrap());
+    Block {
+        digest: hasher.finalize().into(),
+        beneficiary: [0; 32],
+        previous_digest: [0; 32],
+        recipts: vec![],
+        recipts_root: [0; 32],
+        time: 0,
+        height: 0,
     }
 }
 
@@ -159,62 +383,212 @@ impl BlockBuilder {
         self.transactions.push(tx);
     }
 
-    fn build(self, beneficiary: [u8; 32], previous_digest: [u8; 32]) -> Block {
-        let time = Utc::now().timestamp_millis();
-        let buf = &mut [0; 32];
-        hash_recipts(&self.transactions, time, buf);
-        Block {
-            digest: *buf,
-            previous_digest,
+    fn build(self, beneficiary: [u8; 32], previous: &Block, time: i64) -> Block {
+        // never stamp a time at or before the parent's, even if our clock is behind it.
+        let time = time.max(previous.time + 1);
+        let mut block = Block {
+            digest: [0; 32],
+            previous_digest: previous.digest,
             beneficiary,
+            recipts_root: merkle_root(&self.transactions),
             recipts: self.transactions,
             time,
+            height: previous.height + 1,
+        };
+        block.digest = block_digest(&block);
+        block
+    }
+}
+
+/// Walks the chain from genesis up to the head it had when the iteration started, checking that
+/// every block extends the one before it. It stops after the first error.
+pub struct ChainIterator<'a> {
+    storage: &'a BlockStorage,
+    height: u64,
+    head: u64,
+    previous_digest: Option<[u8; 32]>,
+}
+
+impl ChainIterator<'_> {
+    fn next_block(&mut self) -> Result<Block, ChainError> {
+        let block = self
+            .storage
+            .block_by_height(self.height)?
+            .ok_or(ChainError::MissingBlock(self.height))?;
+        if let Some(previous_digest) = self.previous_digest {
+            if block.previous_digest != previous_digest {
+                return Err(ChainError::BrokenLink(self.height));
+            }
+        }
+        self.previous_digest = Some(block.digest);
+        Ok(block)
+    }
+}
+
+impl Iterator for ChainIterator<'_> {
+    type Item = Result<Block, ChainError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.height > self.head {
+            return None;
         }
+        let block = self.next_block();
+        // after an error there is no telling where the rest of the chain is.
+        self.height = if block.is_ok() {
+            self.height + 1
+        } else {
+            self.head + 1
+        };
+        Some(block)
+    }
+
+    /// Jumps over `n` blocks without reading them, so that `skip` starts the walk at any height.
+    /// The first block after the jump is not checked against the one before it.
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.height = self.height.saturating_add(n as u64);
+        self.previous_digest = None;
+        self.next()
     }
 }
 
 pub struct Chain {
     storage: BlockStorage,
-    finalized_block: Block,
+    finalized_block: RwLock<Block>,
     pubkey: [u8; 32],
 }
 
 impl Chain {
-    pub fn new(storage: Arc<dyn Storage>, pubkey: [u8; 32]) -> Self {
-        let storage = BlockStorage::new(storage);
-        storage.maybe_bootstrap();
+    pub fn new(storage: Arc<dyn Storage>, pubkey: [u8; 32]) -> Result<Self, ChainError> {
+        Self::with_genesis(storage, pubkey, &GenesisConfig::default())
+    }
 
-        let finalized_block = storage
-            .latest_block()
-            .expect("Could not bootstrap the chain");
-        Self {
+    /// Opens the chain in `storage`, starting it from `genesis` if it is empty. A chain that was
+    /// started from another genesis is not opened, as its blocks were made under other rules.
+    pub fn with_genesis(
+        storage: Arc<dyn Storage>,
+        pubkey: [u8; 32],
+        genesis: &GenesisConfig,
+    ) -> Result<Self, ChainError> {
+        let storage = BlockStorage::new(storage)?;
+        storage.maybe_bootstrap(genesis)?;
+        let first = storage
+            .block_by_height(0)?
+            .ok_or(ChainError::MissingBlock(0))?;
+        if first.digest != genesis_block(genesis).digest {
+            return Err(ChainError::WrongGenesis);
+        }
+        let finalized_block = storage.latest_block()?.ok_or(ChainError::MissingHead)?;
+        Ok(Self {
             storage,
-            finalized_block,
+            finalized_block: RwLock::new(finalized_block),
             pubkey,
+        })
+    }
+
+    /// Appends `block` to the chain, provided its digest is right and it extends the latest
+    /// block.
+    pub fn insert_block(&self, block: Block) -> Result<(), ChainError> {
+        self.insert_block_with(block, &[])
+    }
+
+    /// Like `insert_block`, storing `writes`, the ops of each namespace named with them, in the
+    /// same batch as the block. A crash then cannot leave the block without what it wrote.
+    pub fn insert_block_with(
+        &self,
+        block: Block,
+        writes: &[(&str, &[WriteOp])],
+    ) -> Result<(), ChainError> {
+        if !block.verify_digest() {
+            return Err(ChainError::InvalidDigest);
+        }
+        let (head, height, time) = self
+            .storage
+            .latest_block()?
+            .map(|block| (block.digest, block.height, block.time))
+            .unwrap_or_default();
+        if block.previous_digest != head {
+            return Err(ChainError::NotOnHead);
+        }
+        if block.height != height + 1 {
+            return Err(ChainError::InvalidHeight(height + 1, block.height));
         }
+        if block.time <= time {
+            return Err(ChainError::NonMonotonicTime(time, block.time));
+        }
+        if block.time > Utc::now().timestamp_millis() + MAX_FUTURE_DRIFT {
+            return Err(ChainError::FutureTime(block.time));
+        }
+
+        self.storage.insert_block(&block, true, writes)?;
+        // bound what a power loss can take with it to the block being written.
+        self.storage.blocks.flush()?;
+        *self.finalized_block.write().unwrap() = block;
+        Ok(())
     }
 
-    pub fn insert_block(&self, block: Block) {
-        self.storage.insert_block(block, true);
+    pub fn latest_block(&self) -> Result<Option<Block>, ChainError> {
+        self.storage.latest_block()
     }
 
-    pub fn block_with_transactions(&self, transactions: Vec<ContractRecipt>) -> Block {
-        BlockBuilder::with_transactions(transactions)
-            .build(self.pubkey, self.finalized_block.digest)
+    /// The height of the latest block, which is recovered from storage on startup.
+    pub fn height(&self) -> u64 {
+        self.finalized_block.read().unwrap().height
+    }
+
+    pub fn block_by_height(&self, height: u64) -> Result<Option<Block>, ChainError> {
+        self.storage.block_by_height(height)
+    }
+
+    /// The blocks from genesis to the latest one, in order.
+    pub fn iter(&self) -> ChainIterator<'_> {
+        ChainIterator {
+            storage: &self.storage,
+            height: 0,
+            head: self.height(),
+            previous_digest: None,
+        }
+    }
+
+    /// A block of `transactions` on top of the head, stamped with `time`, or just after the
+    /// head's time if that is not later.
+    pub fn block_with_transactions(&self, transactions: Vec<ContractRecipt>, time: i64) -> Block {
+        BlockBuilder::with_transactions(transactions).build(
+            self.pubkey,
+            &self.finalized_block.read().unwrap(),
+            time,
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{
+        fs,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
-    use crate::storage::{RocksdbStorage, Storage};
+    use crate::{
+        config::{GenesisConfig, StorageConfig},
+        contracts::balance,
+        storage::{RocksdbStorage, Storage, StorageError, WriteOp},
+    };
 
-    use super::{Chain, ContractRecipt};
-    use ed25519_consensus::SigningKey;
+    use super::{
+        block_digest, genesis_block, merkle::verify_proof, Block, Chain, ChainError,
+        ContractRecipt, TxStatus, MAX_FUTURE_DRIFT,
+    };
+    use ed25519_consensus::{Signature, SigningKey};
     use serde_json::json;
     use serial_test::serial;
 
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
     fn setup_chain() -> Chain {
         let config = Default::default();
         let storage: Arc<dyn Storage> = RocksdbStorage::load(&config);
@@ -224,16 +598,528 @@ mod tests {
                 .verification_key()
                 .to_bytes(),
         )
+        .unwrap()
+    }
+
+    fn fresh_chain(path: &str) -> Chain {
+        fresh_chain_with_genesis(path, &GenesisConfig::default())
+    }
+
+    fn fresh_chain_with_genesis(path: &str, genesis: &GenesisConfig) -> Chain {
+        let _ = fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        Chain::with_genesis(storage, [0; 32], genesis).unwrap()
     }
 
     #[test]
     #[serial]
     fn new_block() {
         let chain = setup_chain();
-        chain.block_with_transactions(vec![ContractRecipt {
-            contract_name: String::from("ginger"),
+        chain.block_with_transactions(
+            vec![ContractRecipt {
+                contract_name: String::from("ginger"),
+                contract_method: String::from("transfer"),
+                req: json!({ "from": "ginger", "to": "hello", "amount": 100_u64 }),
+                author: [0; 32],
+                nonce: 0,
+                signature: None,
+                status: TxStatus::Success,
+            }],
+            now(),
+        );
+    }
+
+    /// A backend that crashes once it has taken `writes` writes, whatever they are.
+    struct CrashingStorage {
+        inner: Arc<dyn Storage>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl CrashingStorage {
+        fn write(&self) -> Result<(), StorageError> {
+            match self
+                .writes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |writes| {
+                    writes.checked_sub(1)
+                }) {
+                Ok(_) => Ok(()),
+                // any rocksdb error will do, such as opening a db inside a file.
+                Err(_) => Err(rocksdb::DB::open(&Default::default(), "Cargo.toml/db")
+                    .err()
+                    .unwrap()
+                    .into()),
+            }
+        }
+    }
+
+    impl Storage for CrashingStorage {
+        fn load(config: &StorageConfig) -> Arc<Self> {
+            Arc::new(Self {
+                inner: RocksdbStorage::load(config),
+                writes: Arc::new(AtomicUsize::new(usize::MAX)),
+            })
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+            self.inner.get(key)
+        }
+
+        fn delete(&self, key: &[u8]) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.delete(key)
+        }
+
+        fn delete_prefix(&self, prefix: &[u8]) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.delete_prefix(prefix)
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_> {
+            self.inner.scan_prefix(prefix)
+        }
+
+        fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.set(key, value)
+        }
+
+        fn batch(&self, ops: &[WriteOp]) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.batch(ops)
+        }
+
+        fn batch_namespaces(&self, batches: &[(&str, &[WriteOp])]) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.batch_namespaces(batches)
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: &[u8],
+            expected: Option<&[u8]>,
+            new: &[u8],
+        ) -> Result<bool, StorageError> {
+            self.write()?;
+            self.inner.compare_and_swap(key, expected, new)
+        }
+
+        fn snapshot(&self, path: &Path) -> Result<(), StorageError> {
+            self.inner.snapshot(path)
+        }
+
+        fn flush(&self) -> Result<(), StorageError> {
+            self.inner.flush()
+        }
+
+        fn compact(&self) -> Result<(), StorageError> {
+            self.inner.compact()
+        }
+
+        fn namespace(&self, name: &str) -> Result<Arc<dyn Storage>, StorageError> {
+            Ok(Arc::new(Self {
+                inner: self.inner.namespace(name)?,
+                writes: self.writes.clone(),
+            }))
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn insert_block_is_atomic() {
+        for writes in 0..4 {
+            let _ = fs::remove_dir_all("db-atomic/");
+            let storage = CrashingStorage::load(&StorageConfig {
+                path: String::from("db-atomic/"),
+                ..Default::default()
+            });
+            let crash_after = storage.writes.clone();
+            let chain = Chain::new(storage, [0; 32]).unwrap();
+            let head = chain.storage.latest_block().unwrap().unwrap().digest;
+            let block = chain.block_with_transactions(vec![], now());
+
+            // however far the backend gets, the block and what it wrote are either all there or
+            // not at all.
+            crash_after.store(writes, Ordering::SeqCst);
+            let state = [WriteOp::Set {
+                key: b"balance".to_vec(),
+                value: b"100".to_vec(),
+            }];
+            let inserted = chain
+                .insert_block_with(block.clone(), &[("state", &state)])
+                .is_ok();
+            assert_eq!(inserted, writes > 0);
+            let latest = chain.storage.latest_block().unwrap().unwrap().digest;
+            assert_eq!(latest, if inserted { block.digest } else { head });
+            let by_hash = chain.storage.block_by_hash(&block.digest).unwrap();
+            let by_height = chain.storage.block_by_height(block.height).unwrap();
+            assert_eq!(by_hash.is_some(), inserted);
+            assert_eq!(by_height.is_some(), inserted);
+            let balance = chain.storage.storage.namespace("state").unwrap();
+            assert_eq!(balance.get(b"balance").unwrap().is_some(), inserted);
+
+            drop(chain);
+            fs::remove_dir_all("db-atomic/").unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn valid_blocks_are_accepted() {
+        let chain = setup_chain();
+        let block = chain.block_with_transactions(
+            vec![ContractRecipt {
+                contract_name: String::from("native"),
+                contract_method: String::from("transfer"),
+                req: json!({ "to": "ginger", "amount": 100_u64 }),
+                author: [0; 32],
+                nonce: 0,
+                signature: None,
+                status: TxStatus::Success,
+            }],
+            now(),
+        );
+        assert!(block.verify_digest());
+        let digest = block.digest;
+        chain.insert_block(block).unwrap();
+
+        // the next block builds on the one just inserted.
+        let next = chain.block_with_transactions(vec![], now());
+        assert_eq!(next.previous_digest, digest);
+        chain.insert_block(next).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn tampered_blocks_are_rejected() {
+        let chain = setup_chain();
+        let mut block = chain.block_with_transactions(
+            vec![ContractRecipt {
+                contract_name: String::from("native"),
+                contract_method: String::from("transfer"),
+                req: json!({ "to": "ginger", "amount": 100_u64 }),
+                author: [0; 32],
+                nonce: 0,
+                signature: None,
+                status: TxStatus::Success,
+            }],
+            now(),
+        );
+        block.recipts[0].req = json!({ "to": "ginger", "amount": 100_000_u64 });
+        assert!(!block.verify_digest());
+        assert!(matches!(
+            chain.insert_block(block),
+            Err(ChainError::InvalidDigest)
+        ));
+
+        let mut block = chain.block_with_transactions(vec![], now());
+        block.previous_digest = [7; 32];
+        block.digest = block_digest(&block);
+        assert!(matches!(
+            chain.insert_block(block),
+            Err(ChainError::NotOnHead)
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn every_header_field_is_part_of_the_digest() {
+        let chain = setup_chain();
+        let block = chain.block_with_transactions(vec![transfer(100)], now());
+        let encoded = serde_json::to_string(&block).unwrap();
+        let tamperings: [fn(&mut Block); 7] = [
+            |block| block.previous_digest = [7; 32],
+            |block| block.height += 1,
+            |block| block.beneficiary = [7; 32],
+            |block| block.time += 1,
+            |block| block.recipts_root = [7; 32],
+            |block| block.recipts.clear(),
+            |block| block.recipts[0].signature = Some(Signature::from([7; 64])),
+        ];
+        for tamper in tamperings {
+            let mut tampered: Block = serde_json::from_str(&encoded).unwrap();
+            tamper(&mut tampered);
+            assert_ne!(block_digest(&tampered), block.digest);
+            assert!(!tampered.verify_digest());
+        }
+    }
+
+    #[test]
+    fn author_is_part_of_the_digest() {
+        let mut signed = transfer(100);
+        signed.author = [1; 32];
+        let first = Block::with_transactions(vec![transfer(100)], [0; 32]);
+        let mut second = Block::with_transactions(vec![signed], [0; 32]);
+        second.time = first.time;
+        assert_ne!(block_digest(&first), block_digest(&second));
+    }
+
+    #[test]
+    #[serial]
+    fn recipts_keep_their_status() {
+        let path = "db-status/";
+        let chain = fresh_chain(path);
+        let reverted = TxStatus::Reverted(String::from("insufficient balance"));
+        let mut block = chain.block_with_transactions(
+            vec![
+                transfer(100),
+                transfer(1_000_000).with_status(reverted.clone()),
+            ],
+            now(),
+        );
+        let digest = block.digest;
+        chain.insert_block(block).unwrap();
+
+        block = chain.storage.block_by_hash(&digest).unwrap().unwrap();
+        assert_eq!(block.recipts()[0].status(), &TxStatus::Success);
+        assert_eq!(block.recipts()[1].status(), &reverted);
+
+        // passing a reverted transaction off as applied changes the digest.
+        block.recipts[1].status = TxStatus::Success;
+        assert!(!block.verify_digest());
+
+        drop(chain);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    fn stamped(mut block: Block, time: i64) -> Block {
+        block.time = time;
+        block.digest = block_digest(&block);
+        block
+    }
+
+    #[test]
+    #[serial]
+    fn block_times_are_monotonic() {
+        let path = "db-times/";
+        let chain = fresh_chain(path);
+        let first = chain.block_with_transactions(vec![], now());
+        let time = first.time;
+        chain.insert_block(first).unwrap();
+
+        let next = || chain.block_with_transactions(vec![], now());
+        assert!(next().time > time);
+        let backdated = stamped(next(), time - 1);
+        assert!(matches!(
+            chain.insert_block(backdated),
+            Err(ChainError::NonMonotonicTime(parent, _)) if parent == time
+        ));
+        let same_time = stamped(next(), time);
+        assert!(matches!(
+            chain.insert_block(same_time),
+            Err(ChainError::NonMonotonicTime(..))
+        ));
+        let future = stamped(next(), time + 2 * MAX_FUTURE_DRIFT);
+        assert!(matches!(
+            chain.insert_block(future),
+            Err(ChainError::FutureTime(_))
+        ));
+        chain.insert_block(next()).unwrap();
+
+        drop(chain);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn blocks_by_height() {
+        let path = "db-heights/";
+        let chain = fresh_chain(path);
+        assert_eq!(chain.height(), 0);
+        let genesis = genesis_block(&GenesisConfig::default()).digest;
+        assert_eq!(chain.block_by_height(0).unwrap().unwrap().digest, genesis);
+
+        let mut digests = vec![];
+        for _ in 0..3 {
+            let block = chain.block_with_transactions(vec![], now());
+            digests.push(block.digest);
+            chain.insert_block(block).unwrap();
+        }
+        assert_eq!(chain.height(), 3);
+
+        for (height, digest) in (1..).zip(digests) {
+            let block = chain.block_by_height(height).unwrap().unwrap();
+            assert_eq!(block.digest, digest);
+            assert_eq!(block.height, height);
+        }
+        assert!(chain.block_by_height(4).unwrap().is_none());
+
+        // a restarted node picks up where it left off.
+        let storage = chain.storage.storage.clone();
+        drop(chain);
+        assert_eq!(Chain::new(storage, [0; 32]).unwrap().height(), 3);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn iterate_from_genesis() {
+        let path = "db-iter/";
+        let chain = fresh_chain(path);
+        let mut digests = vec![genesis_block(&GenesisConfig::default()).digest];
+        for _ in 0..3 {
+            let block = chain.block_with_transactions(vec![], now());
+            digests.push(block.digest);
+            chain.insert_block(block).unwrap();
+        }
+
+        let blocks: Vec<[u8; 32]> = chain.iter().map(|block| block.unwrap().digest).collect();
+        assert_eq!(blocks, digests);
+        let skipped = chain.iter().skip(2).map(|block| block.unwrap().digest);
+        assert_eq!(skipped.collect::<Vec<_>>(), digests[2..]);
+
+        chain
+            .storage
+            .blocks
+            .delete(&[b"block", digests[2].as_ref()].concat())
+            .unwrap();
+        let mut iter = chain.iter();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(
+            iter.next(),
+            Some(Err(ChainError::MissingBlock(2)))
+        ));
+        assert!(iter.next().is_none());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    fn transfer(amount: u64) -> ContractRecipt {
+        ContractRecipt {
+            contract_name: String::from("native"),
             contract_method: String::from("transfer"),
-            req: json!({ "from": "ginger", "to": "hello", "amount": 100_u64 }),
-        }]);
+            req: json!({ "to": "ginger", "amount": amount }),
+            author: [0; 32],
+            nonce: 0,
+            signature: None,
+            status: TxStatus::Success,
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn recipt_inclusion_proofs() {
+        let chain = setup_chain();
+        for count in 1..=5 {
+            let block = chain.block_with_transactions((0..count).map(transfer).collect(), now());
+            let root = block.recipts_root();
+            for (index, recipt) in block.recipts().iter().enumerate() {
+                let proof = block.merkle_proof(index);
+                assert!(verify_proof(root, recipt, index, count as usize, &proof));
+            }
+        }
+
+        let block = chain.block_with_transactions((0..4).map(transfer).collect(), now());
+        let proof = block.merkle_proof(1);
+        assert!(!verify_proof(
+            block.recipts_root(),
+            &transfer(0),
+            1,
+            4,
+            &proof
+        ));
+        assert!(!verify_proof(
+            block.recipts_root(),
+            &transfer(1),
+            2,
+            4,
+            &proof
+        ));
+        assert!(!verify_proof(
+            block.recipts_root(),
+            &transfer(7),
+            1,
+            4,
+            &proof
+        ));
+        assert!(!verify_proof(
+            block.recipts_root(),
+            &transfer(1),
+            1,
+            5,
+            &proof
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn repeating_the_last_recipts_changes_the_root() {
+        let chain = setup_chain();
+        let root = |amounts: &[u64]| {
+            let recipts = amounts.iter().copied().map(transfer).collect();
+            chain.block_with_transactions(recipts, now()).recipts_root()
+        };
+        assert_ne!(root(&[0, 1, 2]), root(&[0, 1, 2, 2]));
+        assert_ne!(root(&[0, 1, 2, 3, 4, 5]), root(&[0, 1, 2, 3, 4, 5, 4, 5]));
+        assert_ne!(root(&[0]), root(&[0, 0]));
+    }
+
+    #[test]
+    #[serial]
+    fn corrupt_blocks_are_not_missing_blocks() {
+        let path = "db-corrupt/";
+        let chain = fresh_chain(path);
+        let digest = [3; 32];
+        chain
+            .storage
+            .blocks
+            .set(&[b"block", digest.as_ref()].concat(), b"\x00garbage")
+            .unwrap();
+
+        assert!(matches!(
+            chain.storage.block_by_hash(&digest),
+            Err(ChainError::Decode(_))
+        ));
+        assert!(chain.storage.block_by_hash(&[4; 32]).unwrap().is_none());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn genesis_is_derived_from_the_allocations() {
+        let mut genesis = GenesisConfig::default();
+        genesis.allocations.insert(String::from("ghostway"), 1000);
+        genesis.allocations.insert(String::from("ginger"), 5);
+
+        let first = fresh_chain_with_genesis("db-genesis-first/", &genesis);
+        let second = fresh_chain_with_genesis("db-genesis-second/", &genesis);
+        let digest = first.latest_block().unwrap().unwrap().digest;
+        assert_eq!(digest, second.latest_block().unwrap().unwrap().digest);
+        assert_ne!(digest, genesis_block(&GenesisConfig::default()).digest);
+
+        assert_eq!(
+            balance(first.storage.storage.clone(), "ginger").unwrap(),
+            Some(5)
+        );
+
+        drop((first, second));
+        fs::remove_dir_all("db-genesis-first/").unwrap();
+        fs::remove_dir_all("db-genesis-second/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn chains_only_open_under_their_genesis() {
+        let path = "db-genesis-params/";
+        let mut genesis = GenesisConfig::default();
+        genesis.params.base_fee = 5;
+        assert_ne!(
+            genesis_block(&genesis).digest,
+            genesis_block(&GenesisConfig::default()).digest
+        );
+
+        let chain = fresh_chain_with_genesis(path, &genesis);
+        let storage = chain.storage.storage.clone();
+        drop(chain);
+        assert!(Chain::with_genesis(storage.clone(), [0; 32], &genesis).is_ok());
+        assert!(matches!(
+            Chain::new(storage.clone(), [0; 32]),
+            Err(ChainError::WrongGenesis)
+        ));
+
+        drop(storage);
+        fs::remove_dir_all(path).unwrap();
     }
 }
@@ -1,51 +1,358 @@
-use serde_derive::Deserialize;
-use std::{fs::read, net::SocketAddr, sync::Arc};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{read, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use toml::Value;
 
-use crate::storage::{RocksdbStorage, Storage};
+#[cfg(feature = "rocksdb-backend")]
+use crate::storage::RocksdbStorage;
+#[cfg(feature = "sled-backend")]
+use crate::storage::SledStorage;
+use crate::{
+    contracts::language::{
+        StackLimits, DEFAULT_GAS_LIMIT, DEFAULT_MAX_STEPS, MAX_STACK_SIZE, RETURN_STACK_SIZE,
+        STACK_SIZE,
+    },
+    storage::Storage,
+    validator::LeaderSchedule,
+};
 
-#[derive(Deserialize)]
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("invalid config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("contracts_exec.threads has to be at least 1")]
+    NoContractThreads,
+    #[error("network.addr is not a socket address: {0}")]
+    InvalidAddr(String),
+    #[error("consensus.slot_duration_ms has to be at least 1")]
+    EmptySlots,
+    #[error("network.known_nodes has {0}, which can not be dialed")]
+    InvalidKnownNode(SocketAddr),
+    #[error("could not write {0}: {1}")]
+    Write(String, io::Error),
+    #[error("rpc.addr is not a socket address: {0}")]
+    InvalidRpcAddr(String),
+    #[error("genesis.params.{0} has to be between 1 and {max}", max = MAX_STACK_SIZE)]
+    InvalidStackSize(&'static str),
+    #[error("genesis.stakes has {0}, which is not a base64 public key")]
+    InvalidGenesisStake(String),
+}
+
+#[derive(Default, Deserialize, Serialize)]
 pub struct TeralConfig {
     pub storage: StorageConfig,
     pub identity: IdentityConfig,
     pub network: NetworkConfig,
     pub contracts_exec: ContractExecConfig,
+    #[serde(default)]
+    pub genesis: GenesisConfig,
+    #[serde(default)]
+    pub consensus: ConsensusConfig,
+    /// Where to serve rpc, if anywhere.
+    #[serde(default)]
+    pub rpc: Option<RpcConfig>,
 }
 
 impl TeralConfig {
-    pub fn read(path: &str) -> Self {
-        let bytes = read(path).expect("Could not read config file");
-        toml::from_slice(&bytes).expect("Config error")
+    /// Reads the config at `path`, with any value in it overridden by its environment variable,
+    /// as `overlay_env` names them.
+    pub fn read(path: &str) -> Result<Self, ConfigError> {
+        let bytes = read(path).map_err(|err| ConfigError::Read(String::from(path), err))?;
+        let mut config: Value = toml::from_slice(&bytes)?;
+        overlay_env(&mut config, ENV_PREFIX, &|name| env::var(name).ok());
+        let config: Self = config.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Writes a config with the defaults for every value to `path`, unless there is a file there
+    /// already.
+    pub fn write_default(path: &str) -> Result<(), ConfigError> {
+        let config = toml::to_string(&Self::default()).expect("the default config is valid toml");
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(config.as_bytes()))
+            .map_err(|err| ConfigError::Write(String::from(path), err))
+    }
+
+    /// Checks what the types alone do not.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.contracts_exec.threads == 0 {
+            return Err(ConfigError::NoContractThreads);
+        }
+        let params = &self.genesis.params;
+        let stack_sizes = [
+            ("stack_size", params.stack_size),
+            ("return_stack_size", params.return_stack_size),
+        ];
+        if let Some((name, _)) = stack_sizes
+            .iter()
+            .find(|(_, size)| !(1..=MAX_STACK_SIZE).contains(size))
+        {
+            return Err(ConfigError::InvalidStackSize(name));
+        }
+        if let Some(validator) = self
+            .genesis
+            .stakes
+            .keys()
+            .find(|validator| decode_pubkey(validator).is_none())
+        {
+            return Err(ConfigError::InvalidGenesisStake(validator.clone()));
+        }
+        if self.network.addr.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::InvalidAddr(self.network.addr.clone()));
+        }
+        if self.consensus.slot_duration_ms == 0 {
+            return Err(ConfigError::EmptySlots);
+        }
+        if let Some(node) = self
+            .network
+            .known_nodes
+            .iter()
+            .find(|node| node.port() == 0 || node.ip().is_unspecified())
+        {
+            return Err(ConfigError::InvalidKnownNode(*node));
+        }
+        if let Some(rpc) = &self.rpc {
+            if rpc.addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::InvalidRpcAddr(rpc.addr.clone()));
+            }
+        }
+        Ok(())
     }
 
     pub fn load_storage(&self) -> Option<Arc<dyn Storage>> {
         match self.storage.backend {
             #[cfg(feature = "rocksdb-backend")]
             DbBackend::Rocksdb => Some(RocksdbStorage::load(&self.storage)),
-            // _ => None,
+            #[cfg(feature = "sled-backend")]
+            DbBackend::Sled => Some(SledStorage::load(&self.storage)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    pub fn get_scheduler(&self) -> LeaderSchedule {
+        match self.network.leader_schedule {
+            LeaderScheduleBackend::StdRng => LeaderSchedule::new(self.consensus.slot_duration()),
+        }
+    }
+}
+
+const ENV_PREFIX: &str = "TERAL";
+
+/// Replaces every value in `config` that has a variable named after its path, so that
+/// `TERAL_NETWORK_ADDR` overrides `addr` in the `[network]` section. Only values the file sets
+/// can be overridden.
+fn overlay_env(config: &mut Value, name: &str, var: &impl Fn(&str) -> Option<String>) {
+    match config {
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                overlay_env(value, &format!("{}_{}", name, key.to_uppercase()), var);
+            }
+        }
+        value => {
+            if let Some(raw) = var(name) {
+                *value = parse_override(value, raw);
+            }
         }
     }
+}
 
-    // pub fn get_scheduler(&self) -> Option<Arc<dyn LeaderSchedule>> {
-    //     match self.network.leader_schedule {
-    //         LeaderScheduleBackend::StdRng => Some(StdRngSchedule::new()),
-    //     }
-    // }
+/// Reads `raw` as a toml value, unless the value it replaces is a string, in which case it is
+/// taken as is. Anything that does not parse stays a string, for deserializing to complain about.
+fn parse_override(current: &Value, raw: String) -> Value {
+    if current.is_str() {
+        return Value::String(raw);
+    }
+    format!("value = {}", raw)
+        .parse::<Value>()
+        .ok()
+        .and_then(|mut parsed| parsed.as_table_mut()?.remove("value"))
+        .unwrap_or(Value::String(raw))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct NetworkConfig {
     pub addr: String,
     pub known_nodes: Vec<SocketAddr>,
-    // pub leader_schedule: LeaderScheduleBackend,
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default)]
+    pub leader_schedule: LeaderScheduleBackend,
+    // tables go last, for toml to be able to write the config out.
+    #[serde(default)]
+    pub gossip: GossipConfig,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            addr: String::from("127.0.0.1:9911"),
+            known_nodes: vec![],
+            encrypt: false,
+            leader_schedule: LeaderScheduleBackend::default(),
+            gossip: GossipConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// How many threads verify gossip signatures.
+    pub threads: usize,
+    /// How many peers a broadcast is sent to.
+    pub fanout: usize,
+    /// How long the gossip threads block on their socket or channel before checking for exit.
+    pub recv_timeout_ms: u64,
+    /// How old a gossip message may be before it is dropped as expired.
+    pub purge_time_ms: i64,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            threads: 8,
+            fanout: 8,
+            recv_timeout_ms: 1000,
+            purge_time_ms: 120 * 1000,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn recv_timeout(&self) -> Duration {
+        Duration::from_millis(self.recv_timeout_ms)
+    }
+}
+
+/// What a fresh chain starts with. Every node of a network has to use the same one, as the genesis
+/// block's digest is derived from it.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GenesisConfig {
+    /// The initial balance of each account, kept sorted so that the digest does not depend on
+    /// the order they were written in.
+    pub allocations: BTreeMap<String, u64>,
+    /// The stake of each validator, by its base64 public key, for there to be leaders before
+    /// anyone stakes.
+    pub stakes: BTreeMap<String, u64>,
+    pub params: ChainParams,
+}
+
+impl GenesisConfig {
+    /// The validators in `stakes` and their stakes, skipping the ones that are not public keys.
+    pub fn validator_stakes(&self) -> Vec<([u8; 32], u64)> {
+        self.stakes
+            .iter()
+            .filter_map(|(validator, stake)| Some((decode_pubkey(validator)?, *stake)))
+            .collect()
+    }
+}
+
+fn decode_pubkey(pubkey: &str) -> Option<[u8; 32]> {
+    base64::decode(pubkey).ok()?.try_into().ok()
+}
+
+/// What a request costs unless the genesis says otherwise, so that flooding a chain is never free.
+pub const DEFAULT_BASE_FEE: u64 = 1;
+
+/// What running a request costs and how far a contract may go, which every node has to agree on
+/// for them to agree on the outcome of each block.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChainParams {
+    /// How much the sender of a request pays to have it run. The fee is burned.
+    pub base_fee: u64,
+    /// How many opcodes a single contract may execute before it is aborted.
+    pub max_steps: usize,
+    /// How much gas a single contract may use before it is aborted.
+    pub gas_limit: u64,
+    /// How many values a contract's stack can hold.
+    pub stack_size: usize,
+    /// How many values a contract can bind at once, arguments included.
+    pub return_stack_size: usize,
+    pub rhai: RhaiLimits,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            base_fee: DEFAULT_BASE_FEE,
+            max_steps: DEFAULT_MAX_STEPS,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            stack_size: STACK_SIZE,
+            return_stack_size: RETURN_STACK_SIZE,
+            rhai: RhaiLimits::default(),
+        }
+    }
+}
+
+impl ChainParams {
+    pub fn stack_limits(&self) -> StackLimits {
+        StackLimits {
+            stack: self.stack_size,
+            return_stack: self.return_stack_size,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConsensusConfig {
+    /// How long each leader has to produce its block.
+    pub slot_duration_ms: u64,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        Self {
+            slot_duration_ms: 1000,
+        }
+    }
+}
+
+impl ConsensusConfig {
+    pub fn slot_duration(&self) -> Duration {
+        Duration::from_millis(self.slot_duration_ms)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RpcConfig {
+    /// The address the json-rpc server listens on.
+    pub addr: String,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            addr: String::from("127.0.0.1:9912"),
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize, Serialize)]
 pub enum LeaderScheduleBackend {
+    #[default]
     #[serde(rename = "stdrng")]
     StdRng,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct StorageConfig {
     pub backend: DbBackend,
     pub path: String,
@@ -62,18 +369,334 @@ impl Default for StorageConfig {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct IdentityConfig {
     pub path: String,
 }
 
-#[derive(Deserialize)]
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("identity.key"),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ContractExecConfig {
     pub threads: usize,
 }
 
-#[derive(Deserialize)]
+impl Default for ContractExecConfig {
+    fn default() -> Self {
+        Self { threads: 4 }
+    }
+}
+
+/// What a rhai contract may use before it is aborted.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RhaiLimits {
+    pub max_operations: u64,
+    /// How deep its function calls may nest.
+    pub max_call_levels: usize,
+    /// How many items an array or a map may hold.
+    pub max_array_size: usize,
+    /// How many bytes a string may hold.
+    pub max_string_size: usize,
+}
+
+impl Default for RhaiLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000,
+            max_call_levels: 32,
+            max_array_size: 1024,
+            max_string_size: 4096,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub enum DbBackend {
     #[serde(rename = "rocksdb")]
     Rocksdb,
+    #[serde(rename = "sled")]
+    Sled,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{
+        overlay_env, ChainParams, ConfigError, ConsensusConfig, ContractExecConfig, GenesisConfig,
+        LeaderScheduleBackend, NetworkConfig, TeralConfig, DEFAULT_BASE_FEE, DEFAULT_GAS_LIMIT,
+        DEFAULT_MAX_STEPS, RETURN_STACK_SIZE, STACK_SIZE,
+    };
+
+    #[test]
+    fn gossip_section_is_optional() {
+        let network: NetworkConfig = toml::from_str(
+            r#"
+            addr = "127.0.0.1:9911"
+            known_nodes = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(network.gossip.threads, 8);
+        assert_eq!(network.gossip.purge_time_ms, 120 * 1000);
+
+        let network: NetworkConfig = toml::from_str(
+            r#"
+            addr = "127.0.0.1:9911"
+            known_nodes = []
+
+            [gossip]
+            threads = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(network.gossip.threads, 1);
+        assert_eq!(network.gossip.fanout, 8);
+    }
+
+    #[test]
+    fn chain_params_default() {
+        let params = GenesisConfig::default().params;
+        assert_eq!(params.max_steps, DEFAULT_MAX_STEPS);
+        assert_eq!(params.gas_limit, DEFAULT_GAS_LIMIT);
+        assert_eq!(params.stack_size, STACK_SIZE);
+        assert_eq!(params.return_stack_size, RETURN_STACK_SIZE);
+        assert_eq!(params.base_fee, DEFAULT_BASE_FEE);
+        assert!(params.base_fee > 0);
+
+        let params: ChainParams = toml::from_str("max_steps = 10").unwrap();
+        assert_eq!(params.max_steps, 10);
+
+        let params: ChainParams = toml::from_str("gas_limit = 10").unwrap();
+        assert_eq!(params.gas_limit, 10);
+
+        let genesis: GenesisConfig = toml::from_str("[params]\nbase_fee = 3").unwrap();
+        assert_eq!(genesis.params.base_fee, 3);
+
+        let params: ChainParams = toml::from_str("[rhai]\nmax_operations = 5").unwrap();
+        assert_eq!(params.rhai.max_operations, 5);
+        assert_eq!(params.rhai.max_call_levels, 32);
+    }
+
+    #[test]
+    fn genesis_allocations() {
+        let genesis: GenesisConfig = toml::from_str(
+            r#"
+            [allocations]
+            ghostway = 1000
+            ginger = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(genesis.allocations["ghostway"], 1000);
+        assert_eq!(genesis.allocations["ginger"], 5);
+
+        let genesis: GenesisConfig = toml::from_str("").unwrap();
+        assert!(genesis.allocations.is_empty());
+        assert!(genesis.validator_stakes().is_empty());
+
+        let validator = base64::encode([7; 32]);
+        let genesis: GenesisConfig =
+            toml::from_str(&format!("[stakes]\n\"{}\" = 100\n", validator)).unwrap();
+        assert_eq!(genesis.validator_stakes(), vec![([7; 32], 100)]);
+    }
+
+    #[test]
+    fn slot_duration() {
+        let consensus: ConsensusConfig = toml::from_str("").unwrap();
+        assert_eq!(consensus.slot_duration_ms, 1000);
+
+        let consensus: ConsensusConfig = toml::from_str("slot_duration_ms = 250").unwrap();
+        assert_eq!(consensus.slot_duration().as_millis(), 250);
+    }
+
+    const CONFIG: &str = r#"
+[storage]
+path = "db/"
+backend = "rocksdb"
+log_history = 5
+
+[identity]
+path = "identity.key"
+
+[network]
+addr = "127.0.0.1:9911"
+known_nodes = []
+
+[contracts_exec]
+threads = 4
+"#;
+
+    fn read_config(path: &str, config: &str) -> Result<TeralConfig, ConfigError> {
+        fs::write(path, config).unwrap();
+        let config = TeralConfig::read(path);
+        fs::remove_file(path).unwrap();
+        config
+    }
+
+    #[test]
+    fn config_errors() {
+        assert!(matches!(
+            TeralConfig::read("missing-teral.toml"),
+            Err(ConfigError::Read(..))
+        ));
+
+        let err = match read_config("teral-malformed.toml", "[storage\npath = 1") {
+            Err(err) => err,
+            Ok(_) => panic!("a malformed config was accepted"),
+        };
+        assert!(matches!(err, ConfigError::Parse(_)));
+        assert!(err.to_string().contains("line 1"), "{}", err);
+
+        let config = CONFIG.replace("threads = 4", "threads = 0");
+        assert!(matches!(
+            read_config("teral-no-threads.toml", &config),
+            Err(ConfigError::NoContractThreads)
+        ));
+
+        let config = CONFIG.replace("127.0.0.1:9911", "localhost");
+        assert!(matches!(
+            read_config("teral-bad-addr.toml", &config),
+            Err(ConfigError::InvalidAddr(_))
+        ));
+
+        let config = CONFIG.replace("known_nodes = []", r#"known_nodes = ["10.0.0.1:0"]"#);
+        assert!(matches!(
+            read_config("teral-bad-node.toml", &config),
+            Err(ConfigError::InvalidKnownNode(_))
+        ));
+
+        let config = format!("{}\n[rpc]\naddr = \"localhost\"\n", CONFIG);
+        assert!(matches!(
+            read_config("teral-bad-rpc.toml", &config),
+            Err(ConfigError::InvalidRpcAddr(_))
+        ));
+
+        let config = format!("{}\n[genesis.params]\nstack_size = 100000000\n", CONFIG);
+        assert!(matches!(
+            read_config("teral-huge-stack.toml", &config),
+            Err(ConfigError::InvalidStackSize("stack_size"))
+        ));
+
+        let config = format!("{}\n[genesis.params]\nreturn_stack_size = 0\n", CONFIG);
+        assert!(matches!(
+            read_config("teral-no-return-stack.toml", &config),
+            Err(ConfigError::InvalidStackSize("return_stack_size"))
+        ));
+
+        let config = format!("{}\n[genesis.stakes]\nghostway = 100\n", CONFIG);
+        assert!(matches!(
+            read_config("teral-bad-stake.toml", &config),
+            Err(ConfigError::InvalidGenesisStake(_))
+        ));
+
+        let config = read_config("teral-valid.toml", CONFIG).unwrap();
+        assert!(config.rpc.is_none());
+        let config = format!("{}\n[rpc]\naddr = \"127.0.0.1:9912\"\n", CONFIG);
+        let config = read_config("teral-rpc.toml", &config).unwrap();
+        assert_eq!(config.rpc.unwrap().addr, "127.0.0.1:9912");
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let path = "teral-default-test.toml";
+        let _ = fs::remove_file(path);
+        TeralConfig::write_default(path).unwrap();
+        let config = TeralConfig::read(path).unwrap();
+        assert_eq!(config.network.addr, "127.0.0.1:9911");
+        assert_eq!(config.contracts_exec.threads, 4);
+        assert_eq!(config.storage.path, "db/");
+
+        // an existing config is left alone.
+        assert!(matches!(
+            TeralConfig::write_default(path),
+            Err(ConfigError::Write(..))
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn leader_schedule_backend() {
+        let config: TeralConfig = toml::from_str(&CONFIG.replace(
+            "known_nodes = []",
+            "known_nodes = []\nleader_schedule = \"stdrng\"",
+        ))
+        .unwrap();
+        assert!(matches!(
+            config.network.leader_schedule,
+            LeaderScheduleBackend::StdRng
+        ));
+        let validators = [([1; 32], 1), ([2; 32], 1)];
+        let head = crate::chain::Block::with_transactions(vec![], [0; 32]);
+        let schedule = crate::validator::LeaderSchedule::new(config.consensus.slot_duration());
+        assert_eq!(
+            config.get_scheduler().get_validator(&head, 0, &validators),
+            schedule.get_validator(&head, 0, &validators)
+        );
+
+        // stdrng is the default.
+        let config: TeralConfig = toml::from_str(CONFIG).unwrap();
+        assert!(matches!(
+            config.network.leader_schedule,
+            LeaderScheduleBackend::StdRng
+        ));
+        assert!(toml::from_str::<TeralConfig>(&CONFIG.replace(
+            "known_nodes = []",
+            "known_nodes = []\nleader_schedule = \"dice\"",
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn nested_values_are_overridden() {
+        let mut config: toml::Value = toml::from_str(
+            r#"
+            [network]
+            addr = "127.0.0.1:9911"
+            known_nodes = []
+
+            [network.gossip]
+            threads = 8
+
+            [contracts_exec]
+            threads = 4
+            "#,
+        )
+        .unwrap();
+        overlay_env(&mut config, "TERAL", &|name| match name {
+            "TERAL_NETWORK_GOSSIP_THREADS" => Some(String::from("2")),
+            "TERAL_NETWORK_KNOWN_NODES" => Some(String::from(r#"["10.0.0.1:8000"]"#)),
+            "TERAL_CONTRACTS_EXEC_THREADS" => Some(String::from("many")),
+            _ => None,
+        });
+        assert_eq!(config["network"]["gossip"]["threads"].as_integer(), Some(2));
+        assert_eq!(config["network"]["addr"].as_str(), Some("127.0.0.1:9911"));
+        let network: NetworkConfig = config["network"].clone().try_into().unwrap();
+        assert_eq!(network.known_nodes, vec!["10.0.0.1:8000".parse().unwrap()]);
+        // not a number, so it is left for deserializing to reject.
+        assert!(config["contracts_exec"]
+            .clone()
+            .try_into::<ContractExecConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn environment_wins_over_the_file() {
+        let path = "teral-env-test.toml";
+        fs::write(path, CONFIG).unwrap();
+        env::set_var("TERAL_NETWORK_ADDR", "0.0.0.0:7000");
+        let config = TeralConfig::read(path).unwrap();
+        env::remove_var("TERAL_NETWORK_ADDR");
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(config.network.addr, "0.0.0.0:7000");
+        assert_eq!(config.storage.path, "db/");
+    }
 }
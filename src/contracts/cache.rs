@@ -0,0 +1,112 @@
+use std::collections::{HashMap, VecDeque};
+
+use sha3::{Digest, Sha3_256};
+
+/// Compiled contracts, keyed by the hash of their code so that identical code is compiled once
+/// and an updated contract never runs what was compiled for its old code. Holds at most
+/// `capacity` entries, and evicts the least recently used one to make room.
+pub(crate) struct CodeCache<T> {
+    capacity: usize,
+    compiled: HashMap<[u8; 32], T>,
+    // least recently used first.
+    order: VecDeque<[u8; 32]>,
+}
+
+impl<T: Clone> CodeCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            compiled: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The compiled `code`, compiling it with `compile` if it is not cached.
+    pub fn get_or_compile<E>(
+        &mut self,
+        code: &str,
+        compile: impl FnOnce(&str) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let hash = code_hash(code);
+        if let Some(compiled) = self.compiled.get(&hash).cloned() {
+            self.forget(&hash);
+            self.order.push_back(hash);
+            return Ok(compiled);
+        }
+
+        let compiled = compile(code)?;
+        if self.capacity == 0 {
+            return Ok(compiled);
+        }
+        if self.compiled.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.compiled.remove(&oldest);
+            }
+        }
+        self.compiled.insert(hash, compiled.clone());
+        self.order.push_back(hash);
+        Ok(compiled)
+    }
+
+    /// Drops what was compiled for `code`, for when a contract moves on from it.
+    pub fn invalidate(&mut self, code: &str) {
+        let hash = code_hash(code);
+        if self.compiled.remove(&hash).is_some() {
+            self.forget(&hash);
+        }
+    }
+
+    fn forget(&mut self, hash: &[u8; 32]) {
+        if let Some(position) = self.order.iter().position(|h| h == hash) {
+            self.order.remove(position);
+        }
+    }
+}
+
+fn code_hash(code: &str) -> [u8; 32] {
+    Sha3_256::digest(code.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::CodeCache;
+
+    #[test]
+    fn same_code_compiles_once() {
+        let compilations = Cell::new(0);
+        let compile = |code: &str| -> Result<usize, ()> {
+            compilations.set(compilations.get() + 1);
+            Ok(code.len())
+        };
+        let mut cache = CodeCache::new(2);
+
+        assert_eq!(cache.get_or_compile("fn a() {}", compile), Ok(9));
+        assert_eq!(cache.get_or_compile("fn a() {}", compile), Ok(9));
+        assert_eq!(compilations.get(), 1);
+
+        // updating a contract moves it to other code, so its old entry is of no use.
+        cache.invalidate("fn a() {}");
+        assert_eq!(cache.get_or_compile("fn a() {}", compile), Ok(9));
+        assert_eq!(compilations.get(), 2);
+        assert_eq!(cache.get_or_compile("fn a() { 1 }", compile), Ok(12));
+        assert_eq!(compilations.get(), 3);
+
+        // "fn a() {}" was used last, so "fn a() { 1 }" makes room for the new code.
+        cache.get_or_compile("fn a() {}", compile).unwrap();
+        cache.get_or_compile("fn b() {}", compile).unwrap();
+        assert_eq!(compilations.get(), 4);
+        cache.get_or_compile("fn a() {}", compile).unwrap();
+        assert_eq!(compilations.get(), 4);
+        cache.get_or_compile("fn a() { 1 }", compile).unwrap();
+        assert_eq!(compilations.get(), 5);
+    }
+
+    #[test]
+    fn failed_compilations_are_not_cached() {
+        let mut cache: CodeCache<usize> = CodeCache::new(2);
+        assert!(cache.get_or_compile("fn", |_| Err(())).is_err());
+        assert_eq!(cache.get_or_compile("fn", |_| Ok::<_, ()>(1)), Ok(1));
+    }
+}
@@ -3,7 +3,7 @@ use std::{collections::HashMap, str::FromStr};
 use primitive_types::U256;
 use thiserror::Error;
 
-use crate::storage::{RocksdbStorage, Storage};
+use crate::storage::Storage;
 
 use crate::contracts::language::Opcode;
 
@@ -27,11 +27,11 @@ impl TryFrom<u32> for Base {
     }
 }
 
-impl Into<u32> for Base {
-    fn into(self) -> u32 {
-        match self {
-            Self::Dec => 10,
-            Self::Hex => 16,
+impl From<Base> for u32 {
+    fn from(base: Base) -> u32 {
+        match base {
+            Base::Dec => 10,
+            Base::Hex => 16,
         }
     }
 }
@@ -51,6 +51,10 @@ pub enum Bin {
     Add,
     Mul,
     Div,
+    Mod,
+    Exp,
+    Shl,
+    Shr,
     Lt,
     Gt,
     Leq,
@@ -73,10 +77,15 @@ pub enum Keyword {
     Require,
     In,
     Iszero,
+    Log,
+    While,
+    Do,
+    Slt,
+    Sgt,
 }
 
 impl TryFrom<&str> for Keyword {
-    type Error = CompileError;
+    type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
@@ -93,10 +102,12 @@ impl TryFrom<&str> for Keyword {
             "require" => Ok(Self::Require),
             "in" => Ok(Self::In),
             "iszero" => Ok(Self::Iszero),
-            _ => Err(CompileError::CantInterpret(
-                value.to_string(),
-                "keyword".to_string(),
-            )),
+            "log" => Ok(Self::Log),
+            "while" => Ok(Self::While),
+            "do" => Ok(Self::Do),
+            "slt" => Ok(Self::Slt),
+            "sgt" => Ok(Self::Sgt),
+            _ => Err(()),
         }
     }
 }
@@ -108,10 +119,15 @@ pub enum Type {
     U32,
     U16,
     U8,
+    I256,
+    I64,
+    I32,
+    I16,
+    I8,
 }
 
 impl TryFrom<&str> for Type {
-    type Error = CompileError;
+    type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
@@ -120,10 +136,12 @@ impl TryFrom<&str> for Type {
             "u32" => Ok(Self::U32),
             "u16" => Ok(Self::U16),
             "u8" => Ok(Self::U8),
-            _ => Err(CompileError::CantInterpret(
-                value.to_string(),
-                "type".to_string(),
-            )),
+            "i256" => Ok(Self::I256),
+            "i64" => Ok(Self::I64),
+            "i32" => Ok(Self::I32),
+            "i16" => Ok(Self::I16),
+            "i8" => Ok(Self::I8),
+            _ => Err(()),
         }
     }
 }
@@ -131,40 +149,99 @@ impl TryFrom<&str> for Type {
 impl Type {
     pub fn byte_count(&self) -> u8 {
         match self {
-            Self::U256 => 32,
-            Self::U64 => 8,
-            Self::U32 => 4,
-            Self::U16 => 2,
-            Self::U8 => 1,
+            Self::U256 | Self::I256 => 32,
+            Self::U64 | Self::I64 => 8,
+            Self::U32 | Self::I32 => 4,
+            Self::U16 | Self::I16 => 2,
+            Self::U8 | Self::I8 => 1,
         }
     }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            Self::I256 | Self::I64 | Self::I32 | Self::I16 | Self::I8
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub value: String,
+    /// Where the token starts, both counting from 1.
+    pub line: usize,
+    pub col: usize,
 }
 
 impl Token {
-    fn new(kind: TokenKind, value: String) -> Self {
-        Self { kind, value }
+    fn new(kind: TokenKind, value: String, (line, col): (usize, usize)) -> Self {
+        Self {
+            kind,
+            value,
+            line,
+            col,
+        }
     }
 }
 
 pub struct Lexer {
     input: Vec<String>,
+    positions: Vec<(usize, usize)>,
     index: usize,
 }
 
 impl Lexer {
     pub fn new(input: String) -> Self {
+        let mut words = vec![];
+        let mut positions = vec![];
+        for (line, code) in input.lines().enumerate() {
+            let mut word = String::new();
+            let mut start = 0;
+            for (col, c) in code.chars().enumerate() {
+                if c.is_whitespace() || c == '#' {
+                    if !word.is_empty() {
+                        words.push(std::mem::take(&mut word));
+                        positions.push((line + 1, start + 1));
+                    }
+                    // `#` starts a comment that runs until the end of the line.
+                    if c == '#' {
+                        break;
+                    }
+                } else {
+                    if word.is_empty() {
+                        start = col;
+                    }
+                    word.push(c);
+                }
+            }
+            if !word.is_empty() {
+                words.push(word);
+                positions.push((line + 1, start + 1));
+            }
+        }
+
         Self {
-            input: input.split_whitespace().map(String::from).collect(),
+            input: words,
+            positions,
             index: 0,
         }
     }
 
+    fn position(&self) -> (usize, usize) {
+        self.positions[self.index]
+    }
+
+    fn unexpected(&self, what: String) -> CompileError {
+        let (line, col) = self.position();
+        CompileError::UnexpectedToken(line, col, what)
+    }
+
+    fn cant_interpret(&self, word: &str, as_what: &str) -> CompileError {
+        let (line, col) = self.position();
+        CompileError::CantInterpret(line, col, word.to_string(), as_what.to_string())
+    }
+
     pub fn should_stop(&self) -> bool {
         self.index >= self.input.len()
     }
@@ -200,20 +277,13 @@ impl Lexer {
     }
 
     fn number(&mut self) -> Result<TokenKind, CompileError> {
-        let (base, word) = if self.first() == '0' {
-            match self.second() {
-                Ok('x') => (16, &self.curr()[2..]),
-                Ok('_') => (10, self.curr()),
-                Err(_) => (10, self.curr()),
-                _ => {
-                    return Err(CompileError::CantInterpret(
-                        self.curr().to_string(),
-                        "num".to_string(),
-                    ))
-                }
-            }
-        } else {
-            (10, self.curr())
+        let negative = self.first() == '-';
+        let unsigned = self.curr().trim_start_matches('-');
+        let mut chars = unsigned.chars();
+        let (base, word) = match (chars.next(), chars.next()) {
+            (Some('0'), Some('x')) => (16, &unsigned[2..]),
+            (Some('0'), Some('_') | None) | (Some('1'..='9'), _) => (10, unsigned),
+            _ => return Err(self.cant_interpret(self.curr(), "num")),
         };
 
         let mut e = 0;
@@ -225,10 +295,17 @@ impl Lexer {
         }
 
         let typ = if word.chars().nth(e) != Some('_') {
-            Type::U256
+            if negative {
+                Type::I256
+            } else {
+                Type::U256
+            }
         } else {
-            Type::try_from(&word[e + 1..])?
+            Type::try_from(&word[e + 1..]).map_err(|_| self.cant_interpret(word, "type"))?
         };
+        if negative && !typ.is_signed() {
+            return Err(self.cant_interpret(self.curr(), "unsigned number"));
+        }
 
         Ok(TokenKind::Num(base.try_into()?, typ))
     }
@@ -242,10 +319,7 @@ impl Lexer {
         let mut index = 0;
         for c in word.chars() {
             if !predicate(c) {
-                return Err(CompileError::CantInterpret(
-                    word.to_string(),
-                    type_name.to_string(),
-                ));
+                return Err(self.cant_interpret(word, type_name));
             }
             index += 1;
         }
@@ -268,20 +342,18 @@ impl Lexer {
     fn less_than(&self) -> Result<TokenKind, CompileError> {
         match self.second() {
             Ok('=') => Ok(TokenKind::Op(Bin::Leq)),
+            Ok('<') => Ok(TokenKind::Op(Bin::Shl)),
             Err(_) => Ok(TokenKind::Op(Bin::Lt)),
-            _ => Err(CompileError::UnexpectedToken(
-                self.second().unwrap().to_string(),
-            )),
+            _ => Err(self.unexpected(self.curr().to_string())),
         }
     }
 
     fn more_than(&self) -> Result<TokenKind, CompileError> {
         match self.second() {
             Ok('=') => Ok(TokenKind::Op(Bin::Geq)),
+            Ok('>') => Ok(TokenKind::Op(Bin::Shr)),
             Err(_) => Ok(TokenKind::Op(Bin::Gt)),
-            _ => Err(CompileError::UnexpectedToken(
-                self.second().unwrap().to_string(),
-            )),
+            _ => Err(self.unexpected(self.curr().to_string())),
         }
     }
 
@@ -290,31 +362,29 @@ impl Lexer {
             'a'..='z' | 'A'..='Z' | '_' => self.identifier()?,
             '0'..='9' => self.number()?,
             '=' if self.second()? == '=' => TokenKind::Op(Bin::EqSign),
+            '-' if self.second().is_ok_and(|c| c.is_ascii_digit()) => self.number()?,
             '-' => TokenKind::Op(Bin::Sub),
             '+' => TokenKind::Op(Bin::Add),
+            '*' if self.second().ok() == Some('*') => TokenKind::Op(Bin::Exp),
             '*' => TokenKind::Op(Bin::Mul),
             '/' => TokenKind::Op(Bin::Div),
+            '%' => TokenKind::Op(Bin::Mod),
             '<' => self.less_than()?,
             '>' => self.more_than()?,
-            _ => {
-                return Err(CompileError::CantInterpret(
-                    self.curr().to_string(),
-                    "any".to_string(),
-                ))
-            }
+            _ => return Err(self.cant_interpret(self.curr(), "any")),
         };
         let value = match kind {
-            TokenKind::Num(_, _) if self.curr().contains('_') => {
-                let without_type = self.curr().split_once('_').unwrap().0;
-                if self.curr().contains('x') {
-                    without_type[2..].to_string()
-                } else {
-                    without_type.to_string()
-                }
+            // only the digits and the sign are kept, the base and the type are in the kind.
+            TokenKind::Num(_, _) => {
+                let without_type = self.curr().split_once('_').map_or(self.curr(), |(n, _)| n);
+                let sign = if without_type.starts_with('-') { "-" } else { "" };
+                let digits = without_type.trim_start_matches('-');
+                let digits = digits.strip_prefix("0x").unwrap_or(digits);
+                format!("{}{}", sign, digits)
             }
             _ => self.curr().to_string(),
         };
-        let tok = Token::new(kind, value);
+        let tok = Token::new(kind, value, self.position());
         self.bump()?;
         Ok(tok)
     }
@@ -1,16 +1,25 @@
 mod lexer;
 mod tests;
 
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
 
 use primitive_types::U256;
+use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::storage::{RocksdbStorage, Storage};
+#[cfg(feature = "rocksdb-backend")]
+use crate::storage::RocksdbStorage;
+use crate::storage::Storage;
 
 use lexer::{Token, TokenKind, Bin, Keyword, Lexer, Base, Type};
 
-use super::language::Opcode;
+use super::language::{
+    disassemble, ExecutionOutcome, Listing, Opcode, StackLimits, DEFAULT_GAS_LIMIT,
+    DEFAULT_MAX_STEPS,
+};
 
 #[derive(Debug, Error)]
 pub enum CompileError {
@@ -22,14 +31,26 @@ pub enum CompileError {
     UnexpectedEoc,
     #[error("{0} syntax error: expected {1} got {2}")]
     SyntaxError(usize, String, String),
-    #[error("'{0}' was unexpected in this context")]
-    UnexpectedToken(String),
-    #[error("can not interpret {0} as a {1}")]
-    CantInterpret(String, String),
+    #[error("line {0}, col {1}: unexpected `{2}`")]
+    UnexpectedToken(usize, usize, String),
+    #[error("line {0}, col {1}: can not interpret `{2}` as a {3}")]
+    CantInterpret(usize, usize, String, String),
+    #[error("line {0}, col {1}: undefined `{2}`; in scope: {3}")]
+    UndefinedIdentifier(usize, usize, String, String),
     #[error("could not convert {0} to Base")]
     BaseParse(u32),
     #[error("eventually expected `{0}` but got <eof>")]
     EventuallyExpected(String),
+    #[error("can not jump over {0} bytes, the limit is 255")]
+    JumpTooFar(usize),
+    #[error("a contract can not declare more than 255 mappings")]
+    TooManyMappings,
+    #[error("can not call a function at {0}, calls only reach the first 65535 bytes")]
+    CallTooFar(usize),
+    #[error("can not call a function with {0} parameters, the limit is 255")]
+    TooManyParameters(usize),
+    #[error("can not bind more than 255 names at once, got {0}")]
+    TooManyNames(usize),
 }
 
 #[derive(Debug)]
@@ -39,6 +60,16 @@ struct Compiler {
     functions: HashMap<String, (usize, Vec<String>)>,
     output: Vec<u8>,
     binded_context: Vec<String>,
+    /// The declared mappings. A mapping's index is its position here plus one, as index 0 is
+    /// what `get` and `store` use when no mapping was named.
+    mappings: Vec<String>,
+    /// The mappings that were named but not yet used by a `get` or `store`.
+    mapping_operands: Vec<u8>,
+    /// Whether binary operations on constants are evaluated at compile time.
+    fold_constants: bool,
+    /// The values of the pushes that run up to `constants_end`, with where each of them starts.
+    constants: Vec<(usize, U256)>,
+    constants_end: usize,
 }
 
 impl Compiler {
@@ -49,9 +80,19 @@ impl Compiler {
             functions: HashMap::new(),
             output: vec![],
             binded_context: vec![],
+            mappings: vec![],
+            mapping_operands: vec![],
+            fold_constants: false,
+            constants: vec![],
+            constants_end: 0,
         }
     }
 
+    fn with_constant_folding(mut self, fold_constants: bool) -> Self {
+        self.fold_constants = fold_constants;
+        self
+    }
+
     fn should_stop(&self) -> bool {
         self.index >= self.input.len()
     }
@@ -65,6 +106,20 @@ impl Compiler {
         }
     }
 
+    fn unexpected(&self, token: &Token) -> CompileError {
+        CompileError::UnexpectedToken(token.line, token.col, token.value.clone())
+    }
+
+    fn cant_interpret(&self, as_what: &str) -> CompileError {
+        let token = self.first();
+        CompileError::CantInterpret(
+            token.line,
+            token.col,
+            token.value.clone(),
+            as_what.to_string(),
+        )
+    }
+
     fn first(&self) -> &Token {
         &self.input[self.index]
     }
@@ -95,99 +150,228 @@ impl Compiler {
 
     fn function(&mut self) -> Result<(), CompileError> {
         self.bump()?;
+        if self.should_stop() {
+            return Err(CompileError::UnexpectedEoc);
+        }
         let name = self.first().value.clone();
         let mut parameters = self.get_parameters()?;
         self.functions
             .insert(name, (self.output.len(), parameters.clone()));
 
+        // a function only sees its own parameters.
+        self.binded_context.clear();
+        self.constants.clear();
         self.binded_context.append(&mut parameters);
         self.advance_until_end()?;
+        self.push_opcode(Opcode::Ret);
         Ok(())
     }
 
     fn number(&mut self, base: Base, typ: Type) -> Result<(), CompileError> {
         let num = &self.first().value.clone();
-        self.push_opcode(Opcode::Push(typ.byte_count()));
-        match typ {
+        let radix: u32 = base.into();
+        let value = match typ {
             Type::U256 => {
-                let bytes = &mut [0; 32];
-                U256::from_str_radix(&num, base.into())
-                    .map_err(|_| CompileError::CantInterpret(num.to_string(), "u256".to_string()))?
-                    .to_little_endian(bytes);
-                self.output.append(&mut bytes.to_vec());
+                U256::from_str_radix(num, radix).map_err(|_| self.cant_interpret("u256"))?
+            }
+            Type::U64 => u64::from_str_radix(num, radix)
+                .map_err(|_| self.cant_interpret("u64"))?
+                .into(),
+            Type::U32 => u32::from_str_radix(num, radix)
+                .map_err(|_| self.cant_interpret("u32"))?
+                .into(),
+            Type::U16 => u16::from_str_radix(num, radix)
+                .map_err(|_| self.cant_interpret("u16"))?
+                .into(),
+            Type::U8 => u8::from_str_radix(num, radix)
+                .map_err(|_| self.cant_interpret("u8"))?
+                .into(),
+            Type::I256 => {
+                let magnitude = U256::from_str_radix(num.trim_start_matches('-'), radix)
+                    .map_err(|_| self.cant_interpret("i256"))?;
+                let min = U256::one() << 255;
+                if num.starts_with('-') && magnitude <= min {
+                    twos_complement(magnitude)
+                } else if !num.starts_with('-') && magnitude < min {
+                    magnitude
+                } else {
+                    return Err(self.cant_interpret("i256"));
+                }
             }
-            Type::U64 => self.output.append(
-                &mut u64::from_str_radix(&num, base.into())
-                    .map_err(|_| CompileError::CantInterpret(num.to_string(), "u64".to_string()))?
-                    .to_le_bytes()
-                    .to_vec(),
+            Type::I64 => signed(
+                i64::from_str_radix(num, radix).map_err(|_| self.cant_interpret("i64"))?,
             ),
-            Type::U32 => self.output.append(
-                &mut u32::from_str_radix(&num, base.into())
-                    .map_err(|_| CompileError::CantInterpret(num.to_string(), "u32".to_string()))?
-                    .to_le_bytes()
-                    .to_vec(),
+            Type::I32 => signed(
+                i32::from_str_radix(num, radix).map_err(|_| self.cant_interpret("i32"))?,
             ),
-            Type::U16 => self.output.append(
-                &mut u16::from_str_radix(&num, base.into())
-                    .map_err(|_| CompileError::CantInterpret(num.to_string(), "u16".to_string()))?
-                    .to_le_bytes()
-                    .to_vec(),
+            Type::I16 => signed(
+                i16::from_str_radix(num, radix).map_err(|_| self.cant_interpret("i16"))?,
             ),
-            Type::U8 => self.output.append(
-                &mut u8::from_str_radix(&num, base.into())
-                    .map_err(|_| CompileError::CantInterpret(num.to_string(), "u8".to_string()))?
-                    .to_le_bytes()
-                    .to_vec(),
+            Type::I8 => signed(
+                i8::from_str_radix(num, radix).map_err(|_| self.cant_interpret("i8"))?,
             ),
-        }; // can simplify this..
+        };
+
+        // pushes zero extend, so a negative number has to be pushed as all 32 of its bytes.
+        let width = if typ.is_signed() && value.bit(255) {
+            32
+        } else {
+            typ.byte_count()
+        };
+        self.push_constant(value, width);
         self.bump()?;
         Ok(())
     }
 
+    fn push_constant(&mut self, value: U256, width: u8) {
+        if self.constants_end != self.output.len() {
+            self.constants.clear();
+        }
+        self.constants.push((self.output.len(), value));
+
+        let mut bytes = [0; 32];
+        value.to_little_endian(&mut bytes);
+        self.push_opcode(Opcode::Push(width));
+        self.output.extend_from_slice(&bytes[..width as usize]);
+        self.constants_end = self.output.len();
+    }
+
+    /// Replaces the last two pushes with the result of `opcode` on them, if they are constants
+    /// that were pushed right before it.
+    fn fold(&mut self, opcode: Opcode) -> bool {
+        if !self.fold_constants
+            || self.constants.len() < 2
+            || self.constants_end != self.output.len()
+        {
+            return false;
+        }
+        let (_, rhs) = self.constants.pop().unwrap();
+        let (start, lhs) = self.constants.pop().unwrap();
+        let result = match opcode.apply(lhs, rhs) {
+            Some(result) => result,
+            None => return false,
+        };
+
+        self.output.truncate(start);
+        self.constants_end = start;
+        let width = result.bits().div_ceil(8).max(1);
+        self.push_constant(result, width as u8);
+        true
+    }
+
+    /// Code can be jumped to from here on, so what was pushed before can not be folded with what
+    /// comes after.
+    fn jump_target(&mut self) {
+        self.constants.clear();
+    }
+
     fn bind_block(&mut self, pop: bool) -> Result<(), CompileError> {
         let names = &mut self.get_parameters()?;
+        let count: u8 = names
+            .len()
+            .try_into()
+            .map_err(|_| CompileError::TooManyNames(names.len()))?;
         if pop {
-            self.push_opcode(Opcode::MoveToReturn(names.len().try_into().unwrap()));
+            self.push_opcode(Opcode::MoveToReturn(count));
         } else {
-            self.push_opcode(Opcode::CopyToReturn(names.len().try_into().unwrap()));
+            self.push_opcode(Opcode::CopyToReturn(count));
         }
+        let outer = self.binded_context.len();
         self.binded_context.append(names);
 
         self.advance_until_end()?;
-        // self.push_opcode(Opcode::ClearReturn); // TODO: clear only the ones we added rn.
-        self.binded_context.truncate(names.len());
+        // the names go out of scope, both here and on the return stack.
+        self.push_opcode(Opcode::DropReturn);
+        self.output.push(count);
+        self.binded_context.truncate(outer);
         Ok(())
     }
 
     fn identifier(&mut self) -> Result<(), CompileError> {
-        if self.binded_context.contains(&self.first().value) {
+        if let Some(pos) = self.mappings.iter().position(|x| *x == self.first().value) {
+            self.mapping_operands.push((pos + 1) as u8);
+            self.bump()?;
+            Ok(())
+        } else if self.binded_context.contains(&self.first().value) {
             let pos = self
                 .binded_context
                 .iter()
                 .rev() // if we push anything with the same name, we want to get the latest one
                 .position(|x| *x == self.first().value);
-            self.push_opcode(Opcode::CopyToMain(
-                (self.binded_context.len() - pos.unwrap() - 1) as u8,
-            ));
+            let depth = self.binded_context.len() - pos.unwrap() - 1;
+            let depth: u8 = depth
+                .try_into()
+                .map_err(|_| CompileError::TooManyNames(self.binded_context.len()))?;
+            self.push_opcode(Opcode::CopyToMain(depth));
             self.bump()?;
             Ok(())
+        } else if let Some((offset, parameters)) = self.functions.get(&self.first().value) {
+            self.call(*offset, parameters.len())
         } else {
-            Err(CompileError::UnexpectedToken(self.first().value.clone()))
+            let mut functions: Vec<&String> = self.functions.keys().collect();
+            functions.sort();
+            let mut in_scope: Vec<&str> = vec![];
+            for name in self.mappings.iter().chain(&self.binded_context).chain(functions) {
+                if !in_scope.contains(&name.as_str()) {
+                    in_scope.push(name);
+                }
+            }
+            let token = self.first();
+            Err(CompileError::UndefinedIdentifier(
+                token.line,
+                token.col,
+                token.value.clone(),
+                in_scope.join(", "),
+            ))
         }
     }
 
-    fn if_(&mut self) -> Result<(), CompileError> {
+    /// Calls the function at `offset`. Its arguments are the top `parameters` values, which the
+    /// call moves to the return stack the way `let` does, so the callee finds them where it
+    /// expects its parameters.
+    fn call(&mut self, offset: usize, parameters: usize) -> Result<(), CompileError> {
+        let offset: u16 = offset
+            .try_into()
+            .map_err(|_| CompileError::CallTooFar(offset))?;
+        let parameters: u8 = parameters
+            .try_into()
+            .map_err(|_| CompileError::TooManyParameters(parameters))?;
+        self.push_opcode(Opcode::Call);
+        self.output.extend_from_slice(&offset.to_le_bytes());
+        self.output.push(parameters);
         self.bump()?;
-        let to = self.input[self.index..]
-            .iter()
-            .position(|tok| {
-                tok.kind == TokenKind::Keyword(Keyword::Else)
-                    || tok.kind == TokenKind::Keyword(Keyword::End)
-            })
-            .expect("Could not find else/end keywords to end `if`");
+        Ok(())
+    }
+
+    /// `while <condition> do <body> end` runs the body for as long as the condition is not zero.
+    fn while_(&mut self) -> Result<(), CompileError> {
+        self.bump()?;
+        self.jump_target();
+        let condition = self.output.len();
+        self.advance_while(|k| k != TokenKind::Keyword(Keyword::Do))?;
+
+        // `Jumpif` skips the body once the condition is zero.
+        self.push_opcode(Opcode::Push(1));
+        let exit_offset = self.output.len();
+        self.output.push(0);
+        self.push_opcode(Opcode::Jumpif);
+        let body = self.output.len();
+
+        self.advance_until_end()?;
         self.push_opcode(Opcode::Push(1));
-        let before = self.output.len();
+        let back = self.output.len() + 2 - condition;
+        self.output.push(jump_offset(back)?);
+        self.push_opcode(Opcode::JumpBack);
+        self.output[exit_offset] = jump_offset(self.output.len() - body)?;
+        self.jump_target();
+        Ok(())
+    }
+
+    fn if_(&mut self) -> Result<(), CompileError> {
+        self.bump()?;
+        // the offsets are only known once the bodies are compiled, so the pushes of the jumps are
+        // inserted afterwards, as wide as they need to be.
+        let jumpif = self.output.len();
         self.push_opcode(Opcode::Jumpif);
 
         self.advance_while(|k| {
@@ -196,18 +380,20 @@ impl Compiler {
 
         let with_else = self.input[self.index - 1].kind == TokenKind::Keyword(Keyword::Else);
         if with_else {
-            self.output
-                .insert(before, (self.output.len() - before + 2) as u8);
-            self.push_opcode(Opcode::Push(1));
-            let before = self.output.len();
+            let jump = self.output.len();
             self.push_opcode(Opcode::Jump);
             self.advance_until_end()?;
-            self.output
-                .insert(before, (self.output.len() - before - 1) as u8);
+            let jump_push = push_offset(self.output.len() - jump - 1);
+            let else_start = jump + jump_push.len() + 1;
+            self.output.splice(jump..jump, jump_push);
+            let jumpif_push = push_offset(else_start - jumpif - 1);
+            self.output.splice(jumpif..jumpif, jumpif_push);
         } else {
-            self.output
-                .insert(before, (self.output.len() - before - 1) as u8);
+            let jumpif_push = push_offset(self.output.len() - jumpif - 1);
+            self.output.splice(jumpif..jumpif, jumpif_push);
         }
+        // the splices moved what was pushed in the bodies, too.
+        self.jump_target();
         Ok(())
     }
 
@@ -217,13 +403,19 @@ impl Compiler {
             Bin::Add => Opcode::Add,
             Bin::Mul => Opcode::Mul,
             Bin::Div => Opcode::Div,
+            Bin::Mod => Opcode::Mod,
+            Bin::Exp => Opcode::Exp,
+            Bin::Shl => Opcode::Shl,
+            Bin::Shr => Opcode::Shr,
             Bin::Lt => Opcode::Lt,
             Bin::Gt => Opcode::Gt,
             Bin::Geq => Opcode::Geq,
-            Bin::Leq => Opcode::Geq,
+            Bin::Leq => Opcode::Leq,
             Bin::EqSign => Opcode::Eqi,
         };
-        self.push_opcode(kind);
+        if !self.fold(kind) {
+            self.push_opcode(kind);
+        }
         self.bump()?;
         Ok(())
     }
@@ -255,6 +447,15 @@ impl Compiler {
         Ok(())
     }
 
+    /// Pushes `get` or `store` along with the mapping they act on.
+    fn storage_access(&mut self, opcode: Opcode) -> Result<(), CompileError> {
+        self.push_opcode(opcode);
+        let map_index = self.mapping_operands.pop().unwrap_or(0);
+        self.output.push(map_index);
+        self.bump()?;
+        Ok(())
+    }
+
     fn push_opcode(&mut self, opcode: Opcode) {
         self.output.push(opcode.to_u8());
     }
@@ -265,22 +466,45 @@ impl Compiler {
             TokenKind::Keyword(Keyword::Let) => self.bind_block(true)?,
             TokenKind::Keyword(Keyword::Peek) => self.bind_block(false)?,
             TokenKind::Keyword(Keyword::If) => self.if_()?,
+            TokenKind::Keyword(Keyword::While) => self.while_()?,
+            TokenKind::Keyword(Keyword::Dup) => {
+                self.push_opcode(Opcode::Dup);
+                self.bump()?;
+            }
             TokenKind::Keyword(Keyword::Require) => self.require()?,
             TokenKind::Ident => self.identifier()?,
             TokenKind::Keyword(Keyword::Iszero) => {
                 self.push_opcode(Opcode::Iszero);
                 self.bump()?;
             }
-            TokenKind::Keyword(Keyword::Get) => {
-                self.push_opcode(Opcode::Get);
+            TokenKind::Keyword(Keyword::Log) => {
+                self.push_opcode(Opcode::Log);
                 self.bump()?;
             }
-            TokenKind::Keyword(Keyword::Store) => {
-                self.push_opcode(Opcode::Store);
+            TokenKind::Keyword(Keyword::Slt) => {
+                if !self.fold(Opcode::Slt) {
+                    self.push_opcode(Opcode::Slt);
+                }
                 self.bump()?;
             }
+            TokenKind::Keyword(Keyword::Sgt) => {
+                if !self.fold(Opcode::Sgt) {
+                    self.push_opcode(Opcode::Sgt);
+                }
+                self.bump()?;
+            }
+            TokenKind::Keyword(Keyword::Get) => self.storage_access(Opcode::Get)?,
+            TokenKind::Keyword(Keyword::Store) => self.storage_access(Opcode::Store)?,
             TokenKind::Op(op) => self.op(op)?,
-            _ => panic!("{:?}", self.first().kind),
+            _ => return Err(self.unexpected(self.first())),
+        }
+        Ok(())
+    }
+
+    /// Compiles every top-level item, each function starting where the previous one ended.
+    fn compile_all(&mut self) -> Result<(), CompileError> {
+        while !self.should_stop() {
+            self.advance()?;
         }
         Ok(())
     }
@@ -290,18 +514,72 @@ impl Compiler {
             TokenKind::Keyword(Keyword::Fnk) => self.function()?,
             TokenKind::Keyword(Keyword::Mapping) => {
                 if self.second()?.kind != TokenKind::Ident {
-                    return Err(CompileError::UnexpectedToken(self.second()?.value.clone()));
+                    return Err(self.unexpected(self.second()?));
+                }
+                if self.mappings.len() >= u8::MAX as usize {
+                    return Err(CompileError::TooManyMappings);
                 }
-                self.binded_context.push(self.second()?.value.clone());
+                self.mappings.push(self.second()?.value.clone());
                 self.bump()?;
                 self.bump()?;
             }
-            _ => panic!("{:?}", self.first().kind),
+            _ => return Err(self.unexpected(self.first())),
         }
         Ok(())
     }
 }
 
+fn twos_complement(magnitude: U256) -> U256 {
+    (!magnitude).overflowing_add(U256::one()).0
+}
+
+/// `value` as a 256 bit two's complement number.
+fn signed(value: impl Into<i64>) -> U256 {
+    let value = value.into();
+    if value < 0 {
+        twos_complement(U256::from(value.unsigned_abs()))
+    } else {
+        U256::from(value)
+    }
+}
+
+/// A push of `offset`, using as few bytes as it fits in.
+fn push_offset(offset: usize) -> Vec<u8> {
+    let bytes = offset.to_le_bytes();
+    let len = bytes.iter().rposition(|byte| *byte != 0).map_or(1, |last| last + 1);
+    let mut push = vec![Opcode::Push(len as u8).to_u8()];
+    push.extend_from_slice(&bytes[..len]);
+    push
+}
+
+fn jump_offset(offset: usize) -> Result<u8, CompileError> {
+    offset
+        .try_into()
+        .map_err(|_| CompileError::JumpTooFar(offset))
+}
+
+/// A contract's bytecode, with where each of its functions starts and the parameters it takes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompiledContract {
+    pub code: Vec<u8>,
+    pub functions: BTreeMap<String, (usize, Vec<String>)>,
+}
+
+/// Compiles the source of a contract, folding constants.
+pub fn compile(input: &str) -> Result<CompiledContract, CompileError> {
+    let mut lexer = Lexer::new(input.to_string());
+    let mut tokens = vec![];
+    while !lexer.should_stop() {
+        tokens.push(lexer.advance()?);
+    }
+    let mut compiler = Compiler::new(tokens).with_constant_folding(true);
+    compiler.compile_all()?;
+    Ok(CompiledContract {
+        code: compiler.output,
+        functions: compiler.functions.into_iter().collect(),
+    })
+}
+
 pub fn parse(input: String) {
     println!("\n\n");
     let st = std::time::Instant::now();
@@ -318,40 +596,31 @@ fn transfer from to amount in
     end
 end"#
         .to_string());
-    let mut compiler = Compiler::new(input);
-    compiler.advance().unwrap();
+    let mut compiler = Compiler::new(input).with_constant_folding(true);
+    compiler.compile_all().unwrap();
     println!("{:?}", st.elapsed());
     println!("{:?} {:?}", compiler.functions, compiler.output.len());
-    println!("{:?}", somewhat_decompile(&compiler.output));
-    super::execute(
-        compiler.output.clone(),
-        vec![U256::from(1234), U256::from(1235), U256::from(101)],
-        RocksdbStorage::load(&Default::default()),
-    );
-    println!("\n\n");
-}
-
-fn somewhat_decompile(input: &[u8]) -> Vec<(Opcode, U256)> {
-    let mut out = vec![];
-    let mut i = 0;
-    while i < input.len() {
-        if let Some(poop) = Opcode::from_u8(input[i]) {
-            let a = match poop {
-                Opcode::Push(n) => {
-                    i += n as usize;
-                    (
-                        poop,
-                        U256::from_little_endian(&input[i - n as usize + 1..i as usize + 1]),
-                    )
-                    // (poop, U256::from(0))
-                }
-                _ => (poop, U256::from(0_usize)),
-            };
-            out.push(a);
-        }
-        i += 1;
+    match disassemble(&compiler.output) {
+        Ok(instructions) => println!("{}", Listing(&instructions)),
+        Err(err) => println!("could not disassemble: {}", err),
     }
-    out
+    #[cfg(feature = "rocksdb-backend")]
+    {
+        let outcome = super::execute(
+            compiler.output.clone(),
+            vec![U256::from(1234), U256::from(1235), U256::from(101)],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap_or_else(|err| {
+            tracing::warn!("contract failed: {}", err);
+            ExecutionOutcome::failed()
+        });
+        println!("{:?}", outcome);
+    }
+    println!("\n\n");
 }
 
 pub fn lex(input: String) -> Vec<Token> {
@@ -1,8 +1,21 @@
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, sync::Arc};
 
-    use crate::contracts::compiler::{lex, Compiler};
+    use primitive_types::U256;
+    use serial_test::serial;
+
+    use crate::{
+        contracts::{
+            compiler::{lex, Base, Bin, CompileError, Compiler, Lexer, TokenKind, Type},
+            execute,
+            language::{
+                disassemble, execute_at, Opcode, StackLimits, VmError, DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+            },
+        },
+        storage::{RocksdbStorage, Storage},
+    };
 
     use super::*;
 
@@ -40,7 +53,7 @@ end"#
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 33, 73, 38, 11, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             38, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 6,
+            0, 0, 0, 0, 0, 6, 0, 192,
         ];
         assert_eq!(expected_output, compiler.output.clone());
     }
@@ -77,7 +90,7 @@ end"#
         let expected_output = vec![
             7, 0, 7, 33, 72, 38, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 74, 76, 1, 7, 33, 72, 38, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192,
         ];
         assert_eq!(expected_output, compiler.output.clone());
     }
@@ -108,8 +121,581 @@ end"#
 
         let expected_output = vec![
             38, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 181, 7, 2, 72, 76, 1,
+            0, 0, 0, 0, 0, 181, 7, 2, 72, 76, 1, 192,
+        ];
+        assert_eq!(expected_output, compiler.output.clone());
+    }
+
+    #[test]
+    fn modulo_and_exponentiation() {
+        let input = lex(r#"
+fn fees amount rate in
+    amount rate %
+    amount 2 **
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        if let Err(err) = compiler.advance() {
+            assert!(false, "{}", err);
+        }
+
+        let expected_output = vec![
+            74, 75, 182, 74, 38, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 183, 192,
         ];
         assert_eq!(expected_output, compiler.output.clone());
     }
+
+    #[test]
+    fn shifts() {
+        let input = lex(r#"
+fn scale amount bits in
+    amount bits <<
+    amount bits >>
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        if let Err(err) = compiler.advance() {
+            assert!(false, "{}", err);
+        }
+
+        let expected_output = vec![74, 75, 184, 74, 75, 185, 192];
+        assert_eq!(expected_output, compiler.output.clone());
+    }
+
+    fn compile(code: &str) -> Vec<u8> {
+        let mut compiler = Compiler::new(lex(code.to_string()));
+        if let Err(err) = compiler.compile_all() {
+            assert!(false, "{}", err);
+        }
+        compiler.output
+    }
+
+    /// Compiles `code` both with and without constant folding.
+    fn compile_folded(code: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut compiler = Compiler::new(lex(code.to_string())).with_constant_folding(true);
+        if let Err(err) = compiler.compile_all() {
+            assert!(false, "{}", err);
+        }
+        (compiler.output, compile(code))
+    }
+
+    #[test]
+    #[serial]
+    fn mappings_do_not_alias() {
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&Default::default());
+        let write = compile(r#"
+mapping Balances
+mapping Allowances
+fn write key in
+    Balances key 1 store
+    Allowances key 2 store
+end"#);
+
+        let outcome = execute(
+            write,
+            vec![U256::from(5)],
+            storage.clone(),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.stores,
+            vec![
+                (1, U256::from(5), U256::one()),
+                (2, U256::from(5), U256::from(2)),
+            ]
+        );
+
+        let read = compile(r#"
+mapping Balances
+mapping Allowances
+fn read key in
+    0 Balances key get store
+    1 Allowances key get store
+end"#);
+        let outcome = execute(
+            read,
+            vec![U256::from(5)],
+            storage,
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.stores,
+            vec![
+                (0, U256::zero(), U256::one()),
+                (0, U256::one(), U256::from(2)),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn logs() {
+        let code = compile(r#"
+fn transfer from amount in
+    1 from log
+    2 amount log
+end"#);
+        let outcome = execute(
+            code,
+            vec![U256::from(3), U256::from(100)],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+
+        let logs: Vec<(U256, U256)> = outcome
+            .logs
+            .iter()
+            .map(|log| (log.topic, log.value))
+            .collect();
+        assert_eq!(
+            logs,
+            vec![
+                (U256::one(), U256::from(3)),
+                (U256::from(2), U256::from(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let commented = compile(r#"
+# keeps track of who owns what.
+mapping Balances
+fn deposit to amount in # credits `to`
+    Balances to amount store
+    #Balances to 0 store
+end"#);
+        let plain = compile(r#"
+mapping Balances
+fn deposit to amount in
+    Balances to amount store
+end"#);
+        assert_eq!(commented, plain);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let input = lex(r#"
+fn transfer from amount in
+    from amount +
+    foo
+end"#
+            .to_string());
+        assert_eq!((input[8].line, input[8].col), (4, 5));
+
+        let mut compiler = Compiler::new(input);
+        let err = compiler.advance().unwrap_err();
+        assert!(matches!(err, CompileError::UndefinedIdentifier(4, 5, _, _)));
+
+        let input = lex("fn f in\n    1 else\nend".to_string());
+        let err = Compiler::new(input).advance().unwrap_err();
+        assert_eq!(err.to_string(), "line 2, col 7: unexpected `else`");
+    }
+
+    #[test]
+    fn misspelled_names_list_what_is_in_scope() {
+        let input = lex(r#"
+mapping Balances
+fn transfer from to amount in
+    Balances to amont store
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        compiler.advance().unwrap();
+        let err = compiler.advance().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4, col 17: undefined `amont`; in scope: Balances, from, to, amount, transfer"
+        );
+    }
+
+    #[test]
+    fn multiple_functions() {
+        let input = lex(r#"
+fn first a b in
+    a b +
+end
+fn second c in
+    c
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        if let Err(err) = compiler.compile_all() {
+            assert!(false, "{}", err);
+        }
+
+        let mut expected_functions = HashMap::new();
+        expected_functions.insert(
+            "first".to_string(),
+            (0_usize, vec!["a".to_string(), "b".to_string()]),
+        );
+        expected_functions.insert("second".to_string(), (4_usize, vec!["c".to_string()]));
+        assert_eq!(expected_functions, compiler.functions);
+        assert_eq!(compiler.output, vec![74, 75, 1, 192, 74, 192]);
+    }
+
+    #[test]
+    fn locals_do_not_leak_between_functions() {
+        let input = lex(r#"
+fn first a in
+    a
+end
+fn second in
+    a
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        assert!(matches!(
+            compiler.compile_all(),
+            Err(CompileError::UndefinedIdentifier(6, 5, _, _))
+        ));
+    }
+
+    #[test]
+    fn less_or_equal() {
+        let output = compile(r#"
+fn check a b in
+    a b <=
+end"#);
+        assert_eq!(output, vec![74, 75, 0xb3, 0xc0]);
+    }
+
+    #[test]
+    #[serial]
+    fn while_loops() {
+        let output = compile(r#"
+fn countdown in
+    3
+    while dup do
+        1 -
+        dup dup store
+    end
+end"#);
+        let outcome = execute(
+            output,
+            vec![],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.stores,
+            vec![
+                (0, U256::from(2), U256::from(2)),
+                (0, U256::one(), U256::one()),
+                (0, U256::zero(), U256::zero()),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn long_if_bodies() {
+        // each number is a 33 byte push, so both bodies are well over 255 bytes.
+        let filler = "1000 ".repeat(10);
+        let output = compile(&format!(
+            r#"
+fn branch cond in
+    cond if
+        {filler}
+        1 1 store
+    else
+        {filler}
+        2 2 store
+    end
+    5 5 store
+end"#
+        ));
+
+        let storage = RocksdbStorage::load(&Default::default());
+        for (cond, taken) in [(1, 1), (0, 2)] {
+            let outcome = execute(
+                output.clone(),
+                vec![U256::from(cond)],
+                storage.clone(),
+                DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                outcome.stores,
+                vec![
+                    (0, U256::from(taken), U256::from(taken)),
+                    (0, U256::from(5), U256::from(5)),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn let_blocks_drop_their_locals() {
+        let output = compile(r#"
+fn f a in
+    10 let x in
+        20 let y in
+            x y + dup store
+        end
+    end
+    40 let z in
+        z z store
+    end
+end"#);
+        let outcome = execute(
+            output,
+            vec![U256::one()],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.stores,
+            vec![
+                (0, U256::from(30), U256::from(30)),
+                (0, U256::from(40), U256::from(40)),
+            ]
+        );
+
+        let mut compiler = Compiler::new(lex(r#"
+fn f in
+    10 let x in
+        20 let y in
+        end
+        y
+    end
+end"#
+            .to_string()));
+        assert!(matches!(
+            compiler.compile_all(),
+            Err(CompileError::UndefinedIdentifier(6, 9, _, _))
+        ));
+    }
+
+    #[test]
+    fn signed_literals() {
+        let tokens = lex("-1_i64 - 0x10_i8 -0x10".to_string());
+        assert_eq!(tokens[0].kind, TokenKind::Num(Base::Dec, Type::I64));
+        assert_eq!(tokens[0].value, "-1");
+        assert_eq!(tokens[1].kind, TokenKind::Op(Bin::Sub));
+        assert_eq!(tokens[2].kind, TokenKind::Num(Base::Hex, Type::I8));
+        assert_eq!(tokens[2].value, "10");
+        assert_eq!(tokens[3].kind, TokenKind::Num(Base::Hex, Type::I256));
+        assert_eq!(tokens[3].value, "-10");
+
+        let output = compile(r#"
+fn f in
+    -1_i64 5_i8 -128_i8
+    -1 slt
+end"#);
+        let instructions = disassemble(&output).unwrap();
+        let pushes: Vec<(Opcode, Option<U256>)> = instructions
+            .iter()
+            .map(|(_, opcode, operand)| (*opcode, *operand))
+            .collect();
+        assert_eq!(
+            pushes,
+            vec![
+                (Opcode::Push(32), Some(U256::MAX)),
+                (Opcode::Push(1), Some(U256::from(5))),
+                (Opcode::Push(32), Some(U256::MAX - 127)),
+                (Opcode::Push(32), Some(U256::MAX)),
+                (Opcode::Slt, None),
+                (Opcode::Ret, None),
+            ]
+        );
+        // and back.
+        assert_eq!(pushes[0].1.unwrap().low_u64() as i64, -1);
+
+        for out_of_range in ["-129_i8", "128_i8"] {
+            let mut compiler = Compiler::new(lex(format!("fn f in {} end", out_of_range)));
+            assert!(matches!(
+                compiler.compile_all(),
+                Err(CompileError::CantInterpret(..))
+            ));
+        }
+        for unsigned in ["-1_u8", "-1_u256"] {
+            let mut lexer = Lexer::new(unsigned.to_string());
+            assert!(matches!(lexer.advance(), Err(CompileError::CantInterpret(..))));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn constant_folding() {
+        let (folded, unfolded) = compile_folded(r#"
+fn f a in
+    1 100 200 + 7 * store
+    2 1 2 - store
+    3 3 300 ** store
+    4 0 1 - 5 slt store
+    5 7 0 / store
+    6 1 255 << 1 >> 10 % store
+    7 a 2 3 * + store
+end"#);
+        assert!(folded.len() < unfolded.len());
+        let instructions = disassemble(&folded).unwrap();
+        let opcodes: Vec<Opcode> = instructions.iter().map(|(_, opcode, _)| *opcode).collect();
+        // only the addition of the argument is left.
+        let binary_ops = opcodes
+            .iter()
+            .filter(|opcode| opcode.apply(U256::zero(), U256::zero()).is_some());
+        assert_eq!(binary_ops.count(), 1);
+        assert_eq!(instructions[1], (33, Opcode::Push(2), Some(U256::from(2100))));
+
+        let storage = RocksdbStorage::load(&Default::default());
+        let run = |output: Vec<u8>| {
+            execute(
+                output,
+                vec![U256::from(4)],
+                storage.clone(),
+                DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            )
+            .unwrap()
+        };
+        let folded = run(folded);
+        let unfolded = run(unfolded);
+        assert_eq!(folded.stores, unfolded.stores);
+        assert!(folded.gas_used < unfolded.gas_used);
+    }
+
+    #[test]
+    #[serial]
+    fn constants_are_not_folded_across_jump_targets() {
+        // the 4 and the 3 are next to each other, but the `if` branch jumps in between them.
+        let (folded, unfolded) = compile_folded(r#"
+fn f cond in
+    1 cond if 2 else 4 end 3 + store
+end"#);
+        assert_eq!(folded, unfolded);
+
+        let storage = RocksdbStorage::load(&Default::default());
+        for (cond, sum) in [(1, 5), (0, 7)] {
+            let outcome = execute(
+                folded.clone(),
+                vec![U256::from(cond)],
+                storage.clone(),
+                DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            )
+            .unwrap();
+            assert_eq!(outcome.stores, vec![(0, U256::one(), U256::from(sum))]);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn function_calls() {
+        let input = lex(r#"
+fn double x in
+    x x +
+end
+fn main a b in
+    a double b double +
+    a +
+end"#
+            .to_string());
+        let mut compiler = Compiler::new(input);
+        if let Err(err) = compiler.compile_all() {
+            assert!(false, "{}", err);
+        }
+
+        let (main, _) = compiler.functions["main"];
+        let outcome = execute_at(
+            compiler.output,
+            main,
+            vec![U256::from(3), U256::from(4)],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        // `a` is still where `main` left it after `double` dropped its own argument.
+        assert_eq!(outcome.return_value, Some(U256::from(17)));
+    }
+
+    #[test]
+    fn calls_take_at_most_255_parameters() {
+        let parameters: Vec<String> = (0..256).map(|i| format!("p{}", i)).collect();
+        let code = format!("fn wide {} in p0 end fn main in wide end", parameters.join(" "));
+        let mut compiler = Compiler::new(lex(code));
+        assert!(matches!(
+            compiler.compile_all(),
+            Err(CompileError::TooManyParameters(256))
+        ));
+    }
+
+    #[test]
+    fn malformed_contracts_are_errors() {
+        use crate::contracts::compiler::compile;
+
+        let names: Vec<String> = (0..257).map(|i| format!("n{}", i)).collect();
+        let wide_let = format!("fn main in let {} in n0 end end", names[..256].join(" "));
+        assert!(matches!(compile(&wide_let), Err(CompileError::TooManyNames(256))));
+
+        // every block fits, but the last name is bound past where `CopyToMain` reaches.
+        let (outer, inner) = (names[..129].join(" "), names[129..].join(" "));
+        let deep_let = format!("fn main in let {} in let {} in n256 end end end", outer, inner);
+        assert!(matches!(compile(&deep_let), Err(CompileError::TooManyNames(257))));
+
+        for source in [
+            "fn",
+            "fn main",
+            "fn main in",
+            "fn main in let",
+            "fn main in let a in",
+            "fn main in if",
+            "fn main in 1 if 2 else",
+            "fn main in while 1 do",
+            "mapping",
+            "mapping 5",
+            "fn main in 300_u8 end",
+            "end",
+            "== ** <= >>",
+        ] {
+            assert!(compile(source).is_err(), "{}", source);
+        }
+        let too_big = [
+            format!("fn main in {} end", "9".repeat(80)),
+            format!("fn main in 0x1{} end", "0".repeat(64)),
+        ];
+        for source in too_big {
+            assert!(compile(&source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn calls_are_bounded() {
+        let output = compile("fn forever in forever end");
+        assert!(matches!(
+            execute(
+                output,
+                vec![],
+                RocksdbStorage::load(&Default::default()),
+                DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            ),
+            Err(VmError::StackOverflow)
+        ));
+    }
 }
@@ -4,13 +4,24 @@ use primitive_types::U256;
 use sha3::Digest;
 use thiserror::Error;
 
-use crate::storage::Storage;
+use crate::{
+    config::ChainParams,
+    storage::{Storage, StorageError},
+};
 
-const STACK_SIZE: usize = 32;
-const RETURN_STACK_SIZE: usize = 32;
+pub const STACK_SIZE: usize = 32;
+pub const RETURN_STACK_SIZE: usize = 32;
+/// The most values either of a contract's stacks may be configured to hold.
+pub const MAX_STACK_SIZE: usize = 1024;
+/// How many calls deep a contract may go before it fails.
+pub const MAX_CALL_DEPTH: usize = 64;
+/// The gas a contract gets when nothing else was specified.
+pub const DEFAULT_GAS_LIMIT: u64 = 1_000_000;
+/// How many opcodes a contract may execute when nothing else was specified.
+pub const DEFAULT_MAX_STEPS: usize = 100_000;
 
 #[derive(Debug, Error)]
-enum VmError {
+pub enum VmError {
     #[error("the code should have stopped (possible reasons are: invalid opcode, reached end of code, or the program raised Stop)")]
     ShouldStop,
     #[error("stack underflow")]
@@ -21,9 +32,25 @@ enum VmError {
     ExpectedValue(usize),
     #[error("tried to jump to {0} but the code's length is only {1}")]
     InvalidJump(U256, usize),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("ran out of gas (the limit is {0})")]
+    OutOfGas(u64),
+    #[error("executed more than {0} opcodes")]
+    StepLimitExceeded(usize),
+    #[error("{0:#04x} at {1} is not an opcode")]
+    InvalidOpcode(u8, usize),
+    #[error("tried to jump {0} back from {1}, which is before the start of the code")]
+    InvalidBackwardJump(U256, usize),
+    #[error("the jump at {0} lands on {1}, which is inside an operand")]
+    MisalignedJump(usize, usize),
+    #[error("can not start executing at {0}, no instruction starts there")]
+    InvalidEntry(usize),
+    #[error("{0} has an operand that is out of range")]
+    InvalidOperand(Opcode),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     Terminate,
     Add,
@@ -48,6 +75,17 @@ pub enum Opcode {
     Jump,
     Dup,
     Iszero,
+    Mod,
+    Exp,
+    Shl,
+    Shr,
+    Log,
+    JumpBack,
+    DropReturn,
+    Slt,
+    Sgt,
+    Call,
+    Ret,
 }
 
 impl Opcode {
@@ -61,7 +99,7 @@ impl Opcode {
             0x05 => Some(Self::Store),
             0x06 => Some(Self::Get),
             0x07..=0x26 => Some(Self::Push(opcode - 0x06)),
-            0x27..=0x47 => Some(Self::Swap(opcode - 0x07)),
+            0x27..=0x47 => Some(Self::Swap(opcode - 0x26)),
             0x48 => Some(Self::Jumpif),
             0x49 => Some(Self::Jump),
             0x4a..=0x6a => Some(Self::CopyToMain(opcode - 0x4a)),
@@ -76,11 +114,22 @@ impl Opcode {
             0xb3 => Some(Self::Leq),
             0xb4 => Some(Self::Jumpifnot),
             0xb5 => Some(Self::Iszero),
+            0xb6 => Some(Self::Mod),
+            0xb7 => Some(Self::Exp),
+            0xb8 => Some(Self::Shl),
+            0xb9 => Some(Self::Shr),
+            0xba => Some(Self::Log),
+            0xbb => Some(Self::JumpBack),
+            0xbc => Some(Self::DropReturn),
+            0xbd => Some(Self::Slt),
+            0xbe => Some(Self::Sgt),
+            0xbf => Some(Self::Call),
+            0xc0 => Some(Self::Ret),
             _ => None,
         }
     }
 
-    pub fn to_u8(&self) -> u8 {
+    pub fn to_u8(self) -> u8 {
         match self {
             Self::Terminate => 0x00,
             Self::Add => 0x01,
@@ -105,23 +154,264 @@ impl Opcode {
             Self::Dup => 0x6b,
             Self::ClearReturn => 0x6c,
             Self::Iszero => 0xb5,
+            Self::Mod => 0xb6,
+            Self::Exp => 0xb7,
+            Self::Shl => 0xb8,
+            Self::Shr => 0xb9,
+            Self::Log => 0xba,
+            Self::JumpBack => 0xbb,
+            Self::DropReturn => 0xbc,
+            Self::Slt => 0xbd,
+            Self::Sgt => 0xbe,
+            Self::Call => 0xbf,
+            Self::Ret => 0xc0,
+        }
+    }
+
+    /// How many bytes of operand follow this opcode in the bytecode.
+    pub fn immediate_len(&self) -> usize {
+        match self {
+            Self::Push(n) => *n as usize,
+            Self::Get | Self::Store | Self::DropReturn => 1,
+            Self::Call => 3,
+            _ => 0,
+        }
+    }
+
+    /// What executing this opcode costs. Moving values costs per value moved, and touching
+    /// storage costs much more than anything done on the stack.
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Self::Terminate => 0,
+            Self::Add | Self::Sub | Self::Shl | Self::Shr | Self::Iszero => 3,
+            Self::Eqi | Self::Lt | Self::Gt | Self::Geq | Self::Leq => 3,
+            Self::Slt | Self::Sgt => 3,
+            Self::Mul | Self::Div | Self::Mod => 5,
+            Self::Exp => 10,
+            Self::Push(n) => 2 + *n as u64 / 8,
+            Self::Swap(_) | Self::Dup | Self::CopyToMain(_) => 3,
+            Self::MoveToReturn(n) | Self::CopyToReturn(n) => 2 + *n as u64,
+            Self::ClearReturn | Self::DropReturn => 2,
+            Self::Jumpif | Self::Jumpifnot | Self::Jump | Self::JumpBack => 8,
+            Self::Call | Self::Ret => 8,
+            Self::Log => 20,
+            Self::Get => 50,
+            Self::Store => 100,
+        }
+    }
+
+    /// The result of a binary operation on `lhs` and `rhs`, `lhs` being the one pushed first, or
+    /// `None` if this is not a binary operation. The compiler folds constants with this too, so
+    /// it has to stay the only place they are evaluated.
+    ///
+    /// Arithmetic wraps around on overflow and underflow, like the evm's does. Contracts that care
+    /// have to check with `require` first.
+    pub fn apply(self, lhs: U256, rhs: U256) -> Option<U256> {
+        // the operands of the signed comparisons are two's complement, flipping their sign bits
+        // makes the unsigned order of them the signed one.
+        let sign = U256::one() << 255;
+        let result = match self {
+            Self::Add => lhs.overflowing_add(rhs).0,
+            Self::Sub => lhs.overflowing_sub(rhs).0,
+            Self::Mul => lhs.overflowing_mul(rhs).0,
+            Self::Div | Self::Mod if rhs.is_zero() => U256::zero(),
+            Self::Div => lhs / rhs,
+            Self::Mod => lhs % rhs,
+            Self::Exp => lhs.overflowing_pow(rhs).0,
+            // shifting everything out leaves zero, like the evm does.
+            Self::Shl | Self::Shr if rhs >= U256::from(256) => U256::zero(),
+            Self::Shl => lhs << rhs.as_usize(),
+            Self::Shr => lhs >> rhs.as_usize(),
+            Self::Eqi => U256::from((lhs == rhs) as u8),
+            Self::Lt => U256::from((lhs < rhs) as u8),
+            Self::Gt => U256::from((lhs > rhs) as u8),
+            Self::Geq => U256::from((lhs >= rhs) as u8),
+            Self::Leq => U256::from((lhs <= rhs) as u8),
+            Self::Slt => U256::from(((lhs ^ sign) < (rhs ^ sign)) as u8),
+            Self::Sgt => U256::from(((lhs ^ sign) > (rhs ^ sign)) as u8),
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Terminate => write!(f, "TERMINATE"),
+            Self::Add => write!(f, "ADD"),
+            Self::Sub => write!(f, "SUB"),
+            Self::Mul => write!(f, "MUL"),
+            Self::Div => write!(f, "DIV"),
+            Self::Mod => write!(f, "MOD"),
+            Self::Exp => write!(f, "EXP"),
+            Self::Shl => write!(f, "SHL"),
+            Self::Shr => write!(f, "SHR"),
+            Self::Eqi => write!(f, "EQ"),
+            Self::Lt => write!(f, "LT"),
+            Self::Gt => write!(f, "GT"),
+            Self::Geq => write!(f, "GEQ"),
+            Self::Leq => write!(f, "LEQ"),
+            Self::Slt => write!(f, "SLT"),
+            Self::Sgt => write!(f, "SGT"),
+            Self::Iszero => write!(f, "ISZERO"),
+            Self::Store => write!(f, "STORE"),
+            Self::Get => write!(f, "GET"),
+            Self::Push(_) => write!(f, "PUSH"),
+            Self::Swap(n) => write!(f, "SWAP{}", n),
+            Self::MoveToReturn(n) => write!(f, "MOVETORETURN{}", n),
+            Self::CopyToReturn(n) => write!(f, "COPYTORETURN{}", n),
+            Self::CopyToMain(n) => write!(f, "COPYTOMAIN{}", n),
+            Self::ClearReturn => write!(f, "CLEARRETURN"),
+            Self::Jumpif => write!(f, "JUMPIF"),
+            Self::Jumpifnot => write!(f, "JUMPIFNOT"),
+            Self::Jump => write!(f, "JUMP"),
+            Self::JumpBack => write!(f, "JUMPBACK"),
+            Self::DropReturn => write!(f, "DROPRETURN"),
+            Self::Dup => write!(f, "DUP"),
+            Self::Log => write!(f, "LOG"),
+            Self::Call => write!(f, "CALL"),
+            Self::Ret => write!(f, "RET"),
+        }
+    }
+}
+
+/// Decodes `bytecode` into its opcodes, each with the position it starts at and its operand, if
+/// it takes one.
+pub fn disassemble(bytecode: &[u8]) -> Result<Vec<(usize, Opcode, Option<U256>)>, VmError> {
+    let mut instructions = vec![];
+    let mut pc = 0;
+    while pc < bytecode.len() {
+        let opcode =
+            Opcode::from_u8(bytecode[pc]).ok_or(VmError::InvalidOpcode(bytecode[pc], pc))?;
+        let len = opcode.immediate_len();
+        let operand = if len > 0 {
+            let bytes = bytecode
+                .get(pc + 1..pc + 1 + len)
+                .ok_or_else(|| VmError::ExpectedValue(pc + 1 + len - bytecode.len()))?;
+            Some(U256::from_little_endian(bytes))
+        } else {
+            None
+        };
+        instructions.push((pc, opcode, operand));
+        pc += 1 + len;
+    }
+    Ok(instructions)
+}
+
+/// Checks that `opcodes` decodes cleanly, that every call lands on the start of an instruction and
+/// that every jump whose offset is pushed right before it does too, or on the end of the code.
+/// Offsets computed at runtime are still only checked when the jump is taken. Swaps have to reach
+/// a value a stack of `limits` can hold.
+pub fn verify_bytecode(opcodes: &[u8], limits: StackLimits) -> Result<(), VmError> {
+    let instructions = disassemble(opcodes)?;
+    let is_boundary = |target: usize| {
+        instructions
+            .binary_search_by_key(&target, |(start, _, _)| *start)
+            .is_ok()
+    };
+    for (pc, opcode, operand) in &instructions {
+        if let Opcode::Swap(nth) = opcode {
+            if *nth == 0 || *nth as usize >= limits.stack {
+                return Err(VmError::InvalidOperand(*opcode));
+            }
+        }
+        if let (Opcode::Call, Some(operand)) = (opcode, operand) {
+            // the low two bytes are the target, the third is the argument count.
+            let target = operand.low_u64() as usize & 0xffff;
+            if target >= opcodes.len() {
+                return Err(VmError::InvalidJump(U256::from(target), opcodes.len()));
+            }
+            if !is_boundary(target) {
+                return Err(VmError::MisalignedJump(*pc, target));
+            }
+        }
+    }
+    for window in instructions.windows(2) {
+        let (_, previous, offset) = window[0];
+        let (pc, opcode, _) = window[1];
+        let offset = match (previous, offset, opcode) {
+            (
+                Opcode::Push(_),
+                Some(offset),
+                Opcode::Jump | Opcode::Jumpif | Opcode::Jumpifnot | Opcode::JumpBack,
+            ) => offset,
+            _ => continue,
+        };
+
+        let after = pc + 1;
+        let target = if opcode == Opcode::JumpBack {
+            if offset > U256::from(after) {
+                return Err(VmError::InvalidBackwardJump(offset, after));
+            }
+            after - offset.as_usize()
+        } else {
+            if offset > U256::from(opcodes.len() - after) {
+                return Err(VmError::InvalidJump(
+                    offset + U256::from(after),
+                    opcodes.len(),
+                ));
+            }
+            after + offset.as_usize()
+        };
+        if target != opcodes.len() && !is_boundary(target) {
+            return Err(VmError::MisalignedJump(pc, target));
+        }
+    }
+    Ok(())
+}
+
+/// Renders disassembled bytecode one opcode per line, like `0000: PUSH 0x0a`.
+pub struct Listing<'a>(pub &'a [(usize, Opcode, Option<U256>)]);
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pc, opcode, operand) in self.0 {
+            write!(f, "{:04x}: {}", pc, opcode)?;
+            if let Some(operand) = operand {
+                let mut bytes = [0; 32];
+                operand.to_big_endian(&mut bytes);
+                let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(31);
+                write!(f, " 0x")?;
+                for byte in &bytes[first..] {
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many values the stacks of a contract can hold.
+#[derive(Debug, Clone, Copy)]
+pub struct StackLimits {
+    pub stack: usize,
+    pub return_stack: usize,
+}
+
+impl Default for StackLimits {
+    fn default() -> Self {
+        Self {
+            stack: STACK_SIZE,
+            return_stack: RETURN_STACK_SIZE,
         }
     }
 }
 
 #[derive(Debug)]
 struct Stack {
-    stack: [U256; STACK_SIZE],
-    return_stack: [U256; RETURN_STACK_SIZE],
+    stack: Vec<U256>,
+    return_stack: Vec<U256>,
     stack_pos: usize,
     return_stack_pos: usize,
 }
 
 impl Stack {
-    fn new() -> Self {
+    fn new(limits: StackLimits) -> Self {
         Self {
-            stack: [U256::zero(); STACK_SIZE],
-            return_stack: [U256::zero(); RETURN_STACK_SIZE],
+            stack: vec![U256::zero(); limits.stack],
+            return_stack: vec![U256::zero(); limits.return_stack],
             stack_pos: 1,
             return_stack_pos: 1,
         }
@@ -151,47 +441,87 @@ impl Stack {
         ret
     }
 
-    fn peek(&mut self) -> U256 {
-        self.stack[self.stack_pos - 1]
+    fn peek(&mut self) -> Result<U256, VmError> {
+        if self.stack_pos == 1 {
+            return Err(VmError::StackUnderflow);
+        }
+        Ok(self.stack[self.stack_pos - 2])
     }
 
+    // the positions start at 1, so the next free slot is at `pos - 1`, and the stacks are full
+    // once that is past their last index.
     fn push(&mut self, value: U256) -> Result<(), VmError> {
-        if self.stack_pos > STACK_SIZE {
-            Err(VmError::StackOverflow)
-        } else {
-            self.stack[self.stack_pos - 1] = value;
-            self.stack_pos += 1;
-            Ok(())
-        }
+        let slot = self
+            .stack
+            .get_mut(self.stack_pos - 1)
+            .ok_or(VmError::StackOverflow)?;
+        *slot = value;
+        self.stack_pos += 1;
+        Ok(())
     }
 
     fn push_to_return(&mut self, value: U256) -> Result<(), VmError> {
-        if self.return_stack_pos > RETURN_STACK_SIZE {
-            Err(VmError::StackOverflow)
-        } else {
-            self.return_stack[self.return_stack_pos - 1] = value;
-            self.return_stack_pos += 1;
-            Ok(())
+        let slot = self
+            .return_stack
+            .get_mut(self.return_stack_pos - 1)
+            .ok_or(VmError::StackOverflow)?;
+        *slot = value;
+        self.return_stack_pos += 1;
+        Ok(())
+    }
+
+    fn drop_from_return(&mut self, n: usize) -> Result<(), VmError> {
+        if self.return_stack_pos - 1 < n {
+            return Err(VmError::StackUnderflow);
         }
+        for _ in 0..n {
+            self.return_stack_pos -= 1;
+            self.return_stack[self.return_stack_pos - 1] = U256::zero();
+        }
+        Ok(())
     }
 
+    /// Swaps the top of the stack with the value `nth` below it.
     fn swap(&mut self, nth: u8) -> Result<(), VmError> {
-        assert!(nth <= self.stack.len() as u8);
-        self.stack.swap(self.stack_pos - 1, nth as usize - 1);
+        if nth == 0 {
+            return Err(VmError::InvalidOperand(Opcode::Swap(nth)));
+        }
+        let top = self
+            .stack_pos
+            .checked_sub(2)
+            .ok_or(VmError::StackUnderflow)?;
+        let other = top
+            .checked_sub(nth as usize)
+            .ok_or(VmError::StackUnderflow)?;
+        self.stack.swap(top, other);
         Ok(())
     }
 
     fn dup(&mut self) -> Result<(), VmError> {
-        if self.stack_pos >= STACK_SIZE {
-            Err(VmError::StackOverflow)
-        } else {
-            self.stack[self.stack_pos] = self.stack[self.stack_pos - 1];
-            Ok(())
-        }
+        let top = self.peek()?;
+        self.push(top)
     }
 }
 
-impl fmt::Debug for Vm {
+/// What the values of a contract's mappings are read from, and written to once it completes.
+pub trait VmStorage: Send + Sync {
+    /// Looks up every key in `keys`, returning the values in the same order.
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError>;
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
+}
+
+impl<S: Storage + ?Sized> VmStorage for S {
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        Storage::get_many(self, keys)
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        Storage::set(self, key, value)
+    }
+}
+
+impl<S: VmStorage + ?Sized> fmt::Debug for Vm<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Vm")
             .field("opcodes", &self.opcodes)
@@ -200,34 +530,53 @@ impl fmt::Debug for Vm {
             .field("should_stop", &self.should_stop())
             .field("terminated", &self.terminated)
             .field("stores", &self.stores)
+            .field("logs", &self.logs)
             .finish()
     }
 }
 
-struct Vm {
+struct Vm<S: VmStorage + ?Sized = dyn Storage> {
     stack: Stack,
     opcodes: Vec<u8>,
     index: usize,
-    storage: Arc<dyn Storage>,
+    storage: Arc<S>,
     terminated: bool,
-    stores: Vec<(U256, U256)>,
+    stores: Vec<(u8, U256, U256)>,
     contract_hash: [u8; 32],
+    gas_limit: u64,
+    gas_used: u64,
+    return_value: Option<U256>,
+    logs: Vec<LogEntry>,
+    /// Where each of the functions that are being called returns to, and the frame of its
+    /// caller.
+    calls: Vec<(usize, usize)>,
+    /// Where the current function's parameters and locals start on the return stack.
+    frame: usize,
 }
 
-impl Vm {
+impl<S: VmStorage + ?Sized> Vm<S> {
     fn new(
         contract_hash: [u8; 32],
         opcodes: Vec<u8>,
-        storage: Arc<dyn Storage>,
+        storage: Arc<S>,
+        gas_limit: u64,
+        limits: StackLimits,
     ) -> Result<Self, VmError> {
+        verify_bytecode(&opcodes, limits)?;
         Ok(Self {
-            stack: Stack::new(),
+            stack: Stack::new(limits),
             opcodes,
             index: 0,
             storage,
             terminated: false,
             stores: vec![],
             contract_hash,
+            gas_limit,
+            gas_used: 0,
+            return_value: None,
+            logs: vec![],
+            calls: vec![],
+            frame: 0,
         })
     }
 
@@ -235,9 +584,12 @@ impl Vm {
         contract_hash: [u8; 32],
         opcodes: Vec<u8>,
         args: Vec<U256>,
-        storage: Arc<dyn Storage>,
+        storage: Arc<S>,
+        gas_limit: u64,
+        limits: StackLimits,
     ) -> Result<Self, VmError> {
-        let mut stack = Stack::new();
+        verify_bytecode(&opcodes, limits)?;
+        let mut stack = Stack::new(limits);
         stack.push_multiple_to_return(args)?;
 
         Ok(Self {
@@ -248,6 +600,12 @@ impl Vm {
             terminated: false,
             stores: vec![],
             contract_hash,
+            gas_limit,
+            gas_used: 0,
+            return_value: None,
+            logs: vec![],
+            calls: vec![],
+            frame: 0,
             // somehow designate a storage location to this storage with this account. maybe hash
             // the two together?
         })
@@ -265,69 +623,117 @@ impl Vm {
         self.terminated || self.index >= self.opcodes.len()
     }
 
+    /// Makes execution start at `entry` instead of at the start of the code.
+    fn start_at(&mut self, entry: usize) -> Result<(), VmError> {
+        let instructions = disassemble(&self.opcodes)?;
+        if instructions
+            .binary_search_by_key(&entry, |(start, _, _)| *start)
+            .is_err()
+        {
+            return Err(VmError::InvalidEntry(entry));
+        }
+        self.index = entry;
+        Ok(())
+    }
+
+    /// Moves the top `n` values to the return stack, keeping their order.
+    fn move_to_return(&mut self, n: u8) -> Result<(), VmError> {
+        let mut poped = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            poped.push(self.stack.pop()?);
+        }
+        for value in poped.into_iter().rev() {
+            self.stack.push_to_return(value)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the one byte operand that follows the current opcode.
+    fn immediate(&mut self) -> Result<u8, VmError> {
+        let byte = *self
+            .opcodes
+            .get(self.index)
+            .ok_or(VmError::ExpectedValue(1))?;
+        self.index += 1;
+        Ok(byte)
+    }
+
     fn advance(&mut self) -> Result<(), VmError> {
         let op = self.next().ok_or(VmError::ShouldStop)?;
+        self.gas_used += op.gas_cost();
+        if self.gas_used > self.gas_limit {
+            return Err(VmError::OutOfGas(self.gas_limit));
+        }
 
         match op {
-            Opcode::Terminate => self.terminated = true,
-            // TODO: macro...
-            Opcode::Add => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(lhs + rhs)?;
-            }
-            Opcode::Sub => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(lhs - rhs)?;
+            Opcode::Terminate => {
+                self.return_value = self.stack.peek().ok();
+                self.terminated = true;
             }
-            Opcode::Mul => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(lhs * rhs)?;
-            }
-            Opcode::Div => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                if rhs.is_zero() {
-                    self.stack.push(U256::zero())?;
-                } else {
-                    self.stack.push(lhs / rhs)?;
+            // the operands are where the callee starts, counting from the start of the code, and
+            // how many arguments it takes. those are moved to the return stack like
+            // `MoveToReturn` does, and start the callee's frame.
+            Opcode::Call => {
+                let target = u16::from_le_bytes([self.immediate()?, self.immediate()?]) as usize;
+                let arguments = self.immediate()?;
+                if self.calls.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::StackOverflow);
                 }
+                let frame = self.stack.return_stack_pos - 1;
+                self.move_to_return(arguments)?;
+                self.calls.push((self.index, self.frame));
+                self.frame = frame;
+                self.index = target;
             }
-            Opcode::Eqi => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(U256::from((lhs == rhs) as u8))?;
-            }
-            Opcode::Lt => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(U256::from((lhs < rhs) as u8))?;
-            }
-            Opcode::Gt => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(U256::from((lhs > rhs) as u8))?;
-            }
-            Opcode::Geq => {
-                let rhs = self.stack.pop()?;
-                let lhs = self.stack.pop()?;
-                self.stack.push(U256::from((lhs >= rhs) as u8))?;
-            }
-            Opcode::Leq => {
+            // drops the callee's frame. returning from the function that was called first ends
+            // the contract.
+            Opcode::Ret => match self.calls.pop() {
+                Some((back, frame)) => {
+                    let depth = self.stack.return_stack_pos - 1;
+                    let locals = depth
+                        .checked_sub(self.frame)
+                        .ok_or(VmError::StackUnderflow)?;
+                    self.stack.drop_from_return(locals)?;
+                    self.frame = frame;
+                    self.index = back;
+                }
+                None => {
+                    self.return_value = self.stack.peek().ok();
+                    self.terminated = true;
+                }
+            },
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::Exp
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::Eqi
+            | Opcode::Lt
+            | Opcode::Gt
+            | Opcode::Geq
+            | Opcode::Leq
+            | Opcode::Slt
+            | Opcode::Sgt => {
                 let rhs = self.stack.pop()?;
                 let lhs = self.stack.pop()?;
-                self.stack.push(U256::from((lhs <= rhs) as u8))?;
+                let result = op.apply(lhs, rhs).ok_or(VmError::ShouldStop)?;
+                self.stack.push(result)?;
             }
             Opcode::Store => {
+                let map_index = self.immediate()?;
                 let value = self.stack.pop()?;
                 let key = self.stack.pop()?;
-                self.stores.push((key, value));
+                self.stores.push((map_index, key, value));
             }
             Opcode::Get => {
+                let map_index = self.immediate()?;
                 let key = self.stack.pop()?;
-                if let Some(value) = self.get_from_storage(1, key) {
+                if let Some(value) = self.pending_store(map_index, key) {
+                    self.stack.push(value)?;
+                } else if let Some(value) = self.get_from_storage(map_index as usize, key)? {
                     self.stack.push(value)?;
                 } else {
                     self.stack.push(U256::zero())?;
@@ -342,24 +748,28 @@ impl Vm {
                     U256::from_little_endian(&self.opcodes[self.index - n as usize..self.index]);
                 self.stack.push(value)?;
             }
-            Opcode::MoveToReturn(n) => {
-                let mut poped = Vec::with_capacity(n as usize);
-                for _ in 0..n {
-                    poped.push(self.stack.pop()?);
-                }
-                for value in poped.into_iter().rev() {
-                    self.stack.push_to_return(value)?;
-                }
-            }
+            Opcode::MoveToReturn(n) => self.move_to_return(n)?,
             Opcode::CopyToReturn(n) => {
-                let initial_pos = self.stack.stack_pos - n as usize;
+                // the top value is at `stack_pos - 2`, as the position points past the free slot.
+                let initial_pos = self
+                    .stack
+                    .stack_pos
+                    .checked_sub(n as usize + 1)
+                    .ok_or(VmError::StackUnderflow)?;
                 for i in 0..n {
                     let value = self.stack.stack[initial_pos + i as usize];
                     self.stack.push_to_return(value)?;
                 }
             }
+            // counts from the start of the current function's frame, so a function finds its
+            // parameters in the same place wherever it is called from.
             Opcode::CopyToMain(n) => {
-                self.stack.push(self.stack.return_stack[n as usize])?;
+                let value = *self
+                    .stack
+                    .return_stack
+                    .get(self.frame + n as usize)
+                    .ok_or(VmError::StackUnderflow)?;
+                self.stack.push(value)?;
             }
             Opcode::ClearReturn => {
                 self.stack
@@ -368,6 +778,12 @@ impl Vm {
                     .for_each(|elem| *elem = U256::zero());
                 self.stack.return_stack_pos = 1;
             }
+            // unlike `ClearReturn`, only drops the values a block bound, leaving the ones bound
+            // around it.
+            Opcode::DropReturn => {
+                let n = self.immediate()?;
+                self.stack.drop_from_return(n as usize)?;
+            }
             Opcode::Swap(n) => self.stack.swap(n)?,
             Opcode::Jumpif => {
                 let alternative_offset = self.stack.pop()?;
@@ -408,39 +824,879 @@ impl Vm {
                     ));
                 }
             }
+            // the only way back, used by loops. the offset counts from the end of this opcode.
+            Opcode::JumpBack => {
+                let offset = self.stack.pop()?;
+                if offset <= U256::from(self.index) {
+                    self.index -= offset.as_usize();
+                } else {
+                    return Err(VmError::InvalidBackwardJump(offset, self.index));
+                }
+            }
+            Opcode::Log => {
+                let value = self.stack.pop()?;
+                let topic = self.stack.pop()?;
+                self.logs.push(LogEntry {
+                    contract_hash: self.contract_hash,
+                    topic,
+                    value,
+                });
+            }
             Opcode::Dup => self.stack.dup()?,
             Opcode::Iszero => {
-                let value = self.stack.peek();
+                let value = self.stack.peek()?;
                 self.stack.push(U256::from(value.is_zero() as u8))?
             }
         }
         Ok(())
     }
 
-    fn get_from_storage(&self, map_index: usize, key: U256) -> Option<U256> {
-        let mut key_bytes = [0; 32];
-        key.to_little_endian(&mut key_bytes);
+    fn storage_key(&self, map_index: usize, key: U256) -> [u8; 32] {
+        storage_key(&self.contract_hash, map_index, key)
+    }
+
+    /// The last value this run stored at `key` in the `map_index` mapping, which storage does not
+    /// have yet.
+    fn pending_store(&self, map_index: u8, key: U256) -> Option<U256> {
+        self.stores
+            .iter()
+            .rev()
+            .find(|(stored_map, stored_key, _)| *stored_map == map_index && *stored_key == key)
+            .map(|(_, _, value)| *value)
+    }
+
+    /// Writes the stores the contract made to storage. Only call this once the contract ran to
+    /// completion, so that a failing contract leaves storage untouched.
+    fn commit(&self) -> Result<(), VmError> {
+        for (map_index, key, value) in &self.stores {
+            let mut value_bytes = [0; 32];
+            value.to_little_endian(&mut value_bytes);
+            self.storage
+                .set(&self.storage_key(*map_index as usize, *key), &value_bytes)?;
+        }
+        Ok(())
+    }
+
+    fn get_from_storage(&self, map_index: usize, key: U256) -> Result<Option<U256>, VmError> {
+        Ok(self.get_many_from_storage(map_index, &[key])?.remove(0))
+    }
+
+    fn get_many_from_storage(
+        &self,
+        map_index: usize,
+        keys: &[U256],
+    ) -> Result<Vec<Option<U256>>, VmError> {
+        let keys: Vec<[u8; 32]> = keys
+            .iter()
+            .map(|key| self.storage_key(map_index, *key))
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        Ok(self
+            .storage
+            .get_many(&keys)?
+            .into_iter()
+            .map(|value| value.map(|bytes| U256::from_little_endian(&bytes)))
+            .collect())
+    }
+}
+
+/// Where the contract `contract_hash` keeps the value at `key` in its `map_index` mapping.
+pub fn storage_key(contract_hash: &[u8; 32], map_index: usize, key: U256) -> [u8; 32] {
+    let mut key_bytes = [0; 32];
+    key.to_little_endian(&mut key_bytes);
+
+    let mut hasher = sha3::Sha3_256::new();
+    hasher.update(map_index.to_le_bytes());
+    hasher.update(key_bytes);
+    hasher.update(contract_hash);
+    hasher.finalize().into()
+}
+
+/// Something a contract logged for whoever watches the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub contract_hash: [u8; 32],
+    pub topic: U256,
+    pub value: U256,
+}
+
+/// What running a contract left behind. A failed contract stores nothing.
+#[derive(Debug)]
+pub struct ExecutionOutcome {
+    pub stores: Vec<(u8, U256, U256)>,
+    pub success: bool,
+    pub gas_used: u64,
+    /// The top of the stack when the contract raised `Terminate`. It is `None` if the stack was
+    /// empty then, or if the contract ran off the end of its code instead.
+    pub return_value: Option<U256>,
+    /// The entries the contract logged, in order.
+    pub logs: Vec<LogEntry>,
+}
 
-        let mut hasher = sha3::Sha3_256::new();
-        hasher.update(map_index.to_le_bytes());
-        hasher.update(key_bytes);
-        hasher.update(self.contract_hash);
-        Some(U256::from_little_endian(
-            &self.storage.get(&hasher.finalize())?,
-        ))
+impl ExecutionOutcome {
+    pub fn failed() -> Self {
+        Self {
+            stores: vec![],
+            success: false,
+            gas_used: 0,
+            return_value: None,
+            logs: vec![],
+        }
     }
 }
 
-pub fn execute(_opcodes: Vec<u8>, args: Vec<U256>, storage: Arc<dyn Storage>) {
-    // let opcodes = vec![0x48, 0x00, 0x07, 4];
-    let st = std::time::Instant::now();
-    let mut vm = Vm::with_arguments([0; 32], _opcodes, args, storage).unwrap();
+pub fn execute(
+    opcodes: Vec<u8>,
+    args: Vec<U256>,
+    storage: Arc<dyn Storage>,
+    gas_limit: u64,
+    max_steps: usize,
+    limits: StackLimits,
+) -> Result<ExecutionOutcome, VmError> {
+    execute_at(opcodes, 0, args, storage, gas_limit, max_steps, limits)
+}
+
+/// Like [`execute`], but starts at `entry`, the offset of the function to run.
+pub fn execute_at(
+    opcodes: Vec<u8>,
+    entry: usize,
+    args: Vec<U256>,
+    storage: Arc<dyn Storage>,
+    gas_limit: u64,
+    max_steps: usize,
+    limits: StackLimits,
+) -> Result<ExecutionOutcome, VmError> {
+    let mut vm = Vm::with_arguments([0; 32], opcodes, args, storage, gas_limit, limits)?;
+    if entry != 0 {
+        vm.start_at(entry)?;
+    }
+    run(&mut vm, max_steps)?;
+    vm.commit()?;
+    tracing::trace!("{:?}", vm);
+    Ok(ExecutionOutcome {
+        stores: vm.stores,
+        success: true,
+        gas_used: vm.gas_used,
+        return_value: vm.return_value,
+        logs: vm.logs,
+    })
+}
+
+/// Runs the function at `entry` of the contract `contract_hash`, within the limits in `config`.
+/// Unlike [`execute_at`] it writes nothing: the stores are left in the outcome, for the caller to
+/// write under [`storage_key`] along with everything else the request wrote.
+pub fn execute_contract<S: VmStorage + ?Sized>(
+    contract_hash: [u8; 32],
+    opcodes: Vec<u8>,
+    entry: usize,
+    args: Vec<U256>,
+    storage: Arc<S>,
+    params: &ChainParams,
+) -> Result<ExecutionOutcome, VmError> {
+    let mut vm = Vm::with_arguments(
+        contract_hash,
+        opcodes,
+        args,
+        storage,
+        params.gas_limit,
+        params.stack_limits(),
+    )?;
+    if entry != 0 {
+        vm.start_at(entry)?;
+    }
+    run(&mut vm, params.max_steps)?;
+    Ok(ExecutionOutcome {
+        stores: vm.stores,
+        success: true,
+        gas_used: vm.gas_used,
+        return_value: vm.return_value,
+        logs: vm.logs,
+    })
+}
+
+/// Advances `vm` until it stops, failing if that takes more than `max_steps` opcodes.
+fn run<S: VmStorage + ?Sized>(vm: &mut Vm<S>, max_steps: usize) -> Result<(), VmError> {
+    let mut steps = 0;
     while !vm.should_stop() {
-        // println!("{:?}", vm);
+        if steps == max_steps {
+            return Err(VmError::StepLimitExceeded(max_steps));
+        }
+        vm.advance()?;
+        steps += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use primitive_types::U256;
+    use serial_test::serial;
+
+    use crate::storage::{RocksdbStorage, Storage};
+
+    use super::{
+        disassemble, execute, verify_bytecode, Listing, Opcode, Stack, Vm, VmError,
+        StackLimits, DEFAULT_GAS_LIMIT, DEFAULT_MAX_STEPS, RETURN_STACK_SIZE, STACK_SIZE,
+    };
+
+    fn run(opcodes: Vec<u8>) -> Vm {
+        run_on(RocksdbStorage::load(&Default::default()), opcodes)
+    }
+
+    fn run_on(storage: Arc<dyn Storage>, opcodes: Vec<u8>) -> Vm {
+        let mut vm = Vm::new(
+            [0; 32],
+            opcodes,
+            storage,
+            DEFAULT_GAS_LIMIT,
+            StackLimits::default(),
+        )
+        .unwrap();
+        while !vm.should_stop() {
+            vm.advance().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn stack_holds_exactly_stack_size_values() {
+        let mut stack = Stack::new(StackLimits::default());
+        for i in 0..STACK_SIZE {
+            stack.push(U256::from(i)).unwrap();
+        }
+        assert!(matches!(
+            stack.push(U256::one()),
+            Err(VmError::StackOverflow)
+        ));
+        assert_eq!(stack.pop().unwrap(), U256::from(STACK_SIZE - 1));
+    }
+
+    #[test]
+    fn return_stack_holds_exactly_return_stack_size_values() {
+        let mut stack = Stack::new(StackLimits::default());
+        for i in 0..RETURN_STACK_SIZE {
+            stack.push_to_return(U256::from(i)).unwrap();
+        }
+        assert!(matches!(
+            stack.push_to_return(U256::one()),
+            Err(VmError::StackOverflow)
+        ));
+        assert_eq!(
+            stack.return_stack[RETURN_STACK_SIZE - 1],
+            U256::from(RETURN_STACK_SIZE - 1)
+        );
+    }
+
+    fn push(value: U256) -> Vec<u8> {
+        let mut bytes = [0; 32];
+        value.to_little_endian(&mut bytes);
+        [&[Opcode::Push(32).to_u8()], bytes.as_ref()].concat()
+    }
+
+    fn binary_op(lhs: U256, rhs: U256, op: Opcode) -> U256 {
+        let vm = run([push(lhs), push(rhs), vec![op.to_u8()]].concat());
+        assert_eq!(vm.stack.stack_pos, 2);
+        vm.stack.stack[0]
+    }
+
+    #[test]
+    #[serial]
+    fn modulo() {
+        assert_eq!(
+            binary_op(U256::from(17), U256::from(5), Opcode::Mod),
+            U256::from(2)
+        );
+        assert_eq!(
+            binary_op(U256::from(17), U256::zero(), Opcode::Mod),
+            U256::zero()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn less_or_equal() {
+        assert_eq!(
+            binary_op(U256::one(), U256::from(2), Opcode::Leq),
+            U256::one()
+        );
+        assert_eq!(
+            binary_op(U256::from(2), U256::from(2), Opcode::Leq),
+            U256::one()
+        );
+        assert_eq!(
+            binary_op(U256::from(2), U256::one(), Opcode::Leq),
+            U256::zero()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn signed_comparisons() {
+        let minus_one = U256::MAX;
+        let minus_two = U256::MAX - 1;
+        assert_eq!(binary_op(minus_one, U256::one(), Opcode::Slt), U256::one());
+        assert_eq!(binary_op(U256::one(), minus_one, Opcode::Slt), U256::zero());
+        assert_eq!(binary_op(minus_two, minus_one, Opcode::Slt), U256::one());
+        assert_eq!(binary_op(minus_one, minus_one, Opcode::Slt), U256::zero());
+
+        assert_eq!(binary_op(U256::one(), minus_one, Opcode::Sgt), U256::one());
+        assert_eq!(binary_op(minus_two, minus_one, Opcode::Sgt), U256::zero());
+        // the unsigned comparison orders them the other way around.
+        assert_eq!(binary_op(minus_one, U256::one(), Opcode::Lt), U256::zero());
+    }
+
+    #[test]
+    #[serial]
+    fn arithmetic_wraps_around() {
+        assert_eq!(
+            binary_op(U256::zero(), U256::one(), Opcode::Sub),
+            U256::MAX
+        );
+        assert_eq!(
+            binary_op(U256::MAX, U256::one(), Opcode::Add),
+            U256::zero()
+        );
+        assert_eq!(
+            binary_op(U256::MAX, U256::from(2), Opcode::Mul),
+            U256::MAX - 1
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn exponentiation_wraps_around() {
+        assert_eq!(
+            binary_op(U256::from(3), U256::from(4), Opcode::Exp),
+            U256::from(81)
+        );
+        assert_eq!(
+            binary_op(U256::from(2), U256::from(256), Opcode::Exp),
+            U256::zero()
+        );
+        assert_eq!(
+            binary_op(U256::from(2), U256::from(257), Opcode::Exp),
+            U256::zero()
+        );
+        assert_eq!(
+            binary_op(U256::MAX, U256::from(3), Opcode::Exp),
+            U256::MAX
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn shifts() {
+        let value = U256::from(0b1011);
+        assert_eq!(binary_op(value, U256::zero(), Opcode::Shl), value);
+        assert_eq!(binary_op(value, U256::zero(), Opcode::Shr), value);
+
+        assert_eq!(
+            binary_op(U256::one(), U256::from(255), Opcode::Shl),
+            U256::one() << 255
+        );
+        assert_eq!(
+            binary_op(U256::MAX, U256::from(255), Opcode::Shr),
+            U256::one()
+        );
+
+        assert_eq!(binary_op(value, U256::from(300), Opcode::Shl), U256::zero());
+        assert_eq!(binary_op(value, U256::from(300), Opcode::Shr), U256::zero());
+    }
+
+    #[test]
+    #[serial]
+    fn underflow_fails_the_contract() {
+        let result = execute(
+            vec![Opcode::Add.to_u8()],
+            vec![],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        );
+        assert!(matches!(result, Err(VmError::StackUnderflow)));
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_jump_fails_the_contract() {
+        let result = execute(
+            [push(U256::from(100)), vec![Opcode::Jump.to_u8()]].concat(),
+            vec![],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        );
+        assert!(matches!(result, Err(VmError::InvalidJump(_, _))));
+    }
+
+    #[test]
+    #[serial]
+    fn successful_contracts_report_their_stores() {
+        let result = execute(
+            [
+                push(U256::from(7)),
+                push(U256::from(42)),
+                vec![Opcode::Store.to_u8(), 0],
+            ]
+            .concat(),
+            vec![],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert!(result.success);
+        assert_eq!(result.stores, vec![(0, U256::from(7), U256::from(42))]);
+    }
+
+    #[test]
+    #[serial]
+    fn stores_are_committed() {
+        let storage = RocksdbStorage::load(&Default::default());
+        execute(
+            [
+                push(U256::from(8)),
+                push(U256::from(43)),
+                vec![Opcode::Store.to_u8(), 0, Opcode::Terminate.to_u8()],
+            ]
+            .concat(),
+            vec![],
+            storage.clone(),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+
+        let vm = run_on(storage, [push(U256::from(8)), vec![Opcode::Get.to_u8(), 0]].concat());
+        assert_eq!(vm.stack.stack[0], U256::from(43));
+    }
+
+    #[test]
+    #[serial]
+    fn get_sees_stores_from_the_same_run() {
+        let vm = run([
+            push(U256::from(10)),
+            push(U256::from(45)),
+            vec![Opcode::Store.to_u8(), 0],
+            push(U256::from(10)),
+            push(U256::from(46)),
+            vec![Opcode::Store.to_u8(), 0],
+            push(U256::from(10)),
+            vec![Opcode::Get.to_u8(), 0],
+        ]
+        .concat());
+        assert_eq!(vm.stack.stack[0], U256::from(46));
+    }
+
+    #[test]
+    #[serial]
+    fn failed_contracts_do_not_commit() {
+        let storage = RocksdbStorage::load(&Default::default());
+        let result = execute(
+            [
+                push(U256::from(9)),
+                push(U256::from(44)),
+                vec![Opcode::Store.to_u8(), 0, Opcode::Add.to_u8()],
+            ]
+            .concat(),
+            vec![],
+            storage.clone(),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        );
+        assert!(result.is_err());
+
+        let vm = run_on(storage, [push(U256::from(9)), vec![Opcode::Get.to_u8(), 0]].concat());
+        assert_eq!(vm.stack.stack[0], U256::zero());
+    }
+
+    #[test]
+    #[serial]
+    fn gas_is_metered() {
+        let opcodes = [
+            push(U256::from(2)),
+            push(U256::from(3)),
+            vec![Opcode::Add.to_u8()],
+        ]
+        .concat();
+        let gas = 2 * Opcode::Push(32).gas_cost() + Opcode::Add.gas_cost();
+
+        let storage = RocksdbStorage::load(&Default::default());
+        let outcome = execute(
+            opcodes.clone(),
+            vec![],
+            storage.clone(),
+            gas,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome.gas_used, gas);
+        assert!(matches!(
+            execute(
+                opcodes,
+                vec![],
+                storage,
+                gas - 1,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            ),
+            Err(VmError::OutOfGas(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn long_running_contracts_run_out_of_gas() {
+        // jumps only go forward, so the closest thing to an endless loop is a long chain of them.
+        let opcodes = [push(U256::zero()), vec![Opcode::Jump.to_u8()]].concat().repeat(100_000);
+        assert!(matches!(
+            execute(
+                opcodes,
+                vec![],
+                RocksdbStorage::load(&Default::default()),
+                DEFAULT_GAS_LIMIT,
+                usize::MAX,
+                StackLimits::default(),
+            ),
+            Err(VmError::OutOfGas(DEFAULT_GAS_LIMIT))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn long_running_contracts_hit_the_step_limit() {
+        let opcodes = [Opcode::Push(1).to_u8(), 0, Opcode::Jump.to_u8()].repeat(50);
+        let storage = RocksdbStorage::load(&Default::default());
+
+        assert!(matches!(
+            execute(
+                opcodes.clone(),
+                vec![],
+                storage.clone(),
+                DEFAULT_GAS_LIMIT,
+                99,
+                StackLimits::default(),
+            ),
+            Err(VmError::StepLimitExceeded(99))
+        ));
+        assert!(execute(
+            opcodes,
+            vec![],
+            storage,
+            DEFAULT_GAS_LIMIT,
+            100,
+            StackLimits::default(),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn opcodes_round_trip() {
+        for byte in 0..=u8::MAX {
+            if let Some(opcode) = Opcode::from_u8(byte) {
+                assert_eq!(opcode.to_u8(), byte, "{:?}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn disassemble_decodes_operands() {
+        let program = vec![
+            (Opcode::Push(1), Some(U256::from(10))),
+            (Opcode::Push(32), Some(U256::from(0x1234))),
+            (Opcode::Get, Some(U256::from(2))),
+            (Opcode::Swap(3), None),
+            (Opcode::CopyToMain(0), None),
+            (Opcode::Store, Some(U256::zero())),
+            (Opcode::Terminate, None),
+        ];
+        let mut bytecode = vec![];
+        let mut expected = vec![];
+        for (opcode, operand) in program {
+            expected.push((bytecode.len(), opcode, operand));
+            bytecode.push(opcode.to_u8());
+            if let Some(operand) = operand {
+                let mut bytes = [0; 32];
+                operand.to_little_endian(&mut bytes);
+                bytecode.extend_from_slice(&bytes[..opcode.immediate_len()]);
+            }
+        }
+
+        let instructions = disassemble(&bytecode).unwrap();
+        assert_eq!(instructions, expected);
+        assert_eq!(
+            Listing(&instructions[..3]).to_string(),
+            "0000: PUSH 0x0a\n0002: PUSH 0x1234\n0023: GET 0x02\n"
+        );
+    }
+
+    #[test]
+    fn disassemble_rejects_truncated_bytecode() {
+        assert!(matches!(
+            disassemble(&[Opcode::Push(4).to_u8(), 1, 2]),
+            Err(VmError::ExpectedValue(2))
+        ));
+        assert!(matches!(
+            disassemble(&[Opcode::Add.to_u8(), 0xff]),
+            Err(VmError::InvalidOpcode(0xff, 1))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn terminate_returns_the_top_of_the_stack() {
+        let storage = RocksdbStorage::load(&Default::default());
+        let outcome = execute(
+            [push(U256::from(42)), vec![Opcode::Terminate.to_u8()]].concat(),
+            vec![],
+            storage.clone(),
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome.return_value, Some(U256::from(42)));
+
+        let outcome = execute(
+            vec![Opcode::Terminate.to_u8()],
+            vec![],
+            storage,
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            StackLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome.return_value, None);
+    }
+
+    #[test]
+    #[serial]
+    fn jumps_back() {
+        // counts down from 3, the loop condition is at 33.
+        let countdown = [
+            push(U256::from(3)),
+            vec![
+                Opcode::Dup.to_u8(),
+                Opcode::Push(1).to_u8(),
+                6,
+                Opcode::Jumpif.to_u8(),
+                Opcode::Push(1).to_u8(),
+                1,
+                Opcode::Sub.to_u8(),
+                Opcode::Push(1).to_u8(),
+                10,
+                Opcode::JumpBack.to_u8(),
+            ],
+        ]
+        .concat();
+        let vm = run(countdown);
+        assert_eq!(vm.stack.stack_pos, 2);
+        assert_eq!(vm.stack.stack[0], U256::zero());
+
+        let before_start = vec![Opcode::Push(1).to_u8(), 4, Opcode::JumpBack.to_u8()];
+        assert!(matches!(
+            verify_bytecode(&before_start, StackLimits::default()),
+            Err(VmError::InvalidBackwardJump(_, 3))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn jumps_must_land_on_instructions() {
+        // jumps over the push's opcode byte and into its operand.
+        let into_operand = [
+            vec![Opcode::Push(1).to_u8(), 1, Opcode::Jump.to_u8()],
+            push(U256::from(7)),
+        ]
+        .concat();
+        assert!(matches!(
+            verify_bytecode(&into_operand, StackLimits::default()),
+            Err(VmError::MisalignedJump(2, 4))
+        ));
+        assert!(matches!(
+            Vm::new(
+                [0; 32],
+                into_operand,
+                RocksdbStorage::load(&Default::default()),
+                100,
+                StackLimits::default(),
+            ),
+            Err(VmError::MisalignedJump(2, 4))
+        ));
+
+        let over_push = [
+            vec![Opcode::Push(1).to_u8(), 33, Opcode::Jump.to_u8()],
+            push(U256::from(7)),
+            vec![Opcode::Terminate.to_u8()],
+        ]
+        .concat();
+        assert!(verify_bytecode(&over_push, StackLimits::default()).is_ok());
+
+        let past_the_end = vec![Opcode::Push(1).to_u8(), 2, Opcode::Jump.to_u8()];
+        assert!(matches!(
+            verify_bytecode(&past_the_end, StackLimits::default()),
+            Err(VmError::InvalidJump(_, 3))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn stack_limits_are_configurable() {
+        let args: Vec<U256> = (0..40).map(U256::from).collect();
+        let storage = RocksdbStorage::load(&Default::default());
+        // binds all 40 arguments, then reads back the one bound first.
+        let opcodes = vec![Opcode::CopyToMain(0).to_u8(), Opcode::Terminate.to_u8()];
+
+        assert!(matches!(
+            execute(
+                opcodes.clone(),
+                args.clone(),
+                storage.clone(),
+                DEFAULT_GAS_LIMIT,
+                DEFAULT_MAX_STEPS,
+                StackLimits::default(),
+            ),
+            Err(VmError::StackOverflow)
+        ));
+
+        let limits = StackLimits {
+            stack: STACK_SIZE,
+            return_stack: 64,
+        };
+        let outcome = execute(
+            opcodes,
+            args,
+            storage,
+            DEFAULT_GAS_LIMIT,
+            DEFAULT_MAX_STEPS,
+            limits,
+        )
+        .unwrap();
+        assert_eq!(outcome.return_value, Some(U256::zero()));
+    }
+
+    #[test]
+    fn drop_from_return_only_drops_n() {
+        let mut stack = Stack::new(StackLimits::default());
+        stack
+            .push_multiple_to_return(vec![U256::one(), U256::from(2), U256::from(3)])
+            .unwrap();
+        stack.drop_from_return(2).unwrap();
+        assert_eq!(stack.return_stack_pos, 2);
+        assert_eq!(stack.return_stack[0], U256::one());
+        assert!(matches!(
+            stack.drop_from_return(2),
+            Err(VmError::StackUnderflow)
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn dup_copies_the_top() {
+        let vm = run(vec![Opcode::Push(1).to_u8(), 42, Opcode::Dup.to_u8()]);
+        assert_eq!(vm.stack.stack_pos, 3);
+        assert_eq!(vm.stack.stack[0], U256::from(42));
+        assert_eq!(vm.stack.stack[1], U256::from(42));
+    }
+
+    #[test]
+    #[serial]
+    fn copy_to_return_copies_the_top() {
+        let push = |value| [Opcode::Push(1).to_u8(), value];
+        let mut opcodes = [push(7), push(42)].concat();
+        opcodes.push(Opcode::CopyToReturn(1).to_u8());
+        let vm = run(opcodes.clone());
+        assert_eq!(vm.stack.stack_pos, 3);
+        assert_eq!(vm.stack.return_stack[0], U256::from(42));
+        drop(vm);
+
+        opcodes.push(Opcode::CopyToReturn(2).to_u8());
+        let vm = run(opcodes);
+        assert_eq!(
+            vm.stack.return_stack[..3],
+            [U256::from(42), U256::from(7), U256::from(42)]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn copy_to_return_stays_within_a_full_stack() {
+        let limits = StackLimits {
+            stack: 1,
+            ..Default::default()
+        };
+        let opcodes = |n| vec![Opcode::Push(1).to_u8(), 42, Opcode::CopyToReturn(n).to_u8()];
+        let new_vm = |opcodes| {
+            let storage = RocksdbStorage::load(&Default::default());
+            Vm::new([0; 32], opcodes, storage, DEFAULT_GAS_LIMIT, limits).unwrap()
+        };
+
+        let mut vm = new_vm(opcodes(1));
+        while !vm.should_stop() {
+            vm.advance().unwrap();
+        }
+        assert_eq!(vm.stack.return_stack[0], U256::from(42));
+        drop(vm);
+
+        let mut vm = new_vm(opcodes(2));
         vm.advance().unwrap();
+        assert!(matches!(vm.advance(), Err(VmError::StackUnderflow)));
+    }
+
+    #[test]
+    #[serial]
+    fn returning_below_the_frame_underflows() {
+        let mut vm = Vm::new(
+            [0; 32],
+            vec![Opcode::Ret.to_u8()],
+            RocksdbStorage::load(&Default::default()),
+            DEFAULT_GAS_LIMIT,
+            StackLimits::default(),
+        )
+        .unwrap();
+        // the callee dropped more than it bound, leaving the return stack below its frame.
+        vm.calls.push((0, 0));
+        vm.frame = 2;
+        assert!(matches!(vm.advance(), Err(VmError::StackUnderflow)));
+    }
+
+    #[test]
+    fn swap_reaches_below_the_top() {
+        let mut stack = Stack::new(StackLimits::default());
+        assert!(matches!(stack.swap(1), Err(VmError::StackUnderflow)));
+        stack
+            .push_multiple(vec![U256::one(), U256::from(2), U256::from(3)])
+            .unwrap();
+        stack.swap(2).unwrap();
+        assert_eq!(stack.stack[..3], [U256::from(3), U256::from(2), U256::one()]);
+        assert!(matches!(stack.swap(3), Err(VmError::StackUnderflow)));
+        assert!(matches!(
+            stack.swap(0),
+            Err(VmError::InvalidOperand(Opcode::Swap(0)))
+        ));
+        assert_eq!(stack.stack_pos, 4);
+    }
+
+    #[test]
+    fn swaps_have_to_fit_the_stack() {
+        let swap = vec![Opcode::Swap(4).to_u8()];
+        let limits = StackLimits {
+            stack: 4,
+            return_stack: RETURN_STACK_SIZE,
+        };
+        assert!(matches!(
+            verify_bytecode(&swap, limits),
+            Err(VmError::InvalidOperand(Opcode::Swap(4)))
+        ));
+        let limits = StackLimits { stack: 5, ..limits };
+        assert!(verify_bytecode(&swap, limits).is_ok());
     }
-    let end = st.elapsed();
-    println!("welp {:?}", end);
-    println!("{:?}", 1.0 / (end.as_secs_f64() * 3.0));
-    tracing::info!("{:?}", vm);
-}
\ No newline at end of file
+}
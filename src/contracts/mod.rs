@@ -1,14 +1,30 @@
 use {
-    self::native::execute_native,
-    crate::storage::Storage,
-    rhai::{serde::to_dynamic, Dynamic, Engine, Map, Scope, AST},
+    self::{
+        cache::CodeCache,
+        compiler::{compile, CompileError, CompiledContract},
+        language::{disassemble, Listing, VmError, VmStorage},
+        native::execute_native,
+    },
+    crate::{
+        chain::{ContractRecipt, TxStatus},
+        config::{ChainParams, ContractExecConfig, RhaiLimits},
+        storage::{Storage, StorageError, WriteOp},
+    },
+    ed25519_consensus::{Signature, SigningKey, VerificationKey},
+    primitive_types::U256,
+    serde_derive::{Deserialize, Serialize},
+    rhai::{
+        serde::{from_dynamic, to_dynamic},
+        Dynamic, Engine, Map, Scope, AST,
+    },
     serde_json::Value,
+    sha3::{Digest, Sha3_256},
     std::{
-        collections::{HashMap, HashSet},
+        collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
         sync::{
             atomic::{AtomicBool, Ordering},
             mpsc::{channel, Receiver},
-            Arc, Mutex,
+            Arc, Condvar, Mutex,
         },
         thread::{self, JoinHandle},
         time::Duration,
@@ -17,23 +33,115 @@ use {
 };
 
 pub(crate) mod language;
+mod cache;
 mod compiler;
 mod native;
 
 pub use language::execute;
-pub use compiler::parse;
 
-pub fn native_init(storage: Arc<dyn Storage>) {
-    native::teral_init(ContractStorage::new(storage));
+/// Gives every account in `allocations` its initial balance.
+pub fn native_init(
+    storage: Arc<dyn Storage>,
+    allocations: &BTreeMap<String, u64>,
+) -> Result<(), StorageError> {
+    native::teral_init(ContractStorage::open(&storage)?, allocations)
+}
+
+/// The bytecode the contract `source` compiles to, one opcode per line, to debug it with.
+pub fn listing(source: &str) -> Result<String, ContractsError> {
+    let compiled = compile(source)?;
+    Ok(Listing(&disassemble(&compiled.code)?).to_string())
+}
+
+/// Gives every validator in `stakes` its initial stake.
+pub fn native_init_stakes(
+    storage: Arc<dyn Storage>,
+    stakes: &[([u8; 32], u64)],
+) -> Result<(), StorageError> {
+    native::teral_init_stakes(ContractStorage::open(&storage)?, stakes)
+}
+
+/// The validators and their stakes, ordered by public key.
+pub fn stakes(storage: Arc<dyn Storage>) -> Result<Vec<([u8; 32], u64)>, StorageError> {
+    Ok(native::teral_stakes(&ContractStorage::open(&storage)?))
+}
+
+/// The balance of `account`, if it has one.
+pub fn balance(storage: Arc<dyn Storage>, account: &str) -> Result<Option<u64>, StorageError> {
+    Ok(native::teral_balance(&ContractStorage::open(&storage)?, account))
+}
+
+/// The nonce the next request of `author` has to carry.
+pub fn nonce(storage: Arc<dyn Storage>, author: [u8; 32]) -> Result<u64, StorageError> {
+    native::teral_nonce(&ContractStorage::open(&storage)?, author)
+}
+
+/// Runs `request` against what was committed so far, and returns what the method it names
+/// returned, for reading a contract without a transaction. Nothing it writes is kept, and the
+/// contracts it calls are not run. A bytecode contract returns the value it terminated with
+/// as a decimal string, or null. The native contract has nothing to query.
+pub fn query(
+    storage: Arc<dyn Storage>,
+    params: &ChainParams,
+    request: &ContractRequest,
+) -> Result<Value, ContractsError> {
+    if request.name == "native" {
+        return Err(ContractsError::NonExistingNative(request.method_name.clone()));
+    }
+    if !request.req.is_object() {
+        return Err(ContractsError::Query("the request is not a json object"));
+    }
+    let mut job = request.clone();
+    job.req["from"] = Value::String(base64::encode(job.author));
+
+    // the writes stay in the buffer of this storage, which is never committed.
+    let mut storage = ContractStorage::open(&storage)?;
+    let schema = storage.get_schema(&job.name)?;
+    validate_schema(&schema, &job.req, false)?;
+
+    match storage.get_code(&job.name)? {
+        ContractCode::Rhai(code) => {
+            let engine = sandboxed_engine(&params.rhai);
+            let ast = engine
+                .compile(code)
+                .map_err(|_| ContractsError::Query("the contract does not compile"))?;
+            let req_arg = to_dynamic(&job.req).map_err(|_| {
+                ContractsError::Query("the request is not valid input for the contract")
+            })?;
+            storage.set_curr_contract(&job.name);
+            let mut scope = Scope::new();
+            scope.push_constant("storage", storage);
+
+            let result: Dynamic = engine
+                .call_fn(&mut scope, &ast, &job.method_name, (req_arg,))
+                .map_err(|err| ContractsError::Query(rhai_failure(*err)))?;
+            from_dynamic(&result)
+                .map_err(|_| ContractsError::Query("the contract returned an invalid value"))
+        }
+        ContractCode::Bytecode(contract) => {
+            let contract_hash: [u8; 32] = Sha3_256::digest(job.name.as_bytes()).into();
+            let outcome = run_bytecode(contract_hash, contract, &job, Arc::new(storage), params)
+                .map_err(ContractsError::Query)?;
+            Ok(outcome
+                .return_value
+                .map_or(Value::Null, |value| Value::String(value.to_string())))
+        }
+    }
 }
 
+// the keyspace of the accounts, the contracts and their state, apart from the chain and the
+// node's own keys.
+const CONTRACTS_NAMESPACE: &str = "contracts";
 const CONTRACT_QUEUE_SIZE: usize = 1024;
+const CODE_CACHE_SIZE: usize = 128;
 const SYNC_RESPONDER_TIMEOUT: Duration = Duration::from_millis(100);
+// how deep contracts may call each other, which also stops a contract calling itself forever.
+const MAX_CALL_DEPTH: usize = 8;
 
 use rhai::EvalAltResult;
 use serde_json::to_string;
 
-use self::native::teral_transfer;
+use self::native::{teral_charge, teral_transfer, teral_use_nonce};
 
 #[derive(Debug, Error)]
 pub enum ContractsError {
@@ -41,77 +149,352 @@ pub enum ContractsError {
     Schema,
     #[error("a get operation failed")]
     Get,
-    #[error("Could not convert from utf8")]
-    FromUtf8Error(#[from] std::string::FromUtf8Error),
     #[error("Could not find native contract {0}")]
     NonExistingNative(String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("the query failed: {0}")]
+    Query(&'static str),
+    #[error("could not compile the contract: {0}")]
+    Compile(#[from] CompileError),
+    #[error("could not disassemble the contract: {0}")]
+    Disassemble(#[from] VmError),
+}
+
+/// An engine for rhai contracts, which only gives them their storage, and aborts them once they
+/// go over `limits`.
+fn sandboxed_engine(limits: &RhaiLimits) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_expr_depths(32, 32);
+    engine.set_max_operations(limits.max_operations);
+    engine.set_max_call_levels(limits.max_call_levels);
+    engine.set_max_array_size(limits.max_array_size);
+    engine.set_max_map_size(limits.max_array_size);
+    engine.set_max_string_size(limits.max_string_size);
+    engine.register_type::<ContractStorage>();
+    engine.register_fn("get", ContractStorage::regular_get_segment);
+    engine.register_fn("set", ContractStorage::regular_set_segment);
+    engine.register_result_fn("native_transfer", ContractStorage::native_transfer);
+    engine.register_fn("call_contract", ContractStorage::call_contract);
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+    engine
+}
+
+/// Runs the function `job` names, passing the fields of `job` of the same names as the function's
+/// parameters as arguments. The stores are left in the outcome.
+fn run_bytecode<S: VmStorage + ?Sized>(
+    contract_hash: [u8; 32],
+    contract: CompiledContract,
+    job: &ContractRequest,
+    storage: Arc<S>,
+    params: &ChainParams,
+) -> Result<language::ExecutionOutcome, &'static str> {
+    let (entry, parameters) = match contract.functions.get(&job.method_name) {
+        Some(function) => function,
+        None => return Err("the contract has no such function"),
+    };
+    let args = parameters
+        .iter()
+        .map(|parameter| job.req.get(parameter).and_then(to_argument))
+        .collect::<Option<Vec<_>>>()
+        .ok_or("the request is missing an argument")?;
+    language::execute_contract(contract_hash, contract.code, *entry, args, storage, params)
+        .map_err(|_| "the contract failed")
+}
+
+/// Why a rhai contract that failed with `err` was aborted.
+fn rhai_failure(err: EvalAltResult) -> &'static str {
+    match err {
+        EvalAltResult::ErrorTooManyOperations(_) => "the contract ran out of operations",
+        EvalAltResult::ErrorDataTooLarge(..) => "the contract's data grew too large",
+        EvalAltResult::ErrorStackOverflow(_) => "the contract's calls nest too deep",
+        _ => "the contract failed",
+    }
+}
+
+/// Turns a field of a request into an argument for a bytecode contract. Numbers are passed as
+/// they are, and so are strings that hold one, while other strings, like account names, are
+/// passed as their hash.
+fn to_argument(value: &Value) -> Option<U256> {
+    match value {
+        Value::Number(number) => number.as_u64().map(U256::from),
+        Value::Bool(boolean) => Some(U256::from(*boolean as u8)),
+        Value::String(string) => U256::from_dec_str(string)
+            .ok()
+            .or_else(|| Some(U256::from_big_endian(&Sha3_256::digest(string.as_bytes())))),
+        _ => None,
+    }
 }
 
-fn validate_schema(schema: &str, req: &Value) -> Result<(), ContractsError> {
-    // schema example: "from:str;to:str;amount:i64"
-    let values = schema.split(';');
-    for v in values {
+/// Checks that `req` holds every field of `schema` with the declared type. A strict check also
+/// rejects fields the schema does not mention.
+fn validate_schema(schema: &str, req: &Value, strict: bool) -> Result<(), ContractsError> {
+    // schema example: "from:str;to:str;amount:u64"
+    let mut fields = HashSet::new();
+    for v in schema.split(';') {
         let (name, typ) = v.split_once(':').ok_or(ContractsError::Schema)?;
         let value = req.get(name).ok_or(ContractsError::Schema)?;
 
         let is_ok = match typ {
             "i64" => value.is_i64(),
             "u64" => value.is_u64(),
+            // big numbers do not fit in json numbers, so they are passed as decimal strings.
+            "u256" => match value {
+                Value::String(s) => U256::from_dec_str(s).is_ok(),
+                v => v.is_u64(),
+            },
+            "bool" => value.is_boolean(),
             "str" => value.is_string(),
             _ => false,
         };
         if !is_ok {
             return Err(ContractsError::Schema);
         }
+        fields.insert(name);
+    }
+
+    if strict {
+        let object = req.as_object().ok_or(ContractsError::Schema)?;
+        if object.keys().any(|k| !fields.contains(k.as_str())) {
+            return Err(ContractsError::Schema);
+        }
     }
     Ok(())
 }
 
+const ACCOUNT_PREFIX: &[u8] = b"acct:";
+const CONTRACT_PREFIX: &[u8] = b"contract:";
+const SEGMENT_PREFIX: &[u8] = b"data:";
+
+/// The account the contract `name` pays out of. Accounts of users are named by their base64 public
+/// key, which never holds a `:`, so a contract can not be named into spending one of theirs.
+fn contract_account(name: &str) -> String {
+    format!("contract:{}", name)
+}
+
+/// Where the rhai contract `contract` keeps `key`. Contracts get their own prefix, so that they
+/// can not write over accounts or code, and are told apart by the hash of their name, which has a
+/// fixed length, so that no name and key run into another's.
+fn segment_key(contract: &str, key: &str) -> Vec<u8> {
+    [SEGMENT_PREFIX, &Sha3_256::digest(contract.as_bytes()), key.as_bytes()].concat()
+}
+
+/// The code of a contract, in whichever language it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum ContractCode {
+    /// A rhai script, compiled when it is first run.
+    Rhai(String),
+    /// The bytecode the compiler produced for a contract, when it was added.
+    Bytecode(CompiledContract),
+}
+
+/// What `add` stores for a contract.
+#[derive(Serialize, Deserialize)]
+struct ContractRecord {
+    code: ContractCode,
+    schema: String,
+    author: [u8; 32],
+}
+
+/// What a request read from and wrote to the storage while it ran.
+#[derive(Default)]
+struct Accesses {
+    // the keys read from the storage itself, rather than from the request's own writes.
+    reads: BTreeSet<Vec<u8>>,
+    // `None` marks a deleted key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // the writes of the requests that ran before in the same buffer, which are only stored along
+    // with the request's own.
+    settled: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Accesses {
+    /// What was written to `key` and not stored yet, if anything was.
+    fn buffered(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.writes.get(key).or_else(|| self.settled.get(key))
+    }
+}
+
+/// A call a contract made to another one. It runs once the caller finishes, as part of the same
+/// request.
+#[derive(Debug, Clone)]
+struct ContractCall {
+    name: String,
+    method_name: String,
+    req: Value,
+}
+
+/// The storage contracts run against. Writes are buffered until the request that made them
+/// finishes, and are then either committed together or discarded, so that a failing request
+/// leaves no trace. The keys a request reads are recorded alongside, so that the executer can
+/// tell whether it read something another request wrote after. Clones share the buffer.
 #[derive(Clone)]
 pub(crate) struct ContractStorage {
     storage: Arc<dyn Storage>,
+    accesses: Arc<Mutex<Accesses>>,
+    calls: Arc<Mutex<Vec<ContractCall>>>,
     curr_contract: String,
     contracts_to_execute: Vec<String>,
 }
 
 unsafe impl Send for ContractStorage {}
 
+/// Lets bytecode contracts read through the buffer, like everything else the request runs.
+impl VmStorage for ContractStorage {
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let mut accesses = self.accesses.lock().unwrap();
+        let unwritten: Vec<&[u8]> = keys
+            .iter()
+            .copied()
+            .filter(|key| accesses.buffered(key).is_none())
+            .collect();
+        let mut stored = self.storage.get_many(&unwritten)?.into_iter();
+        accesses.reads.extend(unwritten.iter().map(|key| key.to_vec()));
+        Ok(keys
+            .iter()
+            .map(|key| match accesses.buffered(key) {
+                Some(value) => value.clone(),
+                None => stored.next().flatten(),
+            })
+            .collect())
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        ContractStorage::set(self, key, value)
+    }
+}
+
 impl ContractStorage {
     fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
             storage,
+            accesses: Arc::new(Mutex::new(Accesses::default())),
+            calls: Arc::new(Mutex::new(vec![])),
             curr_contract: String::from(""),
             contracts_to_execute: vec![],
         }
     }
 
+    /// Opens the contracts keyspace of `storage`.
+    fn open(storage: &Arc<dyn Storage>) -> Result<Self, StorageError> {
+        Ok(Self::new(storage.namespace(CONTRACTS_NAMESPACE)?))
+    }
+
+    /// Reads `key`, seeing the writes that were not committed yet.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut accesses = self.accesses.lock().unwrap();
+        match accesses.buffered(key) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                accesses.reads.insert(key.to_vec());
+                self.storage.get(key)
+            }
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.accesses
+            .lock()
+            .unwrap()
+            .writes
+            .insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.accesses.lock().unwrap().writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Whether the request read any of `keys` from the storage.
+    fn has_read_any(&self, keys: &BTreeSet<Vec<u8>>) -> bool {
+        !self.accesses.lock().unwrap().reads.is_disjoint(keys)
+    }
+
+    /// The keys the request wrote so far.
+    fn written(&self) -> BTreeSet<Vec<u8>> {
+        self.accesses.lock().unwrap().writes.keys().cloned().collect()
+    }
+
+    /// Writes everything buffered so far to the storage, all at once, and starts over.
+    fn commit(&self) -> Result<(), StorageError> {
+        self.storage.batch(&self.take_ops())
+    }
+
+    /// Takes the buffered writes, settled or not, as ops to store them with.
+    fn take_ops(&self) -> Vec<WriteOp> {
+        let accesses = std::mem::take(&mut *self.accesses.lock().unwrap());
+        let mut writes = accesses.settled;
+        writes.extend(accesses.writes);
+        writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => WriteOp::Set { key, value },
+                None => WriteOp::Delete { key },
+            })
+            .collect()
+    }
+
+    /// Drops the buffered writes. What the request read is kept, as it still decided how the
+    /// request ended.
+    fn discard(&self) {
+        self.accesses.lock().unwrap().writes.clear();
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// Keeps what the request wrote for the next one to see, out of reach of `discard`, without
+    /// storing it yet.
+    fn settle(&self) {
+        let mut accesses = self.accesses.lock().unwrap();
+        let writes = std::mem::take(&mut accesses.writes);
+        accesses.settled.extend(writes);
+    }
+
+    /// Forgets everything the request read and wrote, to run it again from scratch.
+    fn reset(&self) {
+        *self.accesses.lock().unwrap() = Accesses::default();
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// The calls made by the contract that ran last, in the order it made them.
+    fn take_calls(&self) -> Vec<ContractCall> {
+        std::mem::take(&mut *self.calls.lock().unwrap())
+    }
+
     fn set_curr_contract(&mut self, name: &str) {
         self.contracts_to_execute = vec![];
         self.curr_contract = name.to_string();
     }
 
-    fn regular_set_segment(&mut self, key: &str, value: Map) {
-        self.storage.set(
-            &[self.curr_contract.as_bytes(), key.as_bytes()].concat(),
-            format!("{:?}", value).as_bytes(),
-        );
+    fn segment_key(&self, key: &str) -> Vec<u8> {
+        segment_key(&self.curr_contract, key)
+    }
+
+    fn regular_set_segment(&mut self, key: &str, value: Map) -> Result<(), Box<EvalAltResult>> {
+        self.set(&self.segment_key(key), format!("{:?}", value).as_bytes())
+            .map_err(|err| err.to_string().into())
     }
 
-    fn regular_get_segment(&mut self, key: &str) -> Dynamic {
+    fn regular_get_segment(&mut self, key: &str) -> Result<Dynamic, Box<EvalAltResult>> {
         let g = self
-            .storage
-            .get(&[self.curr_contract.as_bytes(), key.as_bytes()].concat());
-        match g {
+            .get(&self.segment_key(key))
+            .map_err(|err| err.to_string())?;
+        Ok(match g {
             Some(g) => to_dynamic::<Dynamic>(serde_json::from_slice(&g).unwrap_or_default())
                 .unwrap_or_default(),
             None => Dynamic::ZERO,
-        }
+        })
     }
 
     fn native_transfer(&mut self, to: &str, amount: u64) -> Result<(), Box<EvalAltResult>> {
         teral_transfer(
             &self,
-            &serde_json::json!({ "from": self.curr_contract, "to": to, "amount": amount }),
+            &serde_json::json!({
+                "from": contract_account(&self.curr_contract),
+                "to": to,
+                "amount": amount,
+            }),
         )
         .map_err(|_| EvalAltResult::ErrorFor(rhai::Position::new(1, 1)))?;
         // TODO: somehow execute the contract now instead of later.
@@ -123,115 +506,319 @@ impl ContractStorage {
         Ok(())
     }
 
-    fn native_get_segment(&self, key: &str) -> Option<Value> {
-        let g = self.storage.get(&[b"native", key.as_bytes()].concat())?;
-        serde_json::from_slice(&g).unwrap_or_default()
+    /// Calls `method` of the contract `name` with `req`, as the current contract: the callee
+    /// sees its account in the request's "from", and it pays with that account when calling the
+    /// native `transfer`.
+    fn call_contract(
+        &mut self,
+        name: &str,
+        method: &str,
+        req: Map,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let mut req: Value = from_dynamic(&Dynamic::from_map(req))?;
+        req["from"] = Value::String(contract_account(&self.curr_contract));
+        self.calls.lock().unwrap().push(ContractCall {
+            name: name.to_string(),
+            method_name: method.to_string(),
+            req,
+        });
+        Ok(())
+    }
+
+    fn native_get_segment(&self, key: &str) -> Result<Option<Value>, StorageError> {
+        let g = self.get(&[ACCOUNT_PREFIX, key.as_bytes()].concat())?;
+        Ok(g.and_then(|g| serde_json::from_slice(&g).unwrap_or_default()))
     }
 
-    fn native_set_segment(&self, key: &str, value: Value) {
-        self.storage.set(
-            &[b"native", key.as_bytes()].concat(),
+    fn native_set_segment(&self, key: &str, value: Value) -> Result<(), StorageError> {
+        self.set(
+            &[ACCOUNT_PREFIX, key.as_bytes()].concat(),
             to_string(&value).unwrap_or_default().as_bytes(),
-        );
+        )
     }
 
-    fn add_contract(&self, name: &str, code: &str, schema: &str, author: [u8; 32]) {
-        let entrypoint_key = [name.as_bytes(), b"entrypoint"].concat();
-        let schema_key = [name.as_bytes(), b"schema"].concat();
-        let author_key = [name.as_bytes(), b"author"].concat();
+    fn add_contract(
+        &self,
+        name: &str,
+        code: ContractCode,
+        schema: &str,
+        author: [u8; 32],
+    ) -> Result<(), StorageError> {
+        let record = ContractRecord {
+            code,
+            schema: schema.to_string(),
+            author,
+        };
+        self.set(
+            &[CONTRACT_PREFIX, name.as_bytes()].concat(),
+            &bincode::serialize(&record).unwrap(),
+        )
+    }
 
-        self.storage.set(&entrypoint_key, code.as_bytes());
-        self.storage.set(&schema_key, schema.as_bytes());
-        self.storage.set(&author_key, &author);
+    fn get_contract(&self, name: &str) -> Result<ContractRecord, ContractsError> {
+        let record = self
+            .get(&[CONTRACT_PREFIX, name.as_bytes()].concat())?
+            .ok_or(ContractsError::Get)?;
+        bincode::deserialize(&record).map_err(|_| ContractsError::Get)
     }
 
-    fn get_code(&self, name: &str) -> Result<String, ContractsError> {
-        let key = [name.as_bytes(), b"entrypoint"].concat();
-        Ok(String::from_utf8(
-            self.storage.get(&key).ok_or(ContractsError::Get)?,
-        )?)
+    fn get_code(&self, name: &str) -> Result<ContractCode, ContractsError> {
+        Ok(self.get_contract(name)?.code)
     }
 
     fn get_schema(&self, name: &str) -> Result<String, ContractsError> {
-        let key = [name.as_bytes(), b"schema"].concat();
-        Ok(String::from_utf8(
-            self.storage.get(&key).ok_or(ContractsError::Get)?,
-        )?)
+        Ok(self.get_contract(name)?.schema)
     }
 
-    fn get_author(&self, name: &str) -> Result<Vec<u8>, ContractsError> {
-        let key = [name.as_bytes(), b"author"].concat();
-        self.storage.get(&key).ok_or(ContractsError::Get)
+    fn get_author(&self, name: &str) -> Result<[u8; 32], ContractsError> {
+        Ok(self.get_contract(name)?.author)
     }
 }
 
-#[derive(Debug, Clone)]
+/// Serializes a json `Value` as a string, so that formats that are not self describing, like
+/// bincode, can carry it.
+mod json_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    /// Reads a request, which has to be a json object for the executer to tell the contract who
+    /// sent it.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        match serde_json::from_str(&String::deserialize(deserializer)?) {
+            Ok(Value::Object(req)) => Ok(Value::Object(req)),
+            Ok(_) => Err(D::Error::custom("the request is not a json object")),
+            Err(err) => Err(D::Error::custom(err)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContractRequest {
-    author: [u8; 32], // provided already verified
+    #[serde(default)]
+    author: [u8; 32],
     pub name: String,
     pub method_name: String,
+    #[serde(with = "json_string")]
     pub req: Value,
+    // how many requests the author made before this one, so that each of them runs once.
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    signature: Option<Signature>,
+    #[serde(skip)]
     id: usize,
 }
 
 impl ContractRequest {
-    pub fn new(author: [u8; 32], name: String, method_name: String, req: Value, id: usize) -> Self {
+    pub fn new(
+        author: [u8; 32],
+        name: String,
+        method_name: String,
+        req: Value,
+        nonce: u64,
+        id: usize,
+    ) -> Self {
         Self {
             author,
             name,
             method_name,
             req,
+            nonce,
+            signature: None,
             id,
         }
     }
+
+    /// A request whose author is proven by signing it with their key.
+    pub fn signed(
+        keypair: &SigningKey,
+        name: String,
+        method_name: String,
+        req: Value,
+        nonce: u64,
+        id: usize,
+    ) -> Self {
+        let author = keypair.verification_key().to_bytes();
+        let mut request = Self::new(author, name, method_name, req, nonce, id);
+        request.signature = Some(keypair.sign(&request.signed_data()));
+        request
+    }
+
+    pub fn author(&self) -> [u8; 32] {
+        self.author
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn signature(&self) -> Option<Signature> {
+        self.signature
+    }
+
+    /// The request carrying `signature`, as the recipt it was made into kept it.
+    pub fn with_signature(mut self, signature: Option<Signature>) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    /// The request as bincode, which is how it travels in a gossip push.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("a request always serializes")
+    }
+
+    /// Reads a request written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    /// The bytes the signature covers. `from` is left out, since the executer sets it to the
+    /// author anyway.
+    fn signed_data(&self) -> Vec<u8> {
+        let mut req = self.req.clone();
+        if let Some(req) = req.as_object_mut() {
+            req.remove("from");
+        }
+        bincode::serialize(&(&self.name, &self.method_name, req.to_string(), self.nonce)).unwrap()
+    }
+
+    /// Whether the request carries a valid signature of its author.
+    pub fn verify(&self) -> bool {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return false,
+        };
+        VerificationKey::try_from(self.author)
+            .and_then(|key| key.verify(signature, &self.signed_data()))
+            .is_ok()
+    }
 }
 
 #[derive(Debug)]
 struct ContractResponse {
     id: usize,
-    ok: bool,
+    // none if the request was not charged for.
+    status: Option<TxStatus>,
 }
 
-struct ContractQueue(Mutex<HashMap<String, Mutex<Vec<ContractRequest>>>>);
+/// The requests waiting to run, each with its place in the order they came in. They are taken
+/// in that order, which is also the order their writes are committed in.
+struct ContractQueue(Mutex<(u64, VecDeque<(u64, ContractRequest)>)>);
 
 impl ContractQueue {
     fn new() -> Self {
-        Self(Mutex::new(HashMap::new()))
-    }
-
-    fn get_and_maybe_delete(&self) -> Option<ContractRequest> {
-        let mut locked_queue = self.0.lock().unwrap();
-        // NOTE: this may be simplified with drain_filter: https://doc.rust-lang.org/beta/unstable-book/library-features/drain-filter.html
-        for (name, lock) in locked_queue.iter() {
-            let to_return = if let Ok(mut v) = lock.try_lock() {
-                let to_return = v.pop();
-                Some((to_return, v.is_empty()))
-            } else {
-                None
-            };
-            if let Some((to_return, remove)) = to_return {
-                if remove {
-                    let name = name.clone();
-                    locked_queue.remove(&name);
-                }
-                return to_return;
-            }
-        }
-        None
+        Self(Mutex::new((0, VecDeque::with_capacity(CONTRACT_QUEUE_SIZE))))
+    }
+
+    fn pop(&self) -> Option<(u64, ContractRequest)> {
+        self.0.lock().unwrap().1.pop_front()
     }
 
     fn add(&self, req: ContractRequest) {
-        let mut locked_queue = self.0.lock().unwrap();
-        if locked_queue.contains_key(&req.name) {
-            locked_queue
-                .get(&req.name)
-                .unwrap()
-                .lock()
+        let (next_seq, requests) = &mut *self.0.lock().unwrap();
+        requests.push_back((*next_seq, req));
+        *next_seq += 1;
+    }
+}
+
+/// Commits the requests run by the workers one at a time, in the order they were queued, so that
+/// every node ends up with the same state whatever the number of threads it runs.
+///
+/// Requests run in parallel, each against the storage as it was when it started and its own
+/// buffered writes. When its turn to commit comes, the request is checked against the requests
+/// that committed since it started: if it read a key one of them wrote, it ran on stale data and
+/// is run again, this time with nothing else able to commit in between. The keys written by the
+/// last `capacity` commits are kept for this, which covers every request that can be running at
+/// once; a request that started before the oldest of them is run again to be safe.
+struct CommitOrder {
+    state: Mutex<CommitState>,
+    turn: Condvar,
+    capacity: usize,
+}
+
+struct CommitState {
+    // the place of the request that commits next.
+    next: u64,
+    // the places of the latest commits with the keys they wrote, oldest first.
+    recent: VecDeque<(u64, BTreeSet<Vec<u8>>)>,
+}
+
+impl CommitOrder {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(CommitState {
+                next: 0,
+                recent: VecDeque::with_capacity(capacity),
+            }),
+            turn: Condvar::new(),
+            capacity,
+        }
+    }
+
+    /// The place of the request that commits next. A request that starts now sees the commits
+    /// of everything before it.
+    fn next(&self) -> u64 {
+        self.state.lock().unwrap().next
+    }
+
+    /// Waits until the request at `seq` is the next to commit. Returns false if `exit` was set
+    /// in the meantime.
+    fn wait_for(&self, seq: u64, exit: &AtomicBool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.next != seq {
+            if exit.load(Ordering::Relaxed) {
+                return false;
+            }
+            state = self
+                .turn
+                .wait_timeout(state, SYNC_RESPONDER_TIMEOUT)
                 .unwrap()
-                .push(req);
-        } else {
-            locked_queue.insert(req.name.clone(), Mutex::new(vec![req]));
+                .0;
+        }
+        true
+    }
+
+    /// Whether the request in `storage`, that started when `started` was next to commit, read
+    /// something that was committed since.
+    fn is_stale(&self, started: u64, storage: &ContractStorage) -> bool {
+        let state = self.state.lock().unwrap();
+        if started == state.next {
+            return false;
         }
+        match state.recent.front() {
+            Some((oldest, _)) if *oldest <= started => state
+                .recent
+                .iter()
+                .filter(|(seq, _)| *seq >= started)
+                .any(|(_, written)| storage.has_read_any(written)),
+            _ => true,
+        }
+    }
+
+    /// Records that the request at `seq` committed `written`, and hands the turn on.
+    fn committed(&self, seq: u64, written: BTreeSet<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        debug_assert_eq!(state.next, seq);
+        if state.recent.len() == self.capacity {
+            state.recent.pop_front();
+        }
+        state.recent.push_back((seq, written));
+        state.next += 1;
+        self.turn.notify_all();
+    }
+}
+
+/// What the requests of a replayed block wrote, held back until the block is in the chain.
+pub struct ReplayedBlock(ContractStorage);
+
+impl ReplayedBlock {
+    /// The writes, as ops on the namespace named along with them, to be stored with the block.
+    pub fn into_writes(self) -> (&'static str, Vec<WriteOp>) {
+        (CONTRACTS_NAMESPACE, self.0.take_ops())
     }
 }
 
@@ -239,41 +826,45 @@ pub struct ContractExecuter {
     handlers: Vec<JoinHandle<()>>,
     queue: Arc<ContractQueue>,
     responder: Receiver<ContractResponse>,
+    // blocks from other validators are replayed here rather than on the workers.
+    storage: Arc<dyn Storage>,
+    params: ChainParams,
 
     curr_id: usize,
-    valid: Vec<ContractRequest>,
+    // the id of the first request in `scheduled`.
+    first_id: usize,
+    scheduled: Vec<(ContractRequest, Option<TxStatus>)>,
 }
 
 impl ContractExecuter {
-    pub fn new(storage: Arc<dyn Storage>, exit: Arc<AtomicBool>, thread_number: usize) -> Self {
-        assert!(thread_number > 0);
-
-        let storage = ContractStorage::new(storage);
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        exit: Arc<AtomicBool>,
+        config: &ContractExecConfig,
+        params: &ChainParams,
+    ) -> Result<Self, StorageError> {
+        assert!(config.threads > 0);
+        let storage = storage.namespace(CONTRACTS_NAMESPACE)?;
 
         let queue = Arc::new(ContractQueue::new());
+        let order = Arc::new(CommitOrder::new(config.threads));
 
         let (sender, receiver) = channel();
-        let handlers = (0..thread_number)
+        let handlers = (0..config.threads)
             .map(|i| {
                 let queue = queue.clone();
-                let mut storage = storage.clone();
+                let order = order.clone();
+                // every worker buffers the writes of its own requests.
+                let mut storage = ContractStorage::new(storage.clone());
                 let exit = exit.clone();
                 let sender = sender.clone();
+                let params = params.clone();
                 thread::Builder::new()
                     .name(format!("contract-worker({})", i))
                     .spawn(move || {
-                        let mut cache = HashMap::new();
-
-                        let mut engine = Engine::new();
-                        engine.set_max_expr_depths(32, 32);
-                        engine.register_type::<ContractStorage>();
-                        engine.register_fn("get", ContractStorage::regular_get_segment);
-                        engine.register_fn("set", ContractStorage::regular_set_segment);
-                        engine.register_result_fn(
-                            "native_transfer",
-                            ContractStorage::native_transfer,
-                        );
-                        engine.on_print(|_| {});
+                        let mut cache = CodeCache::new(CODE_CACHE_SIZE);
+
+                        let engine = sandboxed_engine(&params.rhai);
 
                         let scope = &mut Scope::new();
                         loop {
@@ -281,18 +872,45 @@ impl ContractExecuter {
                                 break;
                             }
 
-                            if let Some(mut job) = queue.get_and_maybe_delete() {
+                            if let Some((seq, mut job)) = queue.pop() {
                                 job.req["from"] = Value::String(base64::encode(job.author));
 
-                                let ok = Self::executer_thread(
+                                let started = order.next();
+                                let mut status = Self::run_request(
                                     &mut storage,
                                     &mut cache,
                                     scope,
                                     &engine,
                                     job.clone(),
-                                )
-                                .is_ok();
-                                sender.send(ContractResponse { id: job.id, ok }).unwrap();
+                                    &params,
+                                );
+                                if !order.wait_for(seq, &exit) {
+                                    break;
+                                }
+                                if order.is_stale(started, &storage) {
+                                    storage.reset();
+                                    scope.clear();
+                                    status = Self::run_request(
+                                        &mut storage,
+                                        &mut cache,
+                                        scope,
+                                        &engine,
+                                        job.clone(),
+                                        &params,
+                                    );
+                                }
+
+                                let written = storage.written();
+                                let status = match storage.commit() {
+                                    Ok(()) => status,
+                                    Err(_) => status.map(|_| {
+                                        TxStatus::Reverted(String::from(
+                                            "the contract's writes could not be stored",
+                                        ))
+                                    }),
+                                };
+                                order.committed(seq, written);
+                                sender.send(ContractResponse { id: job.id, status }).unwrap();
                                 scope.clear();
                             }
                         }
@@ -301,73 +919,192 @@ impl ContractExecuter {
             })
             .collect();
         tracing::info!("contracts executer(s) running.");
-        Self {
+        Ok(Self {
             handlers,
             queue,
             responder: receiver,
+            storage,
+            params: params.clone(),
             curr_id: 0,
-            valid: vec![],
+            first_id: 0,
+            scheduled: vec![],
+        })
+    }
+
+    /// Checks the nonce of `job`, charges its sender the `base_fee` and runs it, leaving what it
+    /// wrote in `storage` to be committed. The nonce is used up and the fee kept even when the
+    /// contract fails, while everything else the request wrote is discarded. Returns none if the
+    /// nonce is wrong or the sender can not pay, as a request that costs nothing has no place in
+    /// a block.
+    fn run_request(
+        storage: &mut ContractStorage,
+        cache: &mut CodeCache<AST>,
+        scope: &mut Scope,
+        engine: &Engine,
+        job: ContractRequest,
+        params: &ChainParams,
+    ) -> Option<TxStatus> {
+        let (author, nonce, base_fee) = (job.author, job.nonce, params.base_fee);
+        let payer = job.req["from"].as_str().unwrap_or_default().to_string();
+        if teral_use_nonce(storage, author, nonce)
+            .and_then(|()| teral_charge(storage, &payer, base_fee))
+            .is_err()
+        {
+            storage.discard();
+            return None;
         }
+
+        Some(match Self::executer_thread(storage, cache, scope, engine, job, params, 0) {
+            Ok(()) => TxStatus::Success,
+            Err(reason) => {
+                storage.discard();
+                // nothing was written since the sender could pay, so they still can.
+                if teral_use_nonce(storage, author, nonce)
+                    .and_then(|()| teral_charge(storage, &payer, base_fee))
+                    .is_err()
+                {
+                    tracing::warn!("could not charge {} for a failed request", payer);
+                }
+                TxStatus::Reverted(String::from(reason))
+            }
+        })
     }
 
+    /// Runs `job`, and then the calls it made to other contracts, `depth` calls deep. The calls
+    /// are made with the same storage, so the request commits or fails as a whole.
     fn executer_thread(
         storage: &mut ContractStorage,
-        cache: &mut HashMap<String, AST>,
+        cache: &mut CodeCache<AST>,
         scope: &mut Scope,
         engine: &Engine,
         job: ContractRequest,
-    ) -> Result<(), ()> {
+        params: &ChainParams,
+        depth: usize,
+    ) -> Result<(), &'static str> {
+        let (author, nonce, id) = (job.author, job.nonce, job.id);
         match job.name.as_str() {
-            "native" => execute_native(&job, cache, engine, storage)?,
+            "native" => execute_native(&job, cache, engine, storage)
+                .map_err(|_| "the native contract rejected the request")?,
             _ => {
                 if let Ok(schema) = storage.get_schema(&job.name) {
-                    if validate_schema(&schema, &job.req).is_err() {
-                        return Err(());
+                    if validate_schema(&schema, &job.req, false).is_err() {
+                        return Err("the request does not match the contract's schema");
                     }
                 } else {
-                    return Err(());
+                    return Err("there is no such contract");
                 }
 
-                storage.set_curr_contract(&job.name);
-                scope.push_constant("storage", storage.clone());
-
-                let ast = if let Some(ast) = cache.get(&job.name) {
-                    ast.clone()
-                } else if let Ok(code) = storage.get_code(&job.name) {
-                    let ast = match engine.compile(code) {
-                        Ok(ast) => ast,
-                        Err(_) => return Err(()),
-                    };
-                    cache.insert(job.name, ast.clone());
-                    ast
-                } else {
-                    return Err(());
-                };
-
-                let req_arg = match to_dynamic(job.req) {
-                    Ok(args) => args,
-                    Err(_) => return Err(()),
-                };
-
-                if engine
-                    .call_fn_raw(
-                        scope,
-                        &ast,
-                        false,
-                        false,
-                        job.method_name,
-                        None,
-                        &mut [req_arg],
-                    )
-                    .is_err()
-                {
-                    return Err(());
+                match storage.get_code(&job.name) {
+                    Ok(ContractCode::Rhai(code)) => {
+                        Self::execute_rhai(storage, cache, scope, engine, job, &code)?
+                    }
+                    Ok(ContractCode::Bytecode(contract)) => {
+                        Self::execute_bytecode(storage, job, contract, params)?
+                    }
+                    Err(_) => return Err("the contract's code is missing"),
                 }
             }
         }
+
+        for call in storage.take_calls() {
+            if depth == MAX_CALL_DEPTH {
+                return Err("the contracts call each other too deep");
+            }
+            // a contract may pay out of its own account, but not act for whoever sent the request.
+            if call.name == "native" && call.method_name != "transfer" {
+                return Err("contracts may only call the native transfer");
+            }
+            let callee =
+                ContractRequest::new(author, call.name, call.method_name, call.req, nonce, id);
+            let scope = &mut Scope::new();
+            Self::executer_thread(storage, cache, scope, engine, callee, params, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn execute_rhai(
+        storage: &mut ContractStorage,
+        cache: &mut CodeCache<AST>,
+        scope: &mut Scope,
+        engine: &Engine,
+        job: ContractRequest,
+        code: &str,
+    ) -> Result<(), &'static str> {
+        storage.set_curr_contract(&job.name);
+        scope.push_constant("storage", storage.clone());
+
+        let ast = match cache.get_or_compile(code, |code| engine.compile(code)) {
+            Ok(ast) => ast,
+            Err(_) => return Err("the contract does not compile"),
+        };
+
+        let req_arg = match to_dynamic(job.req) {
+            Ok(args) => args,
+            Err(_) => return Err("the request is not valid input for the contract"),
+        };
+
+        let result = engine.call_fn_raw(
+            scope,
+            &ast,
+            false,
+            false,
+            job.method_name,
+            None,
+            &mut [req_arg],
+        );
+        result.map(|_| ()).map_err(|err| rhai_failure(*err))
+    }
+
+    /// Runs the function the request names, passing the request's fields of the same names as
+    /// the function's parameters as arguments.
+    fn execute_bytecode(
+        storage: &ContractStorage,
+        job: ContractRequest,
+        contract: CompiledContract,
+        params: &ChainParams,
+    ) -> Result<(), &'static str> {
+        let contract_hash: [u8; 32] = Sha3_256::digest(job.name.as_bytes()).into();
+        // reads go through the buffer, so the contract sees what the request wrote before it.
+        let reads = Arc::new(storage.clone());
+        let outcome = run_bytecode(contract_hash, contract, &job, reads, params)?;
+
+        for (map_index, key, value) in outcome.stores {
+            let mut value_bytes = [0; 32];
+            value.to_little_endian(&mut value_bytes);
+            let key = language::storage_key(&contract_hash, map_index as usize, key);
+            storage
+                .set(&key, &value_bytes)
+                .map_err(|_| "the contract's writes could not be stored")?;
+        }
         Ok(())
     }
 
+    /// Runs the requests of `recipts` again, one after another, against what was committed so
+    /// far, for checking a block another validator made. Returns what they wrote if every one of
+    /// them is signed by its author and ended the way its recipt says, and none otherwise.
+    /// Nothing is stored until the writes are committed.
+    pub fn replay(&self, recipts: &[ContractRecipt]) -> Option<ReplayedBlock> {
+        let mut storage = ContractStorage::new(self.storage.clone());
+        let mut cache = CodeCache::new(CODE_CACHE_SIZE);
+        let engine = sandboxed_engine(&self.params.rhai);
+        for recipt in recipts {
+            // the leader could name anyone as the author, were the signature not checked.
+            let mut job = recipt.request();
+            if !job.req.is_object() || !job.verify() {
+                return None;
+            }
+            job.req["from"] = Value::String(base64::encode(job.author));
+            let scope = &mut Scope::new();
+            let status =
+                Self::run_request(&mut storage, &mut cache, scope, &engine, job, &self.params);
+            if status.as_ref() != Some(recipt.status()) {
+                return None;
+            }
+            storage.settle();
+        }
+        Some(ReplayedBlock(storage))
+    }
+
     #[deprecated]
     pub fn execute_multiple(&self, requests: &[ContractRequest]) -> Vec<ContractRequest> {
         let mut out = Vec::with_capacity(requests.len());
@@ -386,7 +1123,7 @@ impl ContractExecuter {
                 println!("{:?}", recipt);
                 received_recipts += 1;
                 enqueued.remove(&requests[recipt.id].name);
-                if recipt.ok {
+                if recipt.status == Some(TxStatus::Success) {
                     out.push(requests[recipt.id].clone()); // so many clones...
                 }
                 if received_recipts == requests.len() {
@@ -399,19 +1136,48 @@ impl ContractExecuter {
     pub fn schedule(&mut self, mut request: ContractRequest) {
         request.id = self.curr_id;
         self.curr_id += 1;
-        self.valid.push(request.clone());
+        // a request that is not an object has nowhere to say who sent it, so it never runs.
+        if !request.req.is_object() {
+            self.scheduled.push((request, None));
+            return;
+        }
+        self.scheduled.push((request.clone(), Some(TxStatus::Success)));
         self.queue.add(request);
     }
 
-    pub fn summary(&mut self) -> &[ContractRequest] {
-        for _ in 0..self.curr_id {
-            if let Ok(response) = self.responder.recv_timeout(SYNC_RESPONDER_TIMEOUT) {
-                if !response.ok {
-                    self.valid.remove(response.id);
-                }
+    /// The recipts of the requests scheduled since the last summary, in the order they were
+    /// scheduled, which is the order they were committed in. The ones that failed are kept,
+    /// marked as reverted, while the ones that could not be charged for are left out. Waits for
+    /// every scheduled request to finish, as a recipt taken before would not say how the request
+    /// really ended.
+    pub fn summary(&mut self) -> Vec<ContractRecipt> {
+        let mut pending = self
+            .scheduled
+            .iter()
+            .filter(|(_, status)| status.is_some())
+            .count();
+        while pending > 0 {
+            // every worker is gone once the responder disconnects.
+            let response = match self.responder.recv() {
+                Ok(response) => response,
+                Err(_) => break,
+            };
+            if let Some((_, status)) = response
+                .id
+                .checked_sub(self.first_id)
+                .and_then(|index| self.scheduled.get_mut(index))
+            {
+                *status = response.status;
+                pending -= 1;
             }
         }
-        &self.valid
+        self.first_id = self.curr_id;
+        self.scheduled
+            .drain(..)
+            .filter_map(|(request, status)| {
+                Some(ContractRecipt::from(request).with_status(status?))
+            })
+            .collect()
     }
 
     pub fn join(self) {
@@ -425,9 +1191,26 @@ impl ContractExecuter {
 mod tests {
     use std::sync::{atomic::AtomicBool, Arc};
 
-    use crate::storage::{RocksdbStorage, Storage};
+    use crate::{
+        chain::TxStatus,
+        storage::{RocksdbStorage, Storage},
+    };
+    use ed25519_consensus::SigningKey;
     use serial_test::serial;
 
+    fn one_thread() -> crate::config::ContractExecConfig {
+        crate::config::ContractExecConfig { threads: 1 }
+    }
+
+    /// The default parameters, without the fee, for the senders of the tests' requests not to
+    /// need a balance.
+    fn free() -> crate::config::ChainParams {
+        crate::config::ChainParams {
+            base_fee: 0,
+            ..Default::default()
+        }
+    }
+
     #[test]
     #[serial]
     fn execute_sync() {
@@ -435,10 +1218,15 @@ mod tests {
 
         let config = Default::default();
         let storage: Arc<dyn Storage> = RocksdbStorage::load(&config);
-        let executer = super::ContractExecuter::new(storage.clone(), exit.clone(), 1);
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        // the storage is shared with other tests, so the nonces do not start at 0.
+        let nonce = |author| super::nonce(storage.clone(), author).unwrap();
+        let key = SigningKey::from([1; 32]);
         let recipts = executer.execute_multiple(&[
-            super::ContractRequest::new(
-                [0; 32],
+            super::ContractRequest::signed(
+                &key,
                 String::from("native"),
                 String::from("add"),
                 serde_json::json!({ "name": "test-sync", "code": r#"
@@ -458,6 +1246,7 @@ fn transfer(req) {
     }
 }
 "#, "schema": "from:str;to:str;amount:u64" }),
+                nonce(key.verification_key().to_bytes()),
                 0,
             ),
             super::ContractRequest::new(
@@ -465,12 +1254,13 @@ fn transfer(req) {
                 String::from("test-sync"),
                 String::from("transfer"),
                 serde_json::json!({"from": "hello", "to": "ginger", "amount": 100_u64}),
+                nonce([0; 32]),
                 1,
             ),
         ]);
         exit.store(true, std::sync::atomic::Ordering::SeqCst);
         executer.join();
-        storage.delete_prefix("test-test".as_bytes());
+        storage.delete_prefix("test-test".as_bytes()).unwrap();
 
         assert!(recipts.len() == 2);
     }
@@ -482,9 +1272,13 @@ fn transfer(req) {
 
         let config = Default::default();
         let storage: Arc<dyn Storage> = RocksdbStorage::load(&config);
-        let mut executer = super::ContractExecuter::new(storage.clone(), exit.clone(), 1);
-        executer.schedule(super::ContractRequest::new(
-            [0; 32],
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let nonce = |author| super::nonce(storage.clone(), author).unwrap();
+        let key = SigningKey::from([1; 32]);
+        executer.schedule(super::ContractRequest::signed(
+            &key,
             String::from("native"),
             String::from("add"),
             serde_json::json!({ "name": "test-async", "code": r#"
@@ -504,6 +1298,7 @@ fn transfer(req) {
     }
 }
 "#, "schema": "from:str;to:str;amount:u64" }),
+            nonce(key.verification_key().to_bytes()),
             0,
         ));
         executer.schedule(super::ContractRequest::new(
@@ -511,14 +1306,1178 @@ fn transfer(req) {
             String::from("test-async"),
             String::from("transfer"),
             serde_json::json!({"from": "hello", "to": "ginger", "amount": 100_u64}),
+            nonce([0; 32]),
             1,
         ));
         exit.store(true, std::sync::atomic::Ordering::SeqCst);
-        storage.delete_prefix("test-test".as_bytes());
+        storage.delete_prefix("test-test".as_bytes()).unwrap();
 
         println!("{:?}", executer.summary());
 
         // assert!(executer.summary().len() == 2);
         executer.join();
     }
+
+    #[test]
+    #[serial]
+    fn summary_holds_each_transfer_once() {
+        let path = "db-summary/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let author = [5; 32];
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(base64::encode(author), 100);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let transfer = |amount: u64, nonce| {
+            super::ContractRequest::new(
+                author,
+                String::from("native"),
+                String::from("transfer"),
+                serde_json::json!({ "to": "ginger", "amount": amount }),
+                nonce,
+                0,
+            )
+        };
+        executer.schedule(transfer(60, 0));
+        executer.schedule(transfer(60, 1));
+
+        let summary = executer.summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].status(), &TxStatus::Success);
+        assert!(matches!(summary[1].status(), TxStatus::Reverted(_)));
+        assert!(executer.summary().is_empty());
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn replayed_requests_are_rejected() {
+        let path = "db-replay/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let key = SigningKey::from([6; 32]);
+        let author = key.verification_key().to_bytes();
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(base64::encode(author), 100);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let transfer = |nonce| {
+            super::ContractRequest::signed(
+                &key,
+                String::from("native"),
+                String::from("transfer"),
+                serde_json::json!({ "to": "ginger", "amount": 10 }),
+                nonce,
+                0,
+            )
+        };
+        // sending the same transfer again takes a new nonce, which tells it apart.
+        assert_ne!(
+            crate::chain::ContractRecipt::from(transfer(0)).hash(),
+            crate::chain::ContractRecipt::from(transfer(1)).hash()
+        );
+
+        executer.schedule(transfer(0));
+        executer.schedule(transfer(0));
+        executer.schedule(transfer(1));
+        executer.schedule(transfer(3));
+        // the replay and the transfer that skips a nonce are not charged for, and stay out.
+        let summary = executer.summary();
+        assert_eq!(summary.len(), 2);
+        for (recipt, nonce) in summary.iter().zip([0, 1]) {
+            assert_eq!(recipt.status(), &TxStatus::Success);
+            assert_eq!(
+                recipt.hash(),
+                crate::chain::ContractRecipt::from(transfer(nonce)).hash()
+            );
+        }
+        assert_eq!(super::nonce(storage.clone(), author).unwrap(), 2);
+        assert_eq!(super::balance(storage.clone(), "ginger").unwrap(), Some(20));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn staking_moves_balance_into_stake() {
+        let path = "db-stake/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(String::from("ghostway"), 100);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let contract_storage = super::ContractStorage::open(&storage).unwrap();
+        let stake = |amount: u64| {
+            super::native::teral_stake(
+                &contract_storage,
+                [3; 32],
+                &serde_json::json!({ "from": "ghostway", "amount": amount }),
+            )?;
+            contract_storage.commit().map_err(|_| ())
+        };
+        let unstake = |amount: u64| {
+            super::native::teral_unstake(
+                &contract_storage,
+                [3; 32],
+                &serde_json::json!({ "from": "ghostway", "amount": amount }),
+            )?;
+            contract_storage.commit().map_err(|_| ())
+        };
+        stake(40).unwrap();
+        stake(20).unwrap();
+        assert!(stake(41).is_err());
+
+        assert_eq!(super::stakes(storage.clone()).unwrap(), vec![([3; 32], 60)]);
+        assert_eq!(
+            contract_storage.native_get_segment("ghostway").unwrap(),
+            Some(serde_json::json!({ "balance": 40, "staked": 60 }))
+        );
+
+        assert!(unstake(61).is_err());
+        unstake(50).unwrap();
+        assert_eq!(super::stakes(storage.clone()).unwrap(), vec![([3; 32], 10)]);
+        unstake(10).unwrap();
+        assert!(super::stakes(storage.clone()).unwrap().is_empty());
+        assert_eq!(
+            contract_storage.native_get_segment("ghostway").unwrap(),
+            Some(serde_json::json!({ "balance": 100, "staked": 0 }))
+        );
+
+        drop((contract_storage, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn schema_types_are_checked() {
+        use super::validate_schema;
+        use serde_json::json;
+
+        let schema = "to:str;amount:u64;supply:u256;burn:bool";
+        let valid = json!({
+            "to": "ginger",
+            "amount": 100,
+            "supply": "115792089237316195423570985008687907853269984665640564039457584007913129639935",
+            "burn": false,
+        });
+        assert!(validate_schema(schema, &valid, true).is_ok());
+
+        let mut wrong_type = valid.clone();
+        wrong_type["amount"] = json!("100");
+        assert!(validate_schema(schema, &wrong_type, false).is_err());
+
+        let mut negative = valid.clone();
+        negative["amount"] = json!(-1);
+        assert!(validate_schema(schema, &negative, false).is_err());
+
+        let mut overflow = valid.clone();
+        overflow["supply"] = json!(
+            "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+        );
+        assert!(validate_schema(schema, &overflow, false).is_err());
+
+        let mut missing = valid.clone();
+        missing.as_object_mut().unwrap().remove("burn");
+        assert!(validate_schema(schema, &missing, false).is_err());
+
+        let mut extra = valid;
+        extra["memo"] = json!("hi");
+        assert!(validate_schema(schema, &extra, false).is_ok());
+        assert!(validate_schema(schema, &extra, true).is_err());
+
+        assert!(validate_schema("amount:f64", &json!({ "amount": 1.5 }), false).is_err());
+    }
+
+    #[test]
+    fn requests_are_signed_by_their_author() {
+        use super::ContractRequest;
+
+        let keypair = SigningKey::from([1; 32]);
+        let add = |req| {
+            let (name, method) = (String::from("native"), String::from("add"));
+            ContractRequest::signed(&keypair, name, method, req, 0, 0)
+        };
+        let signed = add(serde_json::json!({ "name": "token" }));
+        assert!(signed.verify());
+        let received: ContractRequest =
+            bincode::deserialize(&bincode::serialize(&signed).unwrap()).unwrap();
+        assert!(received.verify());
+        assert_eq!(received.author(), keypair.verification_key().to_bytes());
+
+        let mut tampered = signed.clone();
+        tampered.req = serde_json::json!({ "name": "other" });
+        assert!(!tampered.verify());
+
+        let victim = SigningKey::from([2; 32]).verification_key().to_bytes();
+        let mut forged = add(serde_json::json!({ "name": "token" }));
+        forged.author = victim;
+        assert!(!forged.verify());
+
+        let unsigned = ContractRequest::new(
+            victim,
+            String::from("native"),
+            String::from("add"),
+            serde_json::json!({ "name": "token" }),
+            0,
+            0,
+        );
+        assert!(!unsigned.verify());
+    }
+
+    #[test]
+    #[serial]
+    fn transfers_reject_bad_amounts() {
+        let path = "db-transfer/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(String::from("ghostway"), 100);
+        allocations.insert(String::from("ginger"), u64::MAX);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let contract_storage = super::ContractStorage::open(&storage).unwrap();
+        let transfer = |from: &str, to: &str, amount: u64| {
+            super::native::teral_transfer(
+                &contract_storage,
+                &serde_json::json!({ "from": from, "to": to, "amount": amount }),
+            )
+        };
+        let balance = |account: &str| {
+            contract_storage.native_get_segment(account).unwrap().unwrap()["balance"]
+                .as_u64()
+                .unwrap()
+        };
+
+        assert!(transfer("ghostway", "ginger", 1).is_err());
+        assert!(transfer("ghostway", "lemon", 0).is_err());
+        assert!(transfer("ghostway", "ghostway", 10).is_err());
+        assert!(transfer("ghostway", "lemon", 101).is_err());
+        assert_eq!(balance("ghostway"), 100);
+        assert_eq!(balance("ginger"), u64::MAX);
+        assert!(contract_storage.native_get_segment("lemon").unwrap().is_none());
+
+        transfer("ghostway", "lemon", 100).unwrap();
+        assert_eq!((balance("ghostway"), balance("lemon")), (0, 100));
+
+        drop((contract_storage, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn contracts_and_accounts_round_trip() {
+        let path = "db-contract-storage/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let contract_storage = super::ContractStorage::open(&storage).unwrap();
+
+        assert!(contract_storage.get_author("token").is_err());
+        let code = super::ContractCode::Rhai(String::from("fn transfer(req) {}"));
+        contract_storage
+            .add_contract("token", code.clone(), "to:str", [7; 32])
+            .unwrap();
+        assert_eq!(contract_storage.get_author("token").unwrap(), [7; 32]);
+        assert_eq!(contract_storage.get_code("token").unwrap(), code);
+        assert_eq!(contract_storage.get_schema("token").unwrap(), "to:str");
+
+        assert_eq!(contract_storage.native_get_segment("ginger").unwrap(), None);
+        let account = serde_json::json!({ "balance": 5, "staked": 1 });
+        contract_storage
+            .native_set_segment("ginger", account.clone())
+            .unwrap();
+        assert_eq!(
+            contract_storage.native_get_segment("ginger").unwrap(),
+            Some(account)
+        );
+        // contracts keep to their own keyspace.
+        contract_storage.commit().unwrap();
+        assert_eq!(storage.get(b"acct:ginger").unwrap(), None);
+        assert!(super::balance(storage.clone(), "ginger").unwrap().is_some());
+
+        drop((contract_storage, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn contracts_only_spend_their_own_account() {
+        let path = "db-contract-account/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let victim = base64::encode(SigningKey::from([2; 32]).verification_key().to_bytes());
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(victim.clone(), 100);
+        allocations.insert(String::from("contract:vault"), 50);
+        super::native_init(storage.clone(), &allocations).unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let code = r#"
+fn pay(req) {
+    storage.call_contract("native", "transfer", #{ "to": "thief", "amount": 40 });
+}
+"#;
+        let request = |name: &str, method: &str, req, nonce, id| {
+            super::ContractRequest::signed(
+                &SigningKey::from([5; 32]),
+                String::from(name),
+                String::from(method),
+                req,
+                nonce,
+                id,
+            )
+        };
+        let add = |name: &str, nonce, id| {
+            let req = serde_json::json!({ "name": name, "code": code, "schema": "from:str" });
+            request("native", "add", req, nonce, id)
+        };
+        let pay = |name: &str, nonce, id| request(name, "pay", serde_json::json!({}), nonce, id);
+
+        // a contract named after a user pays out of its own, empty, account.
+        let added = executer.execute_multiple(&[add(&victim, 0, 0), add("vault", 1, 1)]);
+        assert_eq!(added.len(), 2);
+        let paid = executer.execute_multiple(&[pay(&victim, 2, 0), pay("vault", 3, 1)]);
+        assert_eq!(paid.len(), 1);
+        assert_eq!(paid[0].name, "vault");
+
+        let balance = |account: &str| super::balance(storage.clone(), account).unwrap();
+        assert_eq!(balance(&victim), Some(100));
+        assert_eq!(balance("contract:vault"), Some(10));
+        assert_eq!(balance("thief"), Some(40));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn contracts_do_not_share_segments() {
+        assert_ne!(super::segment_key("ab", "c"), super::segment_key("a", "bc"));
+        assert_ne!(super::segment_key("a", "bc"), super::segment_key("a", "b"));
+    }
+
+    #[test]
+    #[serial]
+    fn failed_requests_leave_no_writes() {
+        let path = "db-rollback/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+        let request = |method: &str, id| {
+            super::ContractRequest::new(
+                [0; 32],
+                String::from("rollback"),
+                String::from(method),
+                serde_json::json!({}),
+                id as u64,
+                id,
+            )
+        };
+
+        let added = executer.execute_multiple(&[super::ContractRequest::signed(
+            &SigningKey::from([1; 32]),
+            String::from("native"),
+            String::from("add"),
+            serde_json::json!({ "name": "rollback", "code": r#"
+fn seed(req) {
+    storage.set("alice", #{ "balance": 100 });
+    storage.set("bob", #{ "balance": 0 });
+}
+
+fn transfer(req) {
+    storage.set("alice", #{ "balance": 90 });
+    throw;
+    storage.set("bob", #{ "balance": 10 });
+}
+"#, "schema": "from:str" }),
+            0,
+            0,
+        )]);
+        assert_eq!(added.len(), 1);
+        let succeeded = executer.execute_multiple(&[request("seed", 0), request("transfer", 1)]);
+        assert_eq!(succeeded.len(), 1);
+        assert_eq!(succeeded[0].method_name, "seed");
+
+        let balance = |account: &str| {
+            let raw = contracts.get(&super::segment_key("rollback", account));
+            String::from_utf8(raw.unwrap().unwrap()).unwrap()
+        };
+        assert!(balance("alice").contains("100"));
+        assert!(!balance("bob").contains("10"));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn contract_calls_commit_with_their_caller() {
+        let path = "db-calls/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+        let add = |name: &str, code: &str, nonce: u64| {
+            super::ContractRequest::signed(
+                &SigningKey::from([1; 32]),
+                String::from("native"),
+                String::from("add"),
+                serde_json::json!({ "name": name, "code": code, "schema": "from:str" }),
+                nonce,
+                nonce as usize,
+            )
+        };
+        let request = |method: &str, id| {
+            super::ContractRequest::new(
+                [0; 32],
+                String::from("shop"),
+                String::from(method),
+                serde_json::json!({}),
+                id as u64,
+                id,
+            )
+        };
+
+        let added = executer.execute_multiple(&[
+            add(
+                "token",
+                r#"
+fn transfer(req) {
+    storage.set("bob", #{ "amount": 10, "payer": req["from"] });
+}
+
+fn fail(req) {
+    throw;
+}
+"#,
+                0,
+            ),
+            add(
+                "shop",
+                r#"
+fn buy(req) {
+    storage.set("sold", #{ "amount": 1 });
+    storage.call_contract("token", "transfer", #{ "to": "bob" });
+}
+
+fn buy_broken(req) {
+    storage.set("broken", #{ "amount": 1 });
+    storage.call_contract("token", "fail", #{});
+}
+
+fn buy_forever(req) {
+    storage.set("forever", #{ "amount": 1 });
+    storage.call_contract("shop", "buy_forever", #{});
+}
+"#,
+                1,
+            ),
+        ]);
+        assert_eq!(added.len(), 2);
+        let succeeded = executer.execute_multiple(&[
+            request("buy", 0),
+            request("buy_broken", 1),
+            request("buy_forever", 2),
+        ]);
+        assert_eq!(succeeded.len(), 1);
+        assert_eq!(succeeded[0].method_name, "buy");
+
+        let segment = |contract: &str, key: &str| {
+            contracts
+                .get(&super::segment_key(contract, key))
+                .unwrap()
+                .map(|raw| String::from_utf8(raw).unwrap())
+        };
+        // the callee sees the contract that called it as the sender.
+        assert!(segment("token", "bob").unwrap().contains("shop"));
+        assert!(segment("shop", "sold").is_some());
+        // when the callee fails, or the calls go too deep, the caller's writes go too.
+        assert!(segment("shop", "broken").is_none());
+        assert!(segment("shop", "forever").is_none());
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn added_contracts_are_initialized() {
+        let path = "db-init/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+        let key = SigningKey::from([1; 32]);
+        let add = |name: &str, code: &str, nonce: u64| {
+            super::ContractRequest::signed(
+                &key,
+                String::from("native"),
+                String::from("add"),
+                serde_json::json!({ "name": name, "code": code, "schema": "from:str" }),
+                nonce,
+                nonce as usize,
+            )
+        };
+
+        let added = executer.execute_multiple(&[
+            add(
+                "coin",
+                r#"
+fn init(req) {
+    storage.set(req["owner"], #{ "balance": 1000 });
+}
+"#,
+                0,
+            ),
+            add(
+                "broken",
+                r#"
+fn init(req) {
+    storage.set(req["owner"], #{ "balance": 1000 });
+    throw;
+}
+"#,
+                1,
+            ),
+        ]);
+        assert_eq!(added.len(), 1);
+
+        let owner = base64::encode(key.verification_key().to_bytes());
+        let segment = |contract: &str| {
+            contracts
+                .get(&super::segment_key(contract, &owner))
+                .unwrap()
+                .map(|raw| String::from_utf8(raw).unwrap())
+        };
+        assert!(segment("coin").unwrap().contains("1000"));
+        // a failing `init` takes the contract down with it.
+        assert!(segment("broken").is_none());
+        assert!(contracts.get(b"contract:broken").unwrap().is_none());
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn queries_read_without_writing() {
+        let path = "db-query/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+        let request = |method: &str, req: serde_json::Value| {
+            let (name, method) = (String::from("bank"), String::from(method));
+            super::ContractRequest::new([0; 32], name, method, req, 0, 0)
+        };
+
+        let added = executer.execute_multiple(&[super::ContractRequest::signed(
+            &SigningKey::from([1; 32]),
+            String::from("native"),
+            String::from("add"),
+            serde_json::json!({ "name": "bank", "code": r#"
+fn deposit(req) {
+    storage.set("alice", #{ "balance": req["amount"] });
+}
+
+fn balance_of(req) {
+    let account = storage.get(req["who"]);
+    account["balance"]
+}
+"#, "schema": "from:str" }),
+            0,
+            0,
+        )]);
+        assert_eq!(added.len(), 1);
+        let deposited =
+            executer.execute_multiple(&[request("deposit", serde_json::json!({ "amount": 50 }))]);
+        assert_eq!(deposited.len(), 1);
+
+        let query = |request| super::query(storage.clone(), &free(), &request);
+        let balance_of = || request("balance_of", serde_json::json!({ "who": "alice" }));
+        assert_eq!(query(balance_of()).unwrap(), serde_json::json!(50));
+
+        let stored = contracts.get(&super::segment_key("bank", "alice")).unwrap();
+        assert!(query(request("deposit", serde_json::json!({ "amount": 99 }))).is_ok());
+        assert_eq!(contracts.get(&super::segment_key("bank", "alice")).unwrap(), stored);
+        assert_eq!(query(balance_of()).unwrap(), serde_json::json!(50));
+
+        assert!(query(request("missing", serde_json::json!({}))).is_err());
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn requests_round_trip_through_bytes() {
+        let request = super::ContractRequest::signed(
+            &SigningKey::from([2; 32]),
+            String::from("native"),
+            String::from("transfer"),
+            serde_json::json!({ "to": "ginger", "amount": 10 }),
+            3,
+            0,
+        );
+        let decoded = super::ContractRequest::from_bytes(&request.to_bytes()).unwrap();
+        assert_eq!(decoded.author(), request.author());
+        assert_eq!(decoded.name, request.name);
+        assert_eq!(decoded.method_name, request.method_name);
+        assert_eq!(decoded.req, request.req);
+        assert_eq!(decoded.nonce(), 3);
+        assert!(decoded.verify());
+
+        assert!(super::ContractRequest::from_bytes(b"not a request").is_err());
+
+        let mut listed = request.clone();
+        listed.req = serde_json::json!(["ginger", 10]);
+        assert!(super::ContractRequest::from_bytes(&listed.to_bytes()).is_err());
+        let submitted = serde_json::to_value(&listed).unwrap();
+        assert!(serde_json::from_value::<super::ContractRequest>(submitted).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn requests_that_are_not_objects_never_run() {
+        let path = "db-not-objects/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(base64::encode([5; 32]), 15);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let config = crate::config::ContractExecConfig { threads: 2 };
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &config, &free()).unwrap();
+        let transfer = |req, nonce| {
+            let (name, method) = (String::from("native"), String::from("transfer"));
+            super::ContractRequest::new([5; 32], name, method, req, nonce, 0)
+        };
+        executer.schedule(transfer(serde_json::json!(["ginger", 1]), 0));
+        executer.schedule(transfer(serde_json::json!({ "to": "ginger", "amount": 1 }), 0));
+        executer.schedule(transfer(serde_json::json!(7), 1));
+
+        let summary = executer.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].status(), &TxStatus::Success);
+        assert_eq!(super::balance(storage.clone(), "ginger").unwrap(), Some(1));
+
+        let mut query = transfer(serde_json::json!("ginger"), 0);
+        query.name = String::from("bank");
+        assert!(matches!(
+            super::query(storage.clone(), &free(), &query),
+            Err(super::ContractsError::Query(_))
+        ));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn requests_that_can_not_pay_stay_out_of_the_summary() {
+        let path = "db-fee-summary/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let (payer, broke) = ([5; 32], [6; 32]);
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(base64::encode(payer), 15);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let params = crate::config::ChainParams {
+            base_fee: 10,
+            ..Default::default()
+        };
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &params)
+                .unwrap();
+        let transfer = |author, nonce| {
+            super::ContractRequest::new(
+                author,
+                String::from("native"),
+                String::from("transfer"),
+                serde_json::json!({ "to": "ginger", "amount": 1 }),
+                nonce,
+                0,
+            )
+        };
+        executer.schedule(transfer(payer, 0));
+        // the first fee leaves 4, which pays for neither of these.
+        executer.schedule(transfer(payer, 1));
+        executer.schedule(transfer(broke, 0));
+
+        let summary = executer.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].status(), &TxStatus::Success);
+        assert_eq!(
+            summary[0].hash(),
+            crate::chain::ContractRecipt::from(transfer(payer, 0)).hash()
+        );
+        assert_eq!(super::nonce(storage.clone(), payer).unwrap(), 1);
+        assert_eq!(super::nonce(storage.clone(), broke).unwrap(), 0);
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn senders_pay_the_base_fee() {
+        let path = "db-fee/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let author = [5; 32];
+        let mut allocations = std::collections::BTreeMap::new();
+        allocations.insert(base64::encode(author), 25);
+        super::native_init(storage.clone(), &allocations).unwrap();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let params = crate::config::ChainParams {
+            base_fee: 10,
+            ..Default::default()
+        };
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &params)
+                .unwrap();
+        let transfer = |amount: u64, id| {
+            super::ContractRequest::new(
+                author,
+                String::from("native"),
+                String::from("transfer"),
+                serde_json::json!({ "to": "ginger", "amount": amount }),
+                id as u64,
+                id,
+            )
+        };
+        // the second transfer fails but still pays, which leaves nothing for the third's fee.
+        let succeeded =
+            executer.execute_multiple(&[transfer(5, 0), transfer(100, 1), transfer(1, 2)]);
+        assert_eq!(succeeded.len(), 1);
+        assert_eq!(succeeded[0].req["amount"], 5);
+
+        let contract_storage = super::ContractStorage::open(&storage).unwrap();
+        let balance = |account: &str| {
+            contract_storage.native_get_segment(account).unwrap().unwrap()["balance"]
+                .as_u64()
+                .unwrap()
+        };
+        assert_eq!(balance(&base64::encode(author)), 0);
+        assert_eq!(balance("ginger"), 5);
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contract_storage, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn bytecode_contracts_run_in_the_executer() {
+        use super::language::storage_key;
+        use primitive_types::U256;
+        use sha3::{Digest, Sha3_256};
+
+        let path = "db-bytecode/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+
+        let added = executer.execute_multiple(&[super::ContractRequest::signed(
+            &SigningKey::from([1; 32]),
+            String::from("native"),
+            String::from("add_bytecode"),
+            serde_json::json!({ "name": "token", "code": r#"
+mapping Balances
+fn mint to amount in
+    Balances to amount store
+end
+fn transfer from to amount in
+    Balances from get
+    let from_balance in
+        from_balance amount >= require
+        Balances from from_balance amount - store
+        Balances to
+        Balances to get amount +
+        store
+    end
+end
+"#, "schema": "from:str" }),
+            0,
+            0,
+        )]);
+        assert_eq!(added.len(), 1);
+
+        let sender = base64::encode([0; 32]);
+        let request = |method: &str, req, id| {
+            super::ContractRequest::new(
+                [0; 32],
+                String::from("token"),
+                String::from(method),
+                req,
+                id as u64,
+                id,
+            )
+        };
+        let succeeded = executer.execute_multiple(&[
+            request("mint", serde_json::json!({ "to": sender, "amount": 100 }), 0),
+            request("transfer", serde_json::json!({ "to": "ginger", "amount": 30 }), 1),
+            // `require` ends the contract before it stores anything.
+            request("transfer", serde_json::json!({ "to": "ginger", "amount": 71 }), 2),
+            request("transfer", serde_json::json!({ "to": "ginger" }), 3),
+            request("burn", serde_json::json!({ "amount": 1 }), 4),
+        ]);
+        assert_eq!(succeeded.len(), 3);
+
+        let contract: [u8; 32] = Sha3_256::digest(b"token").into();
+        let balance = |account: &str| {
+            let account = U256::from_big_endian(&Sha3_256::digest(account.as_bytes()));
+            let value = contracts.get(&storage_key(&contract, 1, account)).unwrap();
+            U256::from_little_endian(&value.unwrap())
+        };
+        assert_eq!(balance(&sender), U256::from(70));
+        assert_eq!(balance("ginger"), U256::from(30));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn bytecode_contracts_see_the_writes_of_their_request() {
+        use super::language::storage_key;
+        use primitive_types::U256;
+        use sha3::{Digest, Sha3_256};
+
+        let path = "db-bytecode-buffer/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &free())
+                .unwrap();
+        let contracts = storage.namespace(super::CONTRACTS_NAMESPACE).unwrap();
+        let request = |name: &str, method: &str, req, nonce, id| {
+            super::ContractRequest::signed(
+                &SigningKey::from([1; 32]),
+                String::from(name),
+                String::from(method),
+                req,
+                nonce,
+                id,
+            )
+        };
+
+        let token = r#"
+mapping Balances
+fn mint to amount in
+    Balances to amount store
+end
+fn transfer from to amount in
+    Balances from get
+    let from_balance in
+        from_balance amount >= require
+        Balances from from_balance amount - store
+        Balances to
+        Balances to get amount +
+        store
+    end
+end
+"#;
+        // both transfers run in the same request, the second after the first was buffered.
+        let relay = r#"
+fn pay(req) {
+    storage.call_contract("token", "transfer", #{ "to": "ginger", "amount": 20 });
+    storage.call_contract("token", "transfer", #{ "to": "ginger", "amount": 20 });
+}
+"#;
+        let added = executer.execute_multiple(&[
+            request(
+                "native",
+                "add_bytecode",
+                serde_json::json!({ "name": "token", "code": token, "schema": "from:str" }),
+                0,
+                0,
+            ),
+            request(
+                "native",
+                "add",
+                serde_json::json!({ "name": "relay", "code": relay, "schema": "from:str" }),
+                1,
+                1,
+            ),
+        ]);
+        assert_eq!(added.len(), 2);
+        let minted = executer.execute_multiple(&[request(
+            "token",
+            "mint",
+            serde_json::json!({ "to": "contract:relay", "amount": 50 }),
+            2,
+            0,
+        )]);
+        assert_eq!(minted.len(), 1);
+        let pay = request("relay", "pay", serde_json::json!({}), 3, 0);
+        let paid = executer.execute_multiple(&[pay]);
+        assert_eq!(paid.len(), 1);
+
+        let contract: [u8; 32] = Sha3_256::digest(b"token").into();
+        let balance = |account: &str| {
+            let account = U256::from_big_endian(&Sha3_256::digest(account.as_bytes()));
+            let value = contracts.get(&storage_key(&contract, 1, account)).unwrap();
+            U256::from_little_endian(&value.unwrap())
+        };
+        assert_eq!(balance("contract:relay"), U256::from(10));
+        assert_eq!(balance("ginger"), U256::from(40));
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop((contracts, storage));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn runaway_rhai_contracts_are_aborted() {
+        let path = "db-sandbox/";
+        let _ = std::fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut params = free();
+        params.rhai.max_operations = 10_000;
+        let mut executer =
+            super::ContractExecuter::new(storage.clone(), exit.clone(), &one_thread(), &params)
+                .unwrap();
+
+        let added = executer.execute_multiple(&[super::ContractRequest::signed(
+            &SigningKey::from([1; 32]),
+            String::from("native"),
+            String::from("add"),
+            serde_json::json!({ "name": "runaway", "code": r#"
+fn spin(req) {
+    let i = 0;
+    loop { i += 1; }
+}
+
+fn grow(req) {
+    let s = "teral";
+    loop { s += s; }
+}
+
+fn recurse(req) {
+    recurse(req)
+}
+"#, "schema": "from:str" }),
+            0,
+            0,
+        )]);
+        assert_eq!(added.len(), 1);
+        for (nonce, method) in ["spin", "grow", "recurse"].into_iter().enumerate() {
+            executer.schedule(super::ContractRequest::new(
+                [0; 32],
+                String::from("runaway"),
+                String::from(method),
+                serde_json::json!({}),
+                nonce as u64,
+                0,
+            ));
+        }
+
+        let started = std::time::Instant::now();
+        let summary = executer.summary();
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        let statuses: Vec<_> = summary.iter().map(|recipt| recipt.status().clone()).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                TxStatus::Reverted(String::from("the contract ran out of operations")),
+                TxStatus::Reverted(String::from("the contract's data grew too large")),
+                TxStatus::Reverted(String::from("the contract's calls nest too deep")),
+            ]
+        );
+
+        exit.store(true, std::sync::atomic::Ordering::SeqCst);
+        executer.join();
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn recipts_do_not_depend_on_the_threads() {
+        let alice = [5; 32];
+        let bob = [6; 32];
+        let run = |threads: usize| {
+            let path = format!("db-order-{}/", threads);
+            let _ = std::fs::remove_dir_all(&path);
+            let storage: Arc<dyn Storage> = RocksdbStorage::load(&crate::config::StorageConfig {
+                path: path.clone(),
+                ..Default::default()
+            });
+            let mut allocations = std::collections::BTreeMap::new();
+            allocations.insert(base64::encode(alice), 100);
+            allocations.insert(base64::encode(bob), 50);
+            super::native_init(storage.clone(), &allocations).unwrap();
+
+            let exit = Arc::new(AtomicBool::new(false));
+            let config = crate::config::ContractExecConfig { threads };
+            let params = crate::config::ChainParams {
+                base_fee: 1,
+                ..Default::default()
+            };
+            let mut executer =
+                super::ContractExecuter::new(storage.clone(), exit.clone(), &config, &params)
+                    .unwrap();
+            // the transfers share accounts, so whether each succeeds depends on the ones before.
+            for i in 0..24_u64 {
+                // every third transfer is bob's, and the others alice's.
+                let (from, to, nonce) = if i % 3 == 0 {
+                    (bob, alice, i / 3)
+                } else {
+                    (alice, bob, i - i / 3 - 1)
+                };
+                executer.schedule(super::ContractRequest::new(
+                    from,
+                    String::from("native"),
+                    String::from("transfer"),
+                    serde_json::json!({ "to": base64::encode(to), "amount": 7 + i * 3 }),
+                    nonce,
+                    0,
+                ));
+            }
+            let summary = format!("{:?}", executer.summary());
+
+            let contract_storage = super::ContractStorage::open(&storage).unwrap();
+            let balances: Vec<_> = [alice, bob]
+                .iter()
+                .map(|account| {
+                    contract_storage
+                        .native_get_segment(&base64::encode(account))
+                        .unwrap()
+                        .unwrap()["balance"]
+                        .as_u64()
+                        .unwrap()
+                })
+                .collect();
+
+            exit.store(true, std::sync::atomic::Ordering::SeqCst);
+            executer.join();
+            drop((contract_storage, storage));
+            std::fs::remove_dir_all(&path).unwrap();
+            (summary, balances)
+        };
+
+        let (summary, balances) = run(1);
+        assert!(summary.contains("Reverted"));
+        for _ in 0..4 {
+            assert_eq!(run(4), (summary.clone(), balances.clone()));
+        }
+    }
+
+    #[test]
+    fn contracts_are_listed() {
+        let listing = super::listing("fn add a b in\n    a b +\nend").unwrap();
+        assert!(listing.lines().count() > 1);
+        assert!(listing.starts_with("0000: "));
+        assert!(matches!(
+            super::listing("fn add a b in"),
+            Err(super::ContractsError::Compile(_))
+        ));
+    }
 }
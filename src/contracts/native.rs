@@ -1,88 +1,277 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-use rhai::{Engine, AST};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
 use serde_json::{json, Value};
+use teral_macros::schema;
 
-use super::{validate_schema, ContractRequest, ContractStorage};
+use crate::storage::StorageError;
 
-// TODO: maybe have the native contracts in an enum with procmacro so that we can #[schema("from:str;to:str;amount:u64")] and it will implement
-// the schema validation automatically.
+const STAKE_PREFIX: &[u8] = b"stake";
+const NONCE_PREFIX: &[u8] = b"nonce:";
+
+use super::{
+    cache::CodeCache, compiler::compile, validate_schema, ContractCode, ContractRequest,
+    ContractStorage,
+};
 
 pub(crate) fn execute_native(
     job: &ContractRequest,
-    cache: &mut HashMap<String, AST>,
+    cache: &mut CodeCache<AST>,
     engine: &Engine,
     storage: &ContractStorage,
 ) -> Result<(), ()> {
     match job.method_name.as_str() {
-        "add" => {
-            if let Ok(original_author) = storage.get_author(&job.name) {
-                if job.author.to_vec() != original_author {
-                    return Err(());
-                }
-            }
-            validate_schema("name:str;code:str;schema:str", &job.req).map_err(|_| ())?;
-
-            match engine.compile(job.req["code"].as_str().unwrap()) {
-                Ok(ast) => {
-                    let name = job.req["name"].as_str().unwrap().to_string();
-                    cache.insert(name, ast);
-                    storage.add_contract(
-                        job.req["name"].as_str().unwrap(),
-                        job.req["code"].as_str().unwrap(),
-                        job.req["schema"].as_str().unwrap(),
-                        job.author,
-                    );
-                }
-                Err(_) => return Err(()),
-            }
-            // TODO: maybe call here script.init() so the code can init its storage (for example give
-            // the initial supply).
-            Ok(())
-        }
+        "add" | "add_bytecode" => teral_add(storage, cache, engine, job, &job.req),
         "transfer" => teral_transfer(storage, &job.req),
-        "stake" => teral_stake(storage, &job.req),
+        "stake" => teral_stake(storage, job.author, &job.req),
+        "unstake" => teral_unstake(storage, job.author, &job.req),
         _ => Err(()),
     }
 }
 
-pub(crate) fn teral_transfer(storage: &ContractStorage, req: &Value) -> Result<(), ()> {
-    let from = storage.native_get_segment(req["from"].as_str().unwrap());
-    let from = if let Some(from) = from {
-        from
-    } else {
+/// Adds the contract `name`, or replaces its code if the signed author of the request is the one
+/// who added it. `add` takes a rhai script, and `add_bytecode` the source of a contract for the
+/// compiler, which is compiled once here. A rhai contract that is added for the first time is
+/// then initialized.
+#[schema("from:str;name:str;code:str;schema:str")]
+fn teral_add(
+    storage: &ContractStorage,
+    cache: &mut CodeCache<AST>,
+    engine: &Engine,
+    job: &ContractRequest,
+    req: &Value,
+) -> Result<(), ()> {
+    if !job.verify() {
         return Err(());
+    }
+    let previous = storage.get_contract(name).ok();
+    if let Some(previous) = &previous {
+        if job.author != previous.author {
+            return Err(());
+        }
+    }
+    let is_new = previous.is_none();
+
+    let (code, ast) = if job.method_name == "add" {
+        let ast = cache
+            .get_or_compile(code, |code| engine.compile(code))
+            .map_err(|_| ())?;
+        (ContractCode::Rhai(code.to_string()), Some(ast))
+    } else {
+        (ContractCode::Bytecode(compile(code).map_err(|_| ())?), None)
     };
-    if req["amount"].as_u64().unwrap() > from["balance"].as_u64().unwrap() {
+    if let Some(ContractCode::Rhai(previous)) = previous.map(|previous| previous.code) {
+        if !matches!(&code, ContractCode::Rhai(code) if *code == previous) {
+            cache.invalidate(&previous);
+        }
+    }
+    storage
+        .add_contract(name, code, schema, job.author)
+        .map_err(|_| ())?;
+    match ast {
+        Some(ast) if is_new => init_contract(storage, engine, &ast, name, job.author),
+        _ => Ok(()),
+    }
+}
+
+/// Runs the `init` function of the rhai contract `name`, if it has one, so that it can set up
+/// its storage (for example give the initial supply to its owner). The request it gets holds the
+/// author of the contract as its `owner`. It writes through `storage`, so a failing `init` fails
+/// the add with it.
+fn init_contract(
+    storage: &ContractStorage,
+    engine: &Engine,
+    ast: &AST,
+    name: &str,
+    author: [u8; 32],
+) -> Result<(), ()> {
+    if !ast.iter_functions().any(|function| function.name == "init") {
+        return Ok(());
+    }
+    let mut storage = storage.clone();
+    storage.set_curr_contract(name);
+    let mut scope = Scope::new();
+    scope.push_constant("storage", storage);
+
+    let mut req = Map::new();
+    req.insert("owner".into(), Dynamic::from(base64::encode(author)));
+    engine
+        .call_fn::<Dynamic>(&mut scope, ast, "init", (req,))
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Moves `amount` from the balance of `from` to the balance of `to`, creating `to` if it does not
+/// exist yet.
+#[schema("from:str;to:str;amount:u64")]
+pub(crate) fn teral_transfer(storage: &ContractStorage, req: &Value) -> Result<(), ()> {
+    if amount == 0 || from == to {
         return Err(());
     }
 
-    storage.native_set_segment(
-        req["from"].as_str().unwrap(),
-        json!({ "balance": from["balance"].as_u64().unwrap() - req["amount"].as_u64().unwrap() }),
-    );
+    let mut from_account = storage
+        .native_get_segment(from)
+        .map_err(|_| ())?
+        .ok_or(())?;
+    let mut to_account = storage
+        .native_get_segment(to)
+        .map_err(|_| ())?
+        .unwrap_or_else(|| json!({ "balance": 0 }));
+    // if to_name.len() != 32 {
+    //     return Err(()); // names with 32 characters are not contract names (most probably), and if we dont have it then no reason to waste money.
+    // }
 
-    let to = storage.native_get_segment(req["to"].as_str().unwrap());
+    let from_balance = from_account["balance"].as_u64().ok_or(())?.checked_sub(amount);
+    let to_balance = to_account["balance"].as_u64().ok_or(())?.checked_add(amount);
+    from_account["balance"] = json!(from_balance.ok_or(())?);
+    to_account["balance"] = json!(to_balance.ok_or(())?);
 
-    if let Some(to) = to {
-        let balance = to["balance"].as_u64().unwrap() + req["amount"].as_u64().unwrap();
-        storage.native_set_segment(req["to"].as_str().unwrap(), json!({ "balance": balance }));
-    } else {
-        // if req["to"].as_str().unwrap().len() != 32 {
-        //     return Err(()); // names with 32 characters are not contract names (most probably), and if we dont have it then no reason to waste money.
-        // }
-        storage.native_set_segment(
-            req["to"].as_str().unwrap(),
-            json!({ "balance": req["amount"].as_u64().unwrap() }),
-        );
+    storage.native_set_segment(from, from_account).map_err(|_| ())?;
+    storage.native_set_segment(to, to_account).map_err(|_| ())
+}
+
+/// Burns `fee` from the balance of `payer`.
+pub(crate) fn teral_charge(storage: &ContractStorage, payer: &str, fee: u64) -> Result<(), ()> {
+    if fee == 0 {
+        return Ok(());
     }
-    Ok(())
+    let mut account = storage
+        .native_get_segment(payer)
+        .map_err(|_| ())?
+        .ok_or(())?;
+    let balance = account["balance"].as_u64().ok_or(())?;
+    account["balance"] = json!(balance.checked_sub(fee).ok_or(())?);
+    storage.native_set_segment(payer, account).map_err(|_| ())
 }
 
-pub(crate) fn teral_stake(storage: &ContractStorage, req: &Value) -> Result<(), ()> {
-    Ok(())
+/// Moves `amount` from the balance of `from` to its `staked` field, and adds it to the stake of
+/// `author` in the ledger the leader schedule reads.
+#[schema("from:str;amount:u64")]
+pub(crate) fn teral_stake(
+    storage: &ContractStorage,
+    author: [u8; 32],
+    req: &Value,
+) -> Result<(), ()> {
+    let mut account = storage.native_get_segment(from).map_err(|_| ())?.ok_or(())?;
+    let balance = account["balance"].as_u64().ok_or(())?;
+    let staked = account["staked"].as_u64().unwrap_or(0);
+
+    account["balance"] = json!(balance.checked_sub(amount).ok_or(())?);
+    account["staked"] = json!(staked.checked_add(amount).ok_or(())?);
+    let stake = get_stake(storage, author)?.checked_add(amount).ok_or(())?;
+    storage.native_set_segment(from, account).map_err(|_| ())?;
+    set_stake(storage, author, stake)
+}
+
+/// The reverse of `teral_stake`: moves `amount` of the stake of `from` back to its balance.
+#[schema("from:str;amount:u64")]
+pub(crate) fn teral_unstake(
+    storage: &ContractStorage,
+    author: [u8; 32],
+    req: &Value,
+) -> Result<(), ()> {
+    let mut account = storage.native_get_segment(from).map_err(|_| ())?.ok_or(())?;
+    let balance = account["balance"].as_u64().unwrap_or(0);
+    let staked = account["staked"].as_u64().unwrap_or(0);
+
+    account["staked"] = json!(staked.checked_sub(amount).ok_or(())?);
+    account["balance"] = json!(balance.checked_add(amount).ok_or(())?);
+    let stake = get_stake(storage, author)?.checked_sub(amount).ok_or(())?;
+    storage.native_set_segment(from, account).map_err(|_| ())?;
+    set_stake(storage, author, stake)
+}
+
+fn get_stake(storage: &ContractStorage, author: [u8; 32]) -> Result<u64, ()> {
+    Ok(storage
+        .get(&[STAKE_PREFIX, &author].concat())
+        .map_err(|_| ())?
+        .and_then(|stake| stake.try_into().ok())
+        .map_or(0, u64::from_be_bytes))
+}
+
+/// Records the stake of `author`, dropping it from the ledger once it is empty so that it can
+/// not be picked as a leader.
+fn set_stake(storage: &ContractStorage, author: [u8; 32], stake: u64) -> Result<(), ()> {
+    let key = [STAKE_PREFIX, &author].concat();
+    match stake {
+        0 => storage.delete(&key),
+        stake => storage.set(&key, &stake.to_be_bytes()),
+    }
+    .map_err(|_| ())
+}
+
+/// The nonce the next request of `author` has to carry, which is how many of its requests ran.
+pub(crate) fn teral_nonce(
+    storage: &ContractStorage,
+    author: [u8; 32],
+) -> Result<u64, StorageError> {
+    Ok(storage
+        .get(&[NONCE_PREFIX, &author].concat())?
+        .and_then(|nonce| nonce.try_into().ok())
+        .map_or(0, u64::from_be_bytes))
 }
 
-pub(crate) fn teral_init(storage: ContractStorage) {
-    storage.native_set_segment("ghostway", json!({ "balance": 1000_u64 }));
+/// Checks that `nonce` is the next nonce of `author` and moves past it, so that a request can not
+/// be run again.
+pub(crate) fn teral_use_nonce(
+    storage: &ContractStorage,
+    author: [u8; 32],
+    nonce: u64,
+) -> Result<(), ()> {
+    if teral_nonce(storage, author).map_err(|_| ())? != nonce {
+        return Err(());
+    }
+    let next = nonce.checked_add(1).ok_or(())?;
+    storage
+        .set(&[NONCE_PREFIX, &author].concat(), &next.to_be_bytes())
+        .map_err(|_| ())
+}
+
+/// The balance of `account`, if it has one.
+pub(crate) fn teral_balance(storage: &ContractStorage, account: &str) -> Option<u64> {
+    storage.native_get_segment(account).ok()??["balance"].as_u64()
+}
+
+/// Every account that staked, with its stake, ordered by public key.
+pub(crate) fn teral_stakes(storage: &ContractStorage) -> Vec<([u8; 32], u64)> {
+    let mut stakes: Vec<_> = storage
+        .storage
+        .scan_prefix(STAKE_PREFIX)
+        .filter_map(|(key, stake)| {
+            Some((
+                key[STAKE_PREFIX.len()..].try_into().ok()?,
+                u64::from_be_bytes(stake.try_into().ok()?),
+            ))
+        })
+        .collect();
+    stakes.sort_unstable();
+    stakes
+}
+
+pub(crate) fn teral_init(
+    storage: ContractStorage,
+    allocations: &BTreeMap<String, u64>,
+) -> Result<(), StorageError> {
+    for (account, balance) in allocations {
+        storage.native_set_segment(account, json!({ "balance": balance }))?;
+    }
+    storage.commit()
+}
+
+/// Stakes `stake` for each validator, as if it had staked it out of its own account.
+pub(crate) fn teral_init_stakes(
+    storage: ContractStorage,
+    stakes: &[([u8; 32], u64)],
+) -> Result<(), StorageError> {
+    for (validator, stake) in stakes {
+        let from = base64::encode(validator);
+        let mut account = storage
+            .native_get_segment(&from)?
+            .unwrap_or_else(|| json!({ "balance": 0 }));
+        account["staked"] = json!(stake);
+        storage.native_set_segment(&from, account)?;
+        let key = [STAKE_PREFIX, validator].concat();
+        storage.set(&key, &stake.to_be_bytes())?;
+    }
+    storage.commit()
 }
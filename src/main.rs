@@ -1,15 +1,11 @@
-use primitive_types::U256;
-
-use crate::{
-    config::TeralConfig,
-    contracts::{execute, parse},
-    validator::Validator,
-};
+use crate::{config::TeralConfig, validator::Validator};
 
 mod chain;
 mod config;
 mod contracts;
 mod p2p;
+#[cfg(feature = "rpc")]
+mod rpc;
 mod storage;
 mod validator;
 
@@ -19,72 +15,56 @@ fn main() {
         .with_max_level(tracing::Level::DEBUG)
         .compact()
         .init();
-    let config = TeralConfig::read("teral.toml");
-    let mut validator = Validator::new(config);
-
-    // TODO: how are we gonna verify a request is valid? we can make `from` a standard key that we
-    // insert.
-
-    let input = r#"
-mapping Balances
-fn transfer from to amount in
-    Balances from get
-    peek from_balance
-    require
-    from_balance amount > require
-
-    Balances
-    from
-    from_balance amount -
-    store
-
-    Balances to get
-    let to_balance if
-        Balances to
-        to_balance amount +
-        store
-    else
-        Balances
-        to amount
-        store
-    end
-end
-"#
-    .to_string();
-    parse(input);
-
-    validator.schedule_contract(contracts::ContractRequest::new(
-        [0; 32],
-        String::from("native"),
-        String::from("add"),
-        serde_json::json!({ "name": "ginger", "code": r#"
-fn transfer(req) {
-    let from = storage.get(req["from"]);
-    if from == 0 || from["balance"] < req["amount"] { throw; }
-    from["balance"] -= req["amount"];
-    storage.set(req["from"], from);
-
-    let to = storage.get(req["to"]);
-    if to == 0 {
-        storage.set(req["to"], #{ "balance": req["amount"] })
-    } else {
-        to["balance"] += req["amount"];
-        storage.set(req["to"], to);
+    if std::env::args().nth(1).as_deref() == Some("init") {
+        match TeralConfig::write_default("teral.toml") {
+            Ok(()) => tracing::info!("Wrote the default config to teral.toml."),
+            Err(err) => {
+                tracing::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
-}"#, "schema": "from:str;to:str;amount:u64" }),
-        0,
-    ));
-
-    validator.schedule_contract(contracts::ContractRequest::new(
-        [0; 32],
-        String::from("native"),
-        String::from("transfer"),
-        serde_json::json!({ "from": "ghostway", "to": "ginger", "amount": 100_u64}),
-        0,
-    ));
-
-    let r = validator.finalize_contracts();
-    println!("{:?} {}", r, r.recipt_count());
+    if std::env::args().nth(1).as_deref() == Some("disassemble") {
+        let listing = match std::env::args().nth(2) {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|source| contracts::listing(&source).map_err(|err| err.to_string())),
+            None => Err(String::from("usage: teral disassemble <contract>")),
+        };
+        match listing {
+            Ok(listing) => println!("{}", listing),
+            Err(err) => {
+                tracing::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let config = match TeralConfig::read("teral.toml") {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut validator = match Validator::new(config) {
+        Ok(validator) => validator,
+        Err(err) => {
+            tracing::error!("Could not start the validator: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    validator.stop();
+    // the key to stake in the genesis of a new network.
+    tracing::info!("Validating as {}.", base64::encode(validator.pubkey()));
+    // runs until it fails, the process being killed otherwise.
+    let result = validator.run();
+    if let Err(err) = validator.stop() {
+        tracing::error!("Could not stop the validator cleanly: {}", err);
+    }
+    if let Err(err) = result {
+        tracing::error!("The validator stopped: {}", err);
+        std::process::exit(1);
+    }
 }
@@ -1,38 +1,87 @@
+mod noise;
+
 use chrono::DateTime;
 
 use {
-    crate::{chain::Chain, storage::Storage},
+    crate::{
+        chain::{Block, Chain, ChainError},
+        config::GossipConfig,
+        contracts::ContractRequest,
+        storage::{Storage, StorageError},
+        validator::Mempool,
+    },
     bincode::Options,
     chrono::Utc,
-    ed25519_consensus::{Signature, SigningKey, VerificationKey, VerificationKeyBytes},
-    rand::{prelude::SliceRandom, thread_rng},
+    ed25519_consensus::{batch, Signature, SigningKey, VerificationKey, VerificationKeyBytes},
+    rand::{prelude::SliceRandom, thread_rng, Rng},
     rayon::{
         iter::{IntoParallelIterator, ParallelIterator},
         ThreadPool, ThreadPoolBuilder,
     },
     serde_derive::{Deserialize, Serialize},
+    sha3::{Digest, Sha3_256},
     std::{
         collections::{HashMap, HashSet},
         io::{self, Read, Write},
-        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+        net::{
+            IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket,
+        },
         sync::{
-            atomic::{AtomicBool, Ordering},
-            mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender},
-            Arc,
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            mpsc::{
+                channel, sync_channel, Receiver, RecvTimeoutError, SendError, Sender, SyncSender,
+                TrySendError,
+            },
+            Arc, Mutex, RwLock,
         },
         thread::{self, JoinHandle},
-        time::Duration,
+        time::{Duration, Instant},
     },
     thiserror::Error,
 };
 
+pub use self::noise::EncryptedStream;
+
 const GOSSIP_BUFFER_SIZE: usize = 2_usize.pow(16);
+/// The largest datagram `send_udp` will send. Anything bigger risks IP fragmentation, and with it
+/// getting dropped on the way, so large payloads have to go over TCP instead.
+const MAX_UDP_PAYLOAD: usize = 1200;
 const RECEIVER_BUFSIZE: usize = 1024;
 const RECV_TIMEOUT: Duration = Duration::from_secs(1);
 const BLOCK_SYNC_VOTERS: usize = 10;
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_MISSED_PONGS: u32 = 3;
+const BLOCK_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many blocks a peer sends in answer to a single sync request.
+const BLOCK_SYNC_BATCH: usize = 64;
+/// How many sync and discovery requests wait to be served before new ones are dropped.
+const REPLY_QUEUE: usize = 8;
+/// How many of our contacts a discovery request is answered with, and how many of the contacts a
+/// peer answers with are taken.
+const DISCOVERY_RESPONSE_SIZE: usize = 32;
+/// How many contacts are kept. Past that, the oldest make room for new ones.
+const MAX_CONTACTS: usize = 1024;
+/// How many contacts are kept on a single ip. Past that, the oldest of them make room for new ones,
+/// so that a single host cannot take over the contact list.
+const MAX_CONTACTS_PER_HOST: usize = 16;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const DISCOVER_POLL: Duration = Duration::from_millis(100);
+const DISCOVER_BACKOFF: Duration = Duration::from_millis(500);
+const DISCOVER_DIAL_ATTEMPTS: u32 = 3;
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+/// How many tcp connections a receiver serves at once. Any more are closed right away.
+const MAX_TCP_CONNECTIONS: usize = 64;
+/// How long a tcp connection is served for, however busy it keeps itself, before it is cut off.
+const CONNECTION_DEADLINE: Duration = Duration::from_secs(10);
+/// How far ahead of our clock a gossip message may be stamped, in milliseconds. One stamped
+/// further ahead would stay in the dedup log for longer than `purge_time`.
+const MAX_CLOCK_DRIFT_MS: i64 = 5_000;
+/// The keyspace the node keeps what it knows of its peers in.
+pub(crate) const CLUSTER_NAMESPACE: &str = "cluster";
 
 #[derive(Debug, Error)]
-enum P2PError {
+pub enum P2PError {
     #[error("The receiver timed out")]
     ReceiverTimeout(#[from] RecvTimeoutError),
     #[error("The receiver disconnected")]
@@ -45,8 +94,34 @@ enum P2PError {
     CannotDiscover,
     #[error("Tcp error")]
     Tcp,
+    #[error("A {0} byte frame is larger than we accept")]
+    FrameTooLarge(usize),
+    #[error("A {0} byte message does not fit in a datagram, send it over tcp")]
+    PayloadTooLarge(usize),
     #[error("IO error")]
     IOError(#[from] std::io::Error),
+    #[error("Could not bind to {0}: {1}")]
+    Bind(String, std::io::Error),
+    #[error("Noise error: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("The peer hung up mid handshake")]
+    HandshakeClosed,
+    #[error("The peer's noise key is not signed by the identity it claims")]
+    ForgedNoiseKey,
+    #[error("Reached {0:?} instead of the expected peer")]
+    UnexpectedPeer(VerificationKeyBytes),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("Chain error: {0}")]
+    Chain(#[from] ChainError),
+    #[error("A block does not extend our chain")]
+    BrokenChain,
+    #[error("Malformed block: {0}")]
+    MalformedBlock(serde_json::Error),
+    #[error("Malformed transaction: {0}")]
+    MalformedTransaction(bincode::Error),
+    #[error("A transaction is not signed by the author it names")]
+    ForgedTransaction,
 }
 
 impl<T> From<SendError<T>> for P2PError {
@@ -55,9 +130,44 @@ impl<T> From<SendError<T>> for P2PError {
     }
 }
 
+/// What a `Message` carries, serialized with bincode into its `data`.
 #[derive(Debug, Serialize, Deserialize)]
-enum Protocol {
-    GossipPush {},
+pub enum Protocol {
+    GossipPush(Vec<u8>),
+    /// Asks for the contacts of a peer, to be sent as a `DiscoveryResponse` to the tcp listener
+    /// at `reply_to`. The peer takes the asking node, gossiping at `gossip_port`, for a contact.
+    Discovery {
+        reply_to: SocketAddr,
+        gossip_port: u16,
+    },
+    DiscoveryResponse(Vec<SocketAddr>),
+    /// Asks for the blocks built after `since`, to be sent as a json `SyncResponse` carrying
+    /// `token` to the tcp listener at `reply_to`.
+    InitiateSync {
+        since: i64,
+        reply_to: SocketAddr,
+        token: u64,
+    },
+    /// A json encoded `Block` that was just finalized.
+    NewBlock(Vec<u8>),
+    Transaction(ContractRequest),
+    Ping,
+    Pong,
+}
+
+/// The blocks a peer sent in answer to `InitiateSync`. The `token` of the request tells which of
+/// the peers that were asked it comes from.
+#[derive(Serialize, Deserialize)]
+struct SyncResponse {
+    token: u64,
+    blocks: Vec<Block>,
+}
+
+impl Protocol {
+    pub fn new_block(block: &Block) -> Self {
+        // blocks carry json recipts, which bincode cannot deserialize.
+        Self::NewBlock(serde_json::to_vec(block).unwrap())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,6 +179,23 @@ struct Message {
 }
 
 impl Message {
+    /// Wraps `protocol` in a message signed by `keypair`.
+    fn signed(keypair: &SigningKey, protocol: &Protocol) -> Self {
+        let timestamp = Utc::now().timestamp_millis();
+        let data = serialize(protocol).unwrap();
+        let signature = keypair.sign(&[data.as_slice(), &timestamp.to_le_bytes()].concat());
+        Self::new(
+            VerificationKeyBytes::from(keypair.verification_key()),
+            signature,
+            data,
+            timestamp,
+        )
+    }
+
+    fn protocol(&self) -> bincode::Result<Protocol> {
+        deserialize(&self.data)
+    }
+
     pub fn new(
         pubkey: VerificationKeyBytes,
         signature: Signature,
@@ -83,18 +210,61 @@ impl Message {
         }
     }
 
+    /// Identifies a message by what was signed and by whom, leaving out the timestamp so that
+    /// re-stamping a message does not make it look new.
+    fn content_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha3_256::new();
+        hasher.update(self.pubkey.as_ref());
+        hasher.update(self.signature.to_bytes());
+        hasher.update(&self.data);
+        hasher.finalize().into()
+    }
+
+    /// The bytes the signature covers.
+    fn signed_data(&self) -> Vec<u8> {
+        [self.data.as_slice(), &self.timestamp.to_le_bytes()].concat()
+    }
+
+    fn has_valid_signature(&self) -> bool {
+        VerificationKey::try_from(self.pubkey)
+            .and_then(|key| key.verify(&self.signature, &self.signed_data()))
+            .is_ok()
+    }
+
     pub fn verify(self) -> Option<Self> {
-        let sig_data = [self.data.as_slice(), &self.timestamp.to_le_bytes()].concat();
+        self.has_valid_signature().then_some(self)
+    }
+}
 
-        if let Ok(key) = VerificationKey::try_from(self.pubkey) {
-            match key.verify(&self.signature, &sig_data) {
-                Ok(_) => Some(self),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+/// Verifies the messages of `items` as one ed25519 batch and returns the items with a valid
+/// signature. A batch with a bad signature is split in halves and retried, so only the failing
+/// messages end up being verified one by one.
+fn verify_batch_by<T>(items: Vec<T>, message: impl Fn(&T) -> &Message + Copy) -> Vec<T> {
+    if items.len() <= 1 {
+        return items
+            .into_iter()
+            .filter(|item| message(item).has_valid_signature())
+            .collect();
+    }
+
+    let signed_data: Vec<_> = items
+        .iter()
+        .map(|item| message(item).signed_data())
+        .collect();
+    let mut verifier = batch::Verifier::new();
+    for (item, data) in items.iter().zip(&signed_data) {
+        let message = message(item);
+        verifier.queue((message.pubkey, message.signature, data));
     }
+    if verifier.verify(thread_rng()).is_ok() {
+        return items;
+    }
+
+    let mut items = items;
+    let second_half = items.split_off(items.len() / 2);
+    let mut verified = verify_batch_by(items, message);
+    verified.extend(verify_batch_by(second_half, message));
+    verified
 }
 
 fn serialize<T: serde::Serialize>(value: T) -> bincode::Result<Vec<u8>> {
@@ -112,132 +282,649 @@ where
         .deserialize_from(data)
 }
 
+/// What a peer asked `listen` to send to its tcp listener.
+enum Reply {
+    /// The blocks built after `since`, in milliseconds, answering the request that carried
+    /// `token`.
+    Blocks {
+        since: i64,
+        token: u64,
+    },
+    Contacts,
+}
+
 type BufferedSender<T> = Sender<Vec<T>>;
 type BufferedReceiver<T> = Receiver<Vec<T>>;
 
+/// A peer `discover` still has to dial, with how many dials already failed and when it may be
+/// tried again.
+struct PendingDial {
+    addr: SocketAddr,
+    attempts: u32,
+    not_before: Instant,
+}
+
+/// Asks the peers we know of, or the boot nodes if we know nobody, for their contacts until
+/// `target` of them come in, announcing that we gossip on `gossip_port`.
 fn discover(
     listener: TcpListener,
-    cluster_info: Arc<ClusterInfo>,
+    cluster_info: &ClusterInfo,
+    gossip_port: u16,
     target: usize,
 ) -> Result<HashSet<SocketAddr>, P2PError> {
-    const TIMEOUT: Duration = Duration::from_secs(2);
+    let reply_to = listener.local_addr()?;
     let mut discovered = HashSet::new();
+    let mut dialed = HashSet::new();
+    let now = Instant::now();
+    let mut pending: Vec<PendingDial> = cluster_info
+        .discovery_nodes()
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|addr| PendingDial {
+            addr,
+            attempts: 0,
+            not_before: now,
+        })
+        .collect();
+    let deadline = now + DISCOVER_TIMEOUT;
 
     let (send, recv) = channel();
     let exit = Arc::new(AtomicBool::new(false));
-    let receiver_handle = tcp_receiver(listener, send, &exit, "discover-receiver");
+    let receiver_handle = tcp_receiver(
+        listener,
+        send,
+        |frame, _| frame,
+        &exit,
+        "discover-receiver",
+        cluster_info.noise.clone(),
+    );
 
-    while discovered.len() < target {
-        let addr = cluster_info.get_discovery_node().unwrap(); // TODO: find a pretty way so that we do not dial the same peer more than once, and that if it errors out, we retry.
+    let result = loop {
+        if discovered.len() >= target {
+            break Ok(discovered);
+        }
+        if Instant::now() >= deadline {
+            break Err(P2PError::CannotDiscover);
+        }
 
-        let stream = &mut TcpStream::connect_timeout(addr, TIMEOUT);
-        match stream {
-            Ok(stream) => {
-                let _ = send_tcp(stream, cluster_info.new_discovery_message());
+        let ready = pending
+            .iter()
+            .position(|peer| peer.not_before <= Instant::now());
+        if let Some(peer) = ready.map(|index| pending.swap_remove(index)) {
+            if dialed.contains(&peer.addr) {
+                continue;
+            }
+            match TcpStream::connect_timeout(&peer.addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    dialed.insert(peer.addr);
+                    let request = cluster_info.new_discovery_message(reply_to, gossip_port);
+                    let _ = cluster_info.send_tcp(stream, &request);
+                }
+                Err(err) => {
+                    tracing::debug!("error connecting to {:?}: {:?}", peer.addr, err);
+                    if peer.attempts + 1 < DISCOVER_DIAL_ATTEMPTS {
+                        pending.push(PendingDial {
+                            addr: peer.addr,
+                            attempts: peer.attempts + 1,
+                            not_before: Instant::now()
+                                + DISCOVER_BACKOFF * 2_u32.pow(peer.attempts),
+                        });
+                    }
+                }
             }
-            Err(err) => tracing::debug!("error connecting to {:?}: {:?}", addr, err),
         }
 
-        if let Ok(message_bytes) = recv.recv_timeout(TIMEOUT) {
-            if let Ok(received_contacts) = deserialize::<Vec<SocketAddr>>(&message_bytes) {
-                for contact in received_contacts {
-                    discovered.insert(contact);
+        // once there is nobody left to dial, give the peers we did reach one last chance to answer.
+        let wait = if pending.is_empty() {
+            CONNECT_TIMEOUT
+        } else {
+            DISCOVER_POLL
+        };
+        match recv.recv_timeout(wait) {
+            Ok(message_bytes) => {
+                let response = deserialize::<Message>(&message_bytes)
+                    .ok()
+                    .and_then(Message::verify)
+                    .map(|message| message.protocol());
+                if let Some(Ok(Protocol::DiscoveryResponse(received_contacts))) = response {
+                    discovered.extend(received_contacts.into_iter().take(DISCOVERY_RESPONSE_SIZE));
                 }
             }
+            Err(_) if pending.is_empty() => break Err(P2PError::CannotDiscover),
+            Err(_) => {}
         }
-    }
+    };
     exit.store(true, Ordering::Relaxed);
     receiver_handle.join().unwrap();
 
-    Ok(discovered)
+    result
 }
 
+/// Asks some of the peers we know of for the blocks built after `since`, and returns the ones a
+/// majority of them agree on, provided they extend the head of `chain`.
 fn block_sync(
     listener: TcpListener,
     since: DateTime<Utc>,
-    cluster_info: Arc<ClusterInfo>,
-    chain: &mut Chain,
-) -> Result<(), P2PError> {
-    let contacts: Vec<SocketAddr> = discover(listener.try_clone().unwrap(), cluster_info, 100)?
-        .into_iter()
-        .collect();
+    cluster_info: &ClusterInfo,
+    chain: &Chain,
+) -> Result<Vec<Block>, P2PError> {
+    let contacts = cluster_info.discovery_nodes();
+    let voters: Vec<&SocketAddr> = contacts
+        .choose_multiple(&mut thread_rng(), BLOCK_SYNC_VOTERS)
+        .collect(); // TODO: maybe weight with the staking distribution?
+
+    sync_from_voters(listener, since, cluster_info, &voters, chain)
+}
 
+fn sync_from_voters(
+    listener: TcpListener,
+    since: DateTime<Utc>,
+    cluster_info: &ClusterInfo,
+    voters: &[&SocketAddr],
+    chain: &Chain,
+) -> Result<Vec<Block>, P2PError> {
+    let reply_to = listener.local_addr()?;
     let (send, recv) = channel();
     let exit = Arc::new(AtomicBool::new(false));
-    let receiver_handle = tcp_receiver(listener, send, &exit, "sync-reciever");
+    let receiver_handle = tcp_receiver(
+        listener,
+        send,
+        |frame, _| frame,
+        &exit,
+        "sync-reciever",
+        cluster_info.noise.clone(),
+    );
 
-    let voters: Vec<&SocketAddr> = contacts
-        .choose_multiple(&mut thread_rng(), BLOCK_SYNC_VOTERS)
-        .collect(); // TODO: maybe weight with the staking distribution?
+    // every voter gets its own token, so that only one response of each of them is counted and
+    // whatever else arrives at the listener is not.
+    let mut asked = HashMap::new();
+    for voter in voters {
+        match TcpStream::connect_timeout(voter, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                let token = thread_rng().gen();
+                let request = cluster_info.new_initiate_sync_message(since, reply_to, token);
+                if cluster_info.send_tcp(stream, &request).is_ok() {
+                    asked.insert(token, voter);
+                }
+            }
+            Err(err) => tracing::debug!("error connecting to {:?}: {:?}", voter, err),
+        }
+    }
 
-    Ok(())
+    let deadline = Utc::now() + chrono::Duration::from_std(BLOCK_SYNC_TIMEOUT).unwrap();
+    let mut responses = vec![];
+    while !asked.is_empty() && Utc::now() < deadline {
+        if let Ok(response_bytes) = recv.recv_timeout(RECV_TIMEOUT) {
+            // blocks carry json recipts, which bincode cannot deserialize.
+            let response = serde_json::from_slice::<SyncResponse>(&response_bytes);
+            match response.map(|response| (asked.remove(&response.token), response)) {
+                Ok((Some(_), response)) => responses.push(response.blocks),
+                Ok((None, _)) => tracing::debug!("dropped a sync response nobody was asked for"),
+                Err(_) => {}
+            }
+        }
+    }
+    exit.store(true, Ordering::Relaxed);
+    receiver_handle.join().unwrap();
+
+    let mut head = chain
+        .latest_block()?
+        .map(|block| block.digest())
+        .unwrap_or_default();
+    let blocks = agreed_blocks(responses, voters.len());
+    for block in &blocks {
+        if block.previous_digest() != head {
+            return Err(P2PError::BrokenChain);
+        }
+        head = block.digest();
+    }
+
+    Ok(blocks)
+}
+
+/// Up to `BLOCK_SYNC_BATCH` of the blocks built after `since`, oldest first. Block times only
+/// grow with the height, so the first of them is found with a binary search over the heights.
+fn blocks_after(chain: &Chain, since: i64) -> Result<Vec<Block>, P2PError> {
+    let block_at = |height| {
+        chain
+            .block_by_height(height)?
+            .ok_or(ChainError::MissingBlock(height))
+    };
+    // the first height whose block is newer than `since` is in `low..=high`.
+    let (mut low, mut high) = (1, chain.height() + 1);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if block_at(middle)?.time() <= since {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    chain
+        .iter()
+        .skip(low as usize)
+        .take(BLOCK_SYNC_BATCH)
+        .map(|block| Ok(block?))
+        .collect()
+}
+
+/// Sends the peer at `reply_to` up to `BLOCK_SYNC_BATCH` of our blocks built after `since`,
+/// oldest first, along with the `token` of its request, if it proves it is `requester`, the node
+/// that asked for them.
+fn serve_sync(
+    cluster_info: &ClusterInfo,
+    chain: &Chain,
+    since: i64,
+    token: u64,
+    reply_to: SocketAddr,
+    requester: VerificationKeyBytes,
+) -> Result<(), P2PError> {
+    let mut sync = SyncResponse {
+        token,
+        blocks: blocks_after(chain, since)?,
+    };
+
+    // blocks carry json recipts, which bincode cannot deserialize.
+    let mut response = serde_json::to_vec(&sync).map_err(ChainError::Encode)?;
+    while response.len() > GOSSIP_BUFFER_SIZE && !sync.blocks.is_empty() {
+        sync.blocks.pop();
+        response = serde_json::to_vec(&sync).map_err(ChainError::Encode)?;
+    }
+    let stream = TcpStream::connect_timeout(&reply_to, CONNECT_TIMEOUT)?;
+    cluster_info.send_tcp_frame(stream, &response, Some(requester))
+}
+
+/// Sends some of our contacts to `reply_to`, once it proves it is `requester`.
+fn serve_discovery(
+    cluster_info: &ClusterInfo,
+    reply_to: SocketAddr,
+    requester: VerificationKeyBytes,
+) -> Result<(), P2PError> {
+    let contacts = cluster_info.contacts();
+    let contacts = contacts
+        .choose_multiple(&mut thread_rng(), DISCOVERY_RESPONSE_SIZE)
+        .copied()
+        .collect();
+    let response = cluster_info.new_protocol_message(Protocol::DiscoveryResponse(contacts));
+    let response = serialize(&response).map_err(P2PError::Serialize)?;
+    let stream = TcpStream::connect_timeout(&reply_to, CONNECT_TIMEOUT)?;
+    cluster_info.send_tcp_frame(stream, &response, Some(requester))
+}
+
+/// Walks the voters' responses height by height, keeping each block only while a majority of all
+/// `voters` agree on its hash.
+fn agreed_blocks(responses: Vec<Vec<Block>>, voters: usize) -> Vec<Block> {
+    let mut responses: Vec<_> = responses.into_iter().map(Vec::into_iter).collect();
+    let mut agreed = vec![];
+    loop {
+        let mut votes: HashMap<[u8; 32], (usize, Block)> = HashMap::new();
+        for response in responses.iter_mut() {
+            if let Some(block) = response.next() {
+                votes.entry(block.digest()).or_insert((0, block)).0 += 1;
+            }
+        }
+        match votes.into_values().find(|(count, _)| *count > voters / 2) {
+            Some((_, block)) => agreed.push(block),
+            None => return agreed,
+        }
+    }
+}
+
+fn send_udp(socket: &UdpSocket, addr: &SocketAddr, message: &Message) -> Result<usize, P2PError> {
+    let payload = serialize(message).map_err(P2PError::Serialize)?;
+    if payload.len() > MAX_UDP_PAYLOAD {
+        return Err(P2PError::PayloadTooLarge(payload.len()));
+    }
+    Ok(socket.send_to(&payload, addr)?)
+}
+
+/// Writes `payload` prefixed with its length as a big-endian `u32`, so that several messages
+/// can share one connection.
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)
+}
+
+/// Reads one frame written by `write_frame`. Returns `None` if the peer closed the connection
+/// between frames.
+fn read_frame(stream: &mut impl Read) -> Result<Option<Vec<u8>>, P2PError> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > GOSSIP_BUFFER_SIZE {
+        return Err(P2PError::FrameTooLarge(len));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[derive(Default)]
+struct PeerStatus {
+    last_seen: Option<i64>,
+    pinged: bool,
+    answered: bool,
+    missed_pongs: u32,
 }
 
-fn send_udp(socket: &UdpSocket, addr: &SocketAddr, message: Message) -> io::Result<usize> {
-    socket.send_to(&serialize(message).unwrap(), addr)
+const CONTACT_TAG_V4: u8 = 4;
+const CONTACT_TAG_V6: u8 = 6;
+
+/// Encodes contacts for storage: a family tag, then the ip octets and the big endian port.
+pub(crate) fn encode_contacts(contacts: &[SocketAddr]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for addr in contacts {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(CONTACT_TAG_V4);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(CONTACT_TAG_V6);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    bytes
 }
 
-fn send_tcp(stream: &mut TcpStream, message: Message) -> io::Result<usize> {
-    stream.write(&serialize(message).unwrap())
+/// Decodes what `encode_contacts` wrote. Stops at the first unknown tag or truncated entry,
+/// keeping the contacts read up to there.
+fn decode_contacts(mut bytes: &[u8]) -> Vec<SocketAddr> {
+    let mut contacts = vec![];
+    while let Some((&tag, rest)) = bytes.split_first() {
+        let ip_len = match tag {
+            CONTACT_TAG_V4 => 4,
+            CONTACT_TAG_V6 => 16,
+            _ => {
+                tracing::warn!("unknown contact tag {}, dropping the rest of the list", tag);
+                break;
+            }
+        };
+        if rest.len() < ip_len + 2 {
+            tracing::warn!("truncated contact, dropping the rest of the list");
+            break;
+        }
+        let (ip, rest) = rest.split_at(ip_len);
+        let ip: IpAddr = if tag == CONTACT_TAG_V4 {
+            Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into()
+        } else {
+            Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into()
+        };
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        contacts.push(SocketAddr::new(ip, port));
+        bytes = &rest[2..];
+    }
+    contacts
 }
 
 pub struct ClusterInfo {
     keypair: Arc<SigningKey>,
-    contact_list: Vec<SocketAddr>,
+    storage: Arc<dyn Storage>,
+    contact_list: RwLock<Vec<SocketAddr>>,
+    liveness: Mutex<HashMap<SocketAddr, PeerStatus>>,
     boot_nodes: Vec<SocketAddr>,
+    /// The identity tcp connections prove in their noise handshake, if they are encrypted.
+    noise: Option<Arc<SigningKey>>,
 }
 
 impl ClusterInfo {
-    pub fn new(keypair: Arc<SigningKey>, storage: Arc<dyn Storage>) -> Self {
-        let contact_bytes = storage.get_or_set(b"contact_list", b"{}");
-        let contact_list = contact_bytes
-            .chunks_exact(6)
-            .map(Self::ipv4_from_bytes)
-            .collect();
+    pub fn new(
+        keypair: Arc<SigningKey>,
+        storage: Arc<dyn Storage>,
+        boot_nodes: Vec<SocketAddr>,
+    ) -> Result<Self, P2PError> {
+        let storage = storage.namespace(CLUSTER_NAMESPACE)?;
+        let contact_list = decode_contacts(&storage.get_or_set(b"contact_list", b"")?);
 
-        Self {
+        Ok(Self {
             keypair,
-            contact_list,
-            boot_nodes: vec![],
+            storage,
+            contact_list: RwLock::new(contact_list),
+            liveness: Mutex::new(HashMap::new()),
+            boot_nodes,
+            noise: None,
+        })
+    }
+
+    /// Makes every tcp connection, outgoing and incoming, go through a noise handshake keyed by
+    /// this node's identity.
+    pub fn with_encryption(mut self, encrypt: bool) -> Self {
+        self.noise = encrypt.then(|| self.keypair.clone());
+        self
+    }
+
+    fn send_tcp(&self, stream: TcpStream, message: &Message) -> Result<(), P2PError> {
+        let payload = serialize(message).map_err(P2PError::Serialize)?;
+        self.send_tcp_frame(stream, &payload, None)
+    }
+
+    /// Sends `payload` as a single frame. An encrypted stream is only used if the peer proves it
+    /// is `expected`, when that is given.
+    fn send_tcp_frame(
+        &self,
+        mut stream: TcpStream,
+        payload: &[u8],
+        expected: Option<VerificationKeyBytes>,
+    ) -> Result<(), P2PError> {
+        match &self.noise {
+            Some(identity) => EncryptedStream::initiate(stream, identity, expected)?.send(payload),
+            None => Ok(write_frame(&mut stream, payload)?),
         }
     }
 
-    fn ipv4_from_bytes(bytes: &[u8]) -> SocketAddr {
-        let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
-        let port = ((bytes[4] as u16) << 8) | bytes[5] as u16;
-        SocketAddr::new(ip.into(), port)
+    fn persist_contacts(&self, contact_list: &[SocketAddr]) {
+        if let Err(err) = self
+            .storage
+            .set(b"contact_list", &encode_contacts(contact_list))
+        {
+            tracing::warn!("could not persist the contact list: {:?}", err);
+        }
     }
 
-    fn get_discovery_node(&self) -> Option<&SocketAddr> {
-        let rng = &mut thread_rng();
-        if self.contact_list.is_empty() {
-            self.boot_nodes.choose(rng)
+    pub fn contacts(&self) -> Vec<SocketAddr> {
+        self.contact_list.read().unwrap().clone()
+    }
+
+    /// The peers to ask for contacts: the known contacts, falling back to the configured boot
+    /// nodes for a node that has not met anyone yet.
+    fn discovery_nodes(&self) -> Vec<SocketAddr> {
+        let contact_list = self.contact_list.read().unwrap();
+        if contact_list.is_empty() {
+            self.boot_nodes.clone()
         } else {
-            self.contact_list.choose(rng)
+            contact_list.clone()
         }
     }
 
-    fn new_discovery_message(&self) -> Message {
-        let timestamp = Utc::now().timestamp_millis();
-        let msg = r#"{"service": "discovery"}"#.as_bytes();
-        Message::new(
-            VerificationKeyBytes::from(self.keypair.verification_key()),
-            self.keypair.sign(msg),
-            msg.to_vec(),
-            timestamp,
-        )
+    /// Closes the previous round of pings: every contact that did not answer it gets a strike,
+    /// and contacts with `MAX_MISSED_PONGS` strikes in a row are evicted. Returns the contacts
+    /// to ping next.
+    fn ping_round(&self) -> Vec<SocketAddr> {
+        let mut liveness = self.liveness.lock().unwrap();
+        let mut contact_list = self.contact_list.write().unwrap();
+        let known = contact_list.len();
+        contact_list.retain(|addr| {
+            let status = liveness.entry(*addr).or_default();
+            if status.answered {
+                status.missed_pongs = 0;
+            } else if status.pinged {
+                status.missed_pongs += 1;
+            }
+            status.pinged = true;
+            status.answered = false;
+
+            if status.missed_pongs >= MAX_MISSED_PONGS {
+                tracing::debug!("evicting {:?}, it stopped answering pings", addr);
+                liveness.remove(addr);
+                false
+            } else {
+                true
+            }
+        });
+        if contact_list.len() != known {
+            self.persist_contacts(&contact_list);
+        }
+        contact_list.clone()
     }
 
-    fn new_initiate_sync_message(&self, since: DateTime<Utc>) -> Message {
-        // maybe message should be an enum and then we could just match on the deserialized message?
-        let timestamp = Utc::now().timestamp_millis();
-        let msg = format!(r#"{{"service":"block_sync","since":{}}}"#, since);
-        Message::new(
-            VerificationKeyBytes::from(self.keypair.verification_key()),
-            self.keypair.sign(msg.as_bytes()),
-            msg.into_bytes(),
-            timestamp,
-        )
+    fn record_pong(&self, addr: SocketAddr) {
+        if let Some(status) = self.liveness.lock().unwrap().get_mut(&addr) {
+            status.answered = true;
+            status.last_seen = Some(Utc::now().timestamp_millis());
+        }
+    }
+
+    /// How many contacts answered their latest ping.
+    pub fn live_peers(&self) -> usize {
+        self.liveness
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|status| status.last_seen.is_some() && status.missed_pongs == 0)
+            .count()
+    }
+
+    /// When each contact last answered a ping, in milliseconds since the epoch.
+    pub fn last_seen(&self) -> HashMap<SocketAddr, i64> {
+        self.liveness
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(addr, status)| Some((*addr, status.last_seen?)))
+            .collect()
+    }
+
+    fn new_protocol_message(&self, protocol: Protocol) -> Message {
+        Message::signed(&self.keypair, &protocol)
+    }
+
+    fn new_discovery_message(&self, reply_to: SocketAddr, gossip_port: u16) -> Message {
+        self.new_protocol_message(Protocol::Discovery {
+            reply_to,
+            gossip_port,
+        })
+    }
+
+    /// Adds the `contacts` that are not known yet to the contact list, replacing the oldest ones
+    /// on the same host past `MAX_CONTACTS_PER_HOST`, and the oldest ones overall past
+    /// `MAX_CONTACTS`.
+    fn add_contacts(&self, contacts: impl IntoIterator<Item = SocketAddr>) {
+        let mut liveness = self.liveness.lock().unwrap();
+        let mut contact_list = self.contact_list.write().unwrap();
+        let mut changed = false;
+        for addr in contacts {
+            if contact_list.contains(&addr) {
+                continue;
+            }
+            let same_host = |contact: &&SocketAddr| contact.ip() == addr.ip();
+            if contact_list.iter().filter(same_host).count() >= MAX_CONTACTS_PER_HOST {
+                // the list is kept oldest first.
+                let oldest = contact_list.iter().position(|contact| same_host(&contact));
+                liveness.remove(&contact_list.remove(oldest.unwrap()));
+            }
+            contact_list.push(addr);
+            changed = true;
+        }
+        if contact_list.len() > MAX_CONTACTS {
+            let excess = contact_list.len() - MAX_CONTACTS;
+            for addr in contact_list.drain(..excess) {
+                liveness.remove(&addr);
+            }
+        }
+        if changed {
+            self.persist_contacts(&contact_list);
+        }
+    }
+
+    fn new_initiate_sync_message(
+        &self,
+        since: DateTime<Utc>,
+        reply_to: SocketAddr,
+        token: u64,
+    ) -> Message {
+        self.new_protocol_message(Protocol::InitiateSync {
+            since: since.timestamp_millis(),
+            reply_to,
+            token,
+        })
+    }
+}
+
+/// The gossip messages accepted within the last `purge_time`, keyed by content hash. Anything
+/// older is rejected on its timestamp alone, so it can be forgotten.
+struct SeenMessages {
+    logs: HashMap<[u8; 32], i64>,
+    last_sweep: i64,
+    purge_time: i64,
+    sweep_interval: i64,
+}
+
+impl SeenMessages {
+    fn new(now: i64, config: &GossipConfig) -> Self {
+        Self {
+            logs: HashMap::new(),
+            last_sweep: now,
+            purge_time: config.purge_time_ms,
+            sweep_interval: config.recv_timeout_ms as i64,
+        }
+    }
+
+    /// Records `msg` if it is fresh, that is recent enough, not stamped too far ahead of `now` and
+    /// not seen before.
+    fn observe(&mut self, msg: &Message, now: i64) -> Observation {
+        let age = now.saturating_sub(msg.timestamp);
+        if age >= self.purge_time || age < -MAX_CLOCK_DRIFT_MS {
+            return Observation::Expired;
+        }
+        let hash = msg.content_hash();
+        if self.logs.contains_key(&hash) {
+            return Observation::Duplicate;
+        }
+        self.logs.insert(hash, msg.timestamp);
+        Observation::Fresh
+    }
+
+    /// Drops the entries that aged past `purge_time`, at most once per `sweep_interval`.
+    fn maybe_sweep(&mut self, now: i64) {
+        if now - self.last_sweep < self.sweep_interval {
+            return;
+        }
+        self.last_sweep = now;
+        let purge_time = self.purge_time;
+        self.logs
+            .retain(|_, timestamp| now.saturating_sub(*timestamp) < purge_time);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Observation {
+    Fresh,
+    Duplicate,
+    /// Stamped too long ago, or too far ahead of our clock.
+    Expired,
+}
+
+/// Counters for the gossip pipeline, shared between its threads and whoever reports on it.
+#[derive(Debug, Default)]
+pub struct GossipStats {
+    pub datagrams_received: AtomicU64,
+    pub messages_deserialized: AtomicU64,
+    pub signatures_verified: AtomicU64,
+    pub duplicates_dropped: AtomicU64,
+    pub expired_dropped: AtomicU64,
+}
+
+impl GossipStats {
+    fn add(counter: &AtomicU64, count: usize) {
+        counter.fetch_add(count as u64, Ordering::Relaxed);
     }
 }
 
@@ -246,85 +933,435 @@ pub struct GossipMessage {
     message: Vec<u8>,
 }
 
+impl GossipMessage {
+    /// The raw public key of whoever pushed the message, as it is hashed.
+    pub fn author(&self) -> [u8; 32] {
+        self.author
+    }
+
+    /// The transaction the message carries, which has to be signed by its author for other
+    /// validators to check it once it is in a block.
+    pub fn transaction(&self) -> Result<ContractRequest, P2PError> {
+        let request =
+            ContractRequest::from_bytes(&self.message).map_err(P2PError::MalformedTransaction)?;
+        request
+            .verify()
+            .then_some(request)
+            .ok_or(P2PError::ForgedTransaction)
+    }
+}
+
+/// A block a peer announced, for the validator to check against the schedule before it is
+/// applied.
+pub struct GossipedBlock {
+    /// Whoever signed the announcement.
+    pub signer: [u8; 32],
+    pub block: Block,
+}
+
+/// Sends `message` to `fanout` random contacts, over tcp when it does not fit in a datagram.
+fn send_to_peers(
+    cluster_info: &ClusterInfo,
+    socket: &UdpSocket,
+    fanout: usize,
+    message: &Message,
+) -> Result<(), P2PError> {
+    let oversized = serialize(message).map_err(P2PError::Serialize)?.len() > MAX_UDP_PAYLOAD;
+
+    let contacts = cluster_info.contacts();
+    for addr in contacts.choose_multiple(&mut thread_rng(), fanout) {
+        let sent = if oversized {
+            TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)
+                .map_err(P2PError::from)
+                .and_then(|stream| cluster_info.send_tcp(stream, message))
+        } else {
+            send_udp(socket, addr, message).map(drop)
+        };
+        if let Err(err) = sent {
+            tracing::debug!("could not send to {:?}: {:?}", addr, err);
+        }
+    }
+    Ok(())
+}
+
+/// A started `GossipService`, with the pushed messages and announced blocks it receives, and
+/// its counters.
+pub type GossipChannels = (
+    GossipService,
+    Receiver<GossipMessage>,
+    Receiver<GossipedBlock>,
+    Arc<GossipStats>,
+);
+
 pub struct GossipService {
     threads: Vec<JoinHandle<()>>,
+    cluster_info: Arc<ClusterInfo>,
+    socket: Arc<UdpSocket>,
+    config: GossipConfig,
 }
 
 impl GossipService {
     pub fn new(
         cluster_info: Arc<ClusterInfo>,
-        socket: UdpSocket,
+        chain: Arc<Chain>,
+        mempool: Arc<Mempool>,
+        addr: &str,
+        config: &GossipConfig,
         exit: &Arc<AtomicBool>,
-    ) -> (Self, Receiver<GossipMessage>) {
+    ) -> Result<GossipChannels, P2PError> {
+        let socket =
+            UdpSocket::bind(addr).map_err(|err| P2PError::Bind(String::from(addr), err))?;
+        let local_addr = socket.local_addr()?;
+        // blocks too large for a datagram arrive over tcp, on the same port.
+        let listener = TcpListener::bind(local_addr)
+            .map_err(|err| P2PError::Bind(local_addr.to_string(), err))?;
         let socket = Arc::new(socket);
 
-        let mut gossip = GossipService { threads: vec![] };
+        let mut gossip = GossipService {
+            threads: vec![],
+            cluster_info: cluster_info.clone(),
+            socket: socket.clone(),
+            config: config.clone(),
+        };
 
-        tracing::info!("Listening on {}.", socket.local_addr().unwrap());
+        tracing::info!("Listening on {}.", local_addr);
 
         let (req_send, req_recv) = channel();
+        let stats = Arc::new(GossipStats::default());
 
         let exit = exit.clone();
-        let h_receiver = udp_receiver(socket, req_send, &exit, "gossip");
+        let h_receiver = udp_receiver(
+            socket.clone(),
+            req_send.clone(),
+            &exit,
+            &stats,
+            config.recv_timeout(),
+            "gossip",
+        );
+        let h_tcp_receiver = tcp_receiver(
+            listener,
+            req_send,
+            |frame, from| vec![(frame, from)],
+            &exit,
+            "gossip-tcp",
+            cluster_info.noise.clone(),
+        );
 
         let (consume_send, consume_recv) = channel();
-        let h_socket_consume = Self::signature_verifier(consume_send, req_recv, exit.clone());
+        let h_socket_consume =
+            Self::signature_verifier(consume_send, req_recv, config, stats.clone(), exit.clone());
 
         let (validator_send, validator_recv) = channel();
-        let h_listener = Self::listen(consume_recv, validator_send, exit);
-        gossip.threads = vec![h_receiver, h_socket_consume, h_listener];
+        let (blocks_send, blocks_recv) = channel();
+        let (reply_send, reply_recv) = sync_channel(REPLY_QUEUE);
+        let h_listener = Self::listen(
+            consume_recv,
+            validator_send,
+            blocks_send,
+            cluster_info.clone(),
+            mempool,
+            socket.clone(),
+            reply_send,
+            config.clone(),
+            stats.clone(),
+            exit.clone(),
+        );
+        let h_reply_server = Self::reply_server(
+            reply_recv,
+            cluster_info.clone(),
+            chain,
+            config.recv_timeout(),
+            exit.clone(),
+        );
+        let h_heartbeat = Self::heartbeat(cluster_info, socket, config.recv_timeout(), exit);
+        gossip.threads = vec![
+            h_receiver,
+            h_tcp_receiver,
+            h_socket_consume,
+            h_listener,
+            h_reply_server,
+            h_heartbeat,
+        ];
+
+        Ok((gossip, validator_recv, blocks_recv, stats))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, P2PError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// The next batch of blocks our peers agree on after the head of `chain`, for the validator
+    /// to check and apply. There are none once `chain` is caught up.
+    pub fn sync(&self, chain: &Chain) -> Result<Vec<Block>, P2PError> {
+        let since = chain.latest_block()?.map_or(0, |block| block.time());
+        let since = DateTime::from_timestamp_millis(since).unwrap_or_default();
+        // the replies come in on a port of our own, next to the gossip one.
+        let listener = TcpListener::bind((self.local_addr()?.ip(), 0))?;
+        block_sync(listener, since, &self.cluster_info, chain)
+    }
+
+    /// Tells the peers we know of, or the boot nodes if we know nobody, where we gossip, and keeps
+    /// them and the contacts they answer with. Returns how many contacts we have then.
+    pub fn discover(&self) -> Result<usize, P2PError> {
+        let asked = self.cluster_info.discovery_nodes();
+        if asked.is_empty() {
+            return Ok(0);
+        }
+        let local_addr = self.local_addr()?;
+        let listener = TcpListener::bind((local_addr.ip(), 0))?;
+        let discovered = discover(listener, &self.cluster_info, local_addr.port(), 1)?;
+        let contacts = asked.into_iter().chain(discovered);
+        self.cluster_info
+            .add_contacts(contacts.filter(|addr| *addr != local_addr));
+        Ok(self.cluster_info.contacts().len())
+    }
 
-        (gossip, validator_recv)
+    /// How many contacts answered their latest ping.
+    pub fn live_peers(&self) -> usize {
+        self.cluster_info.live_peers()
     }
 
+    /// When each contact last answered a ping, in milliseconds since the epoch.
+    pub fn last_seen(&self) -> HashMap<SocketAddr, i64> {
+        self.cluster_info.last_seen()
+    }
+
+    /// Signs `protocol` and sends it to `fanout` random contacts.
+    pub fn broadcast(&self, protocol: Protocol) -> Result<(), P2PError> {
+        let message = self.cluster_info.new_protocol_message(protocol);
+        send_to_peers(
+            &self.cluster_info,
+            &self.socket,
+            self.config.fanout,
+            &message,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn listen(
-        receiver: BufferedReceiver<Message>,
+        receiver: BufferedReceiver<(Message, SocketAddr)>,
         sender: Sender<GossipMessage>,
+        blocks: Sender<GossipedBlock>,
+        cluster_info: Arc<ClusterInfo>,
+        mempool: Arc<Mempool>,
+        socket: Arc<UdpSocket>,
+        replies: SyncSender<(Reply, SocketAddr, VerificationKeyBytes)>,
+        config: GossipConfig,
+        stats: Arc<GossipStats>,
         exit: Arc<AtomicBool>,
     ) -> JoinHandle<()> {
         thread::Builder::new()
             .name("listen".to_string())
             .spawn(move || {
-                let mut logs = HashMap::new();
+                let mut seen = SeenMessages::new(Utc::now().timestamp_millis(), &config);
 
-                const PURGE_TIME: i64 = 120 * 1000;
                 while !exit.load(Ordering::Relaxed) {
-                    if let Ok(messages) = receiver.recv_timeout(RECV_TIMEOUT) {
-                        let valid_messages: Vec<_> = messages
-                            .iter()
-                            .filter_map(|msg| {
-                                if Utc::now().timestamp_millis() - msg.timestamp < PURGE_TIME
-                                    && !logs.contains_key(&msg.timestamp)
+                    seen.maybe_sweep(Utc::now().timestamp_millis());
+                    if let Ok(messages) = receiver.recv_timeout(config.recv_timeout()) {
+                        let now = Utc::now().timestamp_millis();
+                        let valid_messages =
+                            messages
+                                .into_iter()
+                                .filter(|(msg, _)| match seen.observe(msg, now) {
+                                    Observation::Fresh => true,
+                                    Observation::Duplicate => {
+                                        GossipStats::add(&stats.duplicates_dropped, 1);
+                                        false
+                                    }
+                                    Observation::Expired => {
+                                        GossipStats::add(&stats.expired_dropped, 1);
+                                        false
+                                    }
+                                });
+
+                        for (msg, from) in valid_messages {
+                            match msg.protocol() {
+                                Ok(Protocol::GossipPush(data)) => {
+                                    let message = GossipMessage {
+                                        author: msg.pubkey.to_bytes(),
+                                        message: data,
+                                    };
+                                    if sender.send(message).is_err() {
+                                        return;
+                                    }
+                                }
+                                Ok(Protocol::Ping) => {
+                                    let pong = cluster_info.new_protocol_message(Protocol::Pong);
+                                    if let Err(err) = send_udp(&socket, &from, &pong) {
+                                        tracing::debug!("could not pong {:?}: {:?}", from, err);
+                                    }
+                                }
+                                Ok(Protocol::Pong) => cluster_info.record_pong(from),
+                                Ok(Protocol::Transaction(request)) => {
+                                    if !request.verify() {
+                                        tracing::debug!("forged transaction from {:?}", from);
+                                        continue;
+                                    }
+                                    // relay the signed original, so peers dedup it like we did.
+                                    if mempool.insert(request) {
+                                        let relayed = send_to_peers(
+                                            &cluster_info,
+                                            &socket,
+                                            config.fanout,
+                                            &msg,
+                                        );
+                                        if let Err(err) = relayed {
+                                            tracing::debug!("could not relay: {:?}", err);
+                                        }
+                                    }
+                                }
+                                Ok(Protocol::NewBlock(bytes)) => {
+                                    let block = match serde_json::from_slice(&bytes) {
+                                        Ok(block) => block,
+                                        Err(err) => {
+                                            let err = P2PError::MalformedBlock(err);
+                                            tracing::debug!("bad block from {:?}: {}", from, err);
+                                            continue;
+                                        }
+                                    };
+                                    let gossiped = GossipedBlock {
+                                        signer: msg.pubkey.to_bytes(),
+                                        block,
+                                    };
+                                    if blocks.send(gossiped).is_err() {
+                                        return;
+                                    }
+                                }
+                                // only the peer that asked gets the blocks, so a request can not
+                                // point us at someone else.
+                                Ok(Protocol::InitiateSync { reply_to, .. })
+                                    if reply_to.ip() != from.ip() =>
                                 {
-                                    logs.insert(msg.timestamp, msg.signature);
-                                    Some((&msg.data, msg.pubkey.to_bytes()))
-                                } else {
-                                    None
+                                    tracing::debug!("{:?} asked to sync {:?}", from, reply_to)
                                 }
-                            })
-                            .collect();
-
-                        valid_messages.iter().for_each(|data| {
-                            sender
-                                .send(GossipMessage {
-                                    author: data.1,
-                                    message: data.0.to_vec(),
-                                })
-                                .unwrap()
-                        });
+                                Ok(Protocol::InitiateSync {
+                                    since,
+                                    reply_to,
+                                    token,
+                                }) => {
+                                    let reply =
+                                        (Reply::Blocks { since, token }, reply_to, msg.pubkey);
+                                    match replies.try_send(reply) {
+                                        Err(TrySendError::Full(_)) => {
+                                            tracing::debug!("too busy to sync {:?}", from)
+                                        }
+                                        Err(TrySendError::Disconnected(_)) => return,
+                                        Ok(()) => {}
+                                    }
+                                }
+                                Ok(Protocol::Discovery { reply_to, .. })
+                                    if reply_to.ip() != from.ip() =>
+                                {
+                                    tracing::debug!("{:?} asked to discover {:?}", from, reply_to)
+                                }
+                                Ok(Protocol::Discovery {
+                                    reply_to,
+                                    gossip_port,
+                                }) => {
+                                    // the port is all it tells us, so it can only be a contact
+                                    // on its own address.
+                                    let contact = SocketAddr::new(from.ip(), gossip_port);
+                                    cluster_info.add_contacts([contact]);
+                                    match replies.try_send((Reply::Contacts, reply_to, msg.pubkey))
+                                    {
+                                        Err(TrySendError::Full(_)) => {
+                                            tracing::debug!("too busy to answer {:?}", from)
+                                        }
+                                        Err(TrySendError::Disconnected(_)) => return,
+                                        Ok(()) => {}
+                                    }
+                                }
+                                Ok(protocol) => {
+                                    tracing::debug!("{:?} from {:?} is tcp only", protocol, from)
+                                }
+                                Err(err) => {
+                                    tracing::debug!("malformed message from {:?}: {:?}", from, err)
+                                }
+                            }
+                        }
                     }
                 }
             })
             .unwrap()
     }
 
+    /// Serves the sync and discovery requests `listen` queued, one at a time, so that reading
+    /// and sending replies never holds up gossip.
+    fn reply_server(
+        requests: Receiver<(Reply, SocketAddr, VerificationKeyBytes)>,
+        cluster_info: Arc<ClusterInfo>,
+        chain: Arc<Chain>,
+        recv_timeout: Duration,
+        exit: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("reply-server".to_string())
+            .spawn(move || {
+                while !exit.load(Ordering::Relaxed) {
+                    match requests.recv_timeout(recv_timeout) {
+                        Ok((reply, reply_to, requester)) => {
+                            let served = match reply {
+                                Reply::Blocks { since, token } => serve_sync(
+                                    &cluster_info,
+                                    &chain,
+                                    since,
+                                    token,
+                                    reply_to,
+                                    requester,
+                                ),
+                                Reply::Contacts => {
+                                    serve_discovery(&cluster_info, reply_to, requester)
+                                }
+                            };
+                            if let Err(err) = served {
+                                tracing::debug!("could not reply to {:?}: {:?}", reply_to, err);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    /// Pings every contact each `PING_INTERVAL`, evicting the ones that stopped answering.
+    fn heartbeat(
+        cluster_info: Arc<ClusterInfo>,
+        socket: Arc<UdpSocket>,
+        recv_timeout: Duration,
+        exit: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("heartbeat".to_string())
+            .spawn(move || {
+                let mut last_round: Option<Instant> = None;
+                while !exit.load(Ordering::Relaxed) {
+                    if last_round.is_none_or(|at| at.elapsed() >= PING_INTERVAL) {
+                        last_round = Some(Instant::now());
+                        for addr in cluster_info.ping_round() {
+                            let ping = cluster_info.new_protocol_message(Protocol::Ping);
+                            if let Err(err) = send_udp(&socket, &addr, &ping) {
+                                tracing::debug!("could not ping {:?}: {:?}", addr, err);
+                            }
+                        }
+                    }
+                    thread::sleep(recv_timeout);
+                }
+            })
+            .unwrap()
+    }
+
     fn signature_verifier(
-        sender: BufferedSender<Message>,
-        receiver: BufferedReceiver<Vec<u8>>,
+        sender: BufferedSender<(Message, SocketAddr)>,
+        receiver: BufferedReceiver<(Vec<u8>, SocketAddr)>,
+        config: &GossipConfig,
+        stats: Arc<GossipStats>,
         exit: Arc<AtomicBool>,
     ) -> JoinHandle<()> {
+        let recv_timeout = config.recv_timeout();
         let thread_pool = ThreadPoolBuilder::new()
-            .num_threads(8)
+            .num_threads(config.threads)
             .thread_name(|i| format!("teral-socket-consume({})", i))
             .build()
             .unwrap();
@@ -333,7 +1370,13 @@ impl GossipService {
             .name("socket-consume".to_string())
             .spawn(move || {
                 while !exit.load(Ordering::Relaxed) {
-                    match Self::signature_verifier_thread(&thread_pool, &sender, &receiver) {
+                    match Self::signature_verifier_thread(
+                        &thread_pool,
+                        &sender,
+                        &receiver,
+                        recv_timeout,
+                        &stats,
+                    ) {
                         Err(P2PError::ReceiverTimeout(_)) => tracing::debug!("timeout somehow"),
                         Err(P2PError::Sender) => break,
                         Err(P2PError::ReceiverDisconnect) => break,
@@ -347,22 +1390,24 @@ impl GossipService {
 
     fn signature_verifier_thread(
         thread_pool: &ThreadPool,
-        sender: &BufferedSender<Message>,
-        receiver: &BufferedReceiver<Vec<u8>>,
+        sender: &BufferedSender<(Message, SocketAddr)>,
+        receiver: &BufferedReceiver<(Vec<u8>, SocketAddr)>,
+        recv_timeout: Duration,
+        stats: &GossipStats,
     ) -> Result<(), P2PError> {
-        let verify_sig = |data: Vec<u8>| {
-            let message: bincode::Result<Message> = deserialize(&data);
-            match message {
-                Ok(message) => Some(message.verify()?),
-                Err(_) => None,
-            }
+        let decode = |(data, from): (Vec<u8>, SocketAddr)| {
+            let message: Message = deserialize(&data).ok()?;
+            Some((message, from))
         };
 
-        let packets = receiver.recv_timeout(RECV_TIMEOUT)?;
+        let packets = receiver.recv_timeout(recv_timeout)?;
         let packets: Vec<_> =
-            thread_pool.install(|| packets.into_par_iter().filter_map(verify_sig).collect());
+            thread_pool.install(|| packets.into_par_iter().filter_map(decode).collect());
+        GossipStats::add(&stats.messages_deserialized, packets.len());
 
-        Ok(sender.send(packets)?)
+        let verified = verify_batch_by(packets, |(message, _)| message);
+        GossipStats::add(&stats.signatures_verified, verified.len());
+        Ok(sender.send(verified)?)
     }
 
     pub fn join(self) -> thread::Result<()> {
@@ -375,76 +1420,1097 @@ impl GossipService {
 
 fn udp_receiver(
     socket: Arc<UdpSocket>,
-    channel: BufferedSender<Vec<u8>>,
+    channel: BufferedSender<(Vec<u8>, SocketAddr)>,
     exit: &Arc<AtomicBool>,
+    stats: &Arc<GossipStats>,
+    recv_timeout: Duration,
     name: &str,
 ) -> JoinHandle<()> {
     let exit = exit.clone();
+    let stats = stats.clone();
 
     thread::Builder::new()
         .name(String::from(name))
         .spawn(move || {
-            let _ = udp_recv_loop(&socket, channel, exit.clone());
+            if let Err(err) = udp_recv_loop(&socket, channel, &stats, recv_timeout, exit.clone()) {
+                tracing::error!("udp receiver: {:?}", err);
+            }
         })
         .unwrap()
 }
 
 fn udp_recv_loop(
     socket: &UdpSocket,
-    channel: BufferedSender<Vec<u8>>,
+    channel: BufferedSender<(Vec<u8>, SocketAddr)>,
+    stats: &GossipStats,
+    recv_timeout: Duration,
     exit: Arc<AtomicBool>,
 ) -> Result<(), P2PError> {
-    socket.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+    socket.set_read_timeout(Some(recv_timeout))?;
+    let mut buf = [0; GOSSIP_BUFFER_SIZE];
     loop {
-        let mut msg_buf = Vec::new();
-        msg_buf.reserve(RECEIVER_BUFSIZE);
+        let mut msg_buf = Vec::with_capacity(RECEIVER_BUFSIZE);
         while msg_buf.len() < RECEIVER_BUFSIZE {
             if exit.load(Ordering::Relaxed) {
                 return Ok(());
             }
 
-            let mut buf = [0; GOSSIP_BUFFER_SIZE];
             match socket.recv_from(&mut buf) {
-                Ok((len, _)) if len > 0 => msg_buf.push(buf[..len].to_vec()),
+                Ok((len, from)) if len > 0 => msg_buf.push((buf[..len].to_vec(), from)),
+                // a quiet socket should not hold back what already arrived, e.g. a pong.
+                Err(_) if !msg_buf.is_empty() => break,
                 _ => {}
             }
         }
-        channel.send(msg_buf).unwrap();
+        GossipStats::add(&stats.datagrams_received, msg_buf.len());
+        if channel.send(msg_buf).is_err() {
+            // the consumer hung up, so we are shutting down.
+            return Ok(());
+        }
     }
 }
 
-fn tcp_receiver(
+/// Accepts connections on `listener` and forwards every frame they carry to `channel`, along
+/// with the address of the peer that sent it, as built by `wrap`. Each connection is served on its
+/// own thread, up to `MAX_TCP_CONNECTIONS` at once and for `CONNECTION_DEADLINE` at most.
+fn tcp_receiver<T: Send + 'static>(
     listener: TcpListener,
-    channel: Sender<Vec<u8>>,
+    channel: Sender<T>,
+    wrap: fn(Vec<u8>, SocketAddr) -> T,
     exit: &Arc<AtomicBool>,
     name: &str,
+    noise: Option<Arc<SigningKey>>,
 ) -> JoinHandle<()> {
     let exit = exit.clone();
 
     thread::Builder::new()
         .name(String::from(name))
         .spawn(move || {
-            let _ = tcp_recv_loop(listener, channel, exit);
+            if let Err(err) = tcp_recv_loop(listener, channel, wrap, exit, noise) {
+                tracing::error!("tcp receiver: {:?}", err);
+            }
         })
         .unwrap()
 }
 
-fn tcp_recv_loop(
+/// A connection a receiver is serving on its own thread, which returns whether the consumer hung
+/// up.
+struct TcpConnection {
+    deadline: Instant,
+    stream: TcpStream,
+    handle: JoinHandle<bool>,
+}
+
+fn tcp_recv_loop<T: Send + 'static>(
     listener: TcpListener,
-    channel: Sender<Vec<u8>>,
+    channel: Sender<T>,
+    wrap: fn(Vec<u8>, SocketAddr) -> T,
     exit: Arc<AtomicBool>,
+    noise: Option<Arc<SigningKey>>,
 ) -> Result<(), P2PError> {
     listener.set_nonblocking(true)?;
+    let mut connections: Vec<TcpConnection> = vec![];
     loop {
         if exit.load(Ordering::Relaxed) {
-            return Ok(());
+            break;
+        }
+
+        let (finished, running) = connections
+            .into_iter()
+            .partition::<Vec<_>, _>(|connection| connection.handle.is_finished());
+        connections = running;
+        if finished
+            .into_iter()
+            .any(|connection| connection.handle.join().unwrap_or(false))
+        {
+            // the consumer hung up, so we are shutting down.
+            break;
+        }
+        for connection in &connections {
+            if Instant::now() >= connection.deadline {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        let (stream, addr) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(_) => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+        if connections.len() >= MAX_TCP_CONNECTIONS {
+            tracing::debug!("too many connections, closing the one from {:?}", addr);
+            continue;
+        }
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(RECV_TIMEOUT));
+        let Ok(watched) = stream.try_clone() else {
+            continue;
+        };
+        let (channel, noise) = (channel.clone(), noise.clone());
+        let handle = thread::spawn(move || {
+            let send = |frame| channel.send(wrap(frame, addr)).is_ok();
+            match &noise {
+                Some(identity) => match EncryptedStream::respond(stream, identity) {
+                    Ok(mut stream) => {
+                        tracing::trace!("{:?} proved it is {:?}", addr, stream.remote_identity());
+                        forward_frames(|| stream.recv(), send)
+                    }
+                    Err(err) => {
+                        tracing::debug!("handshake with {:?} failed: {:?}", addr, err);
+                        false
+                    }
+                },
+                None => {
+                    let mut stream = stream;
+                    forward_frames(|| read_frame(&mut stream), send)
+                }
+            }
+        });
+        connections.push(TcpConnection {
+            deadline: Instant::now() + CONNECTION_DEADLINE,
+            stream: watched,
+            handle,
+        });
+    }
+
+    for connection in connections {
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+    Ok(())
+}
+
+/// Hands frames to `send` until the connection is closed, goes quiet or misbehaves. Returns
+/// whether the consumer hung up, which `send` signals by returning false.
+fn forward_frames(
+    mut next_frame: impl FnMut() -> Result<Option<Vec<u8>>, P2PError>,
+    send: impl Fn(Vec<u8>) -> bool,
+) -> bool {
+    while let Ok(Some(frame)) = next_frame() {
+        if !send(frame) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, sync_channel, Receiver},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use chrono::Utc;
+    use ed25519_consensus::SigningKey;
+    use serde_json::json;
+    use serial_test::serial;
+
+    use crate::{
+        chain::{requests_to_recipts, Chain},
+        config::{GossipConfig, NetworkConfig, StorageConfig},
+        contracts::ContractRequest,
+        storage::{RocksdbStorage, Storage},
+        validator::Mempool,
+    };
+
+    use super::{
+        blocks_after, decode_contacts, deserialize, discover, encode_contacts, read_frame,
+        send_udp, sync_from_voters, tcp_receiver, verify_batch_by, write_frame, Block, ClusterInfo,
+        GossipMessage, GossipService, GossipStats, GossipedBlock, Message, Observation, P2PError,
+        Protocol, SeenMessages, SyncResponse, BLOCK_SYNC_BATCH, CLUSTER_NAMESPACE,
+        GOSSIP_BUFFER_SIZE, MAX_CLOCK_DRIFT_MS, MAX_CONTACTS, MAX_CONTACTS_PER_HOST,
+        MAX_MISSED_PONGS, MAX_UDP_PAYLOAD, RECV_TIMEOUT,
+    };
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
+    fn fresh_storage(path: &str) -> Arc<dyn Storage> {
+        let _ = fs::remove_dir_all(path);
+        RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        })
+    }
+
+    /// Syncs a fresh chain from one voter per entry of `votes`, each answering with its block as
+    /// many times as given, and once more with a token it was not given.
+    fn sync_from(votes: Vec<(Block, usize)>) -> Vec<Block> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let voters: Vec<SocketAddr> = votes
+            .into_iter()
+            .map(|(block, copies)| {
+                let voter = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = voter.local_addr().unwrap();
+                thread::spawn(move || {
+                    let (mut stream, _) = voter.accept().unwrap();
+                    let request = read_frame(&mut stream).unwrap().unwrap();
+                    let request = deserialize::<Message>(&request).unwrap();
+                    let Ok(Protocol::InitiateSync { token, .. }) = request.protocol() else {
+                        panic!("expected a sync request");
+                    };
+                    let tokens = std::iter::repeat_n(token, copies);
+                    for token in tokens.chain([token.wrapping_add(1)]) {
+                        let blocks = vec![block.clone()];
+                        let response = serde_json::to_vec(&SyncResponse { token, blocks }).unwrap();
+                        let mut stream = TcpStream::connect(listener_addr).unwrap();
+                        write_frame(&mut stream, &response).unwrap();
+                    }
+                });
+                addr
+            })
+            .collect();
+
+        let storage = fresh_storage("db-sync-local/");
+        let cluster_info = ClusterInfo::new(
+            Arc::new(SigningKey::new(&mut rand::thread_rng())),
+            storage.clone(),
+            vec![],
+        )
+        .unwrap();
+        let chain = Chain::new(storage, [2; 32]).unwrap();
+        let blocks = sync_from_voters(
+            listener,
+            Utc::now(),
+            &cluster_info,
+            &voters.iter().collect::<Vec<_>>(),
+            &chain,
+        )
+        .unwrap();
+
+        drop((chain, cluster_info));
+        fs::remove_dir_all("db-sync-local/").unwrap();
+        blocks
+    }
+
+    #[test]
+    #[serial]
+    fn block_sync_follows_the_majority() {
+        let peer_chain = Chain::new(fresh_storage("db-sync-peer/"), [1; 32]).unwrap();
+        let honest = peer_chain.block_with_transactions(vec![], now());
+        thread::sleep(Duration::from_millis(2));
+        let rogue = peer_chain.block_with_transactions(vec![], now());
+
+        let blocks = sync_from(vec![(honest.clone(), 1), (honest.clone(), 1), (rogue, 1)]);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].digest(), honest.digest());
+
+        drop(peer_chain);
+        fs::remove_dir_all("db-sync-peer/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn a_voter_is_counted_once() {
+        let peer_chain = Chain::new(fresh_storage("db-sync-peer/"), [1; 32]).unwrap();
+        let honest = peer_chain.block_with_transactions(vec![], now());
+        thread::sleep(Duration::from_millis(2));
+        let rogue = peer_chain.block_with_transactions(vec![], now());
+
+        // answering again, or with a token it was not given, does not make the rogue a majority.
+        let blocks = sync_from(vec![(rogue.clone(), 4), (honest.clone(), 1), (honest, 1)]);
+        assert_eq!(blocks.len(), 1);
+        assert_ne!(blocks[0].digest(), rogue.digest());
+        let blocks = sync_from(vec![
+            (rogue, 4),
+            (peer_chain.block_with_transactions(vec![], 0), 1),
+        ]);
+        assert!(blocks.is_empty());
+
+        drop(peer_chain);
+        fs::remove_dir_all("db-sync-peer/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn sync_walks_forward_from_since() {
+        let chain = Chain::new(fresh_storage("db-sync-batch/"), [1; 32]).unwrap();
+        for _ in 0..BLOCK_SYNC_BATCH + 6 {
+            chain
+                .insert_block(chain.block_with_transactions(vec![], now()))
+                .unwrap();
+        }
+        let heights = |since| -> Vec<u64> {
+            let blocks = blocks_after(&chain, since).unwrap();
+            blocks.iter().map(Block::height).collect()
+        };
+
+        assert_eq!(
+            heights(0),
+            (1..=BLOCK_SYNC_BATCH as u64).collect::<Vec<_>>()
+        );
+        let since = chain.block_by_height(64).unwrap().unwrap().time();
+        assert_eq!(heights(since), (65..=70).collect::<Vec<_>>());
+        assert!(heights(chain.latest_block().unwrap().unwrap().time()).is_empty());
+
+        drop(chain);
+        fs::remove_dir_all("db-sync-batch/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn sync_replies_only_to_the_requester() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let node = start_gossip_node("db-sync-server/", &[], &exit, true);
+        for _ in 0..3 {
+            let block = node.chain.block_with_transactions(vec![], now());
+            node.chain.insert_block(block).unwrap();
+        }
+        let cluster_info = |path| {
+            let keypair = Arc::new(SigningKey::new(&mut rand::thread_rng()));
+            let cluster_info = ClusterInfo::new(keypair, fresh_storage(path), vec![]).unwrap();
+            cluster_info.with_encryption(true)
+        };
+        let requester = cluster_info("db-sync-requester/");
+        let listen = |cluster_info: &ClusterInfo| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let reply_to = listener.local_addr().unwrap();
+            let (send, recv) = channel();
+            let noise = cluster_info.noise.clone();
+            let handle = tcp_receiver(listener, send, |frame, _| frame, &exit, "sync-reply", noise);
+            (reply_to, recv, handle)
+        };
+        let request_sync = |reply_to| {
+            let stream = TcpStream::connect(node.gossip.local_addr().unwrap()).unwrap();
+            let since = chrono::DateTime::from_timestamp_millis(0).unwrap();
+            let request = requester.new_initiate_sync_message(since, reply_to, 7);
+            requester.send_tcp(stream, &request).unwrap();
+        };
+
+        // we connect from 127.0.0.1, so a reply to 127.0.0.2 would be a reflection.
+        let elsewhere = TcpListener::bind("127.0.0.2:0").unwrap();
+        elsewhere.set_nonblocking(true).unwrap();
+        request_sync(elsewhere.local_addr().unwrap());
+        thread::sleep(Duration::from_secs(1));
+        assert!(elsewhere.accept().is_err());
+
+        // the blocks only go to a listener that proves it is the requester.
+        let impostor = cluster_info("db-sync-impostor/");
+        let (reply_to, impostor_recv, impostor_handle) = listen(&impostor);
+        request_sync(reply_to);
+        assert!(impostor_recv.recv_timeout(Duration::from_secs(1)).is_err());
+
+        let (reply_to, recv, handle) = listen(&requester);
+        request_sync(reply_to);
+        let reply = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        let reply: SyncResponse = serde_json::from_slice(&reply).unwrap();
+        assert_eq!((reply.token, reply.blocks.len()), (7, 3));
+
+        exit.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        impostor_handle.join().unwrap();
+        node.gossip.join().unwrap();
+        drop((node.chain, node.mempool, requester, impostor));
+        fs::remove_dir_all("db-sync-server/").unwrap();
+        fs::remove_dir_all("db-sync-requester/").unwrap();
+        fs::remove_dir_all("db-sync-impostor/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn known_nodes_seed_discovery() {
+        let network: NetworkConfig = toml::from_str(
+            r#"
+            addr = "127.0.0.1:0"
+            known_nodes = ["10.0.0.1:8000", "10.0.0.2:8000"]
+            "#,
+        )
+        .unwrap();
+        let storage = fresh_storage("db-known-nodes/");
+        let cluster_info = ClusterInfo::new(
+            Arc::new(SigningKey::new(&mut rand::thread_rng())),
+            storage,
+            network.known_nodes.clone(),
+        )
+        .unwrap();
+        assert!(cluster_info.contacts().is_empty());
+        assert_eq!(cluster_info.discovery_nodes(), network.known_nodes);
+
+        drop(cluster_info);
+        fs::remove_dir_all("db-known-nodes/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn contacts_are_capped_oldest_first() {
+        let storage = fresh_storage("db-contact-cap/");
+        let keypair = Arc::new(SigningKey::new(rand::thread_rng()));
+        let cluster_info = ClusterInfo::new(keypair, storage, vec![]).unwrap();
+
+        // a single host only keeps its newest contacts.
+        let one_host = (0..MAX_CONTACTS_PER_HOST as u16 + 4)
+            .map(|port| SocketAddr::from(([10, 0, 0, 1], 8000 + port)))
+            .collect::<Vec<_>>();
+        cluster_info.add_contacts(one_host.clone());
+        assert_eq!(cluster_info.contacts(), one_host[4..]);
+
+        let many_hosts = (0..MAX_CONTACTS as u32)
+            .map(|host| SocketAddr::from(((0x0b00_0000 + host).to_be_bytes(), 8000)))
+            .collect::<Vec<_>>();
+        cluster_info.add_contacts(many_hosts.clone());
+        assert_eq!(cluster_info.contacts(), many_hosts);
+
+        drop(cluster_info);
+        fs::remove_dir_all("db-contact-cap/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn discovery_falls_back_to_boot_nodes() {
+        let storage = RocksdbStorage::load(&Default::default())
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap();
+        storage.delete(b"contact_list").unwrap();
+        let keypair = Arc::new(SigningKey::new(&mut rand::thread_rng()));
+
+        let boot_nodes: Vec<SocketAddr> = vec![
+            "10.0.0.1:8000".parse().unwrap(),
+            "10.0.0.2:8000".parse().unwrap(),
+        ];
+        let cluster_info =
+            ClusterInfo::new(keypair.clone(), storage.clone(), boot_nodes.clone()).unwrap();
+        assert_eq!(cluster_info.discovery_nodes(), boot_nodes);
+
+        let lonely = ClusterInfo::new(keypair, storage, vec![]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            discover(listener, &lonely, 9000, 1),
+            Err(P2PError::CannotDiscover)
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn discover_gives_up_when_target_is_unreachable() {
+        let storage = RocksdbStorage::load(&Default::default())
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap();
+        storage.delete(b"contact_list").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let peers: Vec<_> = (0..2)
+            .map(|_| {
+                let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = peer.local_addr().unwrap();
+                let handle = thread::spawn(move || {
+                    let (mut stream, _) = peer.accept().unwrap();
+                    read_frame(&mut stream).unwrap().unwrap();
+                    let keypair = SigningKey::new(&mut rand::thread_rng());
+                    let response =
+                        Message::signed(&keypair, &Protocol::DiscoveryResponse(vec![addr]));
+                    write_frame(
+                        &mut TcpStream::connect(listener_addr).unwrap(),
+                        &bincode::serialize(&response).unwrap(),
+                    )
+                    .unwrap();
+                    peer
+                });
+                (addr, handle)
+            })
+            .collect();
+        let dead_peer = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let boot_nodes = vec![peers[0].0, peers[1].0, dead_peer, peers[0].0];
+        let cluster_info = ClusterInfo::new(
+            Arc::new(SigningKey::new(&mut rand::thread_rng())),
+            storage,
+            boot_nodes,
+        )
+        .unwrap();
+        assert!(matches!(
+            discover(listener, &cluster_info, 9000, 10),
+            Err(P2PError::CannotDiscover)
+        ));
+
+        for (_, handle) in peers {
+            let peer = handle.join().unwrap();
+            peer.set_nonblocking(true).unwrap();
+            assert!(peer.accept().is_err(), "a peer was dialed twice");
         }
-        if let Ok((mut stream, _)) = listener.accept() {
-            let _ = stream.set_read_timeout(Some(RECV_TIMEOUT));
-            let mut buf = Vec::new();
-            if stream.read_to_end(&mut buf).is_ok() {
-                channel.send(buf).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn listen_keeps_distinct_messages_with_equal_timestamps() {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        let timestamp = Utc::now().timestamp_millis();
+        let from: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let message = |data: &[u8]| {
+            let data = bincode::serialize(&Protocol::GossipPush(data.to_vec())).unwrap();
+            let message = Message::new(
+                keypair.verification_key().into(),
+                keypair.sign(&[data.as_slice(), &timestamp.to_le_bytes()].concat()),
+                data,
+                timestamp,
+            );
+            (message, from)
+        };
+
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&Default::default());
+        let cluster_info =
+            Arc::new(ClusterInfo::new(Arc::new(keypair.clone()), storage.clone(), vec![]).unwrap());
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let (send, recv) = channel();
+        let (gossip_send, gossip_recv) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+        let handle = GossipService::listen(
+            recv,
+            gossip_send,
+            channel().0,
+            cluster_info,
+            Arc::new(Mempool::new(16)),
+            socket,
+            sync_channel(1).0,
+            GossipConfig::default(),
+            Arc::new(GossipStats::default()),
+            exit.clone(),
+        );
+
+        send.send(vec![
+            message(b"first"),
+            message(b"second"),
+            message(b"first"),
+        ])
+        .unwrap();
+        let received: Vec<_> = gossip_recv
+            .iter()
+            .take(2)
+            .map(|gossip| gossip.message)
+            .collect();
+        assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert!(gossip_recv
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        exit.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn duplicates_are_counted() {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        let from: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&Default::default());
+        let cluster_info =
+            Arc::new(ClusterInfo::new(Arc::new(keypair.clone()), storage.clone(), vec![]).unwrap());
+        let stats = Arc::new(GossipStats::default());
+        let (send, recv) = channel();
+        let (gossip_send, gossip_recv) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+        let handle = GossipService::listen(
+            recv,
+            gossip_send,
+            channel().0,
+            cluster_info,
+            Arc::new(Mempool::new(16)),
+            Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            sync_channel(1).0,
+            GossipConfig::default(),
+            stats.clone(),
+            exit.clone(),
+        );
+
+        let message = Message::signed(&keypair, &Protocol::GossipPush(b"block".to_vec()));
+        let copy = bincode::deserialize(&bincode::serialize(&message).unwrap()).unwrap();
+        send.send(vec![(message, from)]).unwrap();
+        gossip_recv.recv().unwrap();
+        send.send(vec![(copy, from)]).unwrap();
+        assert!(gossip_recv
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        exit.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        assert_eq!(stats.duplicates_dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.expired_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn gossip_messages_keep_their_author() {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        let message = Message::signed(&keypair, &Protocol::GossipPush(b"block".to_vec()));
+        let gossip = GossipMessage {
+            author: message.pubkey.to_bytes(),
+            message: b"block".to_vec(),
+        };
+        assert_eq!(gossip.author(), keypair.verification_key().to_bytes());
+    }
+
+    #[test]
+    fn seen_messages_forget_expired_entries() {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        let start = Utc::now().timestamp_millis();
+        let config = GossipConfig::default();
+        let purge_time = config.purge_time_ms;
+        let mut seen = SeenMessages::new(start, &config);
+
+        for i in 0..1000_i64 {
+            let data = i.to_le_bytes();
+            let message = Message::new(
+                keypair.verification_key().into(),
+                keypair.sign(&data),
+                data.to_vec(),
+                start + i,
+            );
+            assert_eq!(seen.observe(&message, start + i), Observation::Fresh);
+            assert_eq!(seen.observe(&message, start + i), Observation::Duplicate);
+        }
+
+        // stamped anywhere but around now, a message is never logged.
+        for timestamp in [i64::MIN, start + MAX_CLOCK_DRIFT_MS + 1, i64::MAX] {
+            let data = timestamp.to_le_bytes();
+            let message = Message::new(
+                keypair.verification_key().into(),
+                keypair.sign(&data),
+                data.to_vec(),
+                timestamp,
+            );
+            assert_eq!(seen.observe(&message, start), Observation::Expired);
+        }
+
+        seen.maybe_sweep(start + purge_time + 500);
+        assert_eq!(seen.logs.len(), 499);
+        seen.maybe_sweep(start + purge_time + 1000);
+        assert_eq!(seen.logs.len(), 499, "swept twice within one tick");
+        seen.maybe_sweep(start + purge_time + 5000);
+        assert!(seen.logs.is_empty());
+    }
+
+    #[test]
+    fn tcp_frames_share_a_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (send, recv) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+        let handle = tcp_receiver(listener, send, |frame, _| frame, &exit, "framing", None);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_frame(&mut stream, b"first").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+        write_frame(&mut stream, b"second").unwrap();
+        drop(stream);
+
+        let frames: Vec<_> = recv.iter().take(3).collect();
+        assert_eq!(frames, vec![b"first".to_vec(), vec![], b"second".to_vec()]);
+
+        exit.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let header = ((GOSSIP_BUFFER_SIZE + 1) as u32).to_be_bytes();
+        assert!(matches!(
+            read_frame(&mut header.as_ref()),
+            Err(P2PError::FrameTooLarge(len)) if len == GOSSIP_BUFFER_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn oversized_datagrams_are_rejected() {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        let data = vec![0; MAX_UDP_PAYLOAD];
+        let message = Message::new(
+            keypair.verification_key().into(),
+            keypair.sign(&data),
+            data,
+            Utc::now().timestamp_millis(),
+        );
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        assert!(matches!(
+            send_udp(&socket, &addr, &message),
+            Err(P2PError::PayloadTooLarge(len)) if len > MAX_UDP_PAYLOAD
+        ));
+    }
+
+    #[test]
+    fn a_quiet_connection_does_not_hold_up_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (send, recv) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+        let handle = tcp_receiver(listener, send, |frame, _| frame, &exit, "quiet", None);
+
+        // half a frame, and then nothing until the read times out.
+        let mut quiet = TcpStream::connect(addr).unwrap();
+        quiet.write_all(&[0, 0]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        write_frame(&mut TcpStream::connect(addr).unwrap(), b"busy").unwrap();
+
+        let frame = recv.recv_timeout(RECV_TIMEOUT / 2).unwrap();
+        assert_eq!(frame, b"busy".to_vec());
+
+        exit.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        drop(quiet);
+    }
+
+    #[test]
+    fn tcp_receiver_exits_when_the_consumer_hangs_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (send, recv) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+        let handle = tcp_receiver(listener, send, |frame, _| frame, &exit, "hung-up", None);
+
+        drop(recv);
+        write_frame(&mut TcpStream::connect(addr).unwrap(), b"nobody listens").unwrap();
+
+        // `exit` is never set, so only the hang up can end the thread.
+        handle.join().unwrap();
+    }
+
+    struct GossipNode {
+        gossip: GossipService,
+        chain: Arc<Chain>,
+        mempool: Arc<Mempool>,
+        blocks: Receiver<GossipedBlock>,
+    }
+
+    /// Starts a gossip service on a fresh storage at `path`, which knows of `contacts`.
+    fn gossip_node(path: &str, contacts: &[SocketAddr], exit: &Arc<AtomicBool>) -> GossipNode {
+        start_gossip_node(path, contacts, exit, false)
+    }
+
+    /// Like `gossip_node`, with its tcp connections encrypted if `encrypt` is set.
+    fn start_gossip_node(
+        path: &str,
+        contacts: &[SocketAddr],
+        exit: &Arc<AtomicBool>,
+        encrypt: bool,
+    ) -> GossipNode {
+        let storage = fresh_storage(path);
+        storage
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap()
+            .set(b"contact_list", &encode_contacts(contacts))
+            .unwrap();
+        let keypair = Arc::new(SigningKey::new(&mut rand::thread_rng()));
+        let chain =
+            Arc::new(Chain::new(storage.clone(), keypair.verification_key().to_bytes()).unwrap());
+        let mempool = Arc::new(Mempool::new(16));
+        let cluster_info = ClusterInfo::new(keypair, storage, vec![]).unwrap();
+        let cluster_info = Arc::new(cluster_info.with_encryption(encrypt));
+        let (gossip, _, blocks, _) = GossipService::new(
+            cluster_info,
+            chain.clone(),
+            mempool.clone(),
+            "127.0.0.1:0",
+            &GossipConfig::default(),
+            exit,
+        )
+        .unwrap();
+        GossipNode {
+            gossip,
+            chain,
+            mempool,
+            blocks,
+        }
+    }
+
+    /// Polls `condition` for a few seconds, returning whether it came true.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /// Broadcasts a block holding `recipts` from one node and returns whether it reached a second
+    /// node, which is the first node's only contact, signed by the block's producer.
+    fn broadcast_reaches_a_peer(recipts: Vec<ContractRequest>) -> bool {
+        let exit = Arc::new(AtomicBool::new(false));
+        let peer = gossip_node("db-broadcast-peer/", &[], &exit);
+        let local = gossip_node(
+            "db-broadcast-local/",
+            &[peer.gossip.local_addr().unwrap()],
+            &exit,
+        );
+
+        let block = local
+            .chain
+            .block_with_transactions(requests_to_recipts(recipts), now());
+        let digest = block.digest();
+        local.gossip.broadcast(Protocol::new_block(&block)).unwrap();
+        local.chain.insert_block(block).unwrap();
+
+        let received = match peer.blocks.recv_timeout(Duration::from_secs(5)) {
+            Ok(gossiped) => {
+                gossiped.block.digest() == digest && gossiped.signer == gossiped.block.beneficiary()
+            }
+            Err(_) => false,
+        };
+
+        exit.store(true, Ordering::Relaxed);
+        local.gossip.join().unwrap();
+        peer.gossip.join().unwrap();
+        fs::remove_dir_all("db-broadcast-local/").unwrap();
+        fs::remove_dir_all("db-broadcast-peer/").unwrap();
+        received
+    }
+
+    #[test]
+    #[serial]
+    fn broadcast_blocks_reach_peers() {
+        assert!(broadcast_reaches_a_peer(vec![]));
+    }
+
+    #[test]
+    #[serial]
+    fn oversized_blocks_are_broadcast_over_tcp() {
+        let recipts = (0..20)
+            .map(|id| {
+                let req = json!({"from": "ginger", "to": "lemon", "amount": id});
+                ContractRequest::new(
+                    [0; 32],
+                    "token".to_string(),
+                    "transfer".to_string(),
+                    req,
+                    0,
+                    id,
+                )
+            })
+            .collect();
+        assert!(broadcast_reaches_a_peer(recipts));
+    }
+
+    #[test]
+    #[serial]
+    fn discovery_makes_nodes_contacts_of_each_other() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let known = gossip_node("db-discovery-known/", &[], &exit);
+        let known_addr = known.gossip.local_addr().unwrap();
+        let fresh = gossip_node("db-discovery-fresh/", &[known_addr], &exit);
+        let fresh_addr = fresh.gossip.local_addr().unwrap();
+
+        // the only contact the known node has is the fresh one, which is left out.
+        assert_eq!(fresh.gossip.discover().unwrap(), 1);
+        assert_eq!(fresh.gossip.cluster_info.contacts(), vec![known_addr]);
+        assert_eq!(known.gossip.cluster_info.contacts(), vec![fresh_addr]);
+
+        exit.store(true, Ordering::Relaxed);
+        known.gossip.join().unwrap();
+        fresh.gossip.join().unwrap();
+        fs::remove_dir_all("db-discovery-known/").unwrap();
+        fs::remove_dir_all("db-discovery-fresh/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn transactions_are_deduped_across_nodes() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let peer = gossip_node("db-mempool-peer/", &[], &exit);
+        let local = gossip_node(
+            "db-mempool-local/",
+            &[peer.gossip.local_addr().unwrap()],
+            &exit,
+        );
+        let transfer = |amount: u64| {
+            let req = json!({"to": "ginger", "amount": amount});
+            ContractRequest::signed(
+                &SigningKey::from([3; 32]),
+                "native".to_string(),
+                "transfer".to_string(),
+                req,
+                0,
+                0,
+            )
+        };
+
+        assert!(local.mempool.insert(transfer(1)));
+        // an unsigned transaction could not be checked once it is in a block.
+        let unsigned = transfer(3).with_signature(None);
+        local
+            .gossip
+            .broadcast(Protocol::Transaction(unsigned))
+            .unwrap();
+        for amount in [1, 1, 2] {
+            let transaction = Protocol::Transaction(transfer(amount));
+            local.gossip.broadcast(transaction).unwrap();
+        }
+
+        assert!(eventually(|| peer.mempool.pending().len() == 2));
+        let amounts: Vec<_> = peer
+            .mempool
+            .pending()
+            .iter()
+            .map(|request| request.req["amount"].clone())
+            .collect();
+        assert_eq!(amounts, vec![json!(1), json!(2)]);
+        assert_eq!(local.mempool.pending().len(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        local.gossip.join().unwrap();
+        peer.gossip.join().unwrap();
+        fs::remove_dir_all("db-mempool-local/").unwrap();
+        fs::remove_dir_all("db-mempool-peer/").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn gossip_reports_a_taken_port() {
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&Default::default());
+        let cluster_info = Arc::new(
+            ClusterInfo::new(
+                Arc::new(SigningKey::new(&mut rand::thread_rng())),
+                storage.clone(),
+                vec![],
+            )
+            .unwrap(),
+        );
+        let chain = Arc::new(Chain::new(storage, [0; 32]).unwrap());
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+
+        let exit = Arc::new(AtomicBool::new(false));
+        assert!(matches!(
+            GossipService::new(
+                cluster_info,
+                chain,
+                Arc::new(Mempool::new(16)),
+                &addr,
+                &GossipConfig::default(),
+                &exit
+            ),
+            Err(P2PError::Bind(..))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn silent_peers_are_evicted() {
+        let storage = RocksdbStorage::load(&Default::default())
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap();
+        let alive: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let silent: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+        storage
+            .set(b"contact_list", &encode_contacts(&[alive, silent]))
+            .unwrap();
+        let cluster_info = ClusterInfo::new(
+            Arc::new(SigningKey::new(&mut rand::thread_rng())),
+            storage.clone(),
+            vec![],
+        )
+        .unwrap();
+
+        for round in 0..=MAX_MISSED_PONGS {
+            let pinged = cluster_info.ping_round();
+            if round < MAX_MISSED_PONGS {
+                assert_eq!(pinged, vec![alive, silent]);
+            } else {
+                assert_eq!(pinged, vec![alive]);
             }
+            cluster_info.record_pong(alive);
         }
+
+        assert_eq!(cluster_info.discovery_nodes(), vec![alive]);
+        assert_eq!(cluster_info.live_peers(), 1);
+        assert!(cluster_info.last_seen().contains_key(&alive));
+        assert_eq!(
+            decode_contacts(&storage.get(b"contact_list").unwrap().unwrap()),
+            vec![alive]
+        );
+        storage.delete(b"contact_list").unwrap();
+    }
+
+    fn signed_messages(count: usize) -> Vec<Message> {
+        let keypair = SigningKey::new(&mut rand::thread_rng());
+        (0..count)
+            .map(|i| Message::signed(&keypair, &Protocol::GossipPush(i.to_le_bytes().to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn batch_verification_drops_only_bad_signatures() {
+        let mut messages = signed_messages(64);
+        messages[5].timestamp += 1;
+        messages[40].data.push(0);
+        let expected: Vec<_> = messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 5 && *i != 40)
+            .map(|(_, message)| message.content_hash())
+            .collect();
+
+        let verified = verify_batch_by(messages, |message| message);
+        assert_eq!(
+            verified
+                .iter()
+                .map(Message::content_hash)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored"]
+    fn batch_verification_benchmark() {
+        let messages = signed_messages(10_000);
+        let copies: Vec<_> = messages
+            .iter()
+            .map(|message| bincode::deserialize(&bincode::serialize(message).unwrap()).unwrap())
+            .collect::<Vec<Message>>();
+
+        let start = Instant::now();
+        let individually = copies.into_iter().filter_map(Message::verify).count();
+        let individual_time = start.elapsed();
+
+        let start = Instant::now();
+        let batched = verify_batch_by(messages, |message| message).len();
+        let batch_time = start.elapsed();
+
+        println!(
+            "individually: {:?}, batched: {:?}",
+            individual_time, batch_time
+        );
+        assert_eq!(individually, 10_000);
+        assert_eq!(batched, 10_000);
+        assert!(batch_time < individual_time);
+    }
+
+    #[test]
+    fn ipv4_contacts_round_trip() {
+        let contacts: Vec<SocketAddr> = vec![
+            "127.0.0.1:9001".parse().unwrap(),
+            "10.0.0.7:65535".parse().unwrap(),
+        ];
+        assert_eq!(decode_contacts(&encode_contacts(&contacts)), contacts);
+    }
+
+    #[test]
+    fn ipv6_contacts_round_trip() {
+        let contacts: Vec<SocketAddr> = vec![
+            "[::1]:9001".parse().unwrap(),
+            "127.0.0.1:9002".parse().unwrap(),
+            "[2001:db8::8a2e:370:7334]:443".parse().unwrap(),
+        ];
+        let bytes = encode_contacts(&contacts);
+        assert_eq!(bytes.len(), 19 + 7 + 19);
+        assert_eq!(decode_contacts(&bytes), contacts);
+        assert_eq!(decode_contacts(&bytes[..bytes.len() - 1]), contacts[..2]);
     }
 }
@@ -0,0 +1,273 @@
+use {
+    super::{read_frame, write_frame, P2PError},
+    ed25519_consensus::{Signature, SigningKey, VerificationKey, VerificationKeyBytes},
+    sha3::{Digest, Sha3_256},
+    snow::{
+        params::DHChoice,
+        resolvers::{CryptoResolver, DefaultResolver},
+        Builder, HandshakeState, TransportState,
+    },
+    std::net::TcpStream,
+};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+/// An identity's public key followed by its signature over the static noise key.
+const IDENTITY_PAYLOAD_LEN: usize = 32 + 64;
+
+/// Derives the static noise key from the node's identity, so that it does not need a second
+/// keypair on disk.
+pub fn noise_key(keypair: &SigningKey) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"teral noise static key");
+    hasher.update(keypair.to_bytes());
+    hasher.finalize().into()
+}
+
+/// What a static noise key is signed under, so the signature can not be passed off as one over
+/// anything else.
+fn signed_static_key(static_public: &[u8]) -> Vec<u8> {
+    [b"teral noise static key".as_slice(), static_public].concat()
+}
+
+/// The handshake payload that binds the static noise key derived from `identity` to it.
+fn identity_payload(identity: &SigningKey) -> Vec<u8> {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .expect("the default resolver supports curve25519");
+    dh.set(&noise_key(identity));
+    let signature = identity.sign(&signed_static_key(dh.pubkey()));
+    [
+        identity.verification_key().as_bytes().as_slice(),
+        &signature.to_bytes(),
+    ]
+    .concat()
+}
+
+/// The identity in `payload`, if it signed `static_public`, the peer's static noise key.
+fn verify_identity(payload: &[u8], static_public: &[u8]) -> Result<VerificationKeyBytes, P2PError> {
+    if payload.len() != IDENTITY_PAYLOAD_LEN {
+        return Err(P2PError::ForgedNoiseKey);
+    }
+    let (identity, signature) = payload.split_at(32);
+    let identity =
+        VerificationKeyBytes::try_from(identity).map_err(|_| P2PError::ForgedNoiseKey)?;
+    let signature = Signature::try_from(signature).map_err(|_| P2PError::ForgedNoiseKey)?;
+    VerificationKey::try_from(identity)
+        .and_then(|key| key.verify(&signature, &signed_static_key(static_public)))
+        .map_err(|_| P2PError::ForgedNoiseKey)?;
+    Ok(identity)
+}
+
+/// A tcp stream that went through a noise XX handshake. Every frame on it is encrypted and
+/// authenticated, so a tampered frame fails to decrypt instead of being delivered. Each side
+/// signs its static noise key with its identity during the handshake, so the stream is known to
+/// lead to the holder of `remote_identity`.
+pub struct EncryptedStream {
+    stream: TcpStream,
+    transport: TransportState,
+    remote_identity: VerificationKeyBytes,
+}
+
+impl EncryptedStream {
+    /// Connects as `identity`, failing unless the peer proves it is `expected`, if given.
+    pub fn initiate(
+        stream: TcpStream,
+        identity: &SigningKey,
+        expected: Option<VerificationKeyBytes>,
+    ) -> Result<Self, P2PError> {
+        let handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&noise_key(identity))
+            .build_initiator()?;
+        let stream = Self::handshake(stream, handshake, &identity_payload(identity))?;
+        match expected {
+            Some(expected) if expected != stream.remote_identity => {
+                Err(P2PError::UnexpectedPeer(stream.remote_identity))
+            }
+            _ => Ok(stream),
+        }
+    }
+
+    pub fn respond(stream: TcpStream, identity: &SigningKey) -> Result<Self, P2PError> {
+        let handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&noise_key(identity))
+            .build_responder()?;
+        Self::handshake(stream, handshake, &identity_payload(identity))
+    }
+
+    fn handshake(
+        mut stream: TcpStream,
+        mut handshake: HandshakeState,
+        payload: &[u8],
+    ) -> Result<Self, P2PError> {
+        let mut buf = vec![0; NOISE_MAX_MESSAGE];
+        let mut remote_identity = None;
+        let mut first = true;
+        while !handshake.is_handshake_finished() {
+            if handshake.is_my_turn() {
+                // the initiator's first message goes out before any key is agreed on, and so in
+                // the clear. Each side's static key goes out with its next one.
+                let payload = if first && handshake.is_initiator() {
+                    &[]
+                } else {
+                    payload
+                };
+                let len = handshake.write_message(payload, &mut buf)?;
+                write_frame(&mut stream, &buf[..len])?;
+            } else {
+                let frame = read_frame(&mut stream)?.ok_or(P2PError::HandshakeClosed)?;
+                let len = handshake.read_message(&frame, &mut buf)?;
+                if let Some(static_public) = handshake.get_remote_static() {
+                    remote_identity = Some(verify_identity(&buf[..len], static_public)?);
+                }
+            }
+            first = false;
+        }
+
+        Ok(Self {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            remote_identity: remote_identity.ok_or(P2PError::ForgedNoiseKey)?,
+        })
+    }
+
+    /// The identity of the peer, as proven in the handshake.
+    pub fn remote_identity(&self) -> VerificationKeyBytes {
+        self.remote_identity
+    }
+
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), P2PError> {
+        if payload.len() + NOISE_TAG_LEN > NOISE_MAX_MESSAGE {
+            return Err(P2PError::FrameTooLarge(payload.len()));
+        }
+        let mut buf = vec![0; payload.len() + NOISE_TAG_LEN];
+        let len = self.transport.write_message(payload, &mut buf)?;
+        Ok(write_frame(&mut self.stream, &buf[..len])?)
+    }
+
+    /// Receives and decrypts one frame. Returns `None` if the peer closed the connection
+    /// between frames.
+    pub fn recv(&mut self) -> Result<Option<Vec<u8>>, P2PError> {
+        let frame = match read_frame(&mut self.stream)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0; frame.len()];
+        let len = self.transport.read_message(&frame, &mut buf)?;
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use ed25519_consensus::{SigningKey, VerificationKeyBytes};
+    use snow::Builder;
+
+    use super::{identity_payload, noise_key, EncryptedStream, NOISE_PARAMS};
+    use crate::p2p::{write_frame, P2PError};
+
+    /// Connects a fresh initiator to a responder that is `responder`, expecting `expected`.
+    fn connect(
+        responder: SigningKey,
+        expected: Option<VerificationKeyBytes>,
+    ) -> (
+        Result<EncryptedStream, P2PError>,
+        thread::JoinHandle<Result<EncryptedStream, P2PError>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            EncryptedStream::respond(listener.accept().unwrap().0, &responder)
+        });
+
+        let identity = SigningKey::new(&mut rand::thread_rng());
+        let stream = TcpStream::connect(addr).unwrap();
+        (
+            EncryptedStream::initiate(stream, &identity, expected),
+            responder,
+        )
+    }
+
+    fn connected_pair() -> (EncryptedStream, EncryptedStream) {
+        let (initiator, responder) = connect(SigningKey::new(&mut rand::thread_rng()), None);
+        (initiator.unwrap(), responder.join().unwrap().unwrap())
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let (mut initiator, mut responder) = connected_pair();
+
+        initiator.send(b"transfer 100 to ginger").unwrap();
+        responder.send(b"ack").unwrap();
+
+        assert_eq!(
+            responder.recv().unwrap(),
+            Some(b"transfer 100 to ginger".to_vec())
+        );
+        assert_eq!(initiator.recv().unwrap(), Some(b"ack".to_vec()));
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let (mut initiator, mut responder) = connected_pair();
+
+        let mut ciphertext = vec![0; 64];
+        let len = initiator
+            .transport
+            .write_message(b"transfer 100 to ginger", &mut ciphertext)
+            .unwrap();
+        ciphertext[0] ^= 1;
+        write_frame(&mut initiator.stream, &ciphertext[..len]).unwrap();
+
+        assert!(matches!(responder.recv(), Err(P2PError::Noise(_))));
+    }
+
+    #[test]
+    fn handshakes_prove_identities() {
+        let identity = SigningKey::new(&mut rand::thread_rng());
+        let expected = VerificationKeyBytes::from(identity.verification_key());
+        let (initiator, responder) = connect(identity, Some(expected));
+        let (initiator, responder) = (initiator.unwrap(), responder.join().unwrap().unwrap());
+        assert_eq!(initiator.remote_identity(), expected);
+        assert_ne!(responder.remote_identity(), expected);
+
+        let someone_else = SigningKey::new(&mut rand::thread_rng());
+        let expected = VerificationKeyBytes::from(someone_else.verification_key());
+        let (initiator, _) = connect(SigningKey::new(&mut rand::thread_rng()), Some(expected));
+        assert!(matches!(initiator, Err(P2PError::UnexpectedPeer(_))));
+    }
+
+    #[test]
+    fn noise_keys_signed_by_another_identity_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let identity = SigningKey::new(&mut rand::thread_rng());
+            EncryptedStream::respond(listener.accept().unwrap().0, &identity)
+        });
+
+        // claims the victim's identity, with a noise key that only the attacker signed.
+        let attacker = SigningKey::new(&mut rand::thread_rng());
+        let victim = SigningKey::new(&mut rand::thread_rng());
+        let mut payload = identity_payload(&attacker);
+        payload[..32].copy_from_slice(victim.verification_key().as_bytes());
+        let handshake = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&noise_key(&attacker))
+            .build_initiator()
+            .unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let _ = EncryptedStream::handshake(stream, handshake, &payload);
+
+        assert!(matches!(
+            responder.join().unwrap(),
+            Err(P2PError::ForgedNoiseKey)
+        ));
+    }
+}
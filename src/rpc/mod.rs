@@ -0,0 +1,618 @@
+use {
+    crate::{
+        chain::{Block, Chain, ChainError, ContractRecipt},
+        config::ChainParams,
+        contracts::{balance, nonce, query, ContractRequest, ContractsError},
+        storage::{Storage, StorageError},
+    },
+    serde_json::{json, Value},
+    std::{
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, Receiver, Sender},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+    thiserror::Error,
+};
+
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+/// How long a client has to send its whole request, so that a slow one can not hold a worker.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most the request line and headers can take together.
+const MAX_HEADER_SIZE: usize = 8 << 10;
+/// The largest request body the server reads, which is plenty for a transaction.
+const MAX_BODY_SIZE: usize = 1 << 20;
+/// The most connections answered at once. Any more are closed right away.
+const MAX_CONNECTIONS: usize = 64;
+
+// the json-rpc 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const UNSIGNED: i64 = -32000;
+const QUERY_FAILED: i64 = -32001;
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("only POST requests are served")]
+    NotPost,
+    #[error("the request has no valid content length")]
+    NoContentLength,
+    #[error("the request body is over {MAX_BODY_SIZE} bytes")]
+    BodyTooLarge,
+    #[error("the request headers are over {MAX_HEADER_SIZE} bytes")]
+    HeadersTooLarge,
+    #[error("the request did not arrive within {REQUEST_TIMEOUT:?}")]
+    TimedOut,
+}
+
+/// Why a json-rpc call failed, as told to the caller.
+#[derive(Debug, Error)]
+enum CallError {
+    #[error("there is no such method")]
+    MethodNotFound,
+    #[error("invalid params: {0}")]
+    InvalidParams(String),
+    #[error("the transaction is not signed by its author")]
+    Unsigned,
+    #[error("the query failed: {0}")]
+    Query(#[from] ContractsError),
+    #[error("chain error: {0}")]
+    Chain(#[from] ChainError),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("could not serialize the result: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl CallError {
+    fn code(&self) -> i64 {
+        match self {
+            Self::MethodNotFound => METHOD_NOT_FOUND,
+            Self::InvalidParams(_) => INVALID_PARAMS,
+            Self::Unsigned => UNSIGNED,
+            Self::Query(_) => QUERY_FAILED,
+            Self::Chain(_) | Self::Storage(_) | Self::Serialize(_) => INTERNAL_ERROR,
+        }
+    }
+}
+
+/// A json-rpc 2.0 server over http, for wallets and explorers to reach the node. It serves:
+///
+/// - `submit_transaction`, which takes a signed `ContractRequest` and returns the hash its
+///   recipt will have, in base64. The transaction is handed to the validator through
+///   `submitted`, for it to gossip and schedule.
+/// - `get_balance`, which takes an `account` and returns its balance, or null.
+/// - `get_nonce`, which takes an `author`, a base64 public key, and returns the nonce its next
+///   transaction has to carry.
+/// - `get_block`, which takes a `height` and returns the block there, or null.
+/// - `get_head`, which returns the latest block.
+/// - `get_recipt_proof`, which takes a `height` and an `index`, and returns the recipt at that
+///   index of the block there, with the merkle proof that it is under the block's
+///   `recipts_root`, or null.
+/// - `query`, which takes a `ContractRequest`, signed or not, and returns what the method it
+///   names returns, without keeping anything it writes.
+pub struct RpcService {
+    thread: JoinHandle<()>,
+    local_addr: SocketAddr,
+    submitted: Receiver<ContractRequest>,
+}
+
+impl RpcService {
+    pub fn new(
+        addr: &str,
+        chain: Arc<Chain>,
+        storage: Arc<dyn Storage>,
+        params: &ChainParams,
+        exit: &Arc<AtomicBool>,
+    ) -> Result<Self, RpcError> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let (sender, submitted) = channel();
+        let handler = Handler {
+            chain,
+            storage,
+            params: params.clone(),
+            submitter: sender,
+        };
+        let exit = exit.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("rpc"))
+            .spawn(move || serve(listener, handler, exit))?;
+        tracing::info!("serving rpc on {}", local_addr);
+        Ok(Self {
+            thread,
+            local_addr,
+            submitted,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The transactions submitted since the last call, oldest first.
+    pub fn submitted(&self) -> impl Iterator<Item = ContractRequest> + '_ {
+        self.submitted.try_iter()
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+/// Answers the connections on `listener`, each on its own worker, until `exit` is set.
+fn serve(listener: TcpListener, handler: Handler, exit: Arc<AtomicBool>) {
+    let handler = Arc::new(handler);
+    let mut workers: Vec<JoinHandle<()>> = Vec::new();
+    while !exit.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(_) => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+        workers.retain(|worker| !worker.is_finished());
+        if workers.len() >= MAX_CONNECTIONS {
+            tracing::debug!(
+                "dropping the rpc connection from {}, too many are open",
+                addr
+            );
+            continue;
+        }
+        let handler = handler.clone();
+        let worker = thread::Builder::new()
+            .name(String::from("rpc-worker"))
+            .spawn(move || answer(stream, addr, &handler));
+        match worker {
+            Ok(worker) => workers.push(worker),
+            Err(err) => tracing::warn!("could not spawn an rpc worker: {}", err),
+        }
+    }
+    // every worker is done by its request's deadline.
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+/// Reads a request off `stream` and answers it.
+fn answer(mut stream: TcpStream, addr: SocketAddr, handler: &Handler) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+    let written = match read_body(&stream, Instant::now() + REQUEST_TIMEOUT) {
+        Ok(body) => write_response(&mut stream, "200 OK", &handler.handle(&body)),
+        Err(err) => {
+            tracing::debug!("bad rpc request from {}: {}", addr, err);
+            let status = match err {
+                RpcError::NotPost => "405 Method Not Allowed",
+                RpcError::HeadersTooLarge => "431 Request Header Fields Too Large",
+                RpcError::TimedOut => "408 Request Timeout",
+                _ => "400 Bad Request",
+            };
+            write_response(&mut stream, status, &json!({ "error": err.to_string() }))
+        }
+    };
+    if let Err(err) = written {
+        tracing::debug!("could not answer {}: {}", addr, err);
+    }
+}
+
+/// Reads off a tcp stream until `deadline`, after which every read times out.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Tells reads that ran past the deadline apart from other io errors.
+fn read_error(err: io::Error) -> RpcError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RpcError::TimedOut,
+        _ => RpcError::Io(err),
+    }
+}
+
+/// Reads the next line of the request head into `line`, out of the `left` bytes the head may
+/// still take.
+fn read_head_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    left: &mut usize,
+) -> Result<(), RpcError> {
+    line.clear();
+    let read = reader
+        .take(*left as u64)
+        .read_line(line)
+        .map_err(read_error)?;
+    *left -= read;
+    match line.ends_with('\n') {
+        true => Ok(()),
+        false if *left == 0 => Err(RpcError::HeadersTooLarge),
+        false => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// Reads an http request off `stream`, and returns its body. The whole request has to arrive
+/// by `deadline`.
+fn read_body(stream: &TcpStream, deadline: Instant) -> Result<Vec<u8>, RpcError> {
+    let mut reader = BufReader::new(DeadlineReader { stream, deadline });
+    let mut line = String::new();
+    let mut left = MAX_HEADER_SIZE;
+    read_head_line(&mut reader, &mut line, &mut left)?;
+    if !line.starts_with("POST ") {
+        return Err(RpcError::NotPost);
+    }
+
+    let mut content_length = None;
+    loop {
+        read_head_line(&mut reader, &mut line, &mut left)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = content_length.ok_or(RpcError::NoContentLength)?;
+    if content_length > MAX_BODY_SIZE {
+        return Err(RpcError::BodyTooLarge);
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(read_error)?;
+    Ok(body)
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// What the json-rpc methods need of the node.
+struct Handler {
+    chain: Arc<Chain>,
+    storage: Arc<dyn Storage>,
+    params: ChainParams,
+    submitter: Sender<ContractRequest>,
+}
+
+impl Handler {
+    /// The json-rpc response to `body`.
+    fn handle(&self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return error_response(Value::Null, PARSE_ERROR, &err.to_string()),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => return error_response(id, INVALID_REQUEST, "the request names no method"),
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        match self.call(method, params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(err) => error_response(id, err.code(), &err.to_string()),
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, CallError> {
+        match method {
+            "submit_transaction" => {
+                let request: ContractRequest = serde_json::from_value(params)
+                    .map_err(|err| CallError::InvalidParams(err.to_string()))?;
+                if !request.verify() {
+                    return Err(CallError::Unsigned);
+                }
+                let hash = ContractRecipt::from(request.clone()).hash();
+                // the validator is gone once the receiver is, and so is anything it would do.
+                let _ = self.submitter.send(request);
+                Ok(json!(base64::encode(hash)))
+            }
+            "get_balance" => {
+                let account = params["account"]
+                    .as_str()
+                    .ok_or_else(|| CallError::InvalidParams(String::from("account is not set")))?;
+                Ok(json!(balance(self.storage.clone(), account)?))
+            }
+            "get_nonce" => {
+                let author = params["author"]
+                    .as_str()
+                    .and_then(|author| base64::decode(author).ok())
+                    .and_then(|author| <[u8; 32]>::try_from(author).ok())
+                    .ok_or_else(|| CallError::InvalidParams(String::from("author is not a key")))?;
+                Ok(json!(nonce(self.storage.clone(), author)?))
+            }
+            "get_block" => {
+                let height = params["height"]
+                    .as_u64()
+                    .ok_or_else(|| CallError::InvalidParams(String::from("height is not set")))?;
+                block_value(self.chain.block_by_height(height)?)
+            }
+            "get_head" => block_value(self.chain.latest_block()?),
+            "get_recipt_proof" => {
+                let (height, index) = params["height"]
+                    .as_u64()
+                    .zip(params["index"].as_u64())
+                    .ok_or_else(|| {
+                        CallError::InvalidParams(String::from("height or index is not set"))
+                    })?;
+                let block = self.chain.block_by_height(height)?;
+                Ok(block
+                    .and_then(|block| recipt_proof(&block, index as usize))
+                    .unwrap_or_default())
+            }
+            "query" => {
+                let request: ContractRequest = serde_json::from_value(params)
+                    .map_err(|err| CallError::InvalidParams(err.to_string()))?;
+                Ok(query(self.storage.clone(), &self.params, &request)?)
+            }
+            _ => Err(CallError::MethodNotFound),
+        }
+    }
+}
+
+fn block_value(block: Option<Block>) -> Result<Value, CallError> {
+    Ok(serde_json::to_value(block)?)
+}
+
+/// The recipt at `index` in `block` along with its merkle proof, if there is one at `index`.
+fn recipt_proof(block: &Block, index: usize) -> Option<Value> {
+    let recipt = block.recipts().get(index)?;
+    let proof: Vec<_> = block
+        .merkle_proof(index)
+        .iter()
+        .map(base64::encode)
+        .collect();
+    Some(json!({
+        "recipt": recipt,
+        "count": block.recipt_count(),
+        "recipts_root": base64::encode(block.recipts_root()),
+        "proof": proof,
+    }))
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::channel,
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+    use serial_test::serial;
+
+    use super::{read_body, Handler, RpcError, RpcService, MAX_HEADER_SIZE, QUERY_FAILED};
+    use crate::{
+        chain::{Chain, ContractRecipt},
+        config::StorageConfig,
+        contracts::ContractRequest,
+        storage::{RocksdbStorage, Storage},
+    };
+
+    /// A connected client, and the server's end of it.
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let (mut client, server) = connection();
+        let header = format!("X-Padding: {}\r\n", "a".repeat(MAX_HEADER_SIZE));
+        write!(client, "POST / HTTP/1.1\r\n{}", header).unwrap();
+        let read = read_body(&server, Instant::now() + Duration::from_secs(1));
+        assert!(matches!(read, Err(RpcError::HeadersTooLarge)));
+
+        // many small headers count towards the same limit.
+        let (mut client, server) = connection();
+        let reader =
+            thread::spawn(move || read_body(&server, Instant::now() + Duration::from_secs(1)));
+        write!(client, "POST / HTTP/1.1\r\n").unwrap();
+        for _ in 0..MAX_HEADER_SIZE / 8 {
+            if write!(client, "X-A: b\r\n").is_err() {
+                break;
+            }
+        }
+        assert!(matches!(
+            reader.join().unwrap(),
+            Err(RpcError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn slow_requests_time_out() {
+        let (mut client, server) = connection();
+        let reader = thread::spawn(move || {
+            let started = Instant::now();
+            let read = read_body(&server, started + Duration::from_millis(300));
+            (read, started.elapsed())
+        });
+        // trickles a byte at a time, each well within what a single read waits for.
+        for byte in b"POST / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaaaaaa" {
+            if client.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let (read, elapsed) = reader.join().unwrap();
+        assert!(matches!(read, Err(RpcError::TimedOut)));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[serial]
+    fn slow_clients_do_not_hold_up_others() {
+        let path = "db-rpc-workers/";
+        let _ = fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let chain = Arc::new(Chain::new(storage.clone(), [1; 32]).unwrap());
+        let exit = Arc::new(AtomicBool::new(false));
+        let rpc = RpcService::new(
+            "127.0.0.1:0",
+            chain.clone(),
+            storage.clone(),
+            &Default::default(),
+            &exit,
+        )
+        .unwrap();
+
+        // opens a connection and never sends anything on it.
+        let idle = TcpStream::connect(rpc.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let body = r#"{"jsonrpc":"2.0","method":"get_head","id":1}"#;
+        let mut stream = TcpStream::connect(rpc.local_addr()).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        exit.store(true, Ordering::Relaxed);
+        drop(idle);
+        rpc.join().unwrap();
+        drop((chain, storage));
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn queries_are_answered_without_a_transaction() {
+        let path = "db-rpc-query/";
+        let _ = fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let chain = Arc::new(Chain::new(storage.clone(), [1; 32]).unwrap());
+        let handler = Handler {
+            chain: chain.clone(),
+            storage: storage.clone(),
+            params: Default::default(),
+            submitter: channel().0,
+        };
+
+        let body = r#"{"jsonrpc":"2.0","method":"query","params":{"name":"missing",
+            "method_name":"get","req":"{}"},"id":1}"#;
+        let response = handler.handle(body.as_bytes());
+        assert_eq!(response["error"]["code"], QUERY_FAILED);
+
+        // a query that does not parse as a request is not the contract's fault.
+        let body = r#"{"jsonrpc":"2.0","method":"query","params":{},"id":2}"#;
+        let response = handler.handle(body.as_bytes());
+        assert_eq!(response["error"]["code"], super::INVALID_PARAMS);
+
+        drop((handler, chain, storage));
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn recipts_are_served_with_their_proof() {
+        let path = "db-rpc-proof/";
+        let _ = fs::remove_dir_all(path);
+        let storage: Arc<dyn Storage> = RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let chain = Arc::new(Chain::new(storage.clone(), [1; 32]).unwrap());
+        let recipts = (0..3)
+            .map(|nonce| {
+                let req = json!({ "to": "ginger", "amount": 1_u64 });
+                let (name, method) = (String::from("native"), String::from("transfer"));
+                ContractRecipt::from(ContractRequest::new([0; 32], name, method, req, nonce, 0))
+            })
+            .collect();
+        let time = chrono::Utc::now().timestamp_millis();
+        let block = chain.block_with_transactions(recipts, time);
+        chain.insert_block(block.clone()).unwrap();
+        let handler = Handler {
+            chain: chain.clone(),
+            storage: storage.clone(),
+            params: Default::default(),
+            submitter: channel().0,
+        };
+        let proof = |index| {
+            let body = json!({
+                "jsonrpc": "2.0",
+                "method": "get_recipt_proof",
+                "params": { "height": 1, "index": index },
+                "id": 1,
+            });
+            handler.handle(body.to_string().as_bytes())["result"].clone()
+        };
+
+        let served = proof(2);
+        let recipt: ContractRecipt = serde_json::from_value(served["recipt"].clone()).unwrap();
+        assert_eq!(recipt.hash(), block.recipts()[2].hash());
+        assert_eq!(served["count"], 3);
+        assert_eq!(served["recipts_root"], base64::encode(block.recipts_root()));
+        let expected: Vec<_> = block.merkle_proof(2).iter().map(base64::encode).collect();
+        assert_eq!(served["proof"], json!(expected));
+        assert!(proof(3).is_null());
+
+        drop((handler, chain, storage));
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -1,29 +1,143 @@
-use std::sync::Arc;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
-pub trait Storage {
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[cfg(feature = "rocksdb-backend")]
+    #[error("rocksdb error: {0}")]
+    Rocksdb(#[from] rocksdb::Error),
+    #[cfg(feature = "sled-backend")]
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
+}
+
+pub trait Storage: Send + Sync {
     fn load(config: &StorageConfig) -> Arc<Self>
     where
         Self: Sized;
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Looks up every key in `keys`, returning the values in the same order.
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Whether `key` is present, without necessarily reading its value.
+    fn contains_key(&self, key: &[u8]) -> Result<bool, StorageError> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), StorageError>;
 
-    fn delete(&self, key: &[u8]);
+    fn delete_prefix(&self, prefix: &[u8]) -> Result<(), StorageError>;
 
-    fn delete_prefix(&self, prefix: &[u8]);
+    /// Iterates over every `(key, value)` pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_>;
 
-    fn set(&self, key: &[u8], value: &[u8]);
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
 
-    fn get_or_set(&self, key: &[u8], alternative_value: &[u8]) -> Vec<u8>;
+    /// Returns the value at `key`, writing `alternative_value` first if the key is absent. When
+    /// several callers race on an absent key exactly one write wins and all of them see it.
+    fn get_or_set(&self, key: &[u8], alternative_value: &[u8]) -> Result<Vec<u8>, StorageError> {
+        loop {
+            if let Some(value) = self.get(key)? {
+                return Ok(value);
+            }
+            if self.compare_and_swap(key, None, alternative_value)? {
+                return Ok(alternative_value.to_vec());
+            }
+        }
+    }
+
+    /// Applies all of `ops` atomically: either every write lands or none of them do.
+    fn batch(&self, ops: &[WriteOp]) -> Result<(), StorageError>;
+
+    /// Like `batch`, over several namespaces at once: the ops of each namespace, named like
+    /// `namespace` names it, land together with all the others or none of them do.
+    fn batch_namespaces(&self, batches: &[(&str, &[WriteOp])]) -> Result<(), StorageError>;
+
+    /// Sets `key` to `new` only if its current value is `expected` (`None` meaning absent).
+    /// Returns whether the swap happened. Only atomic with respect to other `compare_and_swap`s.
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, StorageError>;
+
+    /// Writes a consistent point-in-time copy of the whole store to `path`, without blocking
+    /// writers. The copy can be loaded like any other store, e.g. to seed a new node.
+    fn snapshot(&self, path: &Path) -> Result<(), StorageError>;
+
+    /// Forces buffered writes to disk, so they survive a crash or power loss.
+    fn flush(&self) -> Result<(), StorageError>;
+
+    /// Compacts the whole keyspace, reclaiming space from deleted and overwritten keys.
+    fn compact(&self) -> Result<(), StorageError>;
+
+    /// Returns a handle whose operations are confined to the keyspace `name`, creating it if
+    /// needed. Keys in different namespaces never alias, whatever their prefixes. Namespaces are
+    /// flat: calling this on a namespaced handle opens a sibling, not a child.
+    fn namespace(&self, name: &str) -> Result<Arc<dyn Storage>, StorageError>;
 }
 
+const LOCK_SHARDS: usize = 64;
+
+/// A fixed set of mutexes that keys hash onto, so that conditional writes on the same key are
+/// serialized without a lock per key.
+struct KeyLocks(Vec<Mutex<()>>);
+
+impl KeyLocks {
+    fn new() -> Self {
+        Self((0..LOCK_SHARDS).map(|_| Mutex::new(())).collect())
+    }
+
+    fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.0[hasher.finish() as usize % LOCK_SHARDS]
+            .lock()
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum WriteOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+#[cfg(feature = "sled-backend")]
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+
 #[cfg(feature = "rocksdb-backend")]
-use rocksdb::{Options, DB};
+use rocksdb::{
+    checkpoint::Checkpoint, BoundColumnFamily, Options, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
 
 use crate::config::StorageConfig;
 
 #[cfg(feature = "rocksdb-backend")]
 pub struct RocksdbStorage {
-    db: DB,
+    db: Arc<DB>,
+    column_family: String,
+    locks: Arc<KeyLocks>,
+}
+
+#[cfg(feature = "rocksdb-backend")]
+impl RocksdbStorage {
+    fn cf(&self) -> Arc<BoundColumnFamily<'_>> {
+        self.db
+            .cf_handle(&self.column_family)
+            .expect("column families are created before their handles are given out")
+    }
 }
 
 #[cfg(feature = "rocksdb-backend")]
@@ -34,37 +148,525 @@ impl Storage for RocksdbStorage {
     {
         let mut options = Options::default();
         options.create_if_missing(true);
+        options.create_missing_column_families(true);
         options.set_keep_log_file_num(config.log_history);
 
+        // every column family that exists on disk has to be opened along with the db.
+        let column_families = DB::list_cf(&options, &config.path)
+            .unwrap_or_else(|_| vec![String::from(DEFAULT_COLUMN_FAMILY_NAME)]);
+
+        Arc::new(Self {
+            db: Arc::new(DB::open_cf(&options, &config.path, column_families).unwrap()),
+            column_family: String::from(DEFAULT_COLUMN_FAMILY_NAME),
+            locks: Arc::new(KeyLocks::new()),
+        })
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get_cf(&self.cf(), key)?)
+    }
+
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let cf = self.cf();
+        self.db
+            .multi_get_cf(keys.iter().map(|key| (&cf, key)))
+            .into_iter()
+            .map(|value| value.map_err(StorageError::from))
+            .collect()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, StorageError> {
+        let cf = self.cf();
+        // the bloom filters can only rule a key out, so a maybe has to be confirmed.
+        Ok(self.db.key_may_exist_cf(&cf, key) && self.db.get_pinned_cf(&cf, key)?.is_some())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), StorageError> {
+        Ok(self.db.delete_cf(&self.cf(), key)?)
+    }
+
+    fn delete_prefix(&self, prefix: &[u8]) -> Result<(), StorageError> {
+        for (key, _) in self.scan_prefix(prefix) {
+            self.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_> {
+        let prefix = prefix.to_vec();
+        // without a prefix extractor the iterator just seeks to `prefix` and keeps going.
+        Box::new(
+            self.db
+                .prefix_iterator_cf(&self.cf(), &prefix)
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+                .map(|(key, value)| (key.to_vec(), value.to_vec())),
+        )
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        Ok(self.db.put_cf(&self.cf(), key, value)?)
+    }
+
+    fn batch(&self, ops: &[WriteOp]) -> Result<(), StorageError> {
+        let cf = self.cf();
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                WriteOp::Set { key, value } => batch.put_cf(&cf, key, value),
+                WriteOp::Delete { key } => batch.delete_cf(&cf, key),
+            }
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    fn batch_namespaces(&self, batches: &[(&str, &[WriteOp])]) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        for (name, ops) in batches {
+            if self.db.cf_handle(name).is_none() {
+                self.db.create_cf(name, &Options::default())?;
+            }
+            let cf = self.db.cf_handle(name).unwrap();
+            for op in *ops {
+                match op {
+                    WriteOp::Set { key, value } => batch.put_cf(&cf, key, value),
+                    WriteOp::Delete { key } => batch.delete_cf(&cf, key),
+                }
+            }
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, StorageError> {
+        let _guard = self.locks.lock(key);
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        self.set(key, new)?;
+        Ok(true)
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<(), StorageError> {
+        Ok(Checkpoint::new(&self.db)?.create_checkpoint(path)?)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(self.db.flush_cf(&self.cf())?)
+    }
+
+    fn compact(&self) -> Result<(), StorageError> {
+        self.db
+            .compact_range_cf::<&[u8], &[u8]>(&self.cf(), None, None);
+        Ok(())
+    }
+
+    fn namespace(&self, name: &str) -> Result<Arc<dyn Storage>, StorageError> {
+        if self.db.cf_handle(name).is_none() {
+            self.db.create_cf(name, &Options::default())?;
+        }
+        Ok(Arc::new(Self {
+            db: self.db.clone(),
+            column_family: String::from(name),
+            locks: self.locks.clone(),
+        }))
+    }
+}
+
+#[cfg(feature = "sled-backend")]
+pub struct SledStorage {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+#[cfg(feature = "sled-backend")]
+impl Storage for SledStorage {
+    fn load(config: &StorageConfig) -> Arc<Self>
+    where
+        Self: Sized,
+    {
+        let db = sled::open(&config.path).unwrap();
         Arc::new(Self {
-            db: DB::open(&options, &config.path).unwrap(),
+            tree: (*db).clone(),
+            db,
         })
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.db.get(key).unwrap()
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, StorageError> {
+        Ok(self.tree.contains_key(key)?)
     }
 
-    fn delete(&self, key: &[u8]) {
-        self.db.delete(key).unwrap();
+    fn delete(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.tree.remove(key)?;
+        Ok(())
     }
 
-    fn delete_prefix(&self, prefix: &[u8]) {
-        for key in self.db.prefix_iterator(prefix) {
-            self.delete(&key.0);
+    fn delete_prefix(&self, prefix: &[u8]) -> Result<(), StorageError> {
+        for key in self.tree.scan_prefix(prefix).keys() {
+            self.tree.remove(key?)?;
         }
+        Ok(())
     }
 
-    fn set(&self, key: &[u8], value: &[u8]) {
-        self.db.put(key, value).unwrap();
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + '_> {
+        Box::new(
+            self.tree
+                .scan_prefix(prefix)
+                .filter_map(|item| item.ok())
+                .map(|(key, value)| (key.to_vec(), value.to_vec())),
+        )
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+
+    fn batch(&self, ops: &[WriteOp]) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for op in ops {
+            match op {
+                WriteOp::Set { key, value } => batch.insert(key.as_slice(), value.as_slice()),
+                WriteOp::Delete { key } => batch.remove(key.as_slice()),
+            }
+        }
+        Ok(self.tree.apply_batch(batch)?)
     }
 
-    fn get_or_set(&self, key: &[u8], alternative_value: &[u8]) -> Vec<u8> {
-        if let Some(value) = self.get(key) {
-            value
-        } else {
-            self.set(key, alternative_value);
-            alternative_value.to_vec()
+    fn batch_namespaces(&self, batches: &[(&str, &[WriteOp])]) -> Result<(), StorageError> {
+        let mut trees = vec![];
+        let mut tree_batches = vec![];
+        for (name, ops) in batches {
+            trees.push(self.db.open_tree(name)?);
+            let mut batch = sled::Batch::default();
+            for op in *ops {
+                match op {
+                    WriteOp::Set { key, value } => batch.insert(key.as_slice(), value.as_slice()),
+                    WriteOp::Delete { key } => batch.remove(key.as_slice()),
+                }
+            }
+            tree_batches.push(batch);
         }
+        // a batch only covers one tree, so the trees are written in a single transaction.
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&tree_batches) {
+                    tree.apply_batch(batch)?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Storage(err) => err.into(),
+                TransactionError::Abort(()) => unreachable!("the transaction never aborts"),
+            })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, StorageError> {
+        Ok(self
+            .tree
+            .compare_and_swap(key, expected, Some(new))?
+            .is_ok())
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<(), StorageError> {
+        sled::open(path)?.import(self.db.export());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), StorageError> {
+        // sled reclaims space in the background.
+        Ok(())
+    }
+
+    fn namespace(&self, name: &str) -> Result<Arc<dyn Storage>, StorageError> {
+        Ok(Arc::new(Self {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serial_test::serial;
+
+    use crate::config::StorageConfig;
+
+    use super::{RocksdbStorage, Storage, WriteOp};
+
+    #[test]
+    #[serial]
+    fn scan_prefix_stops_at_boundary() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.delete_prefix(b"scan").unwrap();
+        storage.set(b"scan-a", b"1").unwrap();
+        storage.set(b"scan-b", b"2").unwrap();
+        storage.set(b"scao", b"3").unwrap();
+
+        let scanned: Vec<_> = storage.scan_prefix(b"scan-").collect();
+        assert_eq!(
+            scanned,
+            vec![
+                (b"scan-a".to_vec(), b"1".to_vec()),
+                (b"scan-b".to_vec(), b"2".to_vec())
+            ]
+        );
+
+        storage.delete_prefix(b"scan").unwrap();
+        storage.delete(b"scao").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn compare_and_swap_counter() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.delete(b"cas-counter").unwrap();
+
+        let read_counter = |value: Option<&[u8]>| {
+            value.map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    let mut swaps = 0;
+                    for _ in 0..50 {
+                        let current = storage.get(b"cas-counter").unwrap();
+                        let next = read_counter(current.as_deref()) + 1;
+                        if storage
+                            .compare_and_swap(
+                                b"cas-counter",
+                                current.as_deref(),
+                                &next.to_le_bytes(),
+                            )
+                            .unwrap()
+                        {
+                            swaps += 1;
+                        }
+                    }
+                    swaps
+                })
+            })
+            .collect();
+        let swaps: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        let counter = storage.get(b"cas-counter").unwrap();
+        assert_eq!(read_counter(counter.as_deref()), swaps);
+
+        storage.delete(b"cas-counter").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn get_or_set_race() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.delete(b"get-or-set").unwrap();
+
+        let handles: Vec<_> = (0..32_u8)
+            .map(|i| {
+                let storage = storage.clone();
+                thread::spawn(move || storage.get_or_set(b"get-or-set", &[i]).unwrap())
+            })
+            .collect();
+        let observed: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert!(observed.iter().all(|value| *value == observed[0]));
+        assert_eq!(
+            storage.get(b"get-or-set").unwrap(),
+            Some(observed[0].clone())
+        );
+
+        storage.delete(b"get-or-set").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn get_many_matches_sequential_gets() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.delete_prefix(b"many").unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..64_u8)
+            .map(|i| [b"many".as_ref(), &[i]].concat())
+            .collect();
+        for key in keys.iter().step_by(2) {
+            storage.set(key, &key[4..]).unwrap();
+        }
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+
+        let sequential: Vec<_> = keys.iter().map(|key| storage.get(key).unwrap()).collect();
+        let many = storage.get_many(&keys).unwrap();
+
+        assert_eq!(many, sequential);
+        assert_eq!(many[2], Some(vec![2]));
+        assert_eq!(many[3], None);
+
+        storage.delete_prefix(b"many").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn batches_span_namespaces() {
+        let storage = RocksdbStorage::load(&Default::default());
+        let set = |key: &[u8]| WriteOp::Set {
+            key: key.to_vec(),
+            value: b"1".to_vec(),
+        };
+        storage
+            .batch_namespaces(&[
+                ("batch-a", &[set(b"first"), set(b"second")]),
+                ("batch-b", &[set(b"first")]),
+            ])
+            .unwrap();
+
+        let (a, b) = (
+            storage.namespace("batch-a").unwrap(),
+            storage.namespace("batch-b").unwrap(),
+        );
+        assert_eq!(a.get(b"second").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(b.get(b"first").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(b.get(b"second").unwrap(), None);
+
+        a.delete_prefix(b"").unwrap();
+        b.delete_prefix(b"").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    #[ignore = "benchmark, run with --release -- --ignored"]
+    fn get_many_benchmark() {
+        let path = "db-get-many-bench/";
+        let _ = fs::remove_dir_all(path);
+        let storage = RocksdbStorage::load(&StorageConfig {
+            path: String::from(path),
+            ..Default::default()
+        });
+        let keys: Vec<Vec<u8>> = (0..10_000_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for key in &keys {
+            storage.set(key, key).unwrap();
+        }
+        // read from the tables on disk rather than the memtable, like a replay would.
+        storage.flush().unwrap();
+        storage.compact().unwrap();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+
+        // the best of a few rounds each, taken in turns, so neither gets the warmer cache.
+        let time = |read: &dyn Fn() -> Vec<Option<Vec<u8>>>| {
+            let start = Instant::now();
+            (read(), start.elapsed())
+        };
+        let sequential = || keys.iter().map(|key| storage.get(key).unwrap()).collect();
+        let many = || storage.get_many(&keys).unwrap();
+        let (mut sequential_time, mut many_time) = (Duration::MAX, Duration::MAX);
+        for _ in 0..5 {
+            let (sequential, elapsed) = time(&sequential);
+            sequential_time = sequential_time.min(elapsed);
+            let (many, elapsed) = time(&many);
+            many_time = many_time.min(elapsed);
+            assert_eq!(many, sequential);
+        }
+        assert!(
+            many_time < sequential_time,
+            "get_many took {:?}, sequential gets {:?}",
+            many_time,
+            sequential_time
+        );
+
+        drop(storage);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn snapshot_is_a_full_copy() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.delete_prefix(b"snapshot").unwrap();
+        storage.set(b"snapshot-a", b"1").unwrap();
+        storage.set(b"snapshot-b", b"2").unwrap();
+
+        let path = Path::new("db-snapshot/");
+        let _ = fs::remove_dir_all(path);
+        storage.snapshot(path).unwrap();
+        storage.set(b"snapshot-a", b"3").unwrap();
+
+        let snapshot = RocksdbStorage::load(&StorageConfig {
+            path: String::from("db-snapshot/"),
+            ..Default::default()
+        });
+        assert_eq!(snapshot.get(b"snapshot-a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"snapshot-b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(storage.get(b"snapshot-a").unwrap(), Some(b"3".to_vec()));
+
+        drop(snapshot);
+        fs::remove_dir_all(path).unwrap();
+        storage.delete_prefix(b"snapshot").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn flush_survives_reopen() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.set(b"flushed", b"1").unwrap();
+        storage.flush().unwrap();
+        storage.compact().unwrap();
+        drop(storage);
+
+        let storage = RocksdbStorage::load(&Default::default());
+        assert_eq!(storage.get(b"flushed").unwrap(), Some(b"1".to_vec()));
+        storage.delete(b"flushed").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn contains_key() {
+        let storage = RocksdbStorage::load(&Default::default());
+        storage.set(b"contained", b"").unwrap();
+        assert!(storage.contains_key(b"contained").unwrap());
+
+        storage.delete(b"contained").unwrap();
+        assert!(!storage.contains_key(b"contained").unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn namespaces_do_not_alias() {
+        let storage = RocksdbStorage::load(&Default::default());
+        let first = storage.namespace("first").unwrap();
+        let second = storage.namespace("second").unwrap();
+
+        first.set(b"shared", b"1").unwrap();
+        second.set(b"shared", b"2").unwrap();
+        second.delete_prefix(b"sha").unwrap();
+
+        assert_eq!(first.get(b"shared").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(second.get(b"shared").unwrap(), None);
+        assert_eq!(storage.get(b"shared").unwrap(), None);
+
+        first.delete(b"shared").unwrap();
     }
 }
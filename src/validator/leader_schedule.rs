@@ -1,31 +1,116 @@
+use std::time::Duration;
+
 use rand::{
     distributions::WeightedIndex,
     prelude::{Distribution, StdRng},
-    Rng, SeedableRng,
+    SeedableRng,
 };
+use sha3::{Digest, Sha3_256};
+
+use crate::chain::Block;
 
 const SCHEDULE_SEED: u64 = 13409387784011516370;
 
-// NOTE: weighted random done every epoch by a set of validators that we choose randomly based on the seed.
-// the seed is somehow manipulated every epoch/block.
+// NOTE: weighted random done every slot over the staked validators. the seed is the hash of the
+// head of the chain and how many slots went by since it, so every node that has the same head
+// agrees on the leader, even right after it restarted, and a leader that does not show up is
+// passed over once its slot is gone.
 
 pub struct LeaderSchedule {
-    curr_seed: u64,
-    rng: StdRng,
+    slot_duration: Duration,
 }
 
 impl LeaderSchedule {
-    pub fn new() -> Self {
-        Self {
-            curr_seed: SCHEDULE_SEED,
-            rng: StdRng::seed_from_u64(SCHEDULE_SEED),
-        }
-    }
-
-    pub fn get_validator(&mut self) {
-        let a = WeightedIndex::new([2, 1]).unwrap(); // somehow get the validator list and the stake distribution.
-        a.sample(&mut self.rng);
-        self.curr_seed = 0; // somehow manipulate the seed. maybe hash it with the chosen validator's pubkey?
-        self.rng = StdRng::seed_from_u64(self.curr_seed);
+    pub fn new(slot_duration: Duration) -> Self {
+        Self { slot_duration }
+    }
+
+    /// How many whole slots went by between the block `head` and `time`, in milliseconds.
+    pub fn slot(&self, head: &Block, time: i64) -> u64 {
+        let elapsed = time.saturating_sub(head.time()).max(0) as u128;
+        (elapsed / self.slot_duration.as_millis().max(1)) as u64
+    }
+
+    /// The leader out of `validators`, weighted by their stake, of the block that extends `head`
+    /// at `time`. There is none if no validator has anything staked.
+    pub fn get_validator(
+        &self,
+        head: &Block,
+        time: i64,
+        validators: &[([u8; 32], u64)],
+    ) -> Option<[u8; 32]> {
+        let distribution = WeightedIndex::new(validators.iter().map(|(_, stake)| *stake)).ok()?;
+        let mut hasher = Sha3_256::new();
+        hasher.update(SCHEDULE_SEED.to_be_bytes());
+        hasher.update(head.digest());
+        hasher.update(head.height().to_be_bytes());
+        hasher.update(self.slot(head, time).to_be_bytes());
+        let seed = u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap());
+        let mut rng = StdRng::seed_from_u64(seed);
+        Some(validators[distribution.sample(&mut rng)].0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::LeaderSchedule;
+    use crate::chain::Block;
+
+    const VALIDATORS: [([u8; 32], u64); 3] = [([1; 32], 50), ([2; 32], 0), ([3; 32], 25)];
+    const SLOT: i64 = 1000;
+
+    fn schedule() -> LeaderSchedule {
+        LeaderSchedule::new(Duration::from_millis(SLOT as u64))
+    }
+
+    /// The leaders of the first `count` slots after `head`.
+    fn leaders(head: &Block, count: i64) -> Vec<[u8; 32]> {
+        (0..count)
+            .map(|slot| {
+                let time = head.time() + slot * SLOT + 1;
+                schedule().get_validator(head, time, &VALIDATORS).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn schedule_is_reproducible() {
+        let head = Block::with_transactions(vec![], [0; 32]);
+        let sequence = leaders(&head, 32);
+        assert_eq!(sequence, leaders(&head, 32));
+        // nothing staked, never a leader.
+        assert!(!sequence.contains(&[2; 32]));
+        assert!(sequence.contains(&[1; 32]) && sequence.contains(&[3; 32]));
+    }
+
+    #[test]
+    fn no_stake_no_leader() {
+        let head = Block::with_transactions(vec![], [0; 32]);
+        assert_eq!(schedule().get_validator(&head, head.time(), &[]), None);
+        let unstaked = [([1; 32], 0)];
+        assert_eq!(
+            schedule().get_validator(&head, head.time(), &unstaked),
+            None
+        );
+    }
+
+    #[test]
+    fn a_slot_lasts_until_the_next_one() {
+        let head = Block::with_transactions(vec![], [0; 32]);
+        let leader = |time| schedule().get_validator(&head, time, &VALIDATORS);
+        assert_eq!(schedule().slot(&head, head.time() - 1), 0);
+        assert_eq!(schedule().slot(&head, head.time() + SLOT - 1), 0);
+        assert_eq!(schedule().slot(&head, head.time() + SLOT), 1);
+        assert_eq!(leader(head.time() + 1), leader(head.time() + SLOT - 1));
+    }
+
+    #[test]
+    fn missing_leaders_are_passed_over() {
+        let head = Block::with_transactions(vec![], [0; 32]);
+        // whoever leads the first slot, the other one leads some slot after it.
+        let sequence = leaders(&head, 32);
+        assert!(sequence.iter().any(|leader| *leader != sequence[0]));
     }
 }
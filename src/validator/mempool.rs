@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+};
+
+use crate::{
+    chain::{Block, ContractRecipt},
+    contracts::ContractRequest,
+};
+
+fn request_hash(request: &ContractRequest) -> [u8; 32] {
+    ContractRecipt::from(request.clone()).hash()
+}
+
+#[derive(Default)]
+struct MempoolState {
+    pending: VecDeque<([u8; 32], ContractRequest)>,
+    pending_hashes: HashSet<[u8; 32]>,
+    finalized: VecDeque<[u8; 32]>,
+    finalized_hashes: HashSet<[u8; 32]>,
+}
+
+/// The transactions we heard of that did not make it into a block yet. It holds at most
+/// `capacity` of them, and remembers the last `capacity` finalized ones so that a late copy
+/// of a finalized transaction is not picked up again.
+pub struct Mempool {
+    capacity: usize,
+    state: Mutex<MempoolState>,
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(MempoolState::default()),
+        }
+    }
+
+    /// Adds `request` and returns whether it is new to us, in which case it should be passed on
+    /// to our peers.
+    pub fn insert(&self, request: ContractRequest) -> bool {
+        let hash = request_hash(&request);
+        let mut state = self.state.lock().unwrap();
+        if state.pending.len() >= self.capacity
+            || state.pending_hashes.contains(&hash)
+            || state.finalized_hashes.contains(&hash)
+        {
+            return false;
+        }
+        state.pending_hashes.insert(hash);
+        state.pending.push_back((hash, request));
+        true
+    }
+
+    /// The pending transactions, oldest first.
+    pub fn pending(&self) -> Vec<ContractRequest> {
+        let state = self.state.lock().unwrap();
+        state
+            .pending
+            .iter()
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
+    /// Drops `requests` without taking them for finalized, so that they can be sent again.
+    pub fn remove(&self, requests: &[ContractRequest]) {
+        let hashes: HashSet<_> = requests.iter().map(request_hash).collect();
+        let mut state = self.state.lock().unwrap();
+        let MempoolState {
+            pending,
+            pending_hashes,
+            ..
+        } = &mut *state;
+        pending.retain(|(hash, _)| !hashes.contains(hash));
+        pending_hashes.retain(|hash| !hashes.contains(hash));
+    }
+
+    /// Drops the transactions `block` finalized.
+    pub fn evict_finalized(&self, block: &Block) {
+        let mut state = self.state.lock().unwrap();
+        for hash in block.recipts().iter().map(ContractRecipt::hash) {
+            if state.finalized_hashes.insert(hash) {
+                state.finalized.push_back(hash);
+            }
+            if state.finalized.len() > self.capacity {
+                if let Some(oldest) = state.finalized.pop_front() {
+                    state.finalized_hashes.remove(&oldest);
+                }
+            }
+        }
+
+        let MempoolState {
+            pending,
+            pending_hashes,
+            finalized_hashes,
+            ..
+        } = &mut *state;
+        pending.retain(|(hash, _)| !finalized_hashes.contains(hash));
+        pending_hashes.retain(|hash| !finalized_hashes.contains(hash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Mempool;
+    use crate::{
+        chain::{requests_to_recipts, Block},
+        contracts::ContractRequest,
+    };
+
+    fn transfer(amount: u64) -> ContractRequest {
+        ContractRequest::new(
+            [0; 32],
+            "native".to_string(),
+            "transfer".to_string(),
+            json!({"to": "ginger", "amount": amount}),
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let mempool = Mempool::new(16);
+        assert!(mempool.insert(transfer(1)));
+        assert!(!mempool.insert(transfer(1)));
+        assert!(mempool.insert(transfer(2)));
+        assert_eq!(mempool.pending().len(), 2);
+    }
+
+    #[test]
+    fn mempool_is_bounded() {
+        let mempool = Mempool::new(2);
+        assert!(mempool.insert(transfer(1)));
+        assert!(mempool.insert(transfer(2)));
+        assert!(!mempool.insert(transfer(3)));
+        assert_eq!(mempool.pending().len(), 2);
+    }
+
+    #[test]
+    fn finalized_transactions_are_evicted_for_good() {
+        let mempool = Mempool::new(16);
+        mempool.insert(transfer(1));
+        mempool.insert(transfer(2));
+
+        let block = Block::with_transactions(requests_to_recipts(vec![transfer(1)]), [0; 32]);
+        mempool.evict_finalized(&block);
+
+        assert_eq!(mempool.pending()[0].req, transfer(2).req);
+        assert_eq!(mempool.pending().len(), 1);
+        assert!(!mempool.insert(transfer(1)));
+    }
+
+    #[test]
+    fn removed_transactions_can_come_back() {
+        let mempool = Mempool::new(16);
+        mempool.insert(transfer(1));
+        mempool.insert(transfer(2));
+
+        mempool.remove(&[transfer(1)]);
+        assert_eq!(mempool.pending().len(), 1);
+        assert_eq!(mempool.pending()[0].req, transfer(2).req);
+        assert!(mempool.insert(transfer(1)));
+    }
+}
@@ -1,80 +1,1086 @@
 mod leader_schedule;
+mod mempool;
 use primitive_types::U256;
 
 use crate::contracts::execute;
 
 pub use self::leader_schedule::*;
+pub use self::mempool::Mempool;
+#[cfg(feature = "rpc")]
+use crate::rpc::{RpcError, RpcService};
+
+const MEMPOOL_CAPACITY: usize = 8192;
+/// How often a validator that is not the leader checks whether the leader's block came in.
+const BLOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How often a running validator reports on its peers.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 use {
     crate::{
-        chain::{requests_to_recipts, Block, Chain},
-        config::TeralConfig,
-        contracts::{ContractExecuter, ContractRequest},
-        p2p::{ClusterInfo, GossipService},
+        chain::{Block, Chain, ChainError},
+        config::{IdentityConfig, TeralConfig},
+        contracts::{stakes, ContractExecuter, ContractRequest},
+        p2p::{
+            ClusterInfo, GossipMessage, GossipService, GossipStats, GossipedBlock, P2PError,
+            Protocol,
+        },
+        storage::{Storage, StorageError},
     },
+    chrono::Utc,
     ed25519_consensus::SigningKey,
     std::{
-        net::UdpSocket,
+        fs::{File, OpenOptions},
+        io::{self, ErrorKind, Read, Write},
+        os::unix::fs::{OpenOptionsExt, PermissionsExt},
         sync::{
             atomic::{AtomicBool, Ordering},
+            mpsc::Receiver,
             Arc,
         },
+        thread,
+        time::{Duration, Instant},
     },
+    thiserror::Error,
 };
 
+#[derive(Debug, Error)]
+pub enum ValidatorError {
+    #[error("The configured storage backend is not compiled in")]
+    StorageBackend,
+    #[error("p2p error: {0}")]
+    P2P(#[from] P2PError),
+    #[error("chain error: {0}")]
+    Chain(#[from] ChainError),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("could not read or write the identity: {0}")]
+    Identity(#[from] io::Error),
+    #[error("the identity at {0} is not a 32 byte signing key")]
+    InvalidIdentity(String),
+    #[error("the identity at {0} is open to other users, it should be private (chmod 600)")]
+    ExposedIdentity(String),
+    #[error("nobody staked, so there is no leader whose blocks we could take")]
+    NoLeader,
+    #[error("the block is not the scheduled leader's")]
+    NotTheLeader,
+    #[error("re-executing the block's recipts does not give the statuses it claims")]
+    DivergentBlock,
+    #[error("a gossip thread panicked")]
+    GossipPanicked,
+    #[cfg(feature = "rpc")]
+    #[error("rpc error: {0}")]
+    Rpc(#[from] RpcError),
+    #[cfg(feature = "rpc")]
+    #[error("the rpc thread panicked")]
+    RpcPanicked,
+}
+
+/// Reads the signing key at `config.path`, or generates one and writes it there if there is
+/// none, so that the node keeps its identity across restarts. The key is only readable by its
+/// owner, and one that others can get at is refused.
+fn load_identity(config: &IdentityConfig) -> Result<SigningKey, ValidatorError> {
+    match File::open(&config.path) {
+        Ok(mut file) => {
+            if file.metadata()?.permissions().mode() & 0o077 != 0 {
+                return Err(ValidatorError::ExposedIdentity(config.path.clone()));
+            }
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes)?;
+            <[u8; 32]>::try_from(bytes)
+                .map(SigningKey::from)
+                .map_err(|_| ValidatorError::InvalidIdentity(config.path.clone()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let keypair = SigningKey::new(rand::thread_rng());
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&config.path)?
+                .write_all(&keypair.to_bytes())?;
+            Ok(keypair)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub struct Validator {
     schedule: LeaderSchedule,
     exit: Arc<AtomicBool>,
     gossip: GossipService,
+    // what our peers push to us, which should be transactions.
+    gossip_receiver: Receiver<GossipMessage>,
+    // the blocks our peers announce, applied once they check out.
+    block_receiver: Receiver<GossipedBlock>,
+    gossip_stats: Arc<GossipStats>,
+    mempool: Arc<Mempool>,
+    pubkey: [u8; 32],
+    storage: Arc<dyn Storage>,
+    slot_duration: Duration,
     chain: Arc<Chain>, // arc to share between here and the rpc service.
     contract_executer: ContractExecuter,
+    #[cfg(feature = "rpc")]
+    rpc: Option<RpcService>,
 }
 
 impl Validator {
-    pub fn new(config: TeralConfig) -> Self {
+    pub fn new(config: TeralConfig) -> Result<Self, ValidatorError> {
         let exit = Arc::new(AtomicBool::new(false));
 
-        let storage = config.load_storage().unwrap();
+        let storage = config
+            .load_storage()
+            .ok_or(ValidatorError::StorageBackend)?;
         // native_init(storage.clone());
-        let keypair = Arc::new(SigningKey::new(&mut rand::thread_rng()));
-        let chain = Arc::new(Chain::new(
+        let keypair = Arc::new(load_identity(&config.identity)?);
+        let pubkey = keypair.verification_key().to_bytes();
+        let chain = Arc::new(Chain::with_genesis(
             storage.clone(),
-            keypair.verification_key().to_bytes(),
-        ));
-        let contract_executer =
-            ContractExecuter::new(storage.clone(), exit.clone(), config.contracts_exec.threads);
-        let udp_socket = UdpSocket::bind(&config.network.addr)
-            .unwrap_or_else(|_| panic!("Could not bind udp socket to {}", config.network.addr));
-        let cluster_info = Arc::new(ClusterInfo::new(keypair, storage.clone()));
-        let (gossip, gossip_receiver) = GossipService::new(cluster_info, udp_socket, &exit);
-
-        Self {
+            pubkey,
+            &config.genesis,
+        )?);
+        let contract_executer = ContractExecuter::new(
+            storage.clone(),
+            exit.clone(),
+            &config.contracts_exec,
+            &config.genesis.params,
+        )?;
+        let mempool = Arc::new(Mempool::new(MEMPOOL_CAPACITY));
+        let cluster_info = Arc::new(
+            ClusterInfo::new(keypair, storage.clone(), config.network.known_nodes.clone())?
+                .with_encryption(config.network.encrypt),
+        );
+        let (gossip, gossip_receiver, block_receiver, gossip_stats) = GossipService::new(
+            cluster_info,
+            chain.clone(),
+            mempool.clone(),
+            &config.network.addr,
+            &config.network.gossip,
+            &exit,
+        )?;
+        #[cfg(feature = "rpc")]
+        let rpc = config
+            .rpc
+            .as_ref()
+            .map(|rpc| {
+                let params = &config.genesis.params;
+                RpcService::new(&rpc.addr, chain.clone(), storage.clone(), params, &exit)
+            })
+            .transpose()?;
+        #[cfg(not(feature = "rpc"))]
+        if config.rpc.is_some() {
+            tracing::warn!("rpc is configured, but the node was built without it");
+        }
+
+        Ok(Self {
             exit,
             chain,
             contract_executer,
             gossip,
-            schedule: LeaderSchedule::new(),
+            gossip_receiver,
+            block_receiver,
+            gossip_stats,
+            mempool,
+            pubkey,
+            storage,
+            slot_duration: config.consensus.slot_duration(),
+            schedule: config.get_scheduler(),
+            #[cfg(feature = "rpc")]
+            rpc,
+        })
+    }
+
+    /// Applies the blocks our peers built while we were away, a batch at a time up to their
+    /// head, checking each like a gossiped one.
+    pub fn catch_up(&mut self) -> Result<(), ValidatorError> {
+        let height = self.chain.height();
+        loop {
+            let blocks = self.gossip.sync(&self.chain)?;
+            if blocks.is_empty() {
+                break;
+            }
+            for block in blocks {
+                self.apply_block(block, None)?;
+            }
+            tracing::info!("synced up to height {}", self.chain.height());
+        }
+        tracing::info!(
+            "caught up from height {} to {}",
+            height,
+            self.chain.height()
+        );
+        Ok(())
+    }
+
+    /// Makes itself known to its peers and catches up with them, then produces a block whenever
+    /// the schedule picks us, and otherwise waits for the leader's block to come in through
+    /// gossip, a slot at a time until the node is stopped.
+    pub fn run(&mut self) -> Result<(), ValidatorError> {
+        match self.gossip.discover() {
+            Ok(contacts) => tracing::info!("{} contacts to gossip with", contacts),
+            Err(err) => tracing::warn!("could not discover peers: {}", err),
+        }
+        self.catch_up()?;
+        let mut reported = Instant::now();
+        while !self.exit.load(Ordering::Relaxed) {
+            let slot_start = Instant::now();
+            self.run_slot(slot_start)?;
+            if reported.elapsed() >= REPORT_INTERVAL {
+                self.report();
+                reported = Instant::now();
+            }
+            thread::sleep(self.slot_duration.saturating_sub(slot_start.elapsed()));
         }
+        Ok(())
     }
 
-    pub fn schedule_contract(&mut self, req: ContractRequest) {
-        self.contract_executer.schedule(req);
+    /// Logs the height, how many peers are alive and what gossip went through.
+    fn report(&self) {
+        tracing::info!(
+            "at height {} with {} live peers, {:?}",
+            self.chain.height(),
+            self.gossip.live_peers(),
+            self.gossip_stats
+        );
+        tracing::debug!("peers last seen at {:?}", self.gossip.last_seen());
     }
 
-    pub fn finalize_block(&mut self) {
-        let block = self.finalize_contracts();
-        self.chain.insert_block(block);
+    fn run_slot(&mut self, slot_start: Instant) -> Result<(), ValidatorError> {
+        self.take_gossiped_transactions();
+        self.take_rpc_transactions();
+        // the block is stamped with the time it was decided at, so that it falls in the same slot
+        // for whoever checks it.
+        let time = Utc::now().timestamp_millis();
+        let leader = match self.leader_at(time)? {
+            Some(leader) => leader,
+            None => {
+                tracing::warn!("nobody staked, so there is no leader to produce blocks");
+                return Ok(());
+            }
+        };
+
+        if leader == self.pubkey {
+            let scheduled = self.mempool.pending();
+            for request in scheduled.iter().cloned() {
+                self.contract_executer.schedule(request);
+            }
+            self.finalize_block(time)?;
+            // the ones the block left out could not be charged for, and would only run again.
+            self.mempool.remove(&scheduled);
+            return Ok(());
+        }
+
+        while slot_start.elapsed() < self.slot_duration && !self.exit.load(Ordering::Relaxed) {
+            if self.take_gossiped_blocks() {
+                return Ok(());
+            }
+            thread::sleep(BLOCK_POLL_INTERVAL);
+        }
+        tracing::debug!("the leader did not produce a block this slot");
+        Ok(())
+    }
+
+    /// The leader of the block that extends our head at `time`, or none if nobody staked.
+    fn leader_at(&self, time: i64) -> Result<Option<[u8; 32]>, ValidatorError> {
+        let head = self.chain.latest_block()?.ok_or(ChainError::MissingHead)?;
+        let stakes = stakes(self.storage.clone())?;
+        Ok(self.schedule.get_validator(&head, time, &stakes))
+    }
+
+    /// The public key the node signs with.
+    pub fn pubkey(&self) -> [u8; 32] {
+        self.pubkey
     }
 
-    pub fn finalize_contracts(&mut self) -> Block {
+    /// Adds a transaction to the mempool and passes it on to our peers, unless we already know of
+    /// it.
+    pub fn submit_transaction(&self, req: ContractRequest) {
+        if self.mempool.insert(req.clone()) {
+            if let Err(err) = self.gossip.broadcast(Protocol::Transaction(req)) {
+                tracing::warn!("could not broadcast a transaction: {:?}", err);
+            }
+        }
+    }
+
+    /// Submits the transactions our peers pushed since the last slot, dropping the ones that do
+    /// not decode or are forged.
+    fn take_gossiped_transactions(&self) {
+        for message in self.gossip_receiver.try_iter() {
+            match message.transaction() {
+                Ok(req) => self.submit_transaction(req),
+                Err(err) => {
+                    let author = base64::encode(message.author());
+                    tracing::debug!("dropping a gossip push from {}: {}", author, err)
+                }
+            }
+        }
+    }
+
+    /// Applies the blocks our peers announced since we last looked, dropping the ones that do not
+    /// check out. A block past the next one means we missed some, so we catch up instead.
+    /// Returns whether any was applied.
+    fn take_gossiped_blocks(&mut self) -> bool {
+        let gossiped: Vec<_> = self.block_receiver.try_iter().collect();
+        let mut applied = false;
+        for GossipedBlock { signer, block } in gossiped {
+            let height = self.chain.height();
+            if block.height() > height + 1 {
+                if let Err(err) = self.catch_up() {
+                    tracing::warn!("could not catch up: {}", err);
+                }
+                applied |= self.chain.height() > height;
+                continue;
+            }
+            match self.apply_block(block, Some(signer)) {
+                Ok(()) => applied = true,
+                Err(err) => tracing::debug!("dropping a block from {:?}: {}", signer, err),
+            }
+        }
+        applied
+    }
+
+    /// Applies a block another validator produced. It has to be the block of the leader the
+    /// schedule picks for the slot of its time, announced by that leader if `signer` is given,
+    /// and re-executing its recipts on our state has to give the statuses it claims. Only then is
+    /// it inserted, along with what it wrote.
+    pub fn apply_block(
+        &mut self,
+        block: Block,
+        signer: Option<[u8; 32]>,
+    ) -> Result<(), ValidatorError> {
+        let head = self.chain.latest_block()?.ok_or(ChainError::MissingHead)?;
+        if block.previous_digest() != head.digest() {
+            return Err(ChainError::NotOnHead.into());
+        }
+        let leader = self
+            .leader_at(block.time())?
+            .ok_or(ValidatorError::NoLeader)?;
+        if block.beneficiary() != leader || signer.is_some_and(|signer| signer != leader) {
+            return Err(ValidatorError::NotTheLeader);
+        }
+
+        let replayed = self
+            .contract_executer
+            .replay(block.recipts())
+            .ok_or(ValidatorError::DivergentBlock)?;
+        let (namespace, writes) = replayed.into_writes();
+        self.chain
+            .insert_block_with(block.clone(), &[(namespace, &writes)])?;
+        self.mempool.evict_finalized(&block);
+        Ok(())
+    }
+
+    /// Submits the transactions that came in over rpc since the last slot.
+    fn take_rpc_transactions(&self) {
+        #[cfg(feature = "rpc")]
+        if let Some(rpc) = &self.rpc {
+            for req in rpc.submitted() {
+                self.submit_transaction(req);
+            }
+        }
+    }
+
+    /// Puts what the executer ran since the last block into a block stamped with `time`, and
+    /// announces it.
+    pub fn finalize_block(&mut self, time: i64) -> Result<(), ChainError> {
+        let block = self.finalize_contracts(time);
+        let announcement = Protocol::new_block(&block);
+        self.chain.insert_block(block.clone())?;
+        // only once it is in, so a block that does not make it leaves them to be sent again.
+        self.mempool.evict_finalized(&block);
+        tracing::info!(
+            "finalized block {} with {} recipts",
+            block.height(),
+            block.recipt_count()
+        );
+        if let Err(err) = self.gossip.broadcast(announcement) {
+            tracing::warn!("could not broadcast the finalized block: {:?}", err);
+        }
+        Ok(())
+    }
+
+    pub fn finalize_contracts(&mut self, time: i64) -> Block {
         let transactions = self.contract_executer.summary();
         tracing::debug!("finalizing transactions: {:?}", transactions);
-        self.chain
-            .block_with_transactions(requests_to_recipts(transactions.to_vec()))
+        self.chain.block_with_transactions(transactions, time)
     }
 
-    pub fn stop(self) {
+    /// Stops every thread the validator started and waits for them to finish.
+    pub fn stop(self) -> Result<(), ValidatorError> {
         self.exit.store(true, Ordering::SeqCst);
         self.contract_executer.join();
+        #[cfg(feature = "rpc")]
+        if let Some(rpc) = self.rpc {
+            rpc.join().map_err(|_| ValidatorError::RpcPanicked)?;
+        }
+        self.gossip
+            .join()
+            .map_err(|_| ValidatorError::GossipPanicked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use ed25519_consensus::SigningKey;
+    use serde_json::json;
+    use serial_test::serial;
+
+    use super::{Validator, ValidatorError};
+    use crate::{
+        chain::{ContractRecipt, TxStatus},
+        config::{TeralConfig, DEFAULT_BASE_FEE},
+        contracts::{balance, stakes, ContractRequest},
+        p2p::{encode_contacts, Protocol, CLUSTER_NAMESPACE},
+    };
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
+    /// Writes `key` as the identity at `path`, private like `load_identity` wants it.
+    fn write_identity(path: &str, key: &[u8]) {
+        fs::write(path, key).unwrap();
+        fs::set_permissions(path, Permissions::from_mode(0o600)).unwrap();
+    }
+
+    fn config(path: &str, identity: &str) -> TeralConfig {
+        toml::from_str(&format!(
+            r#"
+[storage]
+path = "{}"
+backend = "rocksdb"
+log_history = 5
+
+[identity]
+path = "{}"
+
+[network]
+addr = "127.0.0.1:0"
+known_nodes = []
+
+[contracts_exec]
+threads = 1
+
+[genesis.allocations]
+ghostway = 1000
+"#,
+            path, identity
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn validator_finalizes_scheduled_contracts() {
+        let path = "db-validator/";
+        let _ = fs::remove_dir_all(path);
+        let mut config = config(path, "identity-validator.key");
+        // enough for the fee.
+        config
+            .genesis
+            .allocations
+            .insert(base64::encode([0; 32]), 1);
+        let mut validator = Validator::new(config).unwrap();
+        validator.contract_executer.schedule(ContractRequest::new(
+            [0; 32],
+            String::from("native"),
+            String::from("transfer"),
+            json!({ "to": "ginger", "amount": 10_u64 }),
+            0,
+            0,
+        ));
+        validator.finalize_block(now()).unwrap();
+        let block = validator.chain.latest_block().unwrap().unwrap();
+        assert_eq!(block.height(), 1);
+        assert_eq!(block.recipts().len(), 1);
+
+        validator.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_file("identity-validator.key").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn identity_survives_restarts() {
+        let identity = "identity-restart.key";
+        let _ = fs::remove_file(identity);
+        let first = Validator::new(config("db-identity-first/", identity)).unwrap();
+        let second = Validator::new(config("db-identity-second/", identity)).unwrap();
+        assert_eq!(first.pubkey(), second.pubkey());
+        first.stop().unwrap();
+        second.stop().unwrap();
+        let mode = fs::metadata(identity).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::set_permissions(identity, Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            Validator::new(config("db-identity-third/", identity)),
+            Err(ValidatorError::ExposedIdentity(_))
+        ));
+
+        write_identity(identity, &[7; 16]);
+        assert!(matches!(
+            Validator::new(config("db-identity-third/", identity)),
+            Err(ValidatorError::InvalidIdentity(_))
+        ));
+
+        for path in [
+            "db-identity-first/",
+            "db-identity-second/",
+            "db-identity-third/",
+        ] {
+            let _ = fs::remove_dir_all(path);
+        }
+        fs::remove_file(identity).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn single_validator_produces_a_block_every_slot() {
+        let (path, identity) = ("db-slots/", "identity-slots.key");
+        let _ = fs::remove_dir_all(path);
+        write_identity(identity, &[9; 32]);
+        let pubkey = SigningKey::from([9; 32]).verification_key().to_bytes();
+        let mut config = config(path, identity);
+        config
+            .genesis
+            .allocations
+            .insert(base64::encode(pubkey), 1000);
+        config.consensus.slot_duration_ms = 100;
+
+        let mut validator = Validator::new(config).unwrap();
+        validator.contract_executer.schedule(ContractRequest::new(
+            pubkey,
+            String::from("native"),
+            String::from("stake"),
+            json!({ "amount": 100_u64 }),
+            0,
+            0,
+        ));
+        validator.finalize_block(now()).unwrap();
+
+        let exit = validator.exit.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(550));
+            exit.store(true, Ordering::SeqCst);
+        });
+        validator.run().unwrap();
+        stopper.join().unwrap();
+
+        // the staking block, and then one for each of the six slots or so.
+        let height = validator.chain.height();
+        assert!((5..=8).contains(&height), "{} blocks", height);
+        let times: Vec<_> = (2..=height)
+            .map(|height| {
+                validator
+                    .chain
+                    .block_by_height(height)
+                    .unwrap()
+                    .unwrap()
+                    .time()
+            })
+            .collect();
+        assert!(times.windows(2).all(|slot| slot[1] - slot[0] >= 80));
+
+        validator.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_file(identity).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn restarted_validator_catches_up_before_producing() {
+        let peer_path = "db-catch-up-peer/";
+        let _ = fs::remove_dir_all(peer_path);
+        // the peer is the leader, so its blocks check out.
+        write_identity("identity-catch-up-peer.key", &[11; 32]);
+        let mut peer =
+            Validator::new(staked_config(peer_path, "identity-catch-up-peer.key")).unwrap();
+        peer.contract_executer.schedule(transfer(10));
+        for _ in 0..3 {
+            peer.finalize_block(now()).unwrap();
+        }
+
+        let path = "db-catch-up/";
+        let _ = fs::remove_dir_all(path);
+        let mut config = staked_config(path, "identity-catch-up.key");
+        config.network.known_nodes = vec![peer.gossip.local_addr().unwrap()];
+        let mut validator = Validator::new(config).unwrap();
+        assert_eq!(validator.chain.height(), 0);
+
+        // stopped already, so `run` only catches up.
+        validator.exit.store(true, Ordering::SeqCst);
+        validator.run().unwrap();
+        assert_eq!(validator.chain.height(), 3);
+        assert_eq!(
+            validator.chain.latest_block().unwrap().unwrap().digest(),
+            peer.chain.latest_block().unwrap().unwrap().digest()
+        );
+        // the blocks were run, not only stored.
+        assert_eq!(
+            balance(validator.storage.clone(), "ginger").unwrap(),
+            Some(10)
+        );
+
+        validator.stop().unwrap();
+        peer.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_dir_all(peer_path).unwrap();
+        fs::remove_file("identity-catch-up.key").unwrap();
+        fs::remove_file("identity-catch-up-peer.key").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn pushed_transactions_are_scheduled() {
+        let (path, peer_path) = ("db-pushed/", "db-pushed-peer/");
+        let _ = fs::remove_dir_all(path);
+        let _ = fs::remove_dir_all(peer_path);
+        let sender = SigningKey::from([4; 32]);
+        let mut validator_config = config(path, "identity-pushed.key");
+        validator_config
+            .genesis
+            .allocations
+            .insert(base64::encode(sender.verification_key().to_bytes()), 100);
+        let mut validator = Validator::new(validator_config).unwrap();
+        // the peer pushes to the contacts it has stored, so it has to know of us beforehand.
+        let peer_config = config(peer_path, "identity-pushed-peer.key");
+        peer_config
+            .load_storage()
+            .unwrap()
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap()
+            .set(
+                b"contact_list",
+                &encode_contacts(&[validator.gossip.local_addr().unwrap()]),
+            )
+            .unwrap();
+        let peer = Validator::new(peer_config).unwrap();
+
+        let transfer = ContractRequest::signed(
+            &sender,
+            String::from("native"),
+            String::from("transfer"),
+            json!({ "to": "ginger", "amount": 10_u64 }),
+            0,
+            0,
+        );
+        // a malformed push is dropped without taking the transactions after it down.
+        for push in [b"garbage".to_vec(), transfer.to_bytes()] {
+            peer.gossip.broadcast(Protocol::GossipPush(push)).unwrap();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while validator.mempool.pending().is_empty() && Instant::now() < deadline {
+            validator.take_gossiped_transactions();
+            thread::sleep(Duration::from_millis(50));
+        }
+        let pending = validator.mempool.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].author(), transfer.author());
+
+        for request in pending {
+            validator.contract_executer.schedule(request);
+        }
+        validator.finalize_block(now()).unwrap();
+        assert_eq!(
+            validator.chain.latest_block().unwrap().unwrap().recipts()[0].hash(),
+            ContractRecipt::from(transfer).hash()
+        );
+
+        validator.stop().unwrap();
+        peer.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_dir_all(peer_path).unwrap();
+        fs::remove_file("identity-pushed.key").unwrap();
+        fs::remove_file("identity-pushed-peer.key").unwrap();
+    }
+
+    /// A config whose genesis stakes the validator with the identity `[11; 32]`, making it the
+    /// only leader, and funds `[4; 32]`.
+    fn staked_config(path: &str, identity: &str) -> TeralConfig {
+        let mut config = config(path, identity);
+        let leader = SigningKey::from([11; 32]).verification_key().to_bytes();
+        config.genesis.stakes.insert(base64::encode(leader), 100);
+        let sender = SigningKey::from([4; 32]).verification_key().to_bytes();
+        config
+            .genesis
+            .allocations
+            .insert(base64::encode(sender), 100);
+        config
+    }
+
+    fn transfer(amount: u64) -> ContractRequest {
+        ContractRequest::signed(
+            &SigningKey::from([4; 32]),
+            String::from("native"),
+            String::from("transfer"),
+            json!({ "to": "ginger", "amount": amount }),
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn followers_apply_the_leaders_blocks() {
+        let (path, leader_path) = ("db-follower/", "db-follower-leader/");
+        let _ = fs::remove_dir_all(path);
+        let _ = fs::remove_dir_all(leader_path);
+        let mut follower = Validator::new(staked_config(path, "identity-follower.key")).unwrap();
+        write_identity("identity-follower-leader.key", &[11; 32]);
+        let leader_config = staked_config(leader_path, "identity-follower-leader.key");
+        leader_config
+            .load_storage()
+            .unwrap()
+            .namespace(CLUSTER_NAMESPACE)
+            .unwrap()
+            .set(
+                b"contact_list",
+                &encode_contacts(&[follower.gossip.local_addr().unwrap()]),
+            )
+            .unwrap();
+        let mut leader = Validator::new(leader_config).unwrap();
+
+        follower.mempool.insert(transfer(10));
+        leader.contract_executer.schedule(transfer(10));
+        leader.finalize_block(now()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !follower.take_gossiped_blocks() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(follower.chain.height(), 1);
+        assert_eq!(
+            follower.chain.latest_block().unwrap().unwrap().digest(),
+            leader.chain.latest_block().unwrap().unwrap().digest()
+        );
+        for account in ["ginger", &base64::encode(transfer(0).author())] {
+            assert_eq!(
+                balance(follower.storage.clone(), account).unwrap(),
+                balance(leader.storage.clone(), account).unwrap()
+            );
+        }
+        assert_eq!(
+            balance(follower.storage.clone(), "ginger").unwrap(),
+            Some(10)
+        );
+        assert!(follower.mempool.pending().is_empty());
+
+        follower.stop().unwrap();
+        leader.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_dir_all(leader_path).unwrap();
+        fs::remove_file("identity-follower.key").unwrap();
+        fs::remove_file("identity-follower-leader.key").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn blocks_that_do_not_check_out_are_dropped() {
+        let paths = ["db-dropped/", "db-dropped-leader/", "db-dropped-other/"];
+        for path in paths {
+            let _ = fs::remove_dir_all(path);
+        }
+        let mut follower = Validator::new(staked_config(paths[0], "identity-dropped.key")).unwrap();
+        write_identity("identity-dropped-leader.key", &[11; 32]);
+        let leader =
+            Validator::new(staked_config(paths[1], "identity-dropped-leader.key")).unwrap();
+        let other = Validator::new(staked_config(paths[2], "identity-dropped-other.key")).unwrap();
+
+        let block = other.chain.block_with_transactions(vec![], now());
+        assert!(matches!(
+            follower.apply_block(block, Some(other.pubkey())),
+            Err(ValidatorError::NotTheLeader)
+        ));
+        let block = leader.chain.block_with_transactions(vec![], now());
+        assert!(matches!(
+            follower.apply_block(block, Some(other.pubkey())),
+            Err(ValidatorError::NotTheLeader)
+        ));
+
+        // the transfer went through, so a recipt that says it reverted is a lie.
+        let lie = ContractRecipt::from(transfer(10))
+            .with_status(TxStatus::Reverted(String::from("insufficient balance")));
+        let block = leader.chain.block_with_transactions(vec![lie], now());
+        assert!(matches!(
+            follower.apply_block(block, Some(leader.pubkey())),
+            Err(ValidatorError::DivergentBlock)
+        ));
+        // a leader can not spend an account whose owner did not sign the request.
+        let unsigned = ContractRecipt::from(transfer(10).with_signature(None));
+        let block = leader.chain.block_with_transactions(vec![unsigned], now());
+        assert!(matches!(
+            follower.apply_block(block, Some(leader.pubkey())),
+            Err(ValidatorError::DivergentBlock)
+        ));
+        assert_eq!(follower.chain.height(), 0);
+        assert_eq!(balance(follower.storage.clone(), "ginger").unwrap(), None);
+
+        let block = leader
+            .chain
+            .block_with_transactions(vec![ContractRecipt::from(transfer(10))], now());
+        follower.apply_block(block, Some(leader.pubkey())).unwrap();
+        assert_eq!(follower.chain.height(), 1);
+        assert_eq!(
+            balance(follower.storage.clone(), "ginger").unwrap(),
+            Some(10)
+        );
+
+        for validator in [follower, leader, other] {
+            validator.stop().unwrap();
+        }
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+        for identity in [
+            "identity-dropped.key",
+            "identity-dropped-leader.key",
+            "identity-dropped-other.key",
+        ] {
+            fs::remove_file(identity).unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn validators_agree_on_the_leader_after_a_stake() {
+        let (path, leader_path) = ("db-agree/", "db-agree-leader/");
+        let _ = fs::remove_dir_all(path);
+        let _ = fs::remove_dir_all(leader_path);
+        write_identity("identity-agree.key", &[12; 32]);
+        write_identity("identity-agree-leader.key", &[11; 32]);
+        let staker = SigningKey::from([12; 32]);
+        let staker_account = base64::encode(staker.verification_key().to_bytes());
+        // each pushes to the other, so the follower's address is picked before either starts.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let with_contact = |mut config: TeralConfig, contact| {
+            // datagrams are handed on once the socket has been quiet this long.
+            config.network.gossip.recv_timeout_ms = 100;
+            config
+                .load_storage()
+                .unwrap()
+                .namespace(CLUSTER_NAMESPACE)
+                .unwrap()
+                .set(b"contact_list", &encode_contacts(&[contact]))
+                .unwrap();
+            config
+        };
+
+        let mut leader_config = staked_config(leader_path, "identity-agree-leader.key");
+        leader_config
+            .genesis
+            .allocations
+            .insert(staker_account.clone(), 1000);
+        let mut leader = Validator::new(with_contact(leader_config, addr)).unwrap();
+        let follower_config = || {
+            let mut config = staked_config(path, "identity-agree.key");
+            config
+                .genesis
+                .allocations
+                .insert(staker_account.clone(), 1000);
+            config.network.addr = addr.to_string();
+            config
+        };
+        let leader_addr = leader.gossip.local_addr().unwrap();
+        let mut follower = Validator::new(with_contact(follower_config(), leader_addr)).unwrap();
+
+        leader.submit_transaction(ContractRequest::signed(
+            &staker,
+            String::from("native"),
+            String::from("stake"),
+            json!({ "amount": 100_u64 }),
+            0,
+            0,
+        ));
+        leader.run_slot(Instant::now()).unwrap();
+        follower.run_slot(Instant::now()).unwrap();
+        let staked = stakes(follower.storage.clone()).unwrap();
+        assert_eq!(staked.len(), 2);
+        assert_eq!(staked, stakes(leader.storage.clone()).unwrap());
+
+        // whoever the schedule picks, the other one picks too and takes its block.
+        let mut producers = vec![];
+        while !producers.contains(&follower.pubkey()) {
+            assert!(producers.len() < 64, "the follower never got to lead");
+            let time = now();
+            let next = |validator: &Validator| validator.leader_at(time).unwrap().unwrap();
+            let producer = next(&leader);
+            assert_eq!(producer, next(&follower));
+            let (producing, waiting) = if producer == leader.pubkey() {
+                (&mut leader, &mut follower)
+            } else {
+                (&mut follower, &mut leader)
+            };
+            producing.run_slot(Instant::now()).unwrap();
+            waiting.run_slot(Instant::now()).unwrap();
+            producers.push(producer);
+        }
+        assert_eq!(follower.chain.height(), producers.len() as u64 + 1);
+        assert_eq!(
+            follower.chain.latest_block().unwrap().unwrap().digest(),
+            leader.chain.latest_block().unwrap().unwrap().digest()
+        );
+
+        // the schedule follows from the chain, so a restart does not set it back.
+        follower.stop().unwrap();
+        let restarted = Validator::new(follower_config()).unwrap();
+        let head_time = leader.chain.latest_block().unwrap().unwrap().time();
+        for slot in 0..8 {
+            let time = head_time + slot * 1000 + 1;
+            assert_eq!(
+                restarted.leader_at(time).unwrap(),
+                leader.leader_at(time).unwrap()
+            );
+        }
+
+        restarted.stop().unwrap();
+        leader.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_dir_all(leader_path).unwrap();
+        fs::remove_file("identity-agree.key").unwrap();
+        fs::remove_file("identity-agree-leader.key").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn stop_joins_every_thread() {
+        let path = "db-stop/";
+        let _ = fs::remove_dir_all(path);
+        let validator = Validator::new(config(path, "identity-stop.key")).unwrap();
+
+        // the gossip threads check for exit once every recv_timeout, a second by default.
+        let stopping = Instant::now();
+        validator.stop().unwrap();
+        assert!(stopping.elapsed() < Duration::from_secs(2));
+
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_file("identity-stop.key").unwrap();
+    }
+
+    /// Sends a json-rpc call to `addr`, and returns the response.
+    #[cfg(feature = "rpc")]
+    fn rpc_call(
+        addr: std::net::SocketAddr,
+        method: &str,
+        params: serde_json::Value,
+    ) -> serde_json::Value {
+        use std::io::{Read, Write};
+
+        let body = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        let body = body.to_string();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            addr,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[cfg(feature = "rpc")]
+    #[test]
+    #[serial]
+    fn transactions_submitted_over_rpc_are_finalized() {
+        let (path, identity) = ("db-rpc/", "identity-rpc.key");
+        let _ = fs::remove_dir_all(path);
+        write_identity(identity, &[9; 32]);
+        let pubkey = SigningKey::from([9; 32]).verification_key().to_bytes();
+        let user = SigningKey::from([3; 32]);
+        let user_account = base64::encode(user.verification_key().to_bytes());
+        let mut config = config(path, identity);
+        let allocations = &mut config.genesis.allocations;
+        allocations.insert(base64::encode(pubkey), 1000);
+        allocations.insert(user_account.clone(), 100);
+        config.rpc = Some(crate::config::RpcConfig {
+            addr: String::from("127.0.0.1:0"),
+        });
+
+        let mut validator = Validator::new(config).unwrap();
+        let addr = validator.rpc.as_ref().unwrap().local_addr();
+        // staking makes us the only leader.
+        validator.contract_executer.schedule(ContractRequest::new(
+            pubkey,
+            String::from("native"),
+            String::from("stake"),
+            json!({ "amount": 100_u64 }),
+            0,
+            0,
+        ));
+        validator.finalize_block(now()).unwrap();
+
+        let transfer = ContractRequest::signed(
+            &user,
+            String::from("native"),
+            String::from("transfer"),
+            json!({ "to": "ginger", "amount": 10_u64 }),
+            0,
+            0,
+        );
+        let response = rpc_call(
+            addr,
+            "submit_transaction",
+            serde_json::to_value(&transfer).unwrap(),
+        );
+        assert!(response["result"].is_string(), "{}", response);
+
+        let unsigned = ContractRequest::new(
+            user.verification_key().to_bytes(),
+            String::from("native"),
+            String::from("transfer"),
+            json!({ "to": "ginger", "amount": 10_u64 }),
+            0,
+            0,
+        );
+        let response = rpc_call(
+            addr,
+            "submit_transaction",
+            serde_json::to_value(&unsigned).unwrap(),
+        );
+        assert_eq!(response["error"]["code"], -32000);
+
+        validator.run_slot(Instant::now()).unwrap();
+        let balance = |account: &str| rpc_call(addr, "get_balance", json!({ "account": account }));
+        assert_eq!(balance("ginger")["result"], 10);
+        assert_eq!(
+            balance(&user_account)["result"],
+            100 - 10 - DEFAULT_BASE_FEE
+        );
+        assert_eq!(balance("nobody")["result"], serde_json::Value::Null);
+        let nonce = rpc_call(addr, "get_nonce", json!({ "author": user_account }));
+        assert_eq!(nonce["result"], 1);
+
+        let head = rpc_call(addr, "get_head", json!(null));
+        assert_eq!(head["result"]["height"], 2);
+        assert_eq!(head["result"]["recipts"].as_array().unwrap().len(), 1);
+        let block = rpc_call(addr, "get_block", json!({ "height": 1 }));
+        assert_eq!(block["result"]["height"], 1);
+        let missing = rpc_call(addr, "get_block", json!({ "height": 99 }));
+        assert_eq!(missing["result"], serde_json::Value::Null);
+        assert_eq!(
+            rpc_call(addr, "get_peers", json!(null))["error"]["code"],
+            -32601
+        );
+
+        validator.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_file(identity).unwrap();
     }
 }
@@ -0,0 +1,106 @@
+//! Macros for the native contracts of teral.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, LitStr, Pat};
+
+/// Checks the `req` parameter of a native contract method against a schema before the body
+/// runs, and binds each field of the schema to a local of the same name and of its type.
+///
+/// ```ignore
+/// #[schema("from:str;to:str;amount:u64")]
+/// fn teral_transfer(storage: &ContractStorage, req: &Value) -> Result<(), ()> {
+///     // `from` and `to` are `&str`, and `amount` is a `u64`.
+/// }
+/// ```
+///
+/// The schema is checked strictly, so a request with fields the schema does not name is
+/// rejected too. The method returns `Err(Default::default())` for a request that does not match,
+/// and it needs `validate_schema` in scope to check it. The types are `i64`, `u64`, `u256` (a
+/// `primitive_types::U256`), `bool` and `str` (a `&str`).
+#[proc_macro_attribute]
+pub fn schema(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let schema = parse_macro_input!(attr as LitStr);
+    let method = parse_macro_input!(item as ItemFn);
+    expand(schema, method)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(schema: LitStr, method: ItemFn) -> syn::Result<TokenStream2> {
+    let has_req = method.sig.inputs.iter().any(|input| match input {
+        FnArg::Typed(input) => matches!(&*input.pat, Pat::Ident(pat) if pat.ident == "req"),
+        FnArg::Receiver(_) => false,
+    });
+    if !has_req {
+        return Err(syn::Error::new_spanned(
+            &method.sig,
+            "#[schema] needs the method to take the request as `req`",
+        ));
+    }
+
+    let fields = parse_schema(&schema)?.into_iter().map(|(name, ty)| {
+        let key = name.to_string();
+        let value = match ty.as_str() {
+            "i64" => quote! { req[#key].as_i64() },
+            "u64" => quote! { req[#key].as_u64() },
+            "bool" => quote! { req[#key].as_bool() },
+            "str" => quote! { req[#key].as_str() },
+            // the same forms `validate_schema` takes: a decimal string, or a number.
+            _ => quote! {
+                (match &req[#key] {
+                    ::serde_json::Value::String(value) => {
+                        ::primitive_types::U256::from_dec_str(value).ok()
+                    }
+                    value => value.as_u64().map(::primitive_types::U256::from),
+                })
+            },
+        };
+        quote! {
+            #[allow(unused_variables)]
+            let Some(#name) = #value else {
+                return Err(Default::default());
+            };
+        }
+    });
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = method;
+    let stmts = &block.stmts;
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            if validate_schema(#schema, &req, true).is_err() {
+                return Err(Default::default());
+            }
+            #(#fields)*
+            #(#stmts)*
+        }
+    })
+}
+
+/// The fields of `schema` with their types, failing on the first one that can not be checked.
+fn parse_schema(schema: &LitStr) -> syn::Result<Vec<(Ident, String)>> {
+    let error = |message: String| syn::Error::new(schema.span(), message);
+    schema
+        .value()
+        .split(';')
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (name, ty) = field
+                .split_once(':')
+                .ok_or_else(|| error(format!("`{}` is not of the form `name:type`", field)))?;
+            let name = syn::parse_str::<Ident>(name)
+                .map_err(|_| error(format!("`{}` is not a valid field name", name)))?;
+            match ty {
+                "i64" | "u64" | "u256" | "bool" | "str" => Ok((name, ty.to_string())),
+                _ => Err(error(format!("`{}` is not a schema type", ty))),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,61 @@
+use std::cell::Cell;
+
+use primitive_types::U256;
+use serde_json::{json, Value};
+use teral_macros::schema;
+
+// just enough of the contracts' `validate_schema` for the tests: every field must be there, and
+// nothing else.
+fn validate_schema(schema: &str, req: &Value, strict: bool) -> Result<(), ()> {
+    let names: Vec<_> = schema
+        .split(';')
+        .map(|field| field.split(':').next())
+        .collect();
+    let fields = req.as_object().ok_or(())?;
+    let missing = names
+        .iter()
+        .flatten()
+        .any(|name| !fields.contains_key(*name));
+    let extra = strict && fields.keys().any(|key| !names.contains(&Some(key)));
+    if missing || extra {
+        return Err(());
+    }
+    Ok(())
+}
+
+#[schema("to:str;amount:u64;supply:u256;burn:bool")]
+fn pay(ran: &Cell<bool>, req: &Value) -> Result<(String, u64, U256, bool), ()> {
+    ran.set(true);
+    Ok((to.to_string(), amount, supply, burn))
+}
+
+#[test]
+fn fields_are_bound_with_their_types() {
+    let ran = Cell::new(false);
+    let req = json!({ "to": "ginger", "amount": 5, "supply": "1000", "burn": true });
+    assert_eq!(
+        pay(&ran, &req),
+        Ok((String::from("ginger"), 5, U256::from(1000), true))
+    );
+    assert!(ran.get());
+}
+
+#[test]
+fn requests_that_do_not_match_never_reach_the_body() {
+    let ran = Cell::new(false);
+    // a field of the wrong type.
+    let req = json!({ "to": "ginger", "amount": "5", "supply": 1000, "burn": true });
+    assert_eq!(pay(&ran, &req), Err(()));
+    // a missing field.
+    assert_eq!(pay(&ran, &json!({ "to": "ginger", "amount": 5 })), Err(()));
+    // an extra one.
+    let req = json!({ "to": "ginger", "amount": 5, "supply": 1, "burn": true, "tip": 1 });
+    assert_eq!(pay(&ran, &req), Err(()));
+    assert!(!ran.get());
+}
+
+#[test]
+fn schemas_are_checked_when_compiling() {
+    let tests = trybuild::TestCases::new();
+    tests.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,8 @@
+use teral_macros::schema;
+
+#[schema("from:str;amount")]
+fn pay(req: &serde_json::Value) -> Result<(), ()> {
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use teral_macros::schema;
+
+#[schema("from:str;amount:u64")]
+fn pay(request: &serde_json::Value) -> Result<(), ()> {
+    Ok(())
+}
+
+fn main() {}